@@ -67,7 +67,12 @@ async fn main() {
     let dht = Arc::new(
         dht::DHTBuilder::new()
             .listen_addr(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))
-            .settings(dht::DHTSettingsBuilder::new().read_only(true).build())
+            .settings(
+                dht::DHTSettingsBuilder::new()
+                    .read_only(true)
+                    .build()
+                    .expect("Invalid DHT settings"),
+            )
             .build(shutdown_rx.clone())
             .expect("Failed to init DHT"),
     );
@@ -0,0 +1,26 @@
+use std::net::{IpAddr, SocketAddr};
+
+/// The IP address family of a [Node](crate::common::Node) or peer.
+///
+/// Used for [BEP0032](http://bittorrent.org/beps/bep_0032.html) dual-stack support, where
+/// a requester can ask for nodes of a particular family with the `want` argument.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum AddressFamily {
+    V4,
+    V6,
+}
+
+impl From<&IpAddr> for AddressFamily {
+    fn from(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => AddressFamily::V4,
+            IpAddr::V6(_) => AddressFamily::V6,
+        }
+    }
+}
+
+impl From<&SocketAddr> for AddressFamily {
+    fn from(addr: &SocketAddr) -> Self {
+        AddressFamily::from(&addr.ip())
+    }
+}
@@ -13,6 +13,16 @@ use crate::errors::RustyDHTError;
 /// The length (in bytes) of BitTorrent info hashes and DHT node ids.
 pub const ID_SIZE: usize = 20;
 
+/// Number of characters in a hex-encoded Id
+const HEX_ID_LEN: usize = ID_SIZE * 2;
+
+/// Number of characters in a base32-encoded Id (no padding needed, since 20 bytes is a
+/// multiple of 5)
+const BASE32_ID_LEN: usize = ID_SIZE * 8 / 5;
+
+/// The RFC 4648 base32 alphabet, as used in magnet links
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
 /// Represents the id of a [Node](crate::common::Node) or a BitTorrent info-hash. Basically, it's a
 /// 20-byte identifier.
 #[derive(Eq, PartialEq, Copy, Clone)]
@@ -74,6 +84,17 @@ impl Id {
         self.bytes.to_vec()
     }
 
+    /// Returns the bytes that make up the Id
+    pub fn as_bytes(&self) -> &[u8; ID_SIZE] {
+        &self.bytes
+    }
+
+    /// Returns the Id as a lowercase hex string, the same as its [Display](std::fmt::Display)
+    /// form. [from_hex](Id::from_hex) parses it back.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.bytes)
+    }
+
     /// Evaluates the Id and decides if it's a valid Id for a DHT node with the
     /// provided IP address (based on [BEP0042](http://bittorrent.org/beps/bep_0042.html)).
     /// Note: the current implementation does not handle non-globally-routable address space
@@ -115,13 +136,69 @@ impl Id {
     /// Creates an Id from a hex string.
     ///
     /// For example: `let id = Id::from_hex("88ffb73943354a00dc2dadd14c54d28020a513c8").unwrap();`
+    ///
+    /// Returns a [PacketParseError](RustyDHTError::PacketParseError) that says what's wrong
+    /// if `h` isn't 40 hex characters.
     pub fn from_hex(h: &str) -> Result<Id, RustyDHTError> {
-        let bytes =
-            hex::decode(h).map_err(|hex_err| RustyDHTError::PacketParseError(hex_err.into()))?;
+        if h.len() != HEX_ID_LEN {
+            return Err(RustyDHTError::PacketParseError(anyhow!(
+                "Hex Id {:?} should be {} characters long, not {}",
+                h,
+                HEX_ID_LEN,
+                h.len()
+            )));
+        }
+        let bytes = hex::decode(h).map_err(|hex_err| {
+            RustyDHTError::PacketParseError(anyhow!("Hex Id {:?} is invalid: {}", h, hex_err))
+        })?;
 
         Id::from_bytes(&bytes)
     }
 
+    /// Creates an Id from a base32 string (RFC 4648, without padding), like the info hashes
+    /// in some magnet links. Upper and lower case letters are both accepted.
+    ///
+    /// For example: `let id = Id::from_base32("RD73OOKDGVFABXBNVXIUYVGSQAQKKE6I").unwrap();`
+    ///
+    /// Returns a [PacketParseError](RustyDHTError::PacketParseError) that says what's wrong
+    /// if `b` isn't 32 base32 characters.
+    pub fn from_base32(b: &str) -> Result<Id, RustyDHTError> {
+        if b.len() != BASE32_ID_LEN {
+            return Err(RustyDHTError::PacketParseError(anyhow!(
+                "Base32 Id {:?} should be {} characters long, not {}",
+                b,
+                BASE32_ID_LEN,
+                b.len()
+            )));
+        }
+        let mut bytes = [0u8; ID_SIZE];
+        let mut buffer: u64 = 0;
+        let mut bits = 0;
+        let mut i = 0;
+        for (position, c) in b.chars().enumerate() {
+            let value = BASE32_ALPHABET
+                .iter()
+                .position(|&a| a as char == c.to_ascii_uppercase())
+                .ok_or_else(|| {
+                    RustyDHTError::PacketParseError(anyhow!(
+                        "Base32 Id {:?} has invalid character {:?} at position {}",
+                        b,
+                        c,
+                        position
+                    ))
+                })?;
+            buffer = (buffer << 5) | value as u64;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                bytes[i] = (buffer >> bits) as u8;
+                i += 1;
+            }
+        }
+
+        Ok(Id { bytes })
+    }
+
     /// Computes the exclusive or (XOR) of this Id with another. The BitTorrent DHT
     /// uses XOR as its distance metric.
     ///
@@ -158,6 +235,38 @@ impl Id {
     };
 }
 
+/// Parses a hex or base32 Id, telling them apart by length. See [Id::from_hex] and
+/// [Id::from_base32].
+impl std::str::FromStr for Id {
+    type Err = RustyDHTError;
+
+    fn from_str(s: &str) -> Result<Id, RustyDHTError> {
+        match s.len() {
+            HEX_ID_LEN => Id::from_hex(s),
+            BASE32_ID_LEN => Id::from_base32(s),
+            len => Err(RustyDHTError::PacketParseError(anyhow!(
+                "Id {:?} is {} characters long, but should be {} (hex) or {} (base32)",
+                s,
+                len,
+                HEX_ID_LEN,
+                BASE32_ID_LEN
+            ))),
+        }
+    }
+}
+
+impl From<[u8; ID_SIZE]> for Id {
+    fn from(bytes: [u8; ID_SIZE]) -> Id {
+        Id { bytes }
+    }
+}
+
+impl AsRef<[u8]> for Id {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
 impl std::fmt::Display for Id {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&hex::encode(&self.bytes))
@@ -178,15 +287,21 @@ impl std::hash::Hash for Id {
 
 impl PartialOrd for Id {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Id {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         for i in 0..self.bytes.len() {
             if self.bytes[i] < other.bytes[i] {
-                return Some(std::cmp::Ordering::Less);
+                return std::cmp::Ordering::Less;
             } else if self.bytes[i] > other.bytes[i] {
-                return Some(std::cmp::Ordering::Greater);
+                return std::cmp::Ordering::Greater;
             }
         }
 
-        Some(std::cmp::Ordering::Equal)
+        std::cmp::Ordering::Equal
     }
 }
 
@@ -225,17 +340,20 @@ impl IdPrefixMagic {
                 };
             }
             IpAddr::V6(ipv6) => {
+                // BEP0042 only uses the first 64 bits (the network prefix) of IPv6 addresses
                 let r64: u64 = seed_r.into();
                 let magic: u64 = 0x0103070f1f3f7fff;
                 let ip_int: u64 = u64::from_be_bytes(
-                    ipv6.octets()[8..]
+                    ipv6.octets()[..8]
                         .try_into()
                         .expect("Failed to get IPv6 bytes"),
                 );
                 let nonsense: u64 = ip_int & magic | (r64 << 61);
                 let crc: u32 = crc32::checksum_castagnoli(&nonsense.to_be_bytes());
                 return IdPrefixMagic {
-                    prefix: crc.to_be_bytes()[..2].try_into().expect("Failed to poop"),
+                    prefix: crc.to_be_bytes()[..3]
+                        .try_into()
+                        .expect("Failed to convert bytes 0-2 of the crc into a 3-byte array"),
                     suffix: seed_r,
                 };
             }
@@ -306,6 +424,21 @@ mod tests {
         assert!(id.is_valid_for_ip(&ip));
     }
 
+    #[test]
+    fn test_valid_id_v6() {
+        let ip: IpAddr = "2001:db8:85a3:8d3:1319:8a2e:370:7348".parse().unwrap();
+        let id = Id::from_ip(&ip);
+        assert!(id.is_valid_for_ip(&ip));
+
+        // Only the first 64 bits of the address matter
+        assert!(id.is_valid_for_ip(&"2001:db8:85a3:8d3::1".parse().unwrap()));
+        assert!(!id.is_valid_for_ip(&"2001:db8:85a3:8d4:1319:8a2e:370:7348".parse().unwrap()));
+
+        let mut bytes = id.to_vec();
+        bytes[0] ^= 0xff;
+        assert!(!Id::from_bytes(&bytes).unwrap().is_valid_for_ip(&ip));
+    }
+
     #[test]
     fn test_id_xor() {
         let h1 = Id::from_hex("0000000000000000000000000000000000000001").unwrap();
@@ -362,6 +495,75 @@ mod tests {
         assert!(h1 != h2);
     }
 
+    #[test]
+    fn test_parse_hex() {
+        let id = Id::from_hex("88ffb73943354a00dc2dadd14c54d28020a513c8").unwrap();
+        assert_eq!(
+            Id::from_hex("88FFB73943354A00DC2DADD14C54D28020A513C8").unwrap(),
+            id
+        );
+        assert_eq!(
+            "88ffb73943354a00dc2dadd14c54d28020a513c8"
+                .parse::<Id>()
+                .unwrap(),
+            id
+        );
+
+        let err = Id::from_hex("88ffb739").unwrap_err();
+        assert!(matches!(err, RustyDHTError::PacketParseError(_)));
+        assert!(err
+            .to_string()
+            .contains("should be 40 characters long, not 8"));
+        let err = Id::from_hex("88ffb73943354a00dc2dadd14c54d28020a513cz").unwrap_err();
+        assert!(matches!(err, RustyDHTError::PacketParseError(_)));
+        assert!(err.to_string().contains("'z'"));
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let id = Id::from_random(&mut thread_rng());
+        assert_eq!(Id::from(*id.as_bytes()), id);
+        assert_eq!(Id::from_bytes(id.to_vec()).unwrap(), id);
+        assert_eq!(Id::from_bytes(id).unwrap(), id);
+        assert_eq!(id.as_ref(), &id.as_bytes()[..]);
+        assert_eq!(Id::from_hex(&id.to_hex()).unwrap(), id);
+        assert_eq!(id.to_hex(), id.to_string());
+
+        let mut map = std::collections::HashMap::new();
+        map.insert(id.to_vec(), 1);
+        assert_eq!(map.get(id.as_ref()), Some(&1));
+    }
+
+    #[test]
+    fn test_parse_base32() {
+        let id = Id::from_hex("88ffb73943354a00dc2dadd14c54d28020a513c8").unwrap();
+        assert_eq!(
+            Id::from_base32("RD73OOKDGVFABXBNVXIUYVGSQAQKKE6I").unwrap(),
+            id
+        );
+        assert_eq!(
+            Id::from_base32("rd73ookdgvfabxbnvxiuyvgsqaqkke6i").unwrap(),
+            id
+        );
+        assert_eq!(
+            "RD73OOKDGVFABXBNVXIUYVGSQAQKKE6I".parse::<Id>().unwrap(),
+            id
+        );
+
+        let err = Id::from_base32("RD73OOKD").unwrap_err();
+        assert!(matches!(err, RustyDHTError::PacketParseError(_)));
+        assert!(err
+            .to_string()
+            .contains("should be 32 characters long, not 8"));
+        let err = Id::from_base32("RD73OOKDGVFABXBNVXIUYVGSQAQKKE61").unwrap_err();
+        assert!(matches!(err, RustyDHTError::PacketParseError(_)));
+        assert!(err.to_string().contains("'1' at position 31"));
+
+        let err = "not an id".parse::<Id>().unwrap_err();
+        assert!(matches!(err, RustyDHTError::PacketParseError(_)));
+        assert!(err.to_string().contains("40 (hex) or 32 (base32)"));
+    }
+
     #[test]
     fn test_matching_prefix_bits() {
         let h1 = Id::from_hex("0000000000000000000000000000000000000000").unwrap();
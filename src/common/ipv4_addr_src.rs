@@ -25,6 +25,14 @@ pub trait IPV4AddrSource: DynClone + Send {
     /// * `proposed_addr` - The external IP address that the other DHT node says we have.
     fn add_vote(&mut self, their_addr: Ipv4Addr, proposed_addr: Ipv4Addr);
 
+    /// Returns how many votes are currently counted, for all proposed addresses.
+    ///
+    /// Only used for reporting. The default implementation returns 0, for sources that
+    /// don't count votes.
+    fn vote_count(&self) -> usize {
+        0
+    }
+
     /// This will get called by DHT at some regular interval. Implementations
     /// can use it to allow old information to "decay" over time.
     fn decay(&mut self);
@@ -115,6 +123,10 @@ impl IPV4AddrSource for IPV4Consensus {
         }
     }
 
+    fn vote_count(&self) -> usize {
+        self.votes.iter().map(|vote| vote.votes as usize).sum()
+    }
+
     fn decay(&mut self) {
         for vote in self.votes.iter_mut() {
             vote.votes = std::cmp::max(0, vote.votes - 1);
@@ -158,6 +170,7 @@ mod tests {
         // Competing vote, still nothing
         src.add_vote(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(2, 2, 2, 2));
         assert_eq!(None, src.get_best_ipv4());
+        assert_eq!(2, src.vote_count());
 
         // Another vote for the first one. Got something now
         src.add_vote(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(1, 1, 1, 1));
@@ -1,9 +1,15 @@
+mod address_family;
+pub use address_family::AddressFamily;
+
 mod id;
 pub use id::{Id, ID_SIZE};
 
 mod node;
 pub use node::Node;
 
+mod typed_id;
+pub use typed_id::{InfoHash, NodeId};
+
 mod transaction_id;
 pub use transaction_id::TransactionId;
 
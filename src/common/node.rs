@@ -1,4 +1,4 @@
-use super::Id;
+use super::{AddressFamily, Id};
 use std::net::SocketAddr;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -17,4 +17,9 @@ impl Node {
             address: address,
         }
     }
+
+    /// Returns the address family (IPv4 or IPv6) of this Node's address.
+    pub fn family(&self) -> AddressFamily {
+        AddressFamily::from(&self.address)
+    }
 }
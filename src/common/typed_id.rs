@@ -0,0 +1,72 @@
+use super::Id;
+
+macro_rules! typed_id {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        #[derive(Eq, PartialEq, Copy, Clone, Hash, PartialOrd)]
+        pub struct $name(pub Id);
+
+        impl From<Id> for $name {
+            fn from(id: Id) -> Self {
+                $name(id)
+            }
+        }
+
+        impl From<$name> for Id {
+            fn from(typed: $name) -> Self {
+                typed.0
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                std::fmt::Display::fmt(&self.0, f)
+            }
+        }
+
+        impl std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                std::fmt::Debug::fmt(&self.0, f)
+            }
+        }
+    };
+}
+
+typed_id!(
+    /// An [Id](crate::common::Id) that is known to be a BitTorrent info hash.
+    ///
+    /// Operations that take an info hash accept anything that converts into an InfoHash.
+    /// A plain Id converts freely, but a [NodeId](crate::common::NodeId) doesn't, so
+    /// passing a node's id where an info hash belongs is caught at compile time.
+    ///
+    /// ```
+    /// use rustydht_lib::common::{Id, InfoHash};
+    /// use rustydht_lib::dht::{operations, DHT};
+    /// use std::time::Duration;
+    ///
+    /// async fn lookup(dht: &DHT, info_hash: InfoHash) {
+    ///     let _ = operations::get_peers(dht, info_hash, Duration::from_secs(10)).await;
+    /// }
+    ///
+    /// let info_hash: InfoHash = Id::from_hex("0011223344556677889900112233445566778899")
+    ///     .unwrap()
+    ///     .into();
+    /// ```
+    ///
+    /// ```compile_fail
+    /// use rustydht_lib::common::NodeId;
+    /// use rustydht_lib::dht::{operations, DHT};
+    /// use std::time::Duration;
+    ///
+    /// async fn oops(dht: &DHT, node_id: NodeId) {
+    ///     // Doesn't compile: a NodeId isn't an info hash
+    ///     let _ = operations::get_peers(dht, node_id, Duration::from_secs(10)).await;
+    /// }
+    /// ```
+    InfoHash
+);
+
+typed_id!(
+    /// An [Id](crate::common::Id) that is known to identify a [Node](crate::common::Node) on the DHT.
+    NodeId
+);
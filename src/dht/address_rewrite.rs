@@ -0,0 +1,20 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// A function that maps an address as the DHT sees it locally to the address that
+/// should be advertised to other nodes instead.
+///
+/// This helps behind NATs that don't preserve ports, e.g. when the DHT's port is
+/// forwarded from a different external port. It's applied to:
+/// * The `requester_ip` field of our responses. The function gets the requester's
+///   address as our socket saw it.
+/// * The port we announce when announcing ourselves as a peer with
+///   [announce_peer](crate::dht::operations::announce_peer). The function gets our
+///   own address: the external IPv4 address from the DHT's
+///   [IPV4AddrSource](crate::common::ipv4_addr_src::IPV4AddrSource) if it knows one
+///   (otherwise the socket's address), with the port being announced. The
+///   rewritten port always wins over the announced one, and is announced explicitly
+///   instead of with `implied_port`.
+///
+/// Install one with [DHTBuilder::address_rewriter](crate::dht::DHTBuilder::address_rewriter).
+pub type AddressRewriter = Arc<dyn Fn(SocketAddr) -> SocketAddr + Send + Sync>;
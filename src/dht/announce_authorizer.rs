@@ -0,0 +1,16 @@
+use crate::packets::AnnouncePeerRequestArguments;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// A function that decides whether an announce_peer request may store its peer, e.g. to
+/// only accept announces for an allow-list of info_hashes in a private overlay.
+///
+/// It gets the requester's address and the request's arguments, and is only called for
+/// requests with a valid announce token. It returns true to store the peer as usual. If it
+/// returns false, the requester gets a KRPC error back and nothing is stored.
+///
+/// The function runs in the DHT's packet handling loop, so it should be quick.
+///
+/// Install one with [DHTBuilder::announce_authorizer](crate::dht::DHTBuilder::announce_authorizer).
+pub type AnnounceAuthorizer =
+    Arc<dyn Fn(SocketAddr, &AnnouncePeerRequestArguments) -> bool + Send + Sync>;
@@ -0,0 +1,81 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The longest a packet will be held back to stay under the outbound bandwidth limit.
+/// Packets that would have to wait longer are dropped instead, as a congested link would.
+pub(crate) const MAX_SEND_DELAY: Duration = Duration::from_secs(2);
+
+/// The lowest rate a [BandwidthLimiter] works with. Its bucket has to hold at least one
+/// full-size packet, or big packets would never go out.
+pub(crate) const MIN_BYTES_PER_SEC: u64 = 1500;
+
+/// Token bucket that limits the rate of outgoing bytes.
+///
+/// The bucket holds up to one second's worth of bytes, so short bursts are sent right
+/// away. Sends that don't fit are given a delay, and the bucket goes into debt to cover
+/// them so that later sends queue up behind them.
+pub(crate) struct BandwidthLimiter {
+    bytes_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl BandwidthLimiter {
+    pub(crate) fn new(bytes_per_sec: u64) -> BandwidthLimiter {
+        BandwidthLimiter {
+            bytes_per_sec: bytes_per_sec as f64,
+            tokens: bytes_per_sec as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Reserves bandwidth for sending `num_bytes` at `now`.
+    ///
+    /// Returns how long to wait before sending, or None if that would be longer than
+    /// [MAX_SEND_DELAY] (in which case nothing is reserved).
+    pub(crate) fn reserve(&mut self, num_bytes: usize, now: Instant) -> Option<Duration> {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+        self.last_refill = now;
+
+        let remaining = self.tokens - num_bytes as f64;
+        if remaining >= 0.0 {
+            self.tokens = remaining;
+            return Some(Duration::ZERO);
+        }
+        let delay = Duration::from_secs_f64(-remaining / self.bytes_per_sec);
+        if delay > MAX_SEND_DELAY {
+            return None;
+        }
+        self.tokens = remaining;
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bandwidth_limiter() {
+        let mut limiter = BandwidthLimiter::new(1000);
+        let now = Instant::now();
+
+        // A second's worth of bytes goes out right away
+        assert_eq!(limiter.reserve(1000, now), Some(Duration::ZERO));
+
+        // Then sends queue up behind each other
+        assert_eq!(limiter.reserve(500, now), Some(Duration::from_millis(500)));
+        assert_eq!(limiter.reserve(500, now), Some(Duration::from_millis(1000)));
+
+        // Too far back in the queue
+        assert_eq!(limiter.reserve(1500, now), None);
+
+        // The bucket refills over time, but never beyond a second's worth
+        let later = now + Duration::from_secs(10);
+        assert_eq!(limiter.reserve(1000, later), Some(Duration::ZERO));
+        assert_eq!(limiter.reserve(1, later), Some(Duration::from_millis(1)));
+    }
+}
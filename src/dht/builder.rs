@@ -1,19 +1,38 @@
 use crate::common::ipv4_addr_src::{IPV4AddrSource, IPV4Consensus};
-use crate::common::Id;
-use crate::dht::{DHTSettings, DHT};
+use crate::common::{Id, Node};
+#[cfg(test)]
+use crate::dht::mock_network::MockNetwork;
+use crate::dht::packet_tap::PacketTap;
+use crate::dht::socket::DHTSocket;
+use crate::dht::{AddressRewriter, AnnounceAuthorizer, DHTSettings, ErrorAction, ErrorPolicy, DHT};
 use crate::errors::RustyDHTError;
+use crate::packets::AnnouncePeerRequestArguments;
 use crate::shutdown::ShutdownReceiver;
 use crate::storage::node_bucket_storage::{NodeBucketStorage, NodeStorage};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use anyhow::anyhow;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
 
 /// Helps to configure and create new [DHT](crate::dht::DHT) instances.
 #[derive(Clone)]
 pub struct DHTBuilder {
     initial_id: Option<Id>,
     listen_addr: Option<SocketAddrV4>,
+    /// Shared by clones of the builder, but only the first DHT built gets to take it
+    socket: Option<Arc<Mutex<Option<UdpSocket>>>>,
     ip_source: Option<Box<dyn IPV4AddrSource + Send>>,
-    route_table: Option<Box<dyn NodeStorage + Send>>,
+    external_port_votes: Option<(usize, usize)>,
+    route_table: Option<Box<dyn NodeStorage + Send + Sync>>,
     settings: Option<DHTSettings>,
+    error_policy: Option<ErrorPolicy>,
+    address_rewriter: Option<AddressRewriter>,
+    announce_authorizer: Option<AnnounceAuthorizer>,
+    packet_tap: Option<PacketTap>,
+    initial_nodes: Vec<Node>,
+    client_only: bool,
+    #[cfg(test)]
+    mock_network: Option<(MockNetwork, SocketAddr)>,
 }
 
 impl DHTBuilder {
@@ -22,9 +41,19 @@ impl DHTBuilder {
         DHTBuilder {
             initial_id: None,
             listen_addr: None,
+            socket: None,
             ip_source: None,
+            external_port_votes: None,
             route_table: None,
             settings: None,
+            error_policy: None,
+            address_rewriter: None,
+            announce_authorizer: None,
+            packet_tap: None,
+            initial_nodes: Vec::new(),
+            client_only: false,
+            #[cfg(test)]
+            mock_network: None,
         }
     }
 
@@ -47,6 +76,20 @@ impl DHTBuilder {
         self
     }
 
+    /// Provides an already bound UDP socket for the DHT to use.
+    ///
+    /// This is useful when the socket is created by the host application, e.g. to
+    /// configure options that DHTBuilder doesn't support or to use a socket passed
+    /// in by systemd socket activation. The socket is used as-is, and
+    /// [listen_addr](crate::dht::DHTBuilder::listen_addr) is ignored.
+    ///
+    /// Only one DHT can use the socket: building another from a clone of this builder
+    /// fails.
+    pub fn with_socket(mut self, socket: UdpSocket) -> Self {
+        self.socket = Some(Arc::new(Mutex::new(Some(socket))));
+        self
+    }
+
     /// Provides an IPV4AddrSource implementation to the DHT.
     ///
     /// DHT will use this object to learn its external IPv4 address and keep
@@ -57,6 +100,15 @@ impl DHTBuilder {
         self
     }
 
+    /// Sets how the DHT settles on its [external port](crate::dht::DHT::get_external_port):
+    /// a port needs at least `min_votes` votes to win, and each port can have at most
+    /// `max_votes`, like in [IPV4Consensus](crate::common::ipv4_addr_src::IPV4Consensus).
+    /// Defaults to 2 and 10.
+    pub fn external_port_votes(mut self, min_votes: usize, max_votes: usize) -> Self {
+        self.external_port_votes = Some((min_votes, max_votes));
+        self
+    }
+
     /// Provides a [NodeStorage](crate::storage::node_bucket_storage::NodeStorage)
     /// implementation to the DHT.
     ///
@@ -64,7 +116,7 @@ impl DHTBuilder {
     /// implementation is [NodeBucketStorage](crate::storage::node_bucket_storage::NodeBucketStorage)
     /// which works roughly the [BEP0005](http://www.bittorrent.org/beps/bep_0005.html)
     /// describes.
-    pub fn route_table(mut self, route_table: Box<dyn NodeStorage + Send>) -> Self {
+    pub fn route_table(mut self, route_table: Box<dyn NodeStorage + Send + Sync>) -> Self {
         self.route_table = Some(route_table);
         self
     }
@@ -78,23 +130,200 @@ impl DHTBuilder {
         self
     }
 
+    /// Provides a function that decides which errors in the DHT's packet handling
+    /// loop are fatal.
+    ///
+    /// Whenever an error occurs while receiving or responding to a packet, DHT will
+    /// call this function. If it returns [ErrorAction::Continue](crate::dht::ErrorAction::Continue)
+    /// the error is logged and the loop keeps going. If it returns
+    /// [ErrorAction::Shutdown](crate::dht::ErrorAction::Shutdown), the error is returned from
+    /// [run_event_loop](crate::dht::DHT::run_event_loop).
+    ///
+    /// If unspecified, [default_error_policy](crate::dht::default_error_policy) is used.
+    pub fn error_policy<F>(mut self, error_policy: F) -> Self
+    where
+        F: Fn(&RustyDHTError) -> ErrorAction + Send + Sync + 'static,
+    {
+        self.error_policy = Some(Arc::new(error_policy));
+        self
+    }
+
+    /// Provides a function that maps addresses seen locally to the addresses that
+    /// should be advertised to other nodes.
+    ///
+    /// See [AddressRewriter](crate::dht::AddressRewriter) for where it's applied and how
+    /// it interacts with the DHT's IPv4 address source. If unspecified, addresses are
+    /// advertised as-is.
+    pub fn address_rewriter<F>(mut self, address_rewriter: F) -> Self
+    where
+        F: Fn(SocketAddr) -> SocketAddr + Send + Sync + 'static,
+    {
+        self.address_rewriter = Some(Arc::new(address_rewriter));
+        self
+    }
+
+    /// Provides a function that decides which announce_peer requests may store their peer.
+    ///
+    /// See [AnnounceAuthorizer](crate::dht::AnnounceAuthorizer). If unspecified, every
+    /// announce with a valid token is accepted.
+    pub fn announce_authorizer<F>(mut self, announce_authorizer: F) -> Self
+    where
+        F: Fn(SocketAddr, &AnnouncePeerRequestArguments) -> bool + Send + Sync + 'static,
+    {
+        self.announce_authorizer = Some(Arc::new(announce_authorizer));
+        self
+    }
+
+    /// Writes a [PacketRecord](crate::dht::PacketRecord) for every packet the DHT sends or
+    /// receives to `writer`, for debugging the protocol offline.
+    ///
+    /// Records are queued for a thread of their own, which makes plain blocking
+    /// [Write](std::io::Write) calls to `writer`, so a slow writer doesn't hold up the DHT.
+    /// If the writer falls more than about a thousand records behind (say, it's blocked on
+    /// a full pipe), new records are dropped until it catches up. A
+    /// [BufWriter](std::io::BufWriter) around a file keeps up easily. Read the records back
+    /// with [PacketRecord::read_from](crate::dht::PacketRecord::read_from). If writing fails,
+    /// the tap is removed. Without a tap, packets aren't copied anywhere.
+    pub fn packet_tap<W>(mut self, writer: W) -> Self
+    where
+        W: std::io::Write + Send + 'static,
+    {
+        self.packet_tap = Some(PacketTap::new(writer));
+        self
+    }
+
+    /// Sets the DHT up as a pure lookup client, which never serves the rest of the network.
+    ///
+    /// In client-only mode:
+    /// * The socket binds to an ephemeral port (unless [listen_addr](DHTBuilder::listen_addr)
+    ///   or [with_socket](DHTBuilder::with_socket) says otherwise), as nobody needs to find it.
+    /// * [read_only](crate::dht::DHTSettings::read_only) is forced on, so inbound queries
+    ///   are never answered and other nodes know not to add us to their routing tables.
+    /// * The periodic tasks that only matter when serving (rotating announce tokens and
+    ///   keeping our Id valid for our IP) don't run.
+    ///
+    /// Lookups like [get_peers](crate::dht::operations::get_peers),
+    /// [find_node](crate::dht::operations::find_node) and
+    /// [announce_peer](crate::dht::operations::announce_peer) work as usual.
+    pub fn client_only(mut self) -> Self {
+        self.client_only = true;
+        self
+    }
+
+    /// Makes the DHT send and receive on `addr` of a [MockNetwork] instead of a UDP socket.
+    #[cfg(test)]
+    pub(crate) fn mock_network(mut self, network: &MockNetwork, addr: SocketAddr) -> Self {
+        self.mock_network = Some((network.clone(), addr));
+        self
+    }
+
+    #[cfg(test)]
+    fn mock_socket(&self, shutdown_rx: &ShutdownReceiver) -> Option<DHTSocket> {
+        self.mock_network
+            .as_ref()
+            .map(|(network, addr)| DHTSocket::new_mock(shutdown_rx.clone(), network.clone(), *addr))
+    }
+
+    #[cfg(not(test))]
+    fn mock_socket(&self, _shutdown_rx: &ShutdownReceiver) -> Option<DHTSocket> {
+        None
+    }
+
+    /// Nodes to put in the routing table (unverified) when the DHT is built.
+    ///
+    /// They're pinged like any other unverified node, so they can be used to bootstrap
+    /// from instead of, or as well as, the [routers](crate::dht::DHTSettings::routers).
+    pub fn initial_nodes(mut self, nodes: Vec<Node>) -> Self {
+        self.initial_nodes = nodes;
+        self
+    }
+
     /// Build a DHT
     ///
     /// This must be called from within a tokio Runtime context because it constructs
     /// a tokio UdpSocket. See [tokio::net::UdpSocket].
     pub fn build(self, shutdown_rx: ShutdownReceiver) -> Result<DHT, RustyDHTError> {
-        DHT::new(
-            shutdown_rx,
-            self.initial_id,
-            std::net::SocketAddr::V4(
-                self.listen_addr
-                    .unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 6881)),
-            ),
-            self.ip_source
-                .unwrap_or_else(|| Box::new(IPV4Consensus::new(2, 10))),
-            self.route_table
-                .unwrap_or_else(|| Box::new(NodeBucketStorage::new(Id::ZERO, 8))),
-            self.settings.unwrap_or_else(|| DHTSettings::default()),
-        )
+        let mock_socket = self.mock_socket(&shutdown_rx);
+        let ip_source = self
+            .ip_source
+            .unwrap_or_else(|| Box::new(IPV4Consensus::new(2, 10)));
+        let mut settings = self.settings.unwrap_or_else(|| DHTSettings::default());
+        let route_table = self.route_table.unwrap_or_else(|| {
+            Box::new(NodeBucketStorage::with_capacity(
+                Id::ZERO,
+                8,
+                8,
+                settings.routing_table_buckets_capacity,
+            ))
+        });
+        if self.client_only {
+            settings.read_only = true;
+        }
+        let default_port = if self.client_only { 0 } else { 6881 };
+        let socket = match self.socket {
+            Some(socket) => Some(socket.lock().unwrap().take().ok_or_else(|| {
+                RustyDHTError::GeneralError(anyhow!(
+                    "The socket given to with_socket is already used by another DHT"
+                ))
+            })?),
+            None => None,
+        };
+        let mut dht = match (mock_socket, socket) {
+            (Some(socket), _) => DHT::new_with_dht_socket(
+                shutdown_rx,
+                self.initial_id,
+                socket,
+                ip_source,
+                route_table,
+                settings,
+            )?,
+            (None, Some(socket)) => DHT::new_with_socket(
+                shutdown_rx,
+                self.initial_id,
+                socket,
+                ip_source,
+                route_table,
+                settings,
+            )?,
+            (None, None) => DHT::new(
+                shutdown_rx,
+                self.initial_id,
+                std::net::SocketAddr::V4(
+                    self.listen_addr
+                        .unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, default_port)),
+                ),
+                ip_source,
+                route_table,
+                settings,
+            )?,
+        };
+
+        if let Some(error_policy) = self.error_policy {
+            dht.set_error_policy(error_policy);
+        }
+
+        if let Some(address_rewriter) = self.address_rewriter {
+            dht.set_address_rewriter(address_rewriter);
+        }
+
+        if let Some(announce_authorizer) = self.announce_authorizer {
+            dht.set_announce_authorizer(announce_authorizer);
+        }
+
+        if let Some(packet_tap) = self.packet_tap {
+            dht.set_packet_tap(packet_tap);
+        }
+
+        if self.client_only {
+            dht.set_client_only();
+        }
+
+        if let Some((min_votes, max_votes)) = self.external_port_votes {
+            dht.set_external_port_votes(min_votes, max_votes);
+        }
+
+        dht.add_initial_nodes(self.initial_nodes);
+
+        Ok(dht)
     }
 }
@@ -0,0 +1,27 @@
+use crate::common::Id;
+
+/// Number of buckets around our own Id that [DHT::coverage_near](crate::dht::DHT::coverage_near)
+/// compares the neighborhood of a target to
+pub(crate) const COVERAGE_BUCKETS: usize = 3;
+
+/// How well the routing table covers the keyspace near a target, returned by
+/// [DHT::coverage_near](crate::dht::DHT::coverage_near).
+///
+/// The routing table knows the keyspace around our own Id best, since its buckets are split
+/// the furthest there. Nodes count as near the target if they're about as close to it as
+/// the nodes in the deepest few buckets are to our own Id. A lookup toward a target with
+/// few near nodes starts out far from it, so it relies on other nodes to get closer and its
+/// results are less reliable. If there are few near nodes even for our own Id, the
+/// routing table is still sparse and the DHT may need to bootstrap further first.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CoverageReport {
+    /// How many leading bits a node has to share with the target to count as near
+    pub prefix_bits: usize,
+
+    /// Number of verified nodes near the target
+    pub near_nodes: usize,
+
+    /// XOR distance from the target to the nearest verified node, or None if there are no
+    /// verified nodes
+    pub nearest_distance: Option<Id>,
+}
@@ -4,26 +4,44 @@ use rand::prelude::SliceRandom;
 use rand::{thread_rng, Rng};
 
 use futures::StreamExt;
-use tokio::net::{lookup_host, UdpSocket};
-use tokio::sync::mpsc;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc, Notify, OwnedSemaphorePermit, Semaphore};
 use tokio::time::sleep;
 
 use log::{debug, error, info, trace, warn};
 
-extern crate crc;
-use crc::{crc32, Hasher32};
+use lru::LruCache;
 
+use sha2::{Digest, Sha256};
+
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, RwLock};
 use std::time::{Duration, Instant};
 
 use crate::common::ipv4_addr_src::IPV4AddrSource;
-use crate::common::{Id, Node};
-use crate::dht::dht_event::{DHTEvent, DHTEventType, MessageReceivedEvent};
+use crate::common::{AddressFamily, Id, Node, ID_SIZE};
+use crate::dht::coverage::COVERAGE_BUCKETS;
+use crate::dht::dht_event::{
+    DHTEvent, DHTEventMask, DHTEventType, EventObserver, IdChangedEvent, IpVoteUpdatedEvent,
+    MessageReceivedEvent, TokenValidationFailedEvent,
+};
+use crate::dht::drop_log_sampler::DropLogSampler;
+use crate::dht::operations::{resolve_router, OperationScheduler, FEW_NODES};
+use crate::dht::packet_tap::PacketTap;
+use crate::dht::port_consensus::PortConsensus;
+use crate::dht::query_rates::{QueryType, QueryTypeCounter};
+use crate::dht::router_stats::{
+    order_routers, ROUTER_PING_STAGGER, ROUTER_PING_TIMEOUT, ROUTER_PROBE_INTERVAL,
+};
 use crate::dht::socket::DHTSocket;
-use crate::dht::DHTSettings;
+use crate::dht::{
+    default_error_policy, AddressRewriter, AnnounceAuthorizer, CoverageReport, DHTSettings,
+    ErrorAction, ErrorPolicy, ImpliedPortPolicy, OwnIdCollisionPolicy, QueryTypeRates,
+    ReadOnlyRequesterPolicy, ResponseVerificationPolicy, RestartPolicy, RouterStats,
+};
 use crate::errors::RustyDHTError;
 use crate::packets;
 use crate::packets::MessageBuilder;
@@ -35,23 +53,155 @@ use crate::storage::throttler::Throttler;
 
 struct DHTState {
     ip4_source: Box<dyn IPV4AddrSource + Send>,
+    /// Notified whenever ip4_source gets a new vote
+    ip4_vote_notify: Arc<Notify>,
+    /// Votes on the port that other nodes see our packets come from
+    external_port_votes: PortConsensus,
     our_id: Id,
-    buckets: Box<dyn NodeStorage + Send>,
     peer_storage: PeerStorage,
     token_secret: Vec<u8>,
     old_token_secret: Vec<u8>,
     settings: DHTSettings,
     subscribers: Vec<mpsc::Sender<DHTEvent>>,
+    observers: broadcast::Sender<DHTEvent>,
+    /// When each recent request arrived, and what it asked for
+    recent_requests: LruCache<(SocketAddr, Vec<u8>), (Instant, packets::RequestSpecific)>,
+    invalid_sender_id_count: usize,
+    old_token_count: usize,
+    token_failure_count: usize,
+    router_stats: HashMap<String, RouterStats>,
+    router_ping_rounds: usize,
+    /// Routers that haven't answered a ping yet
+    router_seeds: Vec<String>,
+    /// Addresses that the routers have resolved to
+    router_addrs: HashSet<SocketAddr>,
+    /// Nodes that routers have told us about, to pass along while we know too few verified
+    /// nodes of our own
+    router_nodes: LruCache<Id, Node>,
+    contacted_addrs: LruCache<SocketAddr, ()>,
+    /// IPs that have answered our requests. Keyed by IP rather than address, like
+    /// `unsolicited_response_throttler`, since the reply limit is about the host.
+    responsive_ips: LruCache<IpAddr, ()>,
+    /// Bytes of replies sent to addresses that never answered our requests
+    unsolicited_response_throttler: ResponseThrottler,
+    /// Packets received per source address, to drop floods before they're handled
+    incoming_throttler: IncomingThrottler,
+    started_at: Instant,
+    /// True once the event loop has checked for something to bootstrap from, so that an
+    /// event loop that's restarted doesn't repeat the warning
+    checked_bootstrap_source: bool,
+    last_unsolicited_request: Option<Instant>,
+    /// IPs of nodes that used our Id, under [OwnIdCollisionPolicy::Ban]
+    banned_ips: LruCache<IpAddr, ()>,
+    /// Packets that used our Id, so that they aren't each logged
+    own_id_collisions: DropLogSampler,
+    /// Incoming requests by type, for [DHT::query_type_rates]
+    query_type_counter: QueryTypeCounter,
+}
+
+/// Number of nodes that routers have told us about to remember
+const MAX_ROUTER_NODES: usize = 64;
+
+/// Number of nodes of each address family to include in a reply, like a full bucket
+const MAX_REPLY_NODES: usize = 8;
+
+/// Number of IPs banned under [OwnIdCollisionPolicy::Ban] to remember
+const MAX_BANNED_IPS: usize = 1024;
+
+/// One of every this many packets that use our Id gets a warning
+const OWN_ID_COLLISION_LOG_RATE: u64 = 100;
+
+/// Number of received packets that may be handled at the same time. Receiving pauses
+/// while this many are in flight.
+const MAX_CONCURRENT_REQUESTS: usize = 64;
+
+/// Number of events kept for [DHT::observe] observers that are behind
+const OBSERVER_BUFFER_SIZE: usize = 1024;
+
+/// Number of addresses that we've sent requests to (or that have answered them) to
+/// remember, for telling apart unsolicited requests
+const MAX_CONTACTED_ADDRS: usize = 4096;
+
+/// Tracks packets received per source address
+type IncomingThrottler = Throttler<32>;
+
+fn make_incoming_throttler(settings: &DHTSettings) -> IncomingThrottler {
+    let mut throttler = Throttler::new(
+        10,
+        Duration::from_secs(6),
+        Duration::from_secs(60),
+        Duration::from_secs(86400),
+    );
+    throttler.set_prefix_lengths(
+        settings.throttle_ipv4_prefix_len,
+        settings.throttle_ipv6_prefix_len,
+    );
+    throttler
+}
+
+/// Tracks reply bytes per source address for
+/// [max_unsolicited_response_bytes_per_min](crate::dht::DHTSettings::max_unsolicited_response_bytes_per_min)
+type ResponseThrottler = Throttler<256>;
+
+fn make_response_throttler(settings: &DHTSettings) -> ResponseThrottler {
+    let mut throttler = Throttler::new(
+        settings
+            .max_unsolicited_response_bytes_per_min
+            .unwrap_or(usize::MAX),
+        Duration::from_secs(60),
+        Duration::from_secs(60),
+        Duration::from_secs(86400),
+    );
+    throttler.set_prefix_lengths(
+        settings.throttle_ipv4_prefix_len,
+        settings.throttle_ipv6_prefix_len,
+    );
+    throttler
 }
 
+/// The routing table, shared between the DHT and the tasks it spawns
+type RoutingTable = Arc<RwLock<Box<dyn NodeStorage + Send + Sync>>>;
+
 /// This struct is the heart of the library - contains data structure and business logic to run a DHT node.
+///
+/// # Reflection attacks
+///
+/// Requests arrive over UDP, so their source address can be forged. An attacker can send
+/// us small requests "from" a victim's address and have us send the victim our (usually
+/// larger) replies. The DHT only replies to requests, and limits how much it helps such an
+/// attack: addresses that have never answered one of our requests can be limited to a number
+/// of reply bytes per minute ([max_unsolicited_response_bytes_per_min](crate::dht::DHTSettings::max_unsolicited_response_bytes_per_min)),
+/// and replies can optionally be capped at a multiple of their request's size
+/// ([max_response_amplification](crate::dht::DHTSettings::max_response_amplification)).
 pub struct DHT {
     socket: Arc<DHTSocket>,
 
     /// Coarse-grained locking for stuff what needs it
     state: Arc<Mutex<DHTState>>,
 
+    /// The routing table. It's read for every find_node and get_peers request, so it has
+    /// its own lock that lets those reads happen at the same time.
+    ///
+    /// To avoid deadlocks, never lock `state` while holding this lock. Locking this while
+    /// holding `state` is fine.
+    buckets: RoutingTable,
+
     shutdown: shutdown::ShutdownReceiver,
+
+    /// Decides which errors in the packet handling loop are fatal
+    error_policy: ErrorPolicy,
+
+    /// Maps local addresses to the ones we advertise
+    address_rewriter: Option<AddressRewriter>,
+
+    /// Decides which announces may store their peer
+    announce_authorizer: Option<AnnounceAuthorizer>,
+
+    /// True if we only do lookups, and never serve the network
+    client_only: bool,
+
+    /// Paces the requests that operations send to each node
+    operation_scheduler: Arc<OperationScheduler>,
 }
 
 impl DHT {
@@ -60,8 +210,78 @@ impl DHT {
         self.state.lock().unwrap().our_id
     }
 
+    /// Returns the port that other nodes see our packets come from, once enough of them
+    /// agree on it.
+    ///
+    /// This is learned from the `requester_ip` of responses, like our external IPv4
+    /// address. It differs from our socket's port behind a NAT that rewrites ports, in
+    /// which case [announce_peer](crate::dht::operations::announce_peer) without a port
+    /// announces it explicitly rather than relying on implied_port.
+    pub fn get_external_port(&self) -> Option<u16> {
+        self.state
+            .lock()
+            .unwrap()
+            .external_port_votes
+            .get_best_port()
+    }
+
+    /// Switches to a new node Id, e.g. to make the DHT harder to track over time.
+    ///
+    /// If `new_id` is None, a random Id is generated (one that's valid for our external
+    /// IPv4 address, if we know it). A given `new_id` is rejected if
+    /// [enforce_id_ip_validity](crate::dht::DHTSettings::enforce_id_ip_validity) is on and
+    /// it isn't valid for our external IPv4 address.
+    ///
+    /// Other nodes file us under our Id, so changing it detaches us from the network
+    /// somewhat. The routing table is cleared and the DHT bootstraps again: it asks the
+    /// nodes it knew that are nearest to the new Id (or the routers, if it knew none) for
+    /// nodes near the new Id. Subscribers get an
+    /// [IdChanged](crate::dht::DHTEventType::IdChanged) event.
+    ///
+    /// Returns the new Id.
+    pub async fn rotate_id(&self, new_id: Option<Id>) -> Result<Id, RustyDHTError> {
+        let (old_id, new_id, bootstrap_nodes) = {
+            let mut state = self.state.lock().unwrap();
+            let our_ip = state.ip4_source.get_best_ipv4().map(IpAddr::V4);
+            let new_id = match (new_id, our_ip) {
+                (Some(id), Some(ip))
+                    if state.settings.enforce_id_ip_validity && !id.is_valid_for_ip(&ip) =>
+                {
+                    return Err(RustyDHTError::GeneralError(anyhow!(
+                        "Id {} is not valid for our IP {}",
+                        id,
+                        ip
+                    )));
+                }
+                (Some(id), _) => id,
+                (None, Some(ip)) => Id::from_ip(&ip),
+                (None, None) => Id::from_random(&mut thread_rng()),
+            };
+            let mut buckets = self.buckets.write().unwrap();
+            let bootstrap_nodes = buckets.get_nearest_nodes(&new_id, None);
+            buckets.set_id(new_id);
+            let old_id = state.our_id;
+            state.our_id = new_id;
+            (old_id, new_id, bootstrap_nodes)
+        };
+        info!(target: "rustydht_lib::DHT", "Rotated our id from {} to {}", old_id, new_id);
+        self.send_event_to_subscribers(DHTEvent {
+            event_type: DHTEventType::IdChanged(IdChangedEvent { old_id, new_id }),
+        });
+
+        if bootstrap_nodes.is_empty() {
+            self.ping_routers().await?;
+        }
+        for node in bootstrap_nodes {
+            self.find_node_internal(self.shutdown.clone(), node.address, Some(node.id), new_id)
+                .await?;
+        }
+        Ok(new_id)
+    }
+
     /// Returns a full dump of all the info hashes and peers in storage.
     /// Peers that haven't announced since the provided `newer_than` can be optionally filtered.
+    /// Only IPv4 peers are included (see [PeerStorage::get_peers_info]).
     pub fn get_info_hashes(&self, newer_than: Option<Instant>) -> Vec<(Id, Vec<PeerInfo>)> {
         let state = self.state.lock().unwrap();
         let hashes = state.peer_storage.get_info_hashes();
@@ -73,9 +293,251 @@ impl DHT {
             .collect()
     }
 
+    /// Pins `info_hash` in peer storage, e.g. because we seed it ourselves.
+    ///
+    /// The peers of a pinned info_hash are never evicted to make room for other info
+    /// hashes, and it has room for more peers than the others. Pinned info hashes don't
+    /// count toward [max_torrents](crate::dht::DHTSettings::max_torrents).
+    pub fn pin_info_hash(&self, info_hash: Id) {
+        self.state.lock().unwrap().peer_storage.pin(info_hash);
+    }
+
+    /// Undoes [pin_info_hash](DHT::pin_info_hash). Returns false if `info_hash` wasn't pinned.
+    pub fn unpin_info_hash(&self, info_hash: &Id) -> bool {
+        self.state.lock().unwrap().peer_storage.unpin(info_hash)
+    }
+
+    /// Lifts the ban of an IP banned under [OwnIdCollisionPolicy::Ban]. Returns false if
+    /// `ip` wasn't banned.
+    pub fn unban_ip(&self, ip: &IpAddr) -> bool {
+        self.state.lock().unwrap().banned_ips.pop(ip).is_some()
+    }
+
+    /// Sets how long the peers of `info_hash` are handed out in get_peers responses,
+    /// overriding [get_peers_freshness_secs](crate::dht::DHTSettings::get_peers_freshness_secs)
+    /// for that info_hash, or goes back to the default if `ttl` is None. Peers older than the
+    /// TTL are also pruned from storage.
+    ///
+    /// Returns false if there are no peers stored for `info_hash`.
+    pub fn set_info_hash_ttl(&self, info_hash: &Id, ttl: Option<Duration>) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .peer_storage
+            .set_ttl(info_hash, ttl)
+    }
+
+    /// Like [get_info_hashes](DHT::get_info_hashes), but returns only how many peers each
+    /// info hash has. This avoids copying every [PeerInfo], so it's much cheaper when a lot
+    /// is stored. Like there, only IPv4 peers count.
+    pub fn info_hash_summary(&self, newer_than: Option<Instant>) -> Vec<(Id, usize)> {
+        let state = self.state.lock().unwrap();
+        state
+            .peer_storage
+            .get_info_hashes()
+            .into_iter()
+            .map(|hash| {
+                (
+                    hash,
+                    state
+                        .peer_storage
+                        .count_peers(&hash, newer_than, &[AddressFamily::V4]),
+                )
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+
+    /// Returns up to `max` of the IPv4 peers stored for `info_hash`, in the compact format
+    /// of [BEP0005](http://bittorrent.org/beps/bep_0005.html): 6 bytes per peer,
+    /// concatenated. IPv6 peers come from [compact_peers6](DHT::compact_peers6), so that
+    /// each list can be split up without knowing which family each peer is.
+    ///
+    /// These are the peers we'd hand out in a get_peers response right now, so peers that
+    /// haven't announced within
+    /// [get_peers_freshness_secs](crate::dht::DHTSettings::get_peers_freshness_secs) (or the
+    /// info_hash's [TTL](DHT::set_info_hash_ttl)) are left out.
+    pub fn compact_peers(&self, info_hash: &Id, max: usize) -> Vec<u8> {
+        self.compact_peers_of_family(info_hash, max, AddressFamily::V4)
+    }
+
+    /// Same as [compact_peers](DHT::compact_peers), but for IPv6 peers: 18 bytes per peer.
+    pub fn compact_peers6(&self, info_hash: &Id, max: usize) -> Vec<u8> {
+        self.compact_peers_of_family(info_hash, max, AddressFamily::V6)
+    }
+
+    fn compact_peers_of_family(
+        &self,
+        info_hash: &Id,
+        max: usize,
+        family: AddressFamily,
+    ) -> Vec<u8> {
+        let state = self.state.lock().unwrap();
+        let newer_than = DHT::freshness_cutoff(&state, info_hash);
+        state
+            .peer_storage
+            .get_peers_for_families(info_hash, newer_than, &[family])
+            .iter()
+            .take(max)
+            .flat_map(packets::sockaddr_to_bytes)
+            .collect()
+    }
+
     /// Returns information about all currently-verified DHT nodes that we're "connected" with.
     pub fn get_nodes(&self) -> Vec<NodeWrapper> {
-        self.state.lock().unwrap().buckets.get_all_verified()
+        self.buckets.read().unwrap().get_all_verified()
+    }
+
+    /// Returns how many verified nodes fall in each XOR distance bucket from our Id.
+    ///
+    /// Index `i` counts the nodes whose Ids share exactly `i` leading bits with ours,
+    /// so higher indexes are closer to us. A routing table with good keyspace coverage
+    /// has entries spread across many of them.
+    pub fn bucket_distribution(&self) -> [usize; ID_SIZE * 8] {
+        let our_id = self.get_id();
+        let mut distribution = [0; ID_SIZE * 8];
+        for node in self.buckets.read().unwrap().get_all_verified() {
+            let bucket = our_id.matching_prefix_bits(&node.node.id);
+            // Only a node with our own Id would share all the bits
+            if let Some(count) = distribution.get_mut(bucket) {
+                *count += 1;
+            }
+        }
+        distribution
+    }
+
+    /// Returns true if verified nodes with Ids `a` and `b` would be stored in the same
+    /// bucket of our routing table, and so compete with each other for its space.
+    ///
+    /// This follows the buckets as they've been split so far: the deepest bucket holds
+    /// every node that shares at least as many leading bits with our Id as its index. If
+    /// the routing table doesn't use buckets, nodes are compared by how many leading bits
+    /// they share with our Id.
+    pub fn same_bucket(&self, a: &Id, b: &Id) -> bool {
+        let buckets = self.buckets.read().unwrap();
+        match (buckets.bucket_index(a), buckets.bucket_index(b)) {
+            (Some(a_index), Some(b_index)) => a_index == b_index,
+            _ => {
+                let our_id = self.get_id();
+                our_id.matching_prefix_bits(a) == our_id.matching_prefix_bits(b)
+            }
+        }
+    }
+
+    /// Reports how well the routing table covers the keyspace near `target`, to help decide
+    /// whether to bootstrap further before relying on lookups toward it. See [CoverageReport].
+    pub fn coverage_near(&self, target: &Id) -> CoverageReport {
+        let our_id = self.get_id();
+        let buckets = self.buckets.read().unwrap();
+        let nodes = buckets.get_all_verified();
+
+        // The deepest bucket is the one our own Id would go in. If the routing table doesn't
+        // use buckets, go by the verified node closest to us instead.
+        let depth = buckets.bucket_index(&our_id).unwrap_or_else(|| {
+            nodes
+                .iter()
+                .map(|node| our_id.matching_prefix_bits(&node.node.id))
+                .max()
+                .unwrap_or(0)
+        });
+        let prefix_bits = depth.saturating_sub(COVERAGE_BUCKETS - 1);
+        let near_nodes = nodes
+            .iter()
+            .filter(|node| target.matching_prefix_bits(&node.node.id) >= prefix_bits)
+            .count();
+        let nearest_distance = nodes.iter().map(|node| node.node.id.xor(target)).min();
+        CoverageReport {
+            prefix_bits,
+            near_nodes,
+            nearest_distance,
+        }
+    }
+
+    /// Dumps the verified nodes in the routing table to a stable, compact binary format.
+    ///
+    /// Each entry is a node's 20 byte Id followed by its compact address (6 bytes for IPv4,
+    /// 18 for IPv6). Entries are sorted, so two DHTs with the same verified nodes produce
+    /// the same bytes.
+    pub fn dump_routing_table_compact(&self) -> Vec<u8> {
+        let mut entries: Vec<Vec<u8>> = self
+            .get_nodes()
+            .iter()
+            .map(|wrapper| {
+                let mut entry = wrapper.node.id.to_vec();
+                entry.append(&mut packets::sockaddr_to_bytes(&wrapper.node.address));
+                entry
+            })
+            .collect();
+        entries.sort();
+        entries.concat()
+    }
+
+    /// Returns the SHA256 hash of [dump_routing_table_compact](DHT::dump_routing_table_compact),
+    /// for cheaply comparing the routing tables of two DHTs.
+    pub fn routing_table_digest(&self) -> [u8; 32] {
+        Sha256::digest(self.dump_routing_table_compact()).into()
+    }
+
+    /// Returns whether other nodes can reach us without us contacting them first (i.e. we're
+    /// not stuck behind a NAT or firewall), based on whether we've received such a request
+    /// within the last [reachability_window_secs](crate::dht::DHTSettings::reachability_window_secs).
+    ///
+    /// Returns None until either such a request arrives or the DHT has been running for
+    /// that long.
+    pub fn is_reachable(&self) -> Option<bool> {
+        let state = self.state.lock().unwrap();
+        let window = Duration::from_secs(state.settings.reachability_window_secs);
+        match state.last_unsolicited_request {
+            Some(last) if last.elapsed() <= window => Some(true),
+            _ if state.started_at.elapsed() >= window => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of queries we've received with a missing or malformed sender id.
+    pub fn invalid_sender_id_count(&self) -> usize {
+        self.state.lock().unwrap().invalid_sender_id_count
+    }
+
+    /// Returns the number of announce_peer requests we've accepted with a token made from
+    /// the previous token secret, rather than the current one.
+    ///
+    /// These requesters held onto their token across a rotation. If many do, many more
+    /// probably held on too long and got rejected (see [token_failure_count](DHT::token_failure_count)).
+    pub fn old_token_count(&self) -> usize {
+        self.state.lock().unwrap().old_token_count
+    }
+
+    /// Returns the token that we'd give `addr` in a get_peers response right now.
+    ///
+    /// The token is what a node at `addr` has to send back in an announce_peer request.
+    /// It's derived from `addr` and a secret that never leaves the DHT, and changes when
+    /// the secret is rotated. This is meant for test harnesses and for debugging announces
+    /// that get rejected.
+    pub fn compute_token(&self, addr: &SocketAddr) -> Vec<u8> {
+        DHT::current_token(&self.state.lock().unwrap(), addr)
+    }
+
+    /// Returns the number of announce_peer requests we've rejected because their token
+    /// didn't match either the current or the previous token secret.
+    pub fn token_failure_count(&self) -> usize {
+        self.state.lock().unwrap().token_failure_count
+    }
+
+    /// Returns how many requests of each type we've received within the last
+    /// [query_rates_window_secs](crate::dht::DHTSettings::query_rates_window_secs).
+    pub fn query_type_rates(&self) -> QueryTypeRates {
+        self.state
+            .lock()
+            .unwrap()
+            .query_type_counter
+            .rates(Instant::now())
+    }
+
+    /// Returns the number of incoming packets we've dropped because they couldn't be
+    /// parsed or came from port 0.
+    pub fn dropped_packet_count(&self) -> u64 {
+        self.socket.dropped_packets().total()
     }
 
     /// Return a copy of the settings used by the DHT
@@ -83,25 +545,152 @@ impl DHT {
         self.state.lock().unwrap().settings.clone()
     }
 
+    /// Replaces the settings of the running DHT with `new_settings`.
+    ///
+    /// Most settings take effect right away. The ones that are only used when the DHT is
+    /// created (`token_secret_size`, `max_peers_per_torrent`, `ip_tos`, `dual_stack`,
+    /// `max_send_bytes_per_sec`, `pinned_info_hashes_capacity` and
+    /// `routing_table_buckets_capacity`) can't be changed without restarting, and neither can
+    /// `max_torrents` be lowered below the number of info_hashes currently stored. A
+    /// [client-only](crate::dht::DHTBuilder::client_only) DHT must stay `read_only`. If any of those
+    /// would change, nothing is applied and a
+    /// [SettingsUpdateError](RustyDHTError::SettingsUpdateError) lists them. Settings that
+    /// fail [validate](DHTSettings::validate) aren't applied either.
+    pub fn update_settings(&self, new_settings: DHTSettings) -> Result<(), RustyDHTError> {
+        new_settings.validate()?;
+        let mut state = self.state.lock().unwrap();
+        let current = &state.settings;
+        let mut rejected = Vec::new();
+        if new_settings.token_secret_size != current.token_secret_size {
+            rejected.push("token_secret_size");
+        }
+        if new_settings.max_peers_per_torrent != current.max_peers_per_torrent {
+            rejected.push("max_peers_per_torrent");
+        }
+        if new_settings.ip_tos != current.ip_tos {
+            rejected.push("ip_tos");
+        }
+        if new_settings.dual_stack != current.dual_stack {
+            rejected.push("dual_stack");
+        }
+        if new_settings.max_send_bytes_per_sec != current.max_send_bytes_per_sec {
+            rejected.push("max_send_bytes_per_sec");
+        }
+        if new_settings.pinned_info_hashes_capacity != current.pinned_info_hashes_capacity {
+            rejected.push("pinned_info_hashes_capacity");
+        }
+        if new_settings.routing_table_buckets_capacity != current.routing_table_buckets_capacity {
+            rejected.push("routing_table_buckets_capacity");
+        }
+        if new_settings.max_torrents < state.peer_storage.count_unpinned() {
+            rejected.push("max_torrents");
+        }
+        if self.client_only && !new_settings.read_only {
+            rejected.push("read_only");
+        }
+        if !rejected.is_empty() {
+            return Err(RustyDHTError::SettingsUpdateError(rejected));
+        }
+
+        state
+            .peer_storage
+            .set_max_torrents(new_settings.max_torrents);
+        state
+            .peer_storage
+            .set_duplicate_policy(new_settings.duplicate_peer_policy);
+        state
+            .recent_requests
+            .resize(new_settings.max_recent_requests);
+        self.socket
+            .dropped_packets()
+            .set_sample_rate(new_settings.drop_log_sample_rate);
+        self.socket.set_request_max_lifetime(
+            Duration::from_secs(new_settings.outgoing_request_prune_secs),
+            Duration::from_secs(new_settings.outgoing_reqiest_check_interval_secs),
+        );
+        state
+            .router_seeds
+            .retain(|router| new_settings.routers.contains(router));
+        if new_settings.max_unsolicited_response_bytes_per_min
+            != state.settings.max_unsolicited_response_bytes_per_min
+            || new_settings.throttle_ipv4_prefix_len != state.settings.throttle_ipv4_prefix_len
+            || new_settings.throttle_ipv6_prefix_len != state.settings.throttle_ipv6_prefix_len
+        {
+            state.unsolicited_response_throttler = make_response_throttler(&new_settings);
+        }
+        state.incoming_throttler.set_prefix_lengths(
+            new_settings.throttle_ipv4_prefix_len,
+            new_settings.throttle_ipv6_prefix_len,
+        );
+        if new_settings.query_rates_window_secs != state.settings.query_rates_window_secs {
+            state.query_type_counter =
+                QueryTypeCounter::new(Duration::from_secs(new_settings.query_rates_window_secs));
+        }
+        self.operation_scheduler
+            .set_min_interval(Duration::from_millis(
+                new_settings.operation_request_interval_millis,
+            ));
+        state.settings = new_settings;
+        Ok(())
+    }
+
     /// Creates a new DHT.
     ///
     /// # Arguments
     /// * `shutdown` - the DHT passes this to any sub-tasks that it spawns, and uses it to know when to stop its event own event loop.
     /// * `id` - an optional initial Id for the DHT. The DHT may change its Id if at some point its not valid for the external IPv4 address (as reported by ip4_source).
-    /// * `listen_port` - the port that the DHT should bind its UDP socket on.
+    /// * `socket_addr` - the address that the DHT should bind its UDP socket on. Must be IPv4 unless
+    ///   [dual_stack](crate::dht::DHTSettings::dual_stack) is enabled, otherwise [RustyDHTError::AddressFamilyError] is returned.
     /// * `ip4_source` - Some type that implements IPV4AddrSource. This object will be used by the DHT to keep up to date on its IPv4 address.
     /// * `buckets` - A function that takes an Id and returns a struct implementing NodeStorage. The NodeStorage-implementing type will be used to keep the nodes
     /// (or routing table) of the DHT.
     /// * `routers` - Array of string slices with hostname:port of DHT routers. These help us get bootstrapped onto the network.
-    /// * `settings` - DHTSettings struct containing settings that DHT will use.
+    /// * `settings` - DHTSettings struct containing settings that DHT will use. Settings that fail
+    ///   [validate](DHTSettings::validate) are refused.
     pub fn new(
         shutdown: shutdown::ShutdownReceiver,
         id: Option<Id>,
         socket_addr: std::net::SocketAddr,
         ip4_source: Box<dyn IPV4AddrSource + Send>,
-        mut buckets: Box<dyn NodeStorage + Send>,
+        buckets: Box<dyn NodeStorage + Send + Sync>,
+        settings: DHTSettings,
+    ) -> Result<DHT, RustyDHTError> {
+        settings.validate()?;
+        check_address_family(socket_addr, &settings)?;
+        let socket = bind_socket(socket_addr, &settings)?;
+        DHT::new_with_socket(shutdown, id, socket, ip4_source, buckets, settings)
+    }
+
+    /// Creates a new DHT that uses an already bound UDP socket rather than binding its own.
+    ///
+    /// The socket is used as-is, so settings that configure the socket (like
+    /// [ip_tos](crate::dht::DHTSettings::ip_tos)) aren't applied to it.
+    pub(crate) fn new_with_socket(
+        shutdown: shutdown::ShutdownReceiver,
+        id: Option<Id>,
+        socket: impl Into<Arc<UdpSocket>>,
+        ip4_source: Box<dyn IPV4AddrSource + Send>,
+        buckets: Box<dyn NodeStorage + Send + Sync>,
+        settings: DHTSettings,
+    ) -> Result<DHT, RustyDHTError> {
+        let socket = socket.into();
+        if let Ok(local_addr) = socket.local_addr() {
+            check_address_family(local_addr, &settings)?;
+        }
+        let socket = DHTSocket::new(shutdown.clone(), socket);
+        DHT::new_with_dht_socket(shutdown, id, socket, ip4_source, buckets, settings)
+    }
+
+    /// Creates a new DHT on top of a [DHTSocket], however that sends and receives its packets.
+    pub(crate) fn new_with_dht_socket(
+        shutdown: shutdown::ShutdownReceiver,
+        id: Option<Id>,
+        mut socket: DHTSocket,
+        ip4_source: Box<dyn IPV4AddrSource + Send>,
+        mut buckets: Box<dyn NodeStorage + Send + Sync>,
         settings: DHTSettings,
     ) -> Result<DHT, RustyDHTError> {
+        settings.validate()?;
         // If we were given a hardcoded id, use that until/unless we decide its invalid based on IP source.
         // If we weren't given a hardcoded id, try to generate one based on IP source.
         // Finally, if all else fails, generate a totally random id.
@@ -130,56 +719,122 @@ impl DHT {
 
         buckets.set_id(our_id);
 
-        // Setup our UDP socket
-        let socket = {
-            let std_sock = std::net::UdpSocket::bind(socket_addr)
-                .map_err(|e| RustyDHTError::GeneralError(e.into()))?;
-            std_sock
-                .set_nonblocking(true)
-                .map_err(|e| RustyDHTError::GeneralError(e.into()))?;
-            let tokio_sock =
-                UdpSocket::from_std(std_sock).map_err(|e| RustyDHTError::GeneralError(e.into()))?;
-            Arc::new(DHTSocket::new(shutdown.clone(), tokio_sock))
-        };
+        socket.set_send_rate_limit(settings.max_send_bytes_per_sec);
+        socket.set_request_max_lifetime(
+            Duration::from_secs(settings.outgoing_request_prune_secs),
+            Duration::from_secs(settings.outgoing_reqiest_check_interval_secs),
+        );
+        socket
+            .dropped_packets()
+            .set_sample_rate(settings.drop_log_sample_rate);
+        let socket = Arc::new(socket);
 
         let token_secret = make_token_secret(settings.token_secret_size);
+        let mut peer_storage = PeerStorage::with_capacity(
+            settings.max_torrents,
+            settings.max_peers_per_torrent,
+            settings.pinned_info_hashes_capacity,
+        );
+        peer_storage.set_duplicate_policy(settings.duplicate_peer_policy);
+        let operation_request_interval_millis = settings.operation_request_interval_millis;
+        if accepts_announces_without_token(&settings) {
+            warn!(target: "rustydht_lib::DHT", "Accepting announces without a token. This is for testing only!");
+        }
 
         let dht = DHT {
             socket: socket,
             state: Arc::new(Mutex::new(DHTState {
                 ip4_source: ip4_source,
+                ip4_vote_notify: Arc::new(Notify::new()),
+                external_port_votes: PortConsensus::new(2, 10),
                 our_id: our_id,
-                buckets: buckets,
-                peer_storage: PeerStorage::new(
-                    settings.max_torrents,
-                    settings.max_peers_per_torrent,
-                ),
+                peer_storage,
                 token_secret: token_secret.clone(),
                 old_token_secret: token_secret,
+                recent_requests: LruCache::new(settings.max_recent_requests),
+                invalid_sender_id_count: 0,
+                old_token_count: 0,
+                token_failure_count: 0,
+                router_stats: HashMap::new(),
+                router_ping_rounds: 0,
+                router_seeds: settings.routers.clone(),
+                router_addrs: HashSet::new(),
+                router_nodes: LruCache::new(MAX_ROUTER_NODES),
+                contacted_addrs: LruCache::new(MAX_CONTACTED_ADDRS),
+                responsive_ips: LruCache::new(MAX_CONTACTED_ADDRS),
+                unsolicited_response_throttler: make_response_throttler(&settings),
+                incoming_throttler: make_incoming_throttler(&settings),
+                started_at: Instant::now(),
+                checked_bootstrap_source: false,
+                last_unsolicited_request: None,
+                banned_ips: LruCache::new(MAX_BANNED_IPS),
+                own_id_collisions: DropLogSampler::new(OWN_ID_COLLISION_LOG_RATE),
+                query_type_counter: QueryTypeCounter::new(Duration::from_secs(
+                    settings.query_rates_window_secs,
+                )),
                 settings: settings,
                 subscribers: vec![],
+                observers: broadcast::channel(OBSERVER_BUFFER_SIZE).0,
             })),
+            buckets: Arc::new(RwLock::new(buckets)),
 
             shutdown: shutdown,
+            error_policy: Arc::new(default_error_policy),
+            address_rewriter: None,
+            announce_authorizer: None,
+            client_only: false,
+            operation_scheduler: Arc::new(OperationScheduler::new(Duration::from_millis(
+                operation_request_interval_millis,
+            ))),
         };
 
         Ok(dht)
     }
 
+    /// Returns the address that the DHT's socket is bound to.
+    ///
+    /// When the DHT was asked to listen on port 0, this has the port the OS picked.
+    pub fn local_addr(&self) -> Result<SocketAddr, RustyDHTError> {
+        self.socket.local_addr()
+    }
+
+    /// Binds a new UDP socket to `new_addr` and switches the DHT over to it.
+    ///
+    /// The routing table, peer storage, and pending requests are kept. The old socket keeps
+    /// receiving for a short time so that responses to requests already sent from it aren't lost.
+    pub fn rebind(&self, new_addr: SocketAddr) -> Result<(), RustyDHTError> {
+        let settings = self.get_settings();
+        check_address_family(new_addr, &settings)?;
+        let socket = bind_socket(new_addr, &settings)?;
+        info!(target: "rustydht_lib::DHT", "Rebinding to {}", new_addr);
+        self.socket.rebind(socket)
+    }
+
     /// Runs the main event loop of the DHT.
     ///
     /// It will only return if there's an error or if the DHT's ShutdownReceiver is signalled to stop the DHT.
     pub async fn run_event_loop(&self) -> Result<(), RustyDHTError> {
+        let first_start = !std::mem::replace(
+            &mut self.state.lock().unwrap().checked_bootstrap_source,
+            true,
+        );
+        if first_start && !self.has_bootstrap_source() {
+            warn!(target: "rustydht_lib::DHT", "No routers are configured and the routing table is empty. This DHT won't join the network unless other nodes contact it first");
+            self.send_event_to_subscribers(DHTEvent {
+                event_type: DHTEventType::BootstrapUnavailable,
+            });
+        }
         match tokio::try_join!(
             // One-time
-            self.ping_routers(self.shutdown.clone()),
+            self.ping_routers(),
             // Loop indefinitely
             self.accept_incoming_packets(),
-            self.periodic_router_ping(self.shutdown.clone()),
+            self.periodic_router_ping(),
             self.periodic_buddy_ping(self.shutdown.clone()),
             self.periodic_find_node(self.shutdown.clone()),
             self.periodic_ip4_maintenance(),
             self.periodic_token_rotation(),
+            self.periodic_drop_summary(),
             async {
                 let to_ret: Result<(), RustyDHTError> = Err(RustyDHTError::ShutdownError(anyhow!(
                     "run_event_loop should shutdown"
@@ -199,12 +854,73 @@ impl DHT {
         }
     }
 
+    /// Runs [run_event_loop](DHT::run_event_loop), restarting it according to
+    /// `restart_policy` when it stops with a fatal error (see
+    /// [DHTBuilder::error_policy](crate::dht::DHTBuilder::error_policy)).
+    ///
+    /// The routing table, peer storage, and everything else the DHT knows are kept across
+    /// restarts. Returns Ok when the DHT is shut down, or the last error once the loop has
+    /// been restarted `max_restarts` times without staying up for `stable_period`.
+    pub async fn run_event_loop_supervised(
+        &self,
+        restart_policy: RestartPolicy,
+    ) -> Result<(), RustyDHTError> {
+        let mut restarts = 0;
+        loop {
+            let started = tokio::time::Instant::now();
+            let err = match self.run_event_loop().await {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            if started.elapsed() >= restart_policy.stable_period {
+                restarts = 0;
+            }
+            if restarts >= restart_policy.max_restarts {
+                error!(target: "rustydht_lib::DHT", "Event loop failed after {} restarts: {:?}", restarts, err);
+                return Err(err);
+            }
+            let backoff = restart_policy.backoff(restarts);
+            restarts += 1;
+            warn!(target: "rustydht_lib::DHT", "Event loop failed, restarting in {:?} ({}/{}): {:?}", backoff, restarts, restart_policy.max_restarts, err);
+            let mut shutdown = self.shutdown.clone();
+            tokio::select! {
+                _ = shutdown.watch() => return Ok(()),
+                _ = sleep(backoff) => {}
+            }
+
+            let is_socket_error = matches!(
+                err,
+                RustyDHTError::SocketSendError(_) | RustyDHTError::SocketRecvError(_)
+            );
+            if is_socket_error {
+                if restart_policy.rebind_on_socket_error {
+                    let settings = self.get_settings();
+                    if let Err(e) = self
+                        .socket
+                        .reopen(|addr| bind_socket(addr, &settings))
+                        .await
+                    {
+                        error!(target: "rustydht_lib::DHT", "Couldn't reopen the socket after a socket error: {:?}", e);
+                        return Err(e);
+                    }
+                } else {
+                    self.socket.resume_receiving();
+                }
+            }
+        }
+    }
+
     /// Sends a [Message](crate::packets::Message), awaits and returns a response.
     ///
     /// Note that `req` must be a request message (not a response or error message),
     /// as this method awaits a reply. DHT automatically handles sending responses for
     /// incoming requests.
     ///
+    /// `req` is sent as-is, so its sender id doesn't have to be the DHT's own Id. Responses
+    /// are matched to requests by transaction id. The one exception is the read-only flag:
+    /// if `req` doesn't set it and the DHT is [read_only](crate::dht::DHTSettings::read_only),
+    /// it's set, so that nodes don't add a read-only DHT to their routing tables.
+    ///
     /// # Arguments
     /// * `req` - the message that should be sent
     /// * `dest` - the IP/port of the intended recipient
@@ -214,16 +930,23 @@ impl DHT {
     /// to the message within the allotted time.
     pub async fn send_request(
         &self,
-        req: packets::Message,
+        mut req: packets::Message,
         dest: SocketAddr,
         dest_id: Option<Id>,
         timeout: Option<Duration>,
     ) -> Result<packets::Message, RustyDHTError> {
+        if req.read_only.is_none()
+            && matches!(req.message_type, packets::MessageType::Request(_))
+            && self.state.lock().unwrap().settings.read_only
+        {
+            req.read_only = Some(true);
+        }
         match timeout {
             Some(timeout) => match tokio::time::timeout(
                 timeout,
                 DHT::common_send_and_handle_response(
                     self.state.clone(),
+                    self.buckets.clone(),
                     self.socket.clone(),
                     req.clone(),
                     dest,
@@ -243,6 +966,7 @@ impl DHT {
             None => {
                 DHT::common_send_and_handle_response(
                     self.state.clone(),
+                    self.buckets.clone(),
                     self.socket.clone(),
                     req.clone(),
                     dest,
@@ -253,80 +977,488 @@ impl DHT {
         }
     }
 
+    /// Sends `bytes` to `dest` verbatim, bypassing [MessageBuilder] and serialization.
+    ///
+    /// This is meant for testing how other DHT implementations cope with hand-crafted or
+    /// fuzzed packets. If `bytes` parse as a request, waits up to `timeout` for the
+    /// reply with the same transaction id and returns its raw bytes, even if the reply is
+    /// an error or doesn't parse. Otherwise nothing is waited for and an empty vector is
+    /// returned.
+    pub async fn send_raw(
+        &self,
+        bytes: Vec<u8>,
+        dest: SocketAddr,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, RustyDHTError> {
+        let response_rx = self.socket.send_raw(bytes, dest).await?;
+        let mut response_rx = match response_rx {
+            Some(response_rx) => response_rx,
+            None => return Ok(Vec::new()),
+        };
+        match tokio::time::timeout(timeout, response_rx.recv()).await {
+            Ok(Some(response)) => Ok(response),
+            Ok(None) => Err(RustyDHTError::GeneralError(anyhow!(
+                "Response channel was unexpectedly closed"
+            ))),
+            Err(_) => Err(RustyDHTError::TimeoutError(anyhow!(
+                "Timed out after {:?} waiting for {} to respond to raw request",
+                timeout,
+                dest
+            ))),
+        }
+    }
+
+    /// Sends `msg` to `dest` and returns a channel for the response (if `msg` is a request)
+    /// instead of waiting for it.
+    ///
+    /// Unlike [send_request](DHT::send_request), the response has no side effects: the
+    /// responder isn't added to the routing table, doesn't vote on our IP address, and so on.
+    /// If `dest_id` is given, responses from any other Id are dropped. Nothing times out on
+    /// its own, so the caller should give up on the channel after a while.
+    pub async fn send_request_raw(
+        &self,
+        msg: packets::Message,
+        dest: SocketAddr,
+        dest_id: Option<Id>,
+    ) -> Result<Option<mpsc::Receiver<packets::Message>>, RustyDHTError> {
+        self.socket.send_to(msg, dest, dest_id).await
+    }
+
     /// Subscribe to DHTEvent notifications from the DHT.
     ///
     /// When you're sick of receiving events from the DHT, just drop the receiver.
+    ///
+    /// Received packets are handled concurrently, so the events about different packets
+    /// (and our replies to them) don't necessarily come in the order the packets arrived.
     pub fn subscribe(&self) -> mpsc::Receiver<DHTEvent> {
         let (tx, rx) = mpsc::channel(32);
         let mut state = self.state.lock().unwrap();
         state.subscribers.push(tx);
         rx
     }
+
+    /// Observe DHTEvent notifications from the DHT.
+    ///
+    /// Like [subscribe](DHT::subscribe), observing is guaranteed never to change the DHT's
+    /// state or hold it up. Unlike it, an observer that falls behind loses the oldest events
+    /// it hasn't received rather than the newest ones: up to the last 1024 events are kept
+    /// for it. See [EventObserver]. As with `subscribe`, events about different received
+    /// packets may come out of the order the packets arrived in.
+    pub fn observe(&self) -> EventObserver {
+        EventObserver::new(self.state.lock().unwrap().observers.subscribe())
+    }
+
+    /// Waits for the next event selected by `filter` and returns it.
+    ///
+    /// This subscribes to events just for the wait, and unsubscribes afterwards. Returns
+    /// a [TimeoutError](crate::errors::RustyDHTError::TimeoutError) if no matching event
+    /// arrives within `timeout`.
+    pub async fn next_event(
+        &self,
+        filter: DHTEventMask,
+        timeout: Duration,
+    ) -> Result<DHTEvent, RustyDHTError> {
+        let mut events = self.subscribe();
+        let result = tokio::time::timeout(timeout, async {
+            while let Some(event) = events.recv().await {
+                if filter.matches(&event) {
+                    return Some(event);
+                }
+            }
+            None
+        })
+        .await;
+
+        drop(events);
+        self.state
+            .lock()
+            .unwrap()
+            .subscribers
+            .retain(|sub| !sub.is_closed());
+
+        match result {
+            Ok(Some(event)) => Ok(event),
+            Ok(None) => Err(RustyDHTError::GeneralError(anyhow!(
+                "DHT stopped sending events"
+            ))),
+            Err(e) => Err(RustyDHTError::TimeoutError(e.into())),
+        }
+    }
 }
 
 impl DHT {
     async fn accept_incoming_packets(&self) -> Result<(), RustyDHTError> {
-        let mut throttler = Throttler::<32>::new(
-            10,
-            Duration::from_secs(6),
-            Duration::from_secs(60),
-            Duration::from_secs(86400),
-        );
         let read_only = self.state.lock().unwrap().settings.read_only;
+        // Packets are screened one at a time, but the ones that pass are handled
+        // concurrently so that a slow reply doesn't hold up the others
+        let mut in_flight = futures::stream::FuturesUnordered::new();
         loop {
-            match async {
-                let (msg, addr) = self.socket.recv_from().await?;
-
-                // Drop the packet if the IP has been throttled.
-                if throttler.check_throttle(addr.ip(), None, None) {
-                    return Ok(());
+            tokio::select! {
+                incoming = self.socket.recv_from(), if in_flight.len() < MAX_CONCURRENT_REQUESTS => {
+                    match self.screen_incoming_packet(incoming, read_only).await {
+                        Ok(Some((msg, addr, request_len, is_duplicate))) => {
+                            in_flight.push(self.handle_incoming_packet(msg, addr, request_len, is_duplicate, read_only));
+                        }
+                        Ok(None) => {}
+                        Err(err) => self.handle_incoming_error(err)?,
+                    }
                 }
 
-                // Filter out packets sent from port 0. We can't reply to these.
-                if addr.port() == 0 {
-                    warn!(target: "rustydht_lib::DHT", "{} has invalid port - dropping packet", addr);
-                    return Ok(());
+                Some(result) = in_flight.next() => {
+                    if let Err(err) = result {
+                        self.handle_incoming_error(err)?;
+                    }
                 }
+            }
+        }
+    }
 
-                // Respond to requests, but only if we're not read-only
-                if !read_only {
-                    self.accept_single_packet(msg.clone(), addr).await?;
+    /// Decides whether a received packet should be handled at all. Returns the packet, its
+    /// size and whether it's a duplicate if so.
+    async fn screen_incoming_packet(
+        &self,
+        incoming: Result<(packets::Message, SocketAddr, usize), RustyDHTError>,
+        read_only: bool,
+    ) -> Result<Option<(packets::Message, SocketAddr, usize, bool)>, RustyDHTError> {
+        let (msg, addr, len) = match incoming {
+            Err(RustyDHTError::InvalidSenderIdError {
+                transaction_id,
+                sender: Some(sender),
+            }) => {
+                if !self.is_throttled(sender.ip()) {
+                    self.handle_invalid_sender_id(transaction_id, sender, read_only)
+                        .await?;
                 }
+                return Ok(None);
+            }
+            incoming => incoming?,
+        };
+
+        // Drop the packet if the IP has been throttled.
+        if self.is_throttled(addr.ip()) {
+            return Ok(None);
+        }
+
+        if self.state.lock().unwrap().banned_ips.contains(&addr.ip()) {
+            return Ok(None);
+        }
+
+        if self.is_ignored_read_only_request(&msg) {
+            trace!(target: "rustydht_lib::DHT", "Ignoring request from read-only node {}", addr);
+            return Ok(None);
+        }
+
+        // Filter out packets sent from port 0. We can't reply to these.
+        if addr.port() == 0 {
+            if self.record_dropped_packet() {
+                warn!(target: "rustydht_lib::DHT", "{} has invalid port - dropping packet", addr);
+            }
+            return Ok(None);
+        }
+
+        let is_duplicate = self.check_duplicate_request(&msg, addr);
+        self.check_unsolicited_request(&msg, addr);
+        Ok(Some((msg, addr, len, is_duplicate)))
+    }
+
+    /// Counts a packet from `ip`, and returns true if `ip` has sent too many to handle
+    fn is_throttled(&self, ip: IpAddr) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .incoming_throttler
+            .check_throttle(ip, None, None)
+    }
+
+    /// Replies to a packet that passed [screen_incoming_packet](DHT::screen_incoming_packet)
+    /// and tells subscribers about it
+    async fn handle_incoming_packet(
+        &self,
+        msg: packets::Message,
+        addr: SocketAddr,
+        request_len: usize,
+        is_duplicate: bool,
+        read_only: bool,
+    ) -> Result<(), RustyDHTError> {
+        // Respond to requests, but only if we're not read-only
+        if !read_only {
+            self.accept_single_packet(msg.clone(), addr, request_len, is_duplicate)
+                .await?;
+        }
 
-                // Send a MessageReceivedEvent to any subscribers
-                self.send_packet_to_subscribers(msg, addr).await;
+        // Send a MessageReceivedEvent to any subscribers
+        self.send_packet_to_subscribers(msg, addr, is_duplicate)
+            .await;
+        Ok(())
+    }
 
-                Ok::<(), RustyDHTError>(())
-            }.await {
-                Ok(_) => continue,
+    /// Counts a query that we couldn't get the sender's id from, and tells the sender
+    /// about it if configured to.
+    async fn handle_invalid_sender_id(
+        &self,
+        transaction_id: Vec<u8>,
+        sender: SocketAddr,
+        read_only: bool,
+    ) -> Result<(), RustyDHTError> {
+        let should_reply = {
+            let mut state = self.state.lock().unwrap();
+            state.invalid_sender_id_count += 1;
+            state.settings.reply_to_invalid_sender_id && !read_only
+        };
+        debug!(target: "rustydht_lib::DHT", "Query from {} has a missing or malformed sender id", sender);
+
+        if should_reply {
+            let reply = MessageBuilder::new_error()
+                .transaction_id(transaction_id)
+                .code(203)
+                .description("Missing or malformed sender id".to_string())
+                .build()?;
+            self.socket.send_to(reply, sender, None).await?;
+        }
+        Ok(())
+    }
 
-                Err(err) => match err {
+    /// Consults the error policy to decide if an error from the packet handling loop
+    /// should stop the loop. Returns Err if it should.
+    fn handle_incoming_error(&self, err: RustyDHTError) -> Result<(), RustyDHTError> {
+        match (self.error_policy)(&err) {
+            ErrorAction::Continue => {
+                match &err {
                     RustyDHTError::PacketParseError(internal) => {
-                        warn!(target: "rustydht_lib::DHT", "Packet parsing error: {:?}", internal);
-                        continue;
+                        if self.record_dropped_packet() {
+                            warn!(target: "rustydht_lib::DHT", "Packet parsing error: {:?}", internal);
+                        }
                     }
 
                     RustyDHTError::ConntrackError(e) => {
                         warn!(target: "rustydht_lib::DHT", "Connection tracking error: {:?}", e);
-                        continue;
                     }
 
                     _ => {
-                        return Err(err.into());
+                        warn!(target: "rustydht_lib::DHT", "Error policy says to continue after error: {:?}", err);
                     }
-                },
+                }
+                Ok(())
             }
+
+            ErrorAction::Shutdown => Err(err),
         }
     }
 
-    /// Carries out some common tasks for each incoming request
-    ///
-    /// 1. Determines if the requester's id is valid for their IP
-    /// 2. Makes sure they have a chance to join the routing table
-    fn common_request_handling(
-        &self,
-        remote_addr: SocketAddr,
-        msg: &packets::Message,
+    /// Counts a dropped incoming packet. Returns true if it should be logged, according
+    /// to [drop_log_sample_rate](crate::dht::DHTSettings::drop_log_sample_rate).
+    fn record_dropped_packet(&self) -> bool {
+        self.socket.dropped_packets().record()
+    }
+
+    /// Replaces the policy used to decide which packet handling errors are fatal.
+    pub(crate) fn set_error_policy(&mut self, error_policy: ErrorPolicy) {
+        self.error_policy = error_policy;
+    }
+
+    /// Starts recording packets to `packet_tap`. See
+    /// [DHTBuilder::packet_tap](crate::dht::DHTBuilder::packet_tap).
+    pub(crate) fn set_packet_tap(&mut self, packet_tap: PacketTap) {
+        self.socket.set_packet_tap(packet_tap);
+    }
+
+    /// Waits for the packet tap, if any, to write everything recorded so far
+    #[cfg(test)]
+    pub(crate) fn flush_packet_tap(&self) {
+        self.socket.flush_packet_tap();
+    }
+
+    /// Puts the DHT in client-only mode. See
+    /// [DHTBuilder::client_only](crate::dht::DHTBuilder::client_only).
+    pub(crate) fn set_client_only(&mut self) {
+        self.client_only = true;
+    }
+
+    /// Replaces the tally of votes for our external port. See
+    /// [DHTBuilder::external_port_votes](crate::dht::DHTBuilder::external_port_votes).
+    pub(crate) fn set_external_port_votes(&mut self, min_votes: usize, max_votes: usize) {
+        self.state.lock().unwrap().external_port_votes = PortConsensus::new(min_votes, max_votes);
+    }
+
+    /// Adds nodes to the routing table as unverified. See [DHTBuilder::initial_nodes].
+    pub(crate) fn add_initial_nodes(&mut self, nodes: Vec<Node>) {
+        let mut buckets = self.buckets.write().unwrap();
+        for node in nodes {
+            buckets.add_or_update(node, false);
+        }
+    }
+
+    /// Returns the [OperationScheduler] that paces the requests of all the
+    /// [operations](crate::dht::operations) running on this DHT. Custom lookups can wait on
+    /// it too, to share the same per-node rate limit.
+    pub fn operation_scheduler(&self) -> Arc<OperationScheduler> {
+        self.operation_scheduler.clone()
+    }
+
+    /// Returns true if the DHT was built with
+    /// [DHTBuilder::client_only](crate::dht::DHTBuilder::client_only).
+    pub fn is_client_only(&self) -> bool {
+        self.client_only
+    }
+
+    /// Replaces the function used to rewrite the addresses that we advertise.
+    pub(crate) fn set_address_rewriter(&mut self, address_rewriter: AddressRewriter) {
+        self.address_rewriter = Some(address_rewriter);
+    }
+
+    pub(crate) fn set_announce_authorizer(&mut self, announce_authorizer: AnnounceAuthorizer) {
+        self.announce_authorizer = Some(announce_authorizer);
+    }
+
+    /// Applies the [AddressRewriter] (if any) to an address before we advertise it.
+    fn advertised_addr(&self, addr: SocketAddr) -> SocketAddr {
+        match &self.address_rewriter {
+            Some(rewrite) => rewrite(addr),
+            None => addr,
+        }
+    }
+
+    /// Returns the port that should be announced when announcing ourselves as a peer on
+    /// `port` (or with implied_port, if `None`), after applying the [AddressRewriter].
+    ///
+    /// Without a rewriter, an explicit `port` is returned unchanged. `None` stays `None`
+    /// (announcing with implied_port), unless other nodes agree that our packets show up
+    /// from a different [external port](DHT::get_external_port) than our socket's. Then the
+    /// external port is announced explicitly.
+    pub(crate) fn advertised_port(&self, port: Option<u16>) -> Result<Option<u16>, RustyDHTError> {
+        if self.address_rewriter.is_none() {
+            if port.is_some() {
+                return Ok(port);
+            }
+            let external_port = match self.get_external_port() {
+                Some(external_port) => external_port,
+                None => return Ok(None),
+            };
+            if external_port != self.socket.local_addr()?.port() {
+                return Ok(Some(external_port));
+            }
+            return Ok(None);
+        }
+        Ok(Some(self.own_advertised_addr(port)?.port()))
+    }
+
+    /// Returns the address other peers should use to reach our peer on `port` (or the DHT's
+    /// own port, if `None`): our external IPv4 address if known (otherwise the socket's),
+    /// after applying the [AddressRewriter].
+    fn own_advertised_addr(&self, port: Option<u16>) -> Result<SocketAddr, RustyDHTError> {
+        let local_addr = self.socket.local_addr()?;
+        let ip = match self.state.lock().unwrap().ip4_source.get_best_ipv4() {
+            Some(ip) => IpAddr::V4(ip),
+            None => local_addr.ip(),
+        };
+        let local_addr = SocketAddr::new(ip, port.unwrap_or_else(|| local_addr.port()));
+        Ok(self.advertised_addr(local_addr))
+    }
+
+    /// Adds the requester's address (as we advertise it) to a response, unless
+    /// [minimal_responses](crate::dht::DHTSettings::minimal_responses) is on.
+    fn with_requester_ip(
+        &self,
+        builder: MessageBuilder,
+        addr: SocketAddr,
+        minimal_responses: bool,
+    ) -> MessageBuilder {
+        if minimal_responses {
+            builder
+        } else {
+            builder.requester_ip(self.advertised_addr(addr))
+        }
+    }
+
+    /// Stores our own peer (on `port`, or the DHT's own port if `None`) for `info_hash` in
+    /// peer storage, so that we include ourselves when others ask us for peers.
+    ///
+    /// Nothing is stored if we don't know an address that others could use to reach us.
+    /// Returns true if our peer was stored.
+    pub(crate) fn announce_self_locally(
+        &self,
+        info_hash: Id,
+        port: Option<u16>,
+    ) -> Result<bool, RustyDHTError> {
+        let addr = self.own_advertised_addr(port)?;
+        let ip = addr.ip();
+        if ip.is_unspecified()
+            || ip.is_multicast()
+            || ip == IpAddr::V4(Ipv4Addr::BROADCAST)
+            || addr.port() == 0
+        {
+            warn!(target: "rustydht_lib::DHT", "Not storing ourselves as a peer for {} since {} isn't a usable address", info_hash, addr);
+            return Ok(false);
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .peer_storage
+            .announce_peer(info_hash, addr);
+        Ok(true)
+    }
+
+    /// Returns true if `msg` is a request from a read-only node that we've been told to
+    /// ignore by [ReadOnlyRequesterPolicy::Ignore].
+    fn is_ignored_read_only_request(&self, msg: &packets::Message) -> bool {
+        msg.read_only == Some(true)
+            && matches!(msg.message_type, packets::MessageType::Request(_))
+            && self
+                .state
+                .lock()
+                .unwrap()
+                .settings
+                .read_only_requester_policy
+                == ReadOnlyRequesterPolicy::Ignore
+    }
+
+    /// Notes when we get a request from an address that we haven't sent anything to,
+    /// which shows that we're reachable.
+    fn check_unsolicited_request(&self, msg: &packets::Message, addr: SocketAddr) {
+        if !matches!(msg.message_type, packets::MessageType::Request(_)) {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if !state.contacted_addrs.contains(&addr) {
+            state.last_unsolicited_request = Some(Instant::now());
+        }
+    }
+
+    /// Remembers incoming requests by source address and transaction id.
+    ///
+    /// Returns true if the same request (with the same contents) was already received within
+    /// [duplicate_request_window_secs](crate::dht::DHTSettings::duplicate_request_window_secs).
+    fn check_duplicate_request(&self, msg: &packets::Message, addr: SocketAddr) -> bool {
+        let request = match &msg.message_type {
+            packets::MessageType::Request(request) => request,
+            _ => return false,
+        };
+        let mut state = self.state.lock().unwrap();
+        let window = Duration::from_secs(state.settings.duplicate_request_window_secs);
+        let now = Instant::now();
+        let is_duplicate = match state
+            .recent_requests
+            .put((addr, msg.transaction_id.clone()), (now, request.clone()))
+        {
+            Some((received_at, previous)) => {
+                now.saturating_duration_since(received_at) < window && previous == *request
+            }
+            None => false,
+        };
+        if is_duplicate {
+            debug!(target: "rustydht_lib::DHT", "Received duplicate request from {}: {:?}", addr, msg);
+        }
+        is_duplicate
+    }
+
+    /// Carries out some common tasks for each incoming request
+    ///
+    /// 1. Determines if the requester's id is valid for their IP
+    /// 2. Makes sure they have a chance to join the routing table
+    fn common_request_handling(
+        &self,
+        remote_addr: SocketAddr,
+        msg: &packets::Message,
     ) -> Result<(), RustyDHTError> {
         let sender_id = match msg.get_author_id() {
             Some(sender_id) => sender_id,
@@ -338,167 +1470,319 @@ impl DHT {
         };
 
         // Is id valid for IP?
-        let is_id_valid = sender_id.is_valid_for_ip(&remote_addr.ip());
+        let is_id_valid = {
+            let mut state = self.state.lock().unwrap();
+            is_id_acceptable(&state.settings, &sender_id, &remote_addr.ip())
+                && !DHT::check_own_id_collision(&mut state, &sender_id, &remote_addr, false)
+        };
         let read_only = match msg.read_only {
             Some(ro) => ro,
             _ => false,
         };
         if is_id_valid && !read_only {
-            self.state
-                .lock()
+            self.buckets
+                .write()
                 .unwrap()
-                .buckets
                 .add_or_update(Node::new(sender_id, remote_addr), false);
         }
         Ok(())
     }
 
+    /// Sends `reply` to the `request_len` byte request that came from `addr`, unless that
+    /// would exceed the limits that protect against reflection attacks (see [DHT]).
+    async fn send_reply(
+        &self,
+        mut reply: packets::Message,
+        request_len: usize,
+        addr: SocketAddr,
+    ) -> Result<(), RustyDHTError> {
+        // Only requests can be read-only
+        reply.read_only = None;
+        let max_response_bytes = {
+            let state = self.state.lock().unwrap();
+            match addr {
+                SocketAddr::V4(_) => state.settings.max_response_bytes,
+                SocketAddr::V6(_) => state.settings.max_ipv6_response_bytes,
+            }
+        };
+        let bytes = match max_response_bytes {
+            Some(max_bytes) => trimmed_reply_bytes(reply, max_bytes, addr)?,
+            None => reply.to_bytes()?,
+        };
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(max_amplification) = state.settings.max_response_amplification {
+                if bytes.len() > request_len.saturating_mul(max_amplification) {
+                    debug!(target: "rustydht_lib::DHT", "Not sending {} byte reply to {} byte request from {}", bytes.len(), request_len, addr);
+                    return Ok(());
+                }
+            }
+            if state
+                .settings
+                .max_unsolicited_response_bytes_per_min
+                .is_some()
+                && !state.responsive_ips.contains(&addr.ip())
+                && state.unsolicited_response_throttler.check_throttle(
+                    addr.ip(),
+                    None,
+                    Some(bytes.len()),
+                )
+            {
+                debug!(target: "rustydht_lib::DHT", "Not replying to {}, which has been sent too many reply bytes", addr);
+                return Ok(());
+            }
+        }
+        self.socket.send_datagram(bytes, addr).await
+    }
+
+    /// Returns true if we know enough verified nodes to answer find_node and get_peers
+    /// requests, per [DHTSettings::min_verified_nodes_to_answer].
+    fn is_bootstrapped_enough_to_answer(&self) -> bool {
+        let min_verified_nodes = self
+            .state
+            .lock()
+            .unwrap()
+            .settings
+            .min_verified_nodes_to_answer;
+        let (_, verified) = self.buckets.read().unwrap().count();
+        verified >= min_verified_nodes
+    }
+
     async fn accept_single_packet(
         &self,
         msg: packets::Message,
         addr: SocketAddr,
+        request_len: usize,
+        is_duplicate: bool,
     ) -> Result<(), RustyDHTError> {
         match &msg.message_type {
             packets::MessageType::Request(request_variant) => {
+                self.state
+                    .lock()
+                    .unwrap()
+                    .query_type_counter
+                    .record(QueryType::of(request_variant), Instant::now());
                 match request_variant {
-                    packets::RequestSpecific::PingRequest(arguments) => {
+                    packets::RequestSpecific::PingRequest(_) => {
                         self.common_request_handling(addr, &msg)?;
 
                         // Build a ping reply
-                        let reply = MessageBuilder::new_ping_response()
-                            .sender_id(self.state.lock().unwrap().our_id)
-                            .transaction_id(msg.transaction_id.clone())
-                            .requester_ip(addr)
+                        let (our_id, minimal_responses) = {
+                            let state = self.state.lock().unwrap();
+                            (state.our_id, state.settings.minimal_responses)
+                        };
+                        let reply = self
+                            .with_requester_ip(
+                                MessageBuilder::new_ping_response()
+                                    .sender_id(our_id)
+                                    .transaction_id(msg.transaction_id.clone()),
+                                addr,
+                                minimal_responses,
+                            )
                             .build()?;
-                        self.socket
-                            .send_to(reply, addr, Some(arguments.requester_id))
-                            .await?;
+                        self.send_reply(reply, request_len, addr).await?;
                     }
 
                     packets::RequestSpecific::GetPeersRequest(arguments) => {
                         self.common_request_handling(addr, &msg)?;
-                        let reply = {
+                        // First, see if we have any peers for their info_hash. The state
+                        // isn't needed for finding the nearest nodes, so let go of it first.
+                        let (peers, token, our_id, minimal_responses, max_nodes_with_peers) = {
                             let state = self.state.lock().unwrap();
+                            let newer_than = DHT::freshness_cutoff(&state, &arguments.info_hash);
+                            let mut peers = state.peer_storage.get_peers_for_families(
+                                &arguments.info_hash,
+                                newer_than,
+                                &wanted_families(&arguments.want, &addr),
+                            );
+                            peers.truncate(state.settings.max_peers_response);
+                            let token = DHT::current_token(&state, &addr);
+                            (
+                                peers,
+                                token,
+                                state.our_id,
+                                state.settings.minimal_responses,
+                                state.settings.max_nodes_with_peers,
+                            )
+                        };
 
-                            // First, see if we have any peers for their info_hash
-                            let peers = {
-                                let newer_than = Instant::now().checked_sub(Duration::from_secs(
-                                    state.settings.get_peers_freshness_secs,
-                                ));
-                                let mut peers = state
-                                    .peer_storage
-                                    .get_peers(&arguments.info_hash, newer_than);
-                                peers.truncate(state.settings.max_peers_response);
-                                peers
-                            };
-                            let token = calculate_token(&addr, state.token_secret.clone());
-
-                            let reply = match peers.len() {
-                                0 => {
-                                    let nearest = state.buckets.get_nearest_nodes(
-                                        &arguments.info_hash,
-                                        Some(&arguments.requester_id),
-                                    );
-
-                                    MessageBuilder::new_get_peers_response()
-                                        .sender_id(state.our_id.clone())
-                                        .transaction_id(msg.transaction_id)
-                                        .requester_ip(addr)
-                                        .token(token.to_vec())
-                                        .nodes(nearest)
-                                        .build()?
-                                }
+                        // Peers are worth handing out even before we know enough nodes
+                        if peers.is_empty() && !self.is_bootstrapped_enough_to_answer() {
+                            debug!(target: "rustydht_lib::DHT", "Not answering get_peers from {} until we have more verified nodes", addr);
+                            return Ok(());
+                        }
 
-                                _ => MessageBuilder::new_get_peers_response()
-                                    .sender_id(state.our_id.clone())
-                                    .transaction_id(msg.transaction_id)
-                                    .requester_ip(addr)
-                                    .token(token.to_vec())
-                                    .peers(peers)
-                                    .build()?,
-                            };
-                            reply
+                        let builder = self
+                            .with_requester_ip(
+                                MessageBuilder::new_get_peers_response()
+                                    .sender_id(our_id)
+                                    .transaction_id(msg.transaction_id.clone()),
+                                addr,
+                                minimal_responses,
+                            )
+                            .token(token);
+                        let nearest = || {
+                            self.nearest_nodes_for_reply(
+                                &our_id,
+                                &arguments.info_hash,
+                                &arguments.requester_id,
+                                &addr,
+                                &arguments.want,
+                            )
+                        };
+                        let reply = match peers.len() {
+                            0 => builder.nodes(nearest()).build()?,
+
+                            _ if max_nodes_with_peers > 0 => {
+                                let mut nodes = nearest();
+                                nodes.truncate(max_nodes_with_peers);
+                                builder.peers_and_nodes(peers, nodes).build()?
+                            }
+
+                            _ => builder.peers(peers).build()?,
                         };
-                        self.socket
-                            .send_to(reply, addr, Some(arguments.requester_id))
-                            .await?;
+                        self.send_reply(reply, request_len, addr).await?;
                     }
 
                     packets::RequestSpecific::FindNodeRequest(arguments) => {
                         self.common_request_handling(addr, &msg)?;
-                        let reply = {
+                        if !self.is_bootstrapped_enough_to_answer() {
+                            debug!(target: "rustydht_lib::DHT", "Not answering find_node from {} until we have more verified nodes", addr);
+                            return Ok(());
+                        }
+                        let (our_id, minimal_responses) = {
                             let state = self.state.lock().unwrap();
-                            let nearest = state.buckets.get_nearest_nodes(
-                                &arguments.target,
-                                Some(&arguments.requester_id),
-                            );
-                            MessageBuilder::new_find_node_response()
-                                .sender_id(state.our_id.clone())
-                                .transaction_id(msg.transaction_id)
-                                .requester_ip(addr)
-                                .nodes(nearest)
-                                .build()?
+                            (state.our_id, state.settings.minimal_responses)
                         };
+                        let nearest = self.nearest_nodes_for_reply(
+                            &our_id,
+                            &arguments.target,
+                            &arguments.requester_id,
+                            &addr,
+                            &arguments.want,
+                        );
+                        let reply = self
+                            .with_requester_ip(
+                                MessageBuilder::new_find_node_response()
+                                    .sender_id(our_id)
+                                    .transaction_id(msg.transaction_id.clone()),
+                                addr,
+                                minimal_responses,
+                            )
+                            .nodes(nearest)
+                            .build()?;
 
-                        self.socket
-                            .send_to(reply, addr, Some(arguments.requester_id))
-                            .await?;
+                        self.send_reply(reply, request_len, addr).await?;
                     }
 
                     packets::RequestSpecific::AnnouncePeerRequest(arguments) => {
                         self.common_request_handling(addr, &msg)?;
-                        let reply = {
+                        let is_token_valid = {
                             let mut state = self.state.lock().unwrap();
-
-                            let is_token_valid = arguments.token
-                                == calculate_token(&addr, state.token_secret.clone())
+                            let token_length = state.settings.token_length;
+                            if accepts_announces_without_token(&state.settings)
                                 || arguments.token
-                                    == calculate_token(&addr, state.old_token_secret.clone());
+                                    == calculate_token(&addr, &state.token_secret, token_length)
+                            {
+                                true
+                            } else if arguments.token
+                                == calculate_token(&addr, &state.old_token_secret, token_length)
+                            {
+                                state.old_token_count += 1;
+                                true
+                            } else {
+                                state.token_failure_count += 1;
+                                debug!(target: "rustydht_lib::DHT", "Rejecting announce_peer from {} with an invalid token", addr);
+                                false
+                            }
+                        };
+                        let is_authorized = match &self.announce_authorizer {
+                            Some(authorizer) if is_token_valid => authorizer(addr, arguments),
+                            _ => true,
+                        };
 
-                            if is_token_valid {
-                                let sockaddr = match arguments.implied_port {
-                                    Some(implied_port) if implied_port == true => addr,
+                        let reply = if !is_token_valid {
+                            None
+                        } else if !is_authorized {
+                            debug!(target: "rustydht_lib::DHT", "Announce of {} from {} was not authorized", arguments.info_hash, addr);
+                            Some(
+                                MessageBuilder::new_error()
+                                    .transaction_id(msg.transaction_id.clone())
+                                    .code(201)
+                                    .description("Announce not authorized".to_string())
+                                    .build()?,
+                            )
+                        } else {
+                            let mut state = self.state.lock().unwrap();
+                            let prefer_explicit = state.settings.implied_port_policy
+                                == ImpliedPortPolicy::PreferExplicit
+                                && arguments.port != 0;
+                            let sockaddr = match arguments.implied_port {
+                                Some(implied_port) if implied_port == true && !prefer_explicit => {
+                                    addr
+                                }
 
-                                    _ => {
-                                        let mut tmp = addr.clone();
-                                        tmp.set_port(arguments.port);
-                                        tmp
-                                    }
-                                };
+                                _ => {
+                                    let mut tmp = addr.clone();
+                                    tmp.set_port(arguments.port);
+                                    tmp
+                                }
+                            };
 
+                            // A retransmitted announce was already stored the first time
+                            if !is_duplicate || !state.settings.skip_duplicate_announces {
                                 state
                                     .peer_storage
                                     .announce_peer(arguments.info_hash, sockaddr);
+                            }
 
-                                Some(
+                            Some(
+                                self.with_requester_ip(
                                     MessageBuilder::new_announce_peer_response()
                                         .sender_id(state.our_id)
-                                        .transaction_id(msg.transaction_id.clone())
-                                        .requester_ip(addr)
-                                        .build()?,
+                                        .transaction_id(msg.transaction_id.clone()),
+                                    addr,
+                                    state.settings.minimal_responses,
                                 )
-                            } else {
-                                None
-                            }
+                                .build()?,
+                            )
                         };
 
-                        if let Some(reply) = reply {
-                            self.socket
-                                .send_to(reply, addr, Some(arguments.requester_id))
-                                .await?;
+                        match reply {
+                            Some(reply) => {
+                                self.send_reply(reply, request_len, addr).await?;
+                            }
+
+                            None => {
+                                let token_failure_events =
+                                    self.state.lock().unwrap().settings.token_failure_events;
+                                if token_failure_events {
+                                    self.send_event_to_subscribers(DHTEvent {
+                                        event_type: DHTEventType::TokenValidationFailed(
+                                            TokenValidationFailedEvent {
+                                                requester: addr,
+                                                info_hash: arguments.info_hash,
+                                            },
+                                        ),
+                                    });
+                                }
+                            }
                         }
                     }
 
                     packets::RequestSpecific::SampleInfoHashesRequest(arguments) => {
                         self.common_request_handling(addr, &msg)?;
+                        let our_id = self.state.lock().unwrap().our_id;
+                        let nearest = self.nearest_nodes_for_reply(
+                            &our_id,
+                            &arguments.target,
+                            &arguments.requester_id,
+                            &addr,
+                            &None,
+                        );
                         let reply = {
                             let state = self.state.lock().unwrap();
 
-                            let nearest = state.buckets.get_nearest_nodes(
-                                &arguments.target,
-                                Some(&arguments.requester_id),
-                            );
-
                             let (info_hashes, total_info_hashes) = {
                                 let info_hashes = state.peer_storage.get_info_hashes();
                                 let total_info_hashes = info_hashes.len();
@@ -518,10 +1802,23 @@ impl DHT {
                                 (info_hashes, total_info_hashes)
                             };
 
-                            MessageBuilder::new_sample_infohashes_response()
-                                .sender_id(state.our_id)
-                                .transaction_id(msg.transaction_id)
-                                .requester_ip(addr)
+                            if total_info_hashes == 0
+                                && state.settings.decline_empty_sample_infohashes
+                            {
+                                debug!(target: "rustydht_lib::DHT", "Declining sample_infohashes from {} as we have no info hashes", addr);
+                                MessageBuilder::new_error()
+                                    .transaction_id(msg.transaction_id.clone())
+                                    .code(202)
+                                    .description("No info hashes to sample".to_string())
+                                    .build()?
+                            } else {
+                                self.with_requester_ip(
+                                    MessageBuilder::new_sample_infohashes_response()
+                                        .sender_id(state.our_id)
+                                        .transaction_id(msg.transaction_id.clone()),
+                                    addr,
+                                    state.settings.minimal_responses,
+                                )
                                 .interval(Duration::from_secs(
                                     state.settings.min_sample_interval_secs.try_into().unwrap(),
                                 ))
@@ -529,11 +1826,10 @@ impl DHT {
                                 .samples(info_hashes)
                                 .num_infohashes(total_info_hashes)
                                 .build()?
+                            }
                         };
 
-                        self.socket
-                            .send_to(reply, addr, Some(arguments.requester_id))
-                            .await?;
+                        self.send_reply(reply, request_len, addr).await?;
                     }
                 }
             }
@@ -552,12 +1848,26 @@ impl DHT {
         return Ok(());
     }
 
-    async fn send_packet_to_subscribers(&self, msg: packets::Message, _addr: SocketAddr) {
+    async fn send_packet_to_subscribers(
+        &self,
+        msg: packets::Message,
+        _addr: SocketAddr,
+        is_duplicate: bool,
+    ) {
         // Notify any subscribers about the event
         let event = DHTEvent {
-            event_type: DHTEventType::MessageReceived(MessageReceivedEvent { message: msg }),
+            event_type: DHTEventType::MessageReceived(MessageReceivedEvent {
+                message: msg,
+                duplicate: is_duplicate,
+            }),
         };
+        self.send_event_to_subscribers(event);
+    }
+
+    fn send_event_to_subscribers(&self, event: DHTEvent) {
         let mut state = self.state.lock().unwrap();
+        // Fails only if nobody is observing
+        let _ = state.observers.send(event.clone());
         state.subscribers.retain(|sub| {
             eprintln!("Gotta do notifications for {:?}", event);
             match sub.try_send(event.clone()) {
@@ -589,7 +1899,8 @@ impl DHT {
             // Package things that need state into a block so that Rust will not complain about MutexGuard kept past .await
             let reverify_interval_secs = {
                 let mut state = self.state.lock().unwrap();
-                let count = state.buckets.count();
+                let mut buckets = self.buckets.write().unwrap();
+                let count = buckets.count();
                 debug!(target: "rustydht_lib::DHT",
                     "Pruning node buckets. Storage has {} unverified, {} verified",
                     count.0,
@@ -597,66 +1908,80 @@ impl DHT {
                 );
                 let reverify_grace_period_secs = state.settings.reverify_grace_period_secs;
                 let verify_grace_period_secs = state.settings.verify_grace_period_secs;
-                state.buckets.prune(
+                buckets.prune(
                     Duration::from_secs(reverify_grace_period_secs),
                     Duration::from_secs(verify_grace_period_secs),
                 );
 
+                state.peer_storage.prune_expired();
+                let min_peers_per_torrent = state.settings.min_peers_per_torrent;
+                if min_peers_per_torrent > 0 {
+                    state.peer_storage.prune_sparse(min_peers_per_torrent);
+                }
+
                 state.settings.reverify_interval_secs
             };
-            match Instant::now().checked_sub(Duration::from_secs(reverify_interval_secs)) {
-                None => {
-                    debug!(target: "rustydht_lib::DHT", "Monotonic clock underflow - skipping this round of pings");
-                }
+            let reverify_interval = Duration::from_secs(reverify_interval_secs);
+            let max_concurrent_pings = self
+                .state
+                .lock()
+                .unwrap()
+                .settings
+                .max_concurrent_buddy_pings;
+            let ping_slots = Arc::new(Semaphore::new(max_concurrent_pings));
 
-                Some(ping_if_older_than) => {
-                    debug!(target: "rustydht_lib::DHT", "Sending pings to all nodes that have never verified or haven't been verified in a while");
-                    let (unverified, verified) = {
-                        let state = self.state.lock().unwrap();
-                        (
-                            state.buckets.get_all_unverified(),
-                            state.buckets.get_all_verified(),
-                        )
-                    };
-                    // Ping everybody we haven't verified
-                    for wrapper in unverified {
-                        // Some things in here are actually verified... don't bother them too often
-                        if let Some(last_verified) = wrapper.last_verified {
-                            if last_verified >= ping_if_older_than {
-                                continue;
-                            }
-                            trace!(target: "rustydht_lib::DHT", "Sending ping to reverify backup {:?}", wrapper.node);
-                        } else {
-                            trace!(target: "rustydht_lib::DHT",
-                                "Sending ping to verify {:?} (last seen {} seconds ago)",
-                                wrapper.node,
-                                (Instant::now() - wrapper.last_seen).as_secs()
-                            );
-                        }
-                        self.ping_internal(
-                            shutdown.clone(),
-                            wrapper.node.address,
-                            Some(wrapper.node.id),
-                        )
-                        .await?;
-                    }
+            debug!(target: "rustydht_lib::DHT", "Sending pings to all nodes that have never verified or haven't been verified in a while");
+            let (unverified, verified) = {
+                let buckets = self.buckets.read().unwrap();
+                (buckets.get_all_unverified(), buckets.get_all_verified())
+            };
+            // Ping everybody we haven't verified
+            for wrapper in unverified {
+                // Some things in here are actually verified... don't bother them too often
+                if !wrapper.is_stale(reverify_interval) {
+                    continue;
+                }
+                if wrapper.last_verified.is_some() {
+                    trace!(target: "rustydht_lib::DHT", "Sending ping to reverify backup {:?}", wrapper.node);
+                } else {
+                    trace!(target: "rustydht_lib::DHT",
+                        "Sending ping to verify {:?} (last seen {} seconds ago)",
+                        wrapper.node,
+                        wrapper.age_since_seen().as_secs()
+                    );
+                }
+                let permit = ping_slots
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|e| RustyDHTError::GeneralError(e.into()))?;
+                self.ping_internal(
+                    shutdown.clone(),
+                    wrapper.node.address,
+                    Some(wrapper.node.id),
+                    Some(permit),
+                )
+                .await?;
+            }
 
-                    // Reverify those who haven't been verified recently
-                    for wrapper in verified {
-                        if let Some(last_verified) = wrapper.last_verified {
-                            if last_verified >= ping_if_older_than {
-                                continue;
-                            }
-                        }
-                        trace!(target: "rustydht_lib::DHT", "Sending ping to reverify {:?}", wrapper.node);
-                        self.ping_internal(
-                            shutdown.clone(),
-                            wrapper.node.address,
-                            Some(wrapper.node.id),
-                        )
-                        .await?;
-                    }
+            // Reverify those who haven't been verified recently
+            for wrapper in verified {
+                if !wrapper.is_stale(reverify_interval) {
+                    continue;
                 }
+                trace!(target: "rustydht_lib::DHT", "Sending ping to reverify {:?}", wrapper.node);
+                let permit = ping_slots
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|e| RustyDHTError::GeneralError(e.into()))?;
+                self.ping_internal(
+                    shutdown.clone(),
+                    wrapper.node.address,
+                    Some(wrapper.node.id),
+                    Some(permit),
+                )
+                .await?;
             }
         }
     }
@@ -670,12 +1995,12 @@ impl DHT {
                 self.state.lock().unwrap().settings.find_nodes_interval_secs;
             sleep(Duration::from_secs(find_node_interval_secs)).await;
 
-            let (count_unverified, count_verified) = self.state.lock().unwrap().buckets.count();
+            let (count_unverified, count_verified) = self.buckets.read().unwrap().count();
 
             // If we don't know anybody, force a router ping.
             // This is helpful if we've been asleep for a while and lost all peers
             if count_verified <= 0 {
-                self.ping_routers(shutdown.clone()).await?;
+                self.ping_routers().await?;
             }
 
             // Package things that need state into this block to avoid issues with MutexGuard kept over .await
@@ -690,7 +2015,10 @@ impl DHT {
 
                 // Find the closest nodes to ask
                 (
-                    state.buckets.get_nearest_nodes(&id_near_us, None),
+                    self.buckets
+                        .read()
+                        .unwrap()
+                        .get_nearest_nodes(&id_near_us, None),
                     id_near_us,
                 )
             };
@@ -708,33 +2036,79 @@ impl DHT {
     }
 
     async fn periodic_ip4_maintenance(&self) -> Result<(), RustyDHTError> {
+        // Nobody checks our Id against our IP if we never answer them
+        if self.client_only {
+            return Ok(());
+        }
+        let vote_notify = self.state.lock().unwrap().ip4_vote_notify.clone();
+        let mut last_decay = tokio::time::Instant::now();
+        let mut last_tally = {
+            let state = self.state.lock().unwrap();
+            IpVoteUpdatedEvent {
+                current_best: state.ip4_source.get_best_ipv4(),
+                votes: state.ip4_source.vote_count(),
+            }
+        };
         loop {
-            sleep(Duration::from_secs(10)).await;
+            // Votes decay on a fixed schedule, but each new vote gets checked right away
+            let interval = Duration::from_secs(
+                self.state
+                    .lock()
+                    .unwrap()
+                    .settings
+                    .ip4_maintenance_interval_secs,
+            );
+            let decay = tokio::select! {
+                _ = tokio::time::sleep_until(last_decay + interval) => true,
+                _ = vote_notify.notified() => false,
+            };
 
             let mut state = self.state.lock().unwrap();
-            state.ip4_source.decay();
+            if decay {
+                last_decay = tokio::time::Instant::now();
+                state.ip4_source.decay();
+                state.external_port_votes.decay();
+            }
 
+            let tally = IpVoteUpdatedEvent {
+                current_best: state.ip4_source.get_best_ipv4(),
+                votes: state.ip4_source.vote_count(),
+            };
+            if tally != last_tally {
+                last_tally = tally.clone();
+                drop(state);
+                self.send_event_to_subscribers(DHTEvent {
+                    event_type: DHTEventType::IpVoteUpdated(tally),
+                });
+                state = self.state.lock().unwrap();
+            }
+
+            if !state.settings.enforce_id_ip_validity {
+                continue;
+            }
             if let Some(ip) = state.ip4_source.get_best_ipv4() {
                 let ip = IpAddr::V4(ip);
                 if !state.our_id.is_valid_for_ip(&ip) {
+                    let old_id = state.our_id;
                     let new_id = Id::from_ip(&ip);
                     info!(target: "rustydht_lib::DHT",
                         "Our current id {} is not valid for IP {}. Using new id {}",
-                        state.our_id,
+                        old_id,
                         ip,
                         new_id
                     );
                     state.our_id = new_id;
-                    state.buckets.set_id(new_id);
+                    self.buckets.write().unwrap().set_id(new_id);
+                    drop(state);
+                    self.send_event_to_subscribers(DHTEvent {
+                        event_type: DHTEventType::IdChanged(IdChangedEvent { old_id, new_id }),
+                    });
                 }
             }
         }
     }
 
-    async fn periodic_router_ping(
-        &self,
-        shutdown: shutdown::ShutdownReceiver,
-    ) -> Result<(), RustyDHTError> {
+    async fn periodic_router_ping(&self) -> Result<(), RustyDHTError> {
         loop {
             let router_ping_interval_secs = self
                 .state
@@ -744,30 +2118,50 @@ impl DHT {
                 .router_ping_interval_secs;
             sleep(Duration::from_secs(router_ping_interval_secs)).await;
             debug!(target: "rustydht_lib::DHT", "Pinging routers");
-            let shutdown_clone = shutdown.clone();
-            self.ping_routers(shutdown_clone).await?;
+            self.ping_routers().await?;
         }
     }
 
     async fn periodic_token_rotation(&self) -> Result<(), RustyDHTError> {
+        // We never hand out tokens
+        if self.client_only {
+            return Ok(());
+        }
         loop {
             sleep(Duration::from_secs(300)).await;
             self.rotate_token_secrets();
         }
     }
 
+    /// Logs how many incoming packets were dropped, once a minute. Covers the drops that
+    /// weren't logged individually because of
+    /// [drop_log_sample_rate](crate::dht::DHTSettings::drop_log_sample_rate).
+    async fn periodic_drop_summary(&self) -> Result<(), RustyDHTError> {
+        loop {
+            sleep(Duration::from_secs(60)).await;
+            let dropped = self.socket.dropped_packets().take_since_summary();
+            if dropped > 0 {
+                info!(target: "rustydht_lib::DHT", "Dropped {} malformed or unanswerable packets in the last minute", dropped);
+            }
+        }
+    }
+
     /// Build and send a ping to a target. Doesn't wait for a response
     async fn ping_internal(
         &self,
         shutdown: shutdown::ShutdownReceiver,
         target: SocketAddr,
         target_id: Option<Id>,
+        permit: Option<OwnedSemaphorePermit>,
     ) -> Result<(), RustyDHTError> {
         let state = self.state.clone();
+        let buckets = self.buckets.clone();
         let socket = self.socket.clone();
         shutdown::ShutdownReceiver::spawn_with_shutdown(
             shutdown,
             async move {
+                // Held until the ping is done, to limit how many are outstanding
+                let _permit = permit;
                 let req = {
                     let state = state.lock().unwrap();
                     MessageBuilder::new_ping_request()
@@ -777,9 +2171,10 @@ impl DHT {
                         .expect("Failed to build ping packet")
                 };
 
-                if let Err(e) =
-                    DHT::common_send_and_handle_response(state, socket, req, target, target_id)
-                        .await
+                if let Err(e) = DHT::common_send_and_handle_response(
+                    state, buckets, socket, req, target, target_id,
+                )
+                .await
                 {
                     match e {
                         RustyDHTError::TimeoutError(e) => {
@@ -807,6 +2202,7 @@ impl DHT {
     /// before we see them.
     async fn common_send_and_handle_response(
         state: Arc<Mutex<DHTState>>,
+        buckets: RoutingTable,
         socket: Arc<DHTSocket>,
         msg: packets::Message,
         target: SocketAddr,
@@ -818,40 +2214,83 @@ impl DHT {
             )));
         }
 
-        let maybe_receiver = socket.send_to(msg.clone(), target, target_id).await?;
+        let (policy, enforce_id_ip_validity) = {
+            let mut state = state.lock().unwrap();
+            state.contacted_addrs.put(target, ());
+            (
+                state.settings.response_verification_policy,
+                state.settings.enforce_id_ip_validity,
+            )
+        };
+
+        // Given the expected id, the socket drops responses from any other id itself
+        let socket_target_id = match policy {
+            ResponseVerificationPolicy::Drop => target_id,
+            _ => None,
+        };
+        let maybe_receiver = socket
+            .send_to(msg.clone(), target, socket_target_id)
+            .await?;
         match maybe_receiver {
             Some(mut receiver) => match receiver.recv().await {
                 Some(reply) => match &reply.message_type {
                     packets::MessageType::Response(response_variant) => {
+                        state.lock().unwrap().responsive_ips.put(target.ip(), ());
+
                         // Get the id of the sender - safe to expect because all Response variants are guaranteed
                         // to have an Id (only error doesn't)
                         let their_id =
                             reply.get_author_id().expect("response doesn't have Id!?");
-                        let id_is_valid = their_id.is_valid_for_ip(&target.ip());
+                        let id_is_valid =
+                            !enforce_id_ip_validity || their_id.is_valid_for_ip(&target.ip());
+                        let trusted = match target_id {
+                            Some(target_id) if target_id != their_id => {
+                                debug!(target: "rustydht_lib::DHT", "Expected response from {} but got one from {} ({})", target_id, their_id, target);
+                                policy != ResponseVerificationPolicy::AcceptWithoutVoting
+                            }
+                            _ => true,
+                        };
 
                         // Node is fit to be in our routing buckets and vote on our IPv4 only
                         // if its id is valid for its IP.
-                        if id_is_valid {
+                        if id_is_valid && trusted {
                             let mut state = state.lock().unwrap();
+                            if DHT::check_own_id_collision(&mut state, &their_id, &target, true) {
+                                return Ok(reply);
+                            }
                             DHT::ip4_vote_helper(&mut state, &target, &reply);
-                            state
-                                .buckets
+                            buckets
+                                .write()
+                                .unwrap()
                                 .add_or_update(Node::new(their_id, target), true);
                         }
 
-                        match response_variant {
-                            // Special handling for find_node responses
-                            // Add the nodes we got back as "seen" (even though we haven't necessarily seen them directly yet).
-                            // They will be pinged later in an attempt to verify them.
-                            packets::ResponseSpecific::FindNodeResponse(args) => {
-                                let mut state = state.lock().unwrap();
-                                for node in &args.nodes {
-                                    if node.id.is_valid_for_ip(&node.address.ip()) {
-                                        state.buckets.add_or_update(node.clone(), false);
+                        // Special handling for find_node responses, and get_peers responses
+                        // (whose nodes serve a lookup just as well).
+                        // Add the nodes we got back as "seen" (even though we haven't necessarily seen them directly yet).
+                        // They will be pinged later in an attempt to verify them.
+                        let nodes = match response_variant {
+                            packets::ResponseSpecific::FindNodeResponse(args) => Some(&args.nodes),
+                            packets::ResponseSpecific::GetPeersResponse(args) => {
+                                match &args.values {
+                                    packets::GetPeersResponseValues::Nodes(nodes)
+                                    | packets::GetPeersResponseValues::PeersAndNodes(_, nodes) => {
+                                        Some(nodes)
                                     }
+                                    packets::GetPeersResponseValues::Peers(_) => None,
+                                }
+                            }
+                            _ => None,
+                        };
+                        if let (Some(nodes), true) = (nodes, trusted) {
+                            let mut state = state.lock().unwrap();
+                            if state.router_addrs.contains(&target) {
+                                for node in nodes {
+                                    state.router_nodes.put(node.id, node.clone());
                                 }
                             }
-                            _ => {}
+                            let mut buckets = buckets.write().unwrap();
+                            DHT::add_find_node_results(&state, buckets.as_mut(), &msg, nodes);
                         }
 
                         Ok(reply)
@@ -867,60 +2306,172 @@ impl DHT {
         }
     }
 
-    async fn ping_router<G: AsRef<str>>(
-        &self,
-        shutdown: shutdown::ShutdownReceiver,
-        hostname: G,
-    ) -> Result<(), RustyDHTError> {
-        let hostname = hostname.as_ref();
-        // Resolve and add to request storage
-        let resolve = lookup_host(hostname).await;
-        if let Err(err) = resolve {
-            // Used to only eat the specific errors corresponding to a failure to resolve,
-            // but they vary by platform and it's a pain. For now, we'll eat all host
-            // resolution errors.
-            warn!(
-                target: "rustydht_lib::DHT",
-                "Failed to resolve host {} due to error {:#?}. Try again later.",
-                hostname, err
-            );
-            return Ok(());
-        }
-
-        for socket_addr in resolve.unwrap() {
-            if socket_addr.is_ipv4() {
-                let shutdown_clone = shutdown.clone();
-                self.ping_internal(shutdown_clone, socket_addr, None)
-                    .await?;
-                break;
+    /// Pings a router and waits (up to [ROUTER_PING_TIMEOUT]) for it to respond, keeping
+    /// track of how it did. Returns true if it responded.
+    async fn ping_router(&self, hostname: &str) -> bool {
+        let latency = match self.resolve_router(hostname).await {
+            Some(socket_addr) => {
+                let req = {
+                    let state = self.state.lock().unwrap();
+                    MessageBuilder::new_ping_request()
+                        .sender_id(state.our_id)
+                        .read_only(state.settings.read_only)
+                        .build()
+                        .expect("Failed to build ping packet")
+                };
+                let start = Instant::now();
+                match tokio::time::timeout(
+                    ROUTER_PING_TIMEOUT,
+                    DHT::common_send_and_handle_response(
+                        self.state.clone(),
+                        self.buckets.clone(),
+                        self.socket.clone(),
+                        req,
+                        socket_addr,
+                        None,
+                    ),
+                )
+                .await
+                {
+                    Ok(Ok(_)) => Some(start.elapsed()),
+                    Ok(Err(e)) => {
+                        debug!(target: "rustydht_lib::DHT", "Ping to router {} failed: {}", hostname, e);
+                        None
+                    }
+                    Err(_) => {
+                        debug!(target: "rustydht_lib::DHT", "Ping to router {} timed out", hostname);
+                        None
+                    }
+                }
             }
-        }
-        Ok(())
-    }
+            None => None,
+        };
 
-    /// Pings some bittorrent routers
-    async fn ping_routers(
-        &self,
-        shutdown: shutdown::ShutdownReceiver,
-    ) -> Result<(), RustyDHTError> {
-        let mut futures = futures::stream::FuturesUnordered::new();
-        let routers = self.state.lock().unwrap().settings.routers.clone();
-        for hostname in routers {
-            let shutdown_clone = shutdown.clone();
-            futures.push(self.ping_router(shutdown_clone, hostname));
+        let mut state = self.state.lock().unwrap();
+        let stats = state.router_stats.entry(hostname.to_string()).or_default();
+        match latency {
+            Some(latency) => stats.record_success(latency),
+            None => stats.record_failure(),
         }
-        while let Some(result) = futures.next().await {
-            result?;
+        if latency.is_some() {
+            state.router_seeds.retain(|seed| seed != hostname);
         }
-        Ok(())
+        latency.is_some()
     }
 
-    fn rotate_token_secrets(&self) {
-        let mut state = self.state.lock().unwrap();
-        let new_token_secret = make_token_secret(state.settings.token_secret_size);
+    /// Looks up the IPv4 address of a router
+    pub(crate) async fn resolve_router(&self, hostname: &str) -> Option<SocketAddr> {
+        match resolve_router(hostname).await {
+            Ok(socket_addr) => {
+                self.state.lock().unwrap().router_addrs.insert(socket_addr);
+                Some(socket_addr)
+            }
+            Err(err) => {
+                // Routers that can't be resolved right now might be resolvable later
+                warn!(target: "rustydht_lib::DHT", "{}. Try again later.", err);
+                None
+            }
+        }
+    }
 
-        state.old_token_secret = state.token_secret.clone();
-        state.token_secret = new_token_secret;
+    /// Pings some bittorrent routers.
+    ///
+    /// Routers are tried best first until one responds. The next router is pinged when
+    /// the ones before it have failed or haven't responded within [ROUTER_PING_STAGGER],
+    /// so a dead router doesn't hold up the others for long. Every
+    /// [ROUTER_PROBE_INTERVAL] rounds (including the first) all of them are pinged at
+    /// once instead, so that we learn how each of them is doing.
+    ///
+    /// Once a router has responded, no more are pinged, but the pings already sent are
+    /// still waited for so that every router we pinged gets its stats updated.
+    async fn ping_routers(&self) -> Result<(), RustyDHTError> {
+        let (routers, probe_all) = {
+            let mut state = self.state.lock().unwrap();
+            let mut routers = state.settings.routers.clone();
+            order_routers(&mut routers, &state.router_stats);
+            let probe_all = state.router_ping_rounds == 0;
+            state.router_ping_rounds = (state.router_ping_rounds + 1) % ROUTER_PROBE_INTERVAL;
+            (routers, probe_all)
+        };
+
+        if probe_all {
+            let mut futures = futures::stream::FuturesUnordered::new();
+            for hostname in &routers {
+                futures.push(self.ping_router(hostname));
+            }
+            while futures.next().await.is_some() {}
+        } else {
+            // Start with the best router, and bring in the next one whenever the ones
+            // already pinged have all failed or have been quiet for a while
+            let mut remaining = routers.iter();
+            let mut pings = futures::stream::FuturesUnordered::new();
+            loop {
+                if pings.is_empty() {
+                    match remaining.next() {
+                        Some(hostname) => pings.push(self.ping_router(hostname)),
+                        None => break,
+                    }
+                }
+                tokio::select! {
+                    Some(answered) = pings.next() => {
+                        if answered {
+                            break;
+                        }
+                    }
+                    _ = sleep(ROUTER_PING_STAGGER), if remaining.len() > 0 => {
+                        if let Some(hostname) = remaining.next() {
+                            pings.push(self.ping_router(hostname));
+                        }
+                    }
+                }
+            }
+            while pings.next().await.is_some() {}
+        }
+        Ok(())
+    }
+
+    /// Returns the routers that haven't answered a ping yet.
+    ///
+    /// Right after the DHT is built, these are all of its routers. Until they've answered,
+    /// their Ids aren't known (and their hostnames may not even resolve), so they aren't put
+    /// in the routing table. Instead, [find_node](crate::dht::operations::find_node) asks
+    /// them for nodes directly if the routing table is still empty when it starts, and
+    /// [get_peers](crate::dht::operations::get_peers) does if it knows too few nodes to start
+    /// from. That way lookups can begin before the first round of router pings has finished.
+    ///
+    /// The nodes that routers return are passed along in our own replies to find_node and
+    /// get_peers requests while we know too few verified nodes, so that requesters don't get
+    /// empty node lists while we're starting up.
+    pub fn router_seeds(&self) -> Vec<String> {
+        self.state.lock().unwrap().router_seeds.clone()
+    }
+
+    /// Returns true if the DHT has something to bootstrap from: either some
+    /// [routers](crate::dht::DHTSettings::routers), or nodes in its routing table (e.g.
+    /// from [DHTBuilder::initial_nodes] or a restored [route_table](DHTBuilder::route_table)).
+    ///
+    /// If not, it only joins the network once other nodes contact it. The event loop logs
+    /// a warning and sends a [BootstrapUnavailable](DHTEventType::BootstrapUnavailable)
+    /// event when it first starts in that state, but not when it's run again.
+    pub fn has_bootstrap_source(&self) -> bool {
+        if !self.state.lock().unwrap().settings.routers.is_empty() {
+            return true;
+        }
+        let (unverified, verified) = self.buckets.read().unwrap().count();
+        unverified + verified > 0
+    }
+
+    /// Returns how responsive each of the configured routers has been so far
+    pub fn get_router_stats(&self) -> HashMap<String, RouterStats> {
+        self.state.lock().unwrap().router_stats.clone()
+    }
+
+    fn rotate_token_secrets(&self) {
+        let mut state = self.state.lock().unwrap();
+        let new_token_secret = make_token_secret(state.settings.token_secret_size);
+
+        state.old_token_secret = state.token_secret.clone();
+        state.token_secret = new_token_secret;
         debug!(
             target: "rustydht_lib::DHT",
             "Rotating token secret. New secret is {:?}, old secret is {:?}",
@@ -937,6 +2488,7 @@ impl DHT {
         target: Id,
     ) -> Result<(), RustyDHTError> {
         let state = self.state.clone();
+        let buckets = self.buckets.clone();
         let socket = self.socket.clone();
         shutdown::ShutdownReceiver::spawn_with_shutdown(
             shutdown,
@@ -952,7 +2504,8 @@ impl DHT {
                 };
 
                 if let Err(e) =
-                    DHT::common_send_and_handle_response(state, socket, req, dest, dest_id).await
+                    DHT::common_send_and_handle_response(state, buckets, socket, req, dest, dest_id)
+                        .await
                 {
                     match e {
                         RustyDHTError::TimeoutError(e) => {
@@ -971,36 +2524,404 @@ impl DHT {
         Ok(())
     }
 
+    /// Gets the nodes nearest to `target` to include in a reply to a request.
+    ///
+    /// Nodes are returned for each of the [wanted_families].
+    ///
+    /// Neither the requester (by Id or address) nor we ourselves are ever included.
+    ///
+    /// Only verified nodes are passed along, unless we know too few of them (e.g. right
+    /// after starting). Then the nodes that routers have told us about make up the
+    /// difference, as they're the best we have.
+    fn nearest_nodes_for_reply(
+        &self,
+        our_id: &Id,
+        target: &Id,
+        requester_id: &Id,
+        requester_addr: &SocketAddr,
+        want: &Option<Vec<AddressFamily>>,
+    ) -> Vec<Node> {
+        let router_nodes: Vec<Node> = self
+            .state
+            .lock()
+            .unwrap()
+            .router_nodes
+            .iter()
+            .map(|(_, node)| node.clone())
+            .collect();
+        // Left out before picking the nearest nodes, so that they don't take the place of
+        // others
+        let keep = |node: &Node| node.id != *our_id && node.address != *requester_addr;
+        let buckets = self.buckets.read().unwrap();
+        wanted_families(want, requester_addr)
+            .iter()
+            .flat_map(|family| {
+                let mut nodes = buckets.get_nearest_nodes_for_family_matching(
+                    target,
+                    Some(requester_id),
+                    *family,
+                    &keep,
+                );
+                if nodes.len() <= FEW_NODES {
+                    let mut extra: Vec<Node> = router_nodes
+                        .iter()
+                        .filter(|node| {
+                            node.family() == *family
+                                && node.id != *requester_id
+                                && keep(node)
+                                && !nodes.iter().any(|known| known.id == node.id)
+                        })
+                        .cloned()
+                        .collect();
+                    extra.sort_by_key(|node| node.id.xor(target));
+                    extra.truncate(MAX_REPLY_NODES.saturating_sub(nodes.len()));
+                    nodes.extend(extra);
+                }
+                nodes
+            })
+            .collect()
+    }
+
+    /// Adds nodes from a response to the find_node or get_peers request `req` to the routing
+    /// table as unverified, within the limits set by the settings.
+    fn add_find_node_results(
+        state: &DHTState,
+        buckets: &mut (dyn NodeStorage + Send + Sync),
+        req: &packets::Message,
+        nodes: &[Node],
+    ) {
+        let target = match &req.message_type {
+            packets::MessageType::Request(packets::RequestSpecific::FindNodeRequest(args)) => {
+                Some(args.target)
+            }
+            packets::MessageType::Request(packets::RequestSpecific::GetPeersRequest(args)) => {
+                Some(args.info_hash)
+            }
+            _ => None,
+        };
+
+        // Only take nodes closer than the farthest of the nodes we already know near the
+        // target. If we don't know a full set of nodes near it yet, anything goes.
+        let farthest_distance = match target {
+            Some(target) if state.settings.require_closer_find_node_results => {
+                let nearest = buckets.get_nearest_nodes(&target, None);
+                if nearest.len() < 8 {
+                    None
+                } else {
+                    nearest.iter().map(|node| node.id.xor(&target)).fold(
+                        None,
+                        |farthest: Option<Id>, distance| match farthest {
+                            Some(farthest) if farthest >= distance => Some(farthest),
+                            _ => Some(distance),
+                        },
+                    )
+                }
+            }
+            _ => None,
+        };
+
+        let max_nodes = state.settings.max_nodes_per_response;
+        let mut added = 0;
+        for node in nodes {
+            if added >= max_nodes {
+                debug!(target: "rustydht_lib::DHT", "Ignoring the rest of {} nodes in find_node response", nodes.len());
+                break;
+            }
+            if !is_id_acceptable(&state.settings, &node.id, &node.address.ip()) {
+                continue;
+            }
+            if let (Some(target), Some(farthest_distance)) = (target, farthest_distance) {
+                if node.id.xor(&target) >= farthest_distance {
+                    continue;
+                }
+            }
+            buckets.add_or_update(node.clone(), false);
+            added += 1;
+        }
+    }
+
+    /// Returns true if `their_id` is our own Id, after dealing with it according to the
+    /// [OwnIdCollisionPolicy]. `is_response` says whether `their_id` came in a response to
+    /// one of our requests, since only those are trusted enough to ban `addr` for.
+    fn check_own_id_collision(
+        state: &mut DHTState,
+        their_id: &Id,
+        addr: &SocketAddr,
+        is_response: bool,
+    ) -> bool {
+        if *their_id != state.our_id {
+            return false;
+        }
+        // Whoever does this usually keeps at it, so only a sample of the packets is logged
+        let should_log = state.own_id_collisions.record();
+        let seen = state.own_id_collisions.total();
+        match state.settings.own_id_collision_policy {
+            OwnIdCollisionPolicy::Ignore => {}
+            OwnIdCollisionPolicy::Log if should_log => {
+                warn!(target: "rustydht_lib::DHT", "{} is using our Id {} ({} packets with our Id so far)", addr, their_id, seen);
+            }
+            OwnIdCollisionPolicy::Log => {}
+            OwnIdCollisionPolicy::Ban if is_response => {
+                // Each IP is only banned once, since its packets are dropped from then on
+                warn!(target: "rustydht_lib::DHT", "{} is using our Id {}. Banning its IP", addr, their_id);
+                state.banned_ips.put(addr.ip(), ());
+            }
+            OwnIdCollisionPolicy::Ban if should_log => {
+                warn!(target: "rustydht_lib::DHT", "{} sent a request with our Id {}. Not banning it, since the address may be spoofed ({} packets with our Id so far)", addr, their_id, seen);
+            }
+            OwnIdCollisionPolicy::Ban => {}
+        }
+        true
+    }
+
+    /// Calculates the token to hand out to `addr` with the current token secret
+    fn current_token(state: &DHTState, addr: &SocketAddr) -> Vec<u8> {
+        calculate_token(addr, &state.token_secret, state.settings.token_length)
+    }
+
+    /// Returns how recently a peer of `info_hash` must have announced to be handed out:
+    /// within the info_hash's TTL if it has one, or else within get_peers_freshness_secs.
+    /// None if that reaches back before the monotonic clock started, as every peer is
+    /// recent enough then.
+    fn freshness_cutoff(state: &DHTState, info_hash: &Id) -> Option<Instant> {
+        let freshness = state
+            .peer_storage
+            .get_ttl(info_hash)
+            .unwrap_or_else(|| Duration::from_secs(state.settings.get_peers_freshness_secs));
+        Instant::now().checked_sub(freshness)
+    }
+
     /// Adds a 'vote' for whatever IP address the sender says we have.
+    /// Also votes on our external port, with whatever port the sender says we're using.
     fn ip4_vote_helper(state: &mut DHTState, addr: &SocketAddr, msg: &packets::Message) {
+        if let Some(they_claim_our_sockaddr) = &msg.requester_ip {
+            state
+                .external_port_votes
+                .add_vote(addr.ip(), they_claim_our_sockaddr.port());
+        }
         if let IpAddr::V4(their_ip) = addr.ip() {
             if let Some(they_claim_our_sockaddr) = &msg.requester_ip {
                 if let SocketAddr::V4(they_claim_our_sockaddr) = they_claim_our_sockaddr {
                     state
                         .ip4_source
                         .add_vote(their_ip, they_claim_our_sockaddr.ip().clone());
+                    state.ip4_vote_notify.notify_one();
                 }
             }
         }
     }
 }
 
-/// Calculates a peer announce token based on a sockaddr and some secret.
-/// Pretty positive this isn't cryptographically safe but I'm not too worried.
-/// If we care about that later we can use a proper HMAC or something.
-fn calculate_token<T: AsRef<[u8]>>(remote: &SocketAddr, secret: T) -> [u8; 4] {
+/// Calculates a peer announce token of `length` bytes based on a sockaddr and some secret.
+///
+/// The token is made of SHA-256 hashes of a block counter, the IP address, and the secret,
+/// so any length can be produced. This still isn't a proper HMAC, but should do for now.
+fn calculate_token<T: AsRef<[u8]>>(remote: &SocketAddr, secret: T, length: usize) -> Vec<u8> {
     let secret = secret.as_ref();
-    let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
-    // digest.write(&crate::packets::sockaddr_to_bytes(remote));
     let octets = match remote.ip() {
         std::net::IpAddr::V4(v4) => v4.octets().to_vec(),
         std::net::IpAddr::V6(v6) => v6.octets().to_vec(),
     };
-    digest.write(&octets);
-    digest.write(secret);
-    let checksum: u32 = digest.sum32();
+    let mut token = Vec::with_capacity(length);
+    let mut block: u32 = 0;
+    while token.len() < length {
+        let mut hasher = Sha256::new();
+        hasher.update(block.to_be_bytes());
+        hasher.update(&octets);
+        hasher.update(secret);
+        token.extend_from_slice(&hasher.finalize());
+        block += 1;
+    }
+    token.truncate(length);
+    token
+}
+
+/// Serializes `reply`, first dropping nodes, and then peers or samples, until it fits in
+/// `max_bytes` (if it can).
+fn trimmed_reply_bytes(
+    mut reply: packets::Message,
+    max_bytes: usize,
+    addr: SocketAddr,
+) -> Result<Vec<u8>, RustyDHTError> {
+    let mut bytes = reply.clone().to_bytes()?;
+    let original_len = bytes.len();
+    while bytes.len() > max_bytes {
+        if !trim_reply(&mut reply, bytes.len() - max_bytes) {
+            warn!(target: "rustydht_lib::DHT", "Reply to {} is {} bytes even with nothing left to trim", addr, bytes.len());
+            break;
+        }
+        bytes = reply.clone().to_bytes()?;
+    }
+    if bytes.len() < original_len {
+        debug!(target: "rustydht_lib::DHT", "Trimmed reply to {} from {} to {} bytes", addr, original_len, bytes.len());
+    }
+    Ok(bytes)
+}
+
+/// Removes enough nodes (or, once there are none, peers or samples) from `reply` to make
+/// it about `excess_bytes` smaller. Returns false if there was nothing to remove.
+fn trim_reply(reply: &mut packets::Message, excess_bytes: usize) -> bool {
+    // Roughly how many bytes each one takes up in a serialized reply. IPv6 addresses are
+    // 12 bytes longer, and IPv6 peers need a longer length prefix.
+    let node_bytes = |node: &Node| match node.address {
+        SocketAddr::V4(_) => 26,
+        SocketAddr::V6(_) => 38,
+    };
+    let peer_bytes = |peer: &SocketAddr| match peer {
+        SocketAddr::V4(_) => 8,
+        SocketAddr::V6(_) => 21,
+    };
+    let sample_bytes = |_: &Id| ID_SIZE;
+
+    let response = match &mut reply.message_type {
+        packets::MessageType::Response(response) => response,
+        _ => return false,
+    };
+    match response {
+        packets::ResponseSpecific::FindNodeResponse(args) => {
+            trim_list(&mut args.nodes, excess_bytes, node_bytes)
+        }
+
+        packets::ResponseSpecific::GetPeersResponse(args) => match &mut args.values {
+            packets::GetPeersResponseValues::Nodes(nodes) => {
+                trim_list(nodes, excess_bytes, node_bytes)
+            }
+            packets::GetPeersResponseValues::Peers(peers) => {
+                trim_list(peers, excess_bytes, peer_bytes)
+            }
+            packets::GetPeersResponseValues::PeersAndNodes(peers, nodes) => {
+                trim_list(nodes, excess_bytes, node_bytes)
+                    || trim_list(peers, excess_bytes, peer_bytes)
+            }
+        },
+
+        packets::ResponseSpecific::SampleInfoHashesResponse(args) => {
+            trim_list(&mut args.nodes, excess_bytes, node_bytes)
+                || trim_list(&mut args.samples, excess_bytes, sample_bytes)
+        }
+
+        packets::ResponseSpecific::PingResponse(_) => false,
+    }
+}
+
+/// Removes items from the end of `list` until their `item_bytes` add up to `excess_bytes`
+/// (at least one). Returns false if `list` was already empty.
+fn trim_list<T>(list: &mut Vec<T>, excess_bytes: usize, item_bytes: impl Fn(&T) -> usize) -> bool {
+    if list.is_empty() {
+        return false;
+    }
+    let mut removed = 0;
+    while let Some(item) = list.pop() {
+        removed += item_bytes(&item);
+        if removed >= excess_bytes {
+            break;
+        }
+    }
+    true
+}
+
+/// Returns the address families that a requester gets nodes and peers of. Per bep0032,
+/// that's each family listed in `want`. If the requester didn't say what it wants, it's
+/// only the family of the requester's address.
+fn wanted_families(
+    want: &Option<Vec<AddressFamily>>,
+    requester_addr: &SocketAddr,
+) -> Vec<AddressFamily> {
+    match want {
+        Some(want) if !want.is_empty() => [AddressFamily::V4, AddressFamily::V6]
+            .iter()
+            .copied()
+            .filter(|family| want.contains(family))
+            .collect(),
+        _ => vec![AddressFamily::from(requester_addr)],
+    }
+}
+
+/// True if announces should be accepted without checking their token. Only ever possible
+/// with the `testing` feature.
+#[cfg(any(test, feature = "testing"))]
+fn accepts_announces_without_token(settings: &DHTSettings) -> bool {
+    settings.accept_announces_without_token
+}
+
+#[cfg(not(any(test, feature = "testing")))]
+fn accepts_announces_without_token(_settings: &DHTSettings) -> bool {
+    false
+}
 
-    return checksum.to_be_bytes();
+/// Returns true if a node with `id` at `ip` may join the routing table, as far as bep0042
+/// (and [enforce_id_ip_validity](crate::dht::DHTSettings::enforce_id_ip_validity)) is concerned.
+fn is_id_acceptable(settings: &DHTSettings, id: &Id, ip: &IpAddr) -> bool {
+    !settings.enforce_id_ip_validity || id.is_valid_for_ip(ip)
+}
+
+/// Makes sure the DHT can work with a socket bound to `socket_addr`. IPv4 address sources
+/// and Id derivation need IPv4 traffic, so IPv6 addresses are only allowed in dual-stack mode.
+fn check_address_family(
+    socket_addr: SocketAddr,
+    settings: &DHTSettings,
+) -> Result<(), RustyDHTError> {
+    match socket_addr {
+        SocketAddr::V4(_) => Ok(()),
+        SocketAddr::V6(_) if settings.dual_stack => Ok(()),
+        SocketAddr::V6(_) => Err(RustyDHTError::AddressFamilyError(socket_addr)),
+    }
+}
+
+fn bind_socket(
+    socket_addr: SocketAddr,
+    settings: &DHTSettings,
+) -> Result<UdpSocket, RustyDHTError> {
+    let std_sock = match socket_addr {
+        SocketAddr::V6(_) if settings.dual_stack => bind_dual_stack(socket_addr),
+        _ => std::net::UdpSocket::bind(socket_addr),
+    }
+    .map_err(|e| RustyDHTError::GeneralError(e.into()))?;
+    if let Some(ip_tos) = settings.ip_tos {
+        set_ip_tos(&std_sock, ip_tos)?;
+    }
+    std_sock
+        .set_nonblocking(true)
+        .map_err(|e| RustyDHTError::GeneralError(e.into()))?;
+    UdpSocket::from_std(std_sock).map_err(|e| RustyDHTError::GeneralError(e.into()))
+}
+
+/// Binds an IPv6 UDP socket that also sends and receives IPv4 traffic
+fn bind_dual_stack(socket_addr: SocketAddr) -> std::io::Result<std::net::UdpSocket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV6,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    socket.set_only_v6(false)?;
+    socket.bind(&socket_addr.into())?;
+    Ok(socket.into())
+}
+
+/// Sets the IP Type of Service byte on packets sent from `socket`
+#[cfg(not(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku"
+)))]
+fn set_ip_tos(socket: &std::net::UdpSocket, ip_tos: u8) -> Result<(), RustyDHTError> {
+    socket2::SockRef::from(socket)
+        .set_tos(ip_tos.into())
+        .map_err(|e| RustyDHTError::GeneralError(e.into()))
+}
+
+#[cfg(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku"
+))]
+fn set_ip_tos(_socket: &std::net::UdpSocket, _ip_tos: u8) -> Result<(), RustyDHTError> {
+    warn!(target: "rustydht_lib::DHT", "Setting IP Type of Service isn't supported on this platform. Ignoring.");
+    Ok(())
 }
 
 fn make_token_secret(size: usize) -> Vec<u8> {
@@ -1013,11 +2934,13 @@ fn make_token_secret(size: usize) -> Vec<u8> {
 mod test {
     use super::*;
     use crate::common::ipv4_addr_src::StaticIPV4AddrSource;
+    use crate::dht::mock_network::{MockEndpoint, MockNetwork};
     use crate::dht::DHTBuilder;
     use crate::dht::DHTSettingsBuilder;
     use anyhow::anyhow;
     use std::boxed::Box;
-    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
+    use std::sync::atomic::{AtomicBool, Ordering};
 
     async fn make_test_dht(
         port: u16,
@@ -1037,6 +2960,27 @@ mod test {
         )
     }
 
+    /// Makes a DHT like [make_test_dht], but on a [MockNetwork] instead of a UDP socket
+    fn make_mock_dht(
+        network: &MockNetwork,
+        addr: SocketAddr,
+    ) -> (DHT, shutdown::ShutdownSender, shutdown::ShutdownReceiver) {
+        let ipv4 = Ipv4Addr::new(1, 2, 3, 4);
+        let phony_ip4 = Box::new(StaticIPV4AddrSource::new(ipv4));
+        let (tx, rx) = shutdown::create_shutdown();
+        (
+            DHTBuilder::new()
+                .initial_id(get_dht_id())
+                .mock_network(network, addr)
+                .ip_source(phony_ip4)
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(rx.clone())
+                .unwrap(),
+            tx,
+            rx,
+        )
+    }
+
     #[tokio::test]
     async fn test_responds_to_ping() -> Result<(), RustyDHTError> {
         let requester_id = Id::from_random(&mut thread_rng());
@@ -1044,8 +2988,10 @@ mod test {
             .sender_id(requester_id)
             .build()?;
 
-        let port = 1948;
-        let (dht, mut shutdown_tx, shutdown_rx) = make_test_dht(port).await;
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
         shutdown::ShutdownReceiver::spawn_with_shutdown(
             shutdown_rx,
             async move {
@@ -1055,7 +3001,10 @@ mod test {
             Some(Duration::from_secs(10)),
         );
 
-        let res = send_and_receive(ping_request.clone(), port).await.unwrap();
+        let res = requester
+            .send_and_receive(ping_request.clone(), dht_addr)
+            .await
+            .unwrap();
 
         assert_eq!(res.transaction_id, ping_request.transaction_id);
         assert_eq!(
@@ -1072,81 +3021,241 @@ mod test {
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_responds_to_get_peers() -> Result<(), RustyDHTError> {
-        let requester_id = Id::from_random(&mut thread_rng());
-        let desired_info_hash = Id::from_random(&mut thread_rng());
-        let request = MessageBuilder::new_get_peers_request()
-            .sender_id(requester_id)
-            .target(desired_info_hash)
-            .build()?;
-
-        let port = 1974;
-        let (dht, mut shutdown_tx, shutdown_rx) = make_test_dht(port).await;
+    #[tokio::test(start_paused = true)]
+    async fn test_failing_router_is_deprioritized() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let responsive_addr: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let failing_addr: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+        let _failing_rx = network.bind(failing_addr);
+        let responsive = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, responsive_addr)
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())?,
+        );
+        let responsive_clone = responsive.clone();
         shutdown::ShutdownReceiver::spawn_with_shutdown(
-            shutdown_rx,
+            shutdown_rx.clone(),
             async move {
-                dht.run_event_loop().await.unwrap();
+                responsive_clone.run_event_loop().await.unwrap();
             },
-            "Test DHT",
-            Some(Duration::from_secs(10)),
+            "Responsive router",
+            None,
+        );
+        let failing = failing_addr.to_string();
+        let responsive_name = responsive_addr.to_string();
+        let dht = DHTBuilder::new()
+            .mock_network(&network, "10.0.0.1:6881".parse().unwrap())
+            .settings(
+                DHTSettingsBuilder::new()
+                    .routers(vec![failing.clone(), responsive_name.clone()])
+                    .build()
+                    .unwrap(),
+            )
+            .build(shutdown_rx.clone())?;
+
+        // The first round pings both routers at once
+        let start = tokio::time::Instant::now();
+        dht.ping_routers().await?;
+        assert!(start.elapsed() < ROUTER_PING_TIMEOUT * 2);
+        let stats = dht.get_router_stats();
+        assert_eq!(stats[&failing].failures, 1);
+        assert_eq!(stats[&responsive_name].successes, 1);
+
+        // After that, the responsive router is tried first, even though the failing one is
+        // configured first, and the failing one isn't waited on
+        for _ in 1..ROUTER_PROBE_INTERVAL {
+            let start = tokio::time::Instant::now();
+            dht.ping_routers().await?;
+            assert!(start.elapsed() < ROUTER_PING_STAGGER);
+        }
+        let stats = dht.get_router_stats();
+        assert_eq!(stats[&failing].failures, 1);
+        assert_eq!(
+            stats[&responsive_name].successes,
+            ROUTER_PROBE_INTERVAL as u32
         );
 
-        let res = send_and_receive(request.clone(), port).await.unwrap();
+        drop(dht);
+        drop(responsive);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
 
-        assert_eq!(res.transaction_id, request.transaction_id);
-        assert!(matches!(
-            res.message_type,
-            packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
-                packets::GetPeersResponseArguments { .. }
-            ))
-        ));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_router_pings_in_flight_are_recorded() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+
+        // A router that answers pings, but only after a while
+        let slow_addr: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let slow_network = network.clone();
+        let mut slow_rx = network.bind(slow_addr);
+        let slow_id = Id::from_random(&mut thread_rng());
+        tokio::spawn(async move {
+            while let Some((bytes, from)) = slow_rx.recv().await {
+                let req = packets::Message::from_bytes(&bytes).unwrap();
+                let reply = MessageBuilder::new_ping_response()
+                    .sender_id(slow_id)
+                    .transaction_id(req.transaction_id)
+                    .build()
+                    .unwrap();
+                let slow_network = slow_network.clone();
+                tokio::spawn(async move {
+                    sleep(ROUTER_PING_STAGGER * 2).await;
+                    slow_network.send(slow_addr, from, reply.to_bytes().unwrap());
+                });
+            }
+        });
+        let slow = slow_addr.to_string();
+        let fast_addr: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+        let fast = fast_addr.to_string();
+        let dht = DHTBuilder::new()
+            .mock_network(&network, "10.0.0.1:6881".parse().unwrap())
+            .settings(
+                DHTSettingsBuilder::new()
+                    .routers(vec![fast.clone(), slow.clone()])
+                    .build()
+                    .unwrap(),
+            )
+            .build(shutdown_rx.clone())?;
+
+        // The fast router isn't up for the first round, so the slow one is tried first
+        // after that
+        dht.ping_routers().await?;
+        let stats = dht.get_router_stats();
+        assert_eq!(stats[&fast].failures, 1);
+        assert_eq!(stats[&slow].successes, 1);
+
+        let fast_router = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, fast_addr)
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())?,
+        );
+        let fast_clone = fast_router.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                fast_clone.run_event_loop().await.unwrap();
+            },
+            "Fast router",
+            None,
+        );
+
+        // The fast router answers first, but the slow one's answer still counts
+        dht.ping_routers().await?;
+        let stats = dht.get_router_stats();
+        assert_eq!(stats[&fast].successes, 1);
+        assert_eq!(stats[&slow].successes, 2);
+        assert_eq!(stats[&slow].failures, 0);
 
+        drop(dht);
+        drop(fast_router);
+        drop(shutdown_rx);
         shutdown_tx.shutdown().await;
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_responds_to_find_node() -> Result<(), RustyDHTError> {
-        let port = 1995;
-        let (dht, mut shutdown_tx, shutdown_rx) = make_test_dht(port).await;
+    async fn test_announce_with_announce_token() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
         shutdown::ShutdownReceiver::spawn_with_shutdown(
-            shutdown_rx,
+            shutdown_rx.clone(),
             async move {
-                dht.run_event_loop().await.unwrap();
+                dht_clone.run_event_loop().await.unwrap();
             },
             "Test DHT",
             Some(Duration::from_secs(10)),
         );
 
         let requester_id = Id::from_random(&mut thread_rng());
-        let target = Id::from_random(&mut thread_rng());
-        let request = MessageBuilder::new_find_node_request()
-            .sender_id(requester_id)
-            .target(target)
-            .build()?;
-        let res = send_and_receive(request.clone(), port).await.unwrap();
+        let info_hash = Id::from_random(&mut thread_rng());
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let reply = requester
+            .send_and_receive(
+                MessageBuilder::new_get_peers_request()
+                    .sender_id(requester_id)
+                    .target(info_hash)
+                    .build()?,
+                dht_addr,
+            )
+            .await?;
+
+        // The token from the helper is accepted by announce_peer
+        let token = reply.announce_token().unwrap().to_vec();
+        let reply = requester
+            .send_and_receive(
+                MessageBuilder::new_announce_peer_request()
+                    .sender_id(requester_id)
+                    .target(info_hash)
+                    .port(1234)
+                    .token(token)
+                    .build()?,
+                dht_addr,
+            )
+            .await?;
+        assert!(matches!(
+            reply.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::PingResponse(_))
+        ));
+        assert!(reply.announce_token().is_none());
+        assert_eq!(dht.get_info_hashes(None).len(), 1);
 
-        assert_eq!(res.transaction_id, request.transaction_id);
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_with_socket_used_once() -> Result<(), RustyDHTError> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let builder = DHTBuilder::new()
+            .with_socket(socket)
+            .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap());
+        let dht = builder.clone().build(shutdown_rx.clone())?;
         assert!(matches!(
-            res.message_type,
-            packets::MessageType::Response(packets::ResponseSpecific::FindNodeResponse(
-                packets::FindNodeResponseArguments { .. }
-            ))
+            builder.build(shutdown_rx.clone()),
+            Err(RustyDHTError::GeneralError(_))
         ));
 
+        drop(dht);
+        drop(shutdown_rx);
         shutdown_tx.shutdown().await;
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_responds_to_announce_peer() -> Result<(), RustyDHTError> {
-        let requester_id = Id::from_random(&mut thread_rng());
-        let info_hash = Id::from_random(&mut thread_rng());
-        let port = 2014;
-        let (dht, mut shutdown_tx, shutdown_rx) = make_test_dht(port).await;
+    async fn test_with_socket() -> Result<(), RustyDHTError> {
+        let ping_request = MessageBuilder::new_ping_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .build()?;
+
+        let port = 2310;
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = DHTBuilder::new()
+            .initial_id(get_dht_id())
+            // Must be ignored in favor of the provided socket
+            .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2311))
+            .with_socket(socket)
+            .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+            .build(shutdown_rx.clone())
+            .unwrap();
         shutdown::ShutdownReceiver::spawn_with_shutdown(
             shutdown_rx,
             async move {
@@ -1156,235 +3265,5219 @@ mod test {
             Some(Duration::from_secs(10)),
         );
 
-        // Send a get_peers request and get the response
-        let reply = send_and_receive(
-            MessageBuilder::new_get_peers_request()
-                .sender_id(requester_id)
-                .target(info_hash)
-                .build()?,
-            port,
-        )
-        .await
-        .unwrap();
+        let requester = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        requester
+            .send_to(&ping_request.clone().to_bytes()?, ("127.0.0.1", port))
+            .await
+            .unwrap();
+        let mut buf = [0; 2048];
+        let num_read = requester.recv_from(&mut buf).await.unwrap().0;
+        let res = packets::Message::from_bytes(&buf[..num_read])?;
+        assert_eq!(res.transaction_id, ping_request.transaction_id);
+        assert_eq!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::PingResponse(
+                packets::PingResponseArguments {
+                    responder_id: get_dht_id()
+                }
+            ))
+        );
 
-        // Extract the token from the get_peers response
-        let token = {
-            if let packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
-                packets::GetPeersResponseArguments { token, .. },
-            )) = reply.message_type
-            {
-                token
-            } else {
-                return Err(RustyDHTError::GeneralError(anyhow!("Didn't get token")));
-            }
-        };
-
-        // Send an announce_peer request and get the response
-        let reply = send_and_receive(
-            MessageBuilder::new_announce_peer_request()
-                .sender_id(requester_id)
-                .target(info_hash)
-                .port(1234)
-                .token(token)
-                .build()?,
-            port,
-        )
-        .await
-        .unwrap();
+        shutdown_tx.shutdown().await;
 
-        // The response must be a ping response
-        assert!(matches!(
-            reply.message_type,
-            packets::MessageType::Response(packets::ResponseSpecific::PingResponse(
-                packets::PingResponseArguments { .. }
-            ))
-        ));
+        Ok(())
+    }
 
-        // Send get peers again - this time we'll get a peer back (ourselves)
-        let reply = send_and_receive(
-            MessageBuilder::new_get_peers_request()
-                .sender_id(requester_id)
-                .target(info_hash)
-                .build()?,
-            port,
-        )
-        .await
-        .unwrap();
+    #[tokio::test]
+    async fn test_bucket_distribution() {
+        let (dht, _, _) = make_test_dht(2312).await;
+        let our_id = get_dht_id().to_vec();
+        let add_node_at = |prefix_bits: usize, port: u16| {
+            let mut bytes = our_id.clone();
+            bytes[prefix_bits / 8] ^= 0x80 >> (prefix_bits % 8);
+            let node = Node::new(
+                Id::from_bytes(&bytes).unwrap(),
+                SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 5), port)),
+            );
+            dht.buckets.write().unwrap().add_or_update(node, true);
+        };
+        add_node_at(0, 1000);
+        add_node_at(3, 1001);
+        add_node_at(159, 1002);
+
+        // Flip a later bit too, so it's still at distance 3 but a different node
+        let mut bytes = get_dht_id().to_vec();
+        bytes[0] ^= 0x10;
+        bytes[19] ^= 0x01;
+        dht.buckets.write().unwrap().add_or_update(
+            Node::new(
+                Id::from_bytes(&bytes).unwrap(),
+                "1.2.3.5:1003".parse().unwrap(),
+            ),
+            true,
+        );
 
-        eprintln!("Received {:?}", reply);
+        let distribution = dht.bucket_distribution();
+        assert_eq!(distribution[0], 1);
+        assert_eq!(distribution[3], 2);
+        assert_eq!(distribution[159], 1);
+        assert_eq!(distribution.iter().sum::<usize>(), 4);
+    }
 
-        // Make sure we got a peer back
-        let peers = {
-            if let packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
-                packets::GetPeersResponseArguments {
-                    values: packets::GetPeersResponseValues::Peers(p),
-                    ..
-                },
-            )) = reply.message_type
-            {
-                p
+    #[tokio::test]
+    async fn test_coverage_near() {
+        let network = MockNetwork::new();
+        let (dht, mut shutdown_tx, shutdown_rx) =
+            make_mock_dht(&network, "10.0.0.1:6881".parse().unwrap());
+        let our_id = dht.get_id();
+
+        // A sparse routing table: a dozen nodes near us, and a few anywhere
+        for i in 0..16 {
+            let id = if i < 12 {
+                our_id.make_mutant(2).unwrap()
             } else {
-                return Err(RustyDHTError::GeneralError(anyhow!("Didn't get peers")));
-            }
-        };
-        assert_eq!(peers.len(), 1);
-        assert_eq!(peers[0].port(), 1234);
-        eprintln!("all good!");
+                Id::from_random(&mut thread_rng())
+            };
+            dht.buckets.write().unwrap().add_or_update(
+                Node::new(id, SocketAddr::new([127, 0, 1, i].into(), 6881)),
+                true,
+            );
+        }
+
+        let near_us = dht.coverage_near(&our_id);
+        assert!(near_us.prefix_bits >= 8);
+        assert!(near_us.near_nodes >= 8);
+
+        // Somewhere on the other side of the keyspace
+        let mut far_bytes = Id::from_random(&mut thread_rng()).to_vec();
+        far_bytes[0] = !our_id.to_vec()[0];
+        let far_target = Id::from_bytes(&far_bytes).unwrap();
+        let far = dht.coverage_near(&far_target);
+        assert_eq!(far.prefix_bits, near_us.prefix_bits);
+        assert_eq!(far.near_nodes, 0);
+        assert!(far.nearest_distance.unwrap() > near_us.nearest_distance.unwrap());
+
+        drop(dht);
+        drop(shutdown_rx);
         shutdown_tx.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_local_addr() -> Result<(), RustyDHTError> {
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = DHTBuilder::new()
+            .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+            .build(shutdown_rx.clone())?;
 
+        let local_addr = dht.local_addr()?;
+        assert_eq!(local_addr.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_ne!(local_addr.port(), 0);
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_responds_to_sample_infohashes() -> Result<(), RustyDHTError> {
-        let requester_id = Id::from_random(&mut thread_rng());
-        let target = Id::from_random(&mut thread_rng());
-        let request = MessageBuilder::new_sample_infohashes_request()
-            .sender_id(requester_id)
-            .target(target)
-            .build()?;
+    async fn test_same_bucket() {
+        let network = MockNetwork::new();
+        let (dht, mut shutdown_tx, shutdown_rx) =
+            make_mock_dht(&network, "10.0.0.1:6881".parse().unwrap());
+        // Fill the routing table enough that its first bucket gets split
+        for i in 0..100 {
+            dht.buckets.write().unwrap().add_or_update(
+                Node::new(
+                    Id::from_random(&mut thread_rng()),
+                    SocketAddr::new([1, 2, 3, 5].into(), 1000 + i),
+                ),
+                true,
+            );
+        }
+        let our_id = dht.get_id();
+        let flip_bit = |bit: usize| {
+            let mut bytes = our_id.to_vec();
+            bytes[bit / 8] ^= 0x80 >> (bit % 8);
+            Id::from_bytes(&bytes).unwrap()
+        };
 
-        let port = 2037;
-        let (dht, mut shutdown_tx, shutdown_rx) = make_test_dht(port).await;
+        // Both share a long prefix with us, so they end up in the deepest bucket
+        assert!(dht.same_bucket(&flip_bit(150), &flip_bit(155)));
+
+        // These differ from us (and each other) in the high bits
+        assert!(!dht.same_bucket(&flip_bit(0), &flip_bit(1)));
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_replies_to_query_without_id() -> Result<(), RustyDHTError> {
+        let port = 2313;
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .initial_id(get_dht_id())
+                .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![])
+                        .reply_to_invalid_sender_id(true)
+                        .build()
+                        .unwrap(),
+                )
+                .build(shutdown_rx.clone())
+                .unwrap(),
+        );
+        let dht_clone = dht.clone();
         shutdown::ShutdownReceiver::spawn_with_shutdown(
             shutdown_rx,
             async move {
-                dht.run_event_loop().await.unwrap();
+                dht_clone.run_event_loop().await.unwrap();
             },
             "Test DHT",
             Some(Duration::from_secs(10)),
         );
 
-        let res = send_and_receive(request.clone(), port).await.unwrap();
+        // A ping with an empty arguments dictionary
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sock.send_to(
+            b"d1:ade1:q4:ping1:t2:aa1:y1:qe",
+            format!("127.0.0.1:{}", port),
+        )
+        .await
+        .unwrap();
+        let mut recv_buf = [0; 2048];
+        let num_read = sock.recv_from(&mut recv_buf).await.unwrap().0;
+        let res = packets::Message::from_bytes(&recv_buf[..num_read])?;
 
-        assert_eq!(res.transaction_id, request.transaction_id);
-        assert!(matches!(
+        assert_eq!(res.transaction_id, b"aa".to_vec());
+        assert_eq!(
             res.message_type,
-            packets::MessageType::Response(packets::ResponseSpecific::SampleInfoHashesResponse(
-                packets::SampleInfoHashesResponseArguments { num: 0, .. }
-            ))
-        ));
+            packets::MessageType::Error(packets::ErrorSpecific {
+                code: 203,
+                description: "Missing or malformed sender id".to_string(),
+            })
+        );
+        assert_eq!(dht.invalid_sender_id_count(), 1);
 
+        // Shutdown waits for every ShutdownReceiver to drop, including the DHT's
+        drop(dht);
         shutdown_tx.shutdown().await;
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_event_loop_pings_routers() {
+    async fn test_routing_table_digest() {
+        let (dht1, _, _) = make_test_dht(2314).await;
+        let (dht2, _, _) = make_test_dht(2315).await;
+        // Each node differs from our id in a different bit, so they all land in different
+        // buckets and none get turned away by a full one
+        let id_with_flipped_bit = |bit: usize| {
+            let mut bytes = get_dht_id().to_vec();
+            bytes[bit / 8] ^= 0x80 >> (bit % 8);
+            Id::from_bytes(bytes).unwrap()
+        };
+        let nodes: Vec<Node> = (0..10)
+            .map(|i| {
+                Node::new(
+                    id_with_flipped_bit(i),
+                    SocketAddr::V4(SocketAddrV4::new(
+                        Ipv4Addr::new(1, 2, 3, 5),
+                        1000 + i as u16,
+                    )),
+                )
+            })
+            .collect();
+        for node in &nodes {
+            dht1.buckets
+                .write()
+                .unwrap()
+                .add_or_update(node.clone(), true);
+        }
+        for node in nodes.iter().rev() {
+            dht2.buckets
+                .write()
+                .unwrap()
+                .add_or_update(node.clone(), true);
+        }
+
+        assert_eq!(dht1.dump_routing_table_compact().len(), 10 * 26);
+        assert_eq!(
+            dht1.dump_routing_table_compact(),
+            dht2.dump_routing_table_compact()
+        );
+        assert_eq!(dht1.routing_table_digest(), dht2.routing_table_digest());
+
+        dht2.buckets.write().unwrap().add_or_update(
+            Node::new(id_with_flipped_bit(20), "1.2.3.6:1234".parse().unwrap()),
+            true,
+        );
+        assert_ne!(dht1.routing_table_digest(), dht2.routing_table_digest());
+    }
+
+    #[tokio::test]
+    async fn test_find_node_results_are_capped() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
         let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
-        let port1 = 2171;
-        let dht1 = Arc::new(
+        let dht = Arc::new(
             DHTBuilder::new()
                 .initial_id(get_dht_id())
-                .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port1))
-                .ip_source(Box::new(StaticIPV4AddrSource::new(Ipv4Addr::new(
-                    1, 2, 3, 4,
-                ))))
-                .settings(DHTSettingsBuilder::new().routers(vec![]).build())
+                .mock_network(&network, "127.0.0.1:2328".parse().unwrap())
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![])
+                        .max_nodes_per_response(10)
+                        .build()
+                        .unwrap(),
+                )
                 .build(shutdown_rx.clone())
                 .unwrap(),
         );
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
 
-        let dht2 = Arc::new(
+        // 100 nodes that would each land in a different bucket
+        let nodes: Vec<Node> = (0..100)
+            .map(|i| {
+                let mut bytes = get_dht_id().to_vec();
+                bytes[i / 8] ^= 0x80 >> (i % 8);
+                Node::new(
+                    Id::from_bytes(&bytes).unwrap(),
+                    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3000 + i as u16)),
+                )
+            })
+            .collect();
+        let responder_addr: SocketAddr = "127.0.0.1:2329".parse().unwrap();
+        let responder_task = spawn_nodes_responder(&network, responder_addr, nodes);
+
+        dht.send_request(
+            MessageBuilder::new_find_node_request()
+                .sender_id(get_dht_id())
+                .target(Id::from_random(&mut thread_rng()))
+                .build()?,
+            responder_addr,
+            None,
+            Some(Duration::from_secs(5)),
+        )
+        .await?;
+        responder_task.await.unwrap();
+
+        // The responder is verified, and only 10 of the nodes it sent are added
+        assert_eq!(dht.buckets.read().unwrap().count(), (10, 1));
+
+        // Shutdown waits for every ShutdownReceiver to drop, including the DHT's
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_node_results_must_be_closer() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
             DHTBuilder::new()
-                .initial_id(get_dht_id().make_mutant(4).unwrap())
-                .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
-                .ip_source(Box::new(StaticIPV4AddrSource::new(Ipv4Addr::new(
-                    1, 2, 3, 4,
-                ))))
+                .initial_id(get_dht_id())
+                .mock_network(&network, "127.0.0.1:2330".parse().unwrap())
                 .settings(
                     DHTSettingsBuilder::new()
-                        .router_ping_interval_secs(1)
-                        .routers(vec![format!("127.0.0.1:{}", port1)])
-                        .build(),
+                        .routers(vec![])
+                        .require_closer_find_node_results(true)
+                        .build()
+                        .unwrap(),
                 )
                 .build(shutdown_rx.clone())
                 .unwrap(),
         );
-
-        let mut receiver = dht2.subscribe();
-
+        let dht_clone = dht.clone();
         shutdown::ShutdownReceiver::spawn_with_shutdown(
             shutdown_rx.clone(),
             async move {
-                dht1.run_event_loop().await.unwrap();
+                dht_clone.run_event_loop().await.unwrap();
             },
-            "DHT1",
-            None,
+            "Test DHT",
+            Some(Duration::from_secs(10)),
         );
 
-        let dht2_clone = dht2.clone();
-        shutdown::ShutdownReceiver::spawn_with_shutdown(
-            shutdown_rx,
-            async move { dht2_clone.run_event_loop().await.unwrap() },
-            "DHT2",
-            None,
-        );
+        // We already know 8 verified nodes that share 8 bytes with the target
+        let target = get_dht_id().make_mutant(4).unwrap();
+        for i in 0..8 {
+            dht.buckets.write().unwrap().add_or_update(
+                Node::new(
+                    target.make_mutant(8).unwrap(),
+                    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3000 + i)),
+                ),
+                true,
+            );
+        }
 
-        receiver.recv().await;
-        let (unverified, verified) = dht2.state.lock().unwrap().buckets.count();
+        let close_nodes: Vec<Node> = (0..2)
+            .map(|i| {
+                Node::new(
+                    target.make_mutant(16).unwrap(),
+                    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 4000 + i)),
+                )
+            })
+            .collect();
+        let far_nodes: Vec<Node> = (0..3)
+            .map(|i| {
+                Node::new(
+                    target.make_mutant(2).unwrap(),
+                    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 5000 + i)),
+                )
+            })
+            .collect();
+        let responder_addr: SocketAddr = "127.0.0.1:2331".parse().unwrap();
+        let responder_task = spawn_nodes_responder(
+            &network,
+            responder_addr,
+            far_nodes
+                .iter()
+                .chain(close_nodes.iter())
+                .cloned()
+                .collect(),
+        );
 
-        // Must drop dht2 as it contains a ShutdownReceiver channel which will block shutdown
-        drop(dht2);
+        dht.send_request(
+            MessageBuilder::new_find_node_request()
+                .sender_id(get_dht_id())
+                .target(target)
+                .build()?,
+            responder_addr,
+            None,
+            Some(Duration::from_secs(5)),
+        )
+        .await?;
+        responder_task.await.unwrap();
+
+        // Only the nodes closer to the target than the ones we knew are added
+        let unverified: Vec<Node> = dht
+            .buckets
+            .read()
+            .unwrap()
+            .get_all_unverified()
+            .into_iter()
+            .map(|wrapper| wrapper.node)
+            .collect();
+        assert_eq!(unverified.len(), 2);
+        for node in close_nodes {
+            assert!(unverified.contains(&node));
+        }
 
+        drop(dht);
+        drop(shutdown_rx);
         shutdown_tx.shutdown().await;
-        assert_eq!(unverified, 0);
-        assert_eq!(verified, 1);
+
+        Ok(())
+    }
+
+    /// Spawns a task that answers the first request sent to `addr` on `network` (a find_node
+    /// or get_peers) with a response carrying `nodes`
+    fn spawn_nodes_responder(
+        network: &MockNetwork,
+        addr: SocketAddr,
+        nodes: Vec<Node>,
+    ) -> tokio::task::JoinHandle<()> {
+        let network = network.clone();
+        let mut rx = network.bind(addr);
+        tokio::spawn(async move {
+            let (bytes, from) = rx.recv().await.unwrap();
+            let req = packets::Message::from_bytes(&bytes).unwrap();
+            let builder = match req.message_type {
+                packets::MessageType::Request(packets::RequestSpecific::GetPeersRequest(_)) => {
+                    MessageBuilder::new_get_peers_response().token(vec![1, 2, 3])
+                }
+                _ => MessageBuilder::new_find_node_response(),
+            };
+            let reply = builder
+                .sender_id(Id::from_random(&mut thread_rng()))
+                .transaction_id(req.transaction_id)
+                .nodes(nodes)
+                .build()
+                .unwrap();
+            network.send(addr, from, reply.to_bytes().unwrap());
+        })
     }
 
     #[tokio::test]
-    async fn test_token_secret_rotation() {
-        let ipv4 = Ipv4Addr::new(1, 2, 3, 4);
-        let phony_ip4 = Box::new(StaticIPV4AddrSource::new(ipv4));
-        let port = 2244;
+    async fn test_get_peers_results_are_added() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "127.0.0.1:2332".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
 
-        let dht = DHTBuilder::new()
-            .initial_id(get_dht_id())
-            .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))
-            .ip_source(phony_ip4)
-            .settings(DHTSettingsBuilder::new().routers(vec![]).build())
-            .build(shutdown::create_shutdown().1)
-            .unwrap();
+        let nodes: Vec<Node> = (0..3)
+            .map(|i| {
+                Node::new(
+                    Id::from_random(&mut thread_rng()),
+                    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3000 + i)),
+                )
+            })
+            .collect();
+        let responder_addr: SocketAddr = "127.0.0.1:2333".parse().unwrap();
+        let responder_task = spawn_nodes_responder(&network, responder_addr, nodes.clone());
 
-        assert_eq!(
-            dht.state.lock().unwrap().token_secret.len(),
-            DHTSettings::default().token_secret_size
-        );
+        dht.send_request(
+            MessageBuilder::new_get_peers_request()
+                .sender_id(get_dht_id())
+                .target(Id::from_random(&mut thread_rng()))
+                .build()?,
+            responder_addr,
+            None,
+            Some(Duration::from_secs(5)),
+        )
+        .await?;
+        responder_task.await.unwrap();
+
+        // The nodes from a get_peers response go into the routing table like find_node's do
+        let unverified: Vec<Node> = dht
+            .buckets
+            .read()
+            .unwrap()
+            .get_all_unverified()
+            .into_iter()
+            .map(|wrapper| wrapper.node)
+            .collect();
+        for node in nodes {
+            assert!(unverified.contains(&node));
+        }
 
-        dht.rotate_token_secrets();
-        assert_eq!(
-            dht.state.lock().unwrap().old_token_secret.len(),
-            DHTSettings::default().token_secret_size
-        );
-        assert_eq!(
-            dht.state.lock().unwrap().token_secret.len(),
-            DHTSettings::default().token_secret_size
-        );
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
 
-        let state = dht.state.lock().unwrap();
-        assert_ne!(state.old_token_secret, state.token_secret);
+        Ok(())
     }
 
-    // Dumb helper function because we can't declare a const or static Id
-    fn get_dht_id() -> Id {
-        Id::from_hex("0011223344556677889900112233445566778899").unwrap()
+    #[tokio::test]
+    async fn test_is_reachable() -> Result<(), RustyDHTError> {
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let make_dht = |port| {
+            Arc::new(
+                DHTBuilder::new()
+                    .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            )
+        };
+        let dht1 = make_dht(2330);
+        let dht2 = make_dht(2331);
+        for dht in [dht1.clone(), dht2.clone()] {
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+        }
+        assert_eq!(dht1.is_reachable(), None);
+
+        // dht2 contacts dht1 out of the blue
+        dht2.send_request(
+            MessageBuilder::new_ping_request()
+                .sender_id(dht2.get_id())
+                .build()?,
+            "127.0.0.1:2330".parse().unwrap(),
+            None,
+            Some(Duration::from_secs(5)),
+        )
+        .await?;
+        assert_eq!(dht1.is_reachable(), Some(true));
+        // The response dht2 got was solicited, so dht2 still doesn't know
+        assert_eq!(dht2.is_reachable(), None);
+
+        // Shutdown waits for every ShutdownReceiver to drop, including the DHTs'
+        drop(dht1);
+        drop(dht2);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rebind() -> Result<(), RustyDHTError> {
+        let (dht, mut shutdown_tx, shutdown_rx) = make_test_dht(2305).await;
+        let node = Node::new(
+            Id::from_random(&mut thread_rng()),
+            "1.2.3.5:1234".parse().unwrap(),
+        );
+        dht.buckets.write().unwrap().add_or_update(node, true);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx,
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        dht.rebind(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2306)))?;
+
+        let request = MessageBuilder::new_ping_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .build()?;
+        let requester = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        requester
+            .send_to(&request.clone().to_bytes()?, ("127.0.0.1", 2306))
+            .await
+            .unwrap();
+        let mut buf = [0; 2048];
+        let num_read = requester.recv_from(&mut buf).await.unwrap().0;
+        let res = packets::Message::from_bytes(&buf[..num_read])?;
+        assert_eq!(res.transaction_id, request.transaction_id);
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::PingResponse(
+                packets::PingResponseArguments { .. }
+            ))
+        ));
+        assert_eq!(dht.get_nodes().len(), 1);
+
+        // Shutdown waits for every ShutdownReceiver to drop, including the DHT's
+        drop(dht);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_supervisor_reopens_failed_socket() -> Result<(), RustyDHTError> {
+        let (dht, mut shutdown_tx, shutdown_rx) = make_test_dht(2334).await;
+        let dht = Arc::new(dht);
+        let old_socket = Arc::downgrade(&dht.socket.udp_socket());
+        let policy = RestartPolicy {
+            max_restarts: 1,
+            initial_backoff: Duration::from_millis(100),
+            ..RestartPolicy::default()
+        };
+        let dht_clone = dht.clone();
+        let supervisor =
+            tokio::spawn(async move { dht_clone.run_event_loop_supervised(policy).await });
+
+        dht.socket
+            .inject_incoming_error(RustyDHTError::SocketRecvError(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Socket broke",
+            )))
+            .await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // The old socket was closed, and a new one bound to the same address
+        assert!(!supervisor.is_finished());
+        assert!(old_socket.upgrade().is_none());
+        assert_eq!(
+            dht.local_addr()?,
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2334))
+        );
+        let request = MessageBuilder::new_ping_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .build()?;
+        let requester = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        requester
+            .send_to(&request.clone().to_bytes()?, ("127.0.0.1", 2334))
+            .await
+            .unwrap();
+        let mut buf = [0; 2048];
+        let num_read = requester.recv_from(&mut buf).await.unwrap().0;
+        let res = packets::Message::from_bytes(&buf[..num_read])?;
+        assert_eq!(res.transaction_id, request.transaction_id);
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        assert!(supervisor.await.unwrap().is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ip_tos_is_applied() {
+        let (_, shutdown_rx) = shutdown::create_shutdown();
+        let dht = DHTBuilder::new()
+            .initial_id(get_dht_id())
+            .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2309))
+            .settings(
+                DHTSettingsBuilder::new()
+                    .routers(vec![])
+                    .ip_tos(Some(0x20))
+                    .build()
+                    .unwrap(),
+            )
+            .build(shutdown_rx)
+            .unwrap();
+
+        let socket = dht.socket.udp_socket();
+        assert_eq!(socket2::SockRef::from(&*socket).tos().unwrap(), 0x20);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_ipv6_listen_addr() {
+        let (_, shutdown_rx) = shutdown::create_shutdown();
+        let listen_addr: SocketAddr = "[::1]:2332".parse().unwrap();
+        let result = DHT::new(
+            shutdown_rx.clone(),
+            Some(get_dht_id()),
+            listen_addr,
+            Box::new(StaticIPV4AddrSource::new(Ipv4Addr::new(1, 2, 3, 4))),
+            Box::new(crate::storage::node_bucket_storage::NodeBucketStorage::new(
+                Id::ZERO,
+                8,
+            )),
+            DHTSettingsBuilder::new().routers(vec![]).build().unwrap(),
+        );
+        assert!(
+            matches!(result, Err(RustyDHTError::AddressFamilyError(addr)) if addr == listen_addr)
+        );
+
+        // A provided socket is checked too
+        let socket = UdpSocket::bind(listen_addr).await.unwrap();
+        let result = DHTBuilder::new()
+            .with_socket(socket)
+            .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+            .build(shutdown_rx);
+        assert!(matches!(result, Err(RustyDHTError::AddressFamilyError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_responds_to_get_peers() -> Result<(), RustyDHTError> {
+        let requester_id = Id::from_random(&mut thread_rng());
+        let desired_info_hash = Id::from_random(&mut thread_rng());
+        let request = MessageBuilder::new_get_peers_request()
+            .sender_id(requester_id)
+            .target(desired_info_hash)
+            .build()?;
+
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx,
+            async move {
+                dht.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let res = requester
+            .send_and_receive(request.clone(), dht_addr)
+            .await
+            .unwrap();
+
+        assert_eq!(res.transaction_id, request.transaction_id);
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
+                packets::GetPeersResponseArguments { .. }
+            ))
+        ));
+
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_min_verified_nodes_to_answer() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, dht_addr)
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![])
+                        .min_verified_nodes_to_answer(1)
+                        .build()
+                        .unwrap(),
+                )
+                .build(shutdown_rx.clone())?,
+        );
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let get_peers = MessageBuilder::new_get_peers_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .target(Id::from_random(&mut thread_rng()))
+            .build()?;
+
+        // Pings are answered right away, lookups aren't
+        let ping = MessageBuilder::new_ping_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .build()?;
+        assert!(requester.send_and_receive(ping, dht_addr).await.is_ok());
+        assert!(matches!(
+            requester
+                .send_and_receive(get_peers.clone(), dht_addr)
+                .await,
+            Err(RustyDHTError::TimeoutError(_))
+        ));
+
+        // Unless we have peers for the info hash
+        let info_hash = Id::from_random(&mut thread_rng());
+        let peer: SocketAddr = "10.0.0.4:6881".parse().unwrap();
+        dht.state
+            .lock()
+            .unwrap()
+            .peer_storage
+            .announce_peer(info_hash, peer);
+        let res = requester
+            .send_and_receive(
+                MessageBuilder::new_get_peers_request()
+                    .sender_id(Id::from_random(&mut thread_rng()))
+                    .target(info_hash)
+                    .build()?,
+                dht_addr,
+            )
+            .await?;
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
+                packets::GetPeersResponseArguments {
+                    values: packets::GetPeersResponseValues::Peers(peers),
+                    ..
+                }
+            )) if peers == vec![peer]
+        ));
+
+        let known_node = Node::new(
+            Id::from_random(&mut thread_rng()),
+            "127.0.0.3:6881".parse().unwrap(),
+        );
+        dht.buckets
+            .write()
+            .unwrap()
+            .add_or_update(known_node.clone(), true);
+        let res = requester.send_and_receive(get_peers, dht_addr).await?;
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
+                packets::GetPeersResponseArguments {
+                    values: packets::GetPeersResponseValues::Nodes(nodes),
+                    ..
+                }
+            )) if nodes.contains(&known_node)
+        ));
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_request_max_lifetime() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let dead_addr: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let _dead_rx = network.bind(dead_addr);
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let mut settings = dht.get_settings();
+        let ping = || {
+            MessageBuilder::new_ping_request()
+                .sender_id(Id::from_random(&mut thread_rng()))
+                .build()
+        };
+
+        // A request without a timeout still gives up once it's pruned
+        let start = tokio::time::Instant::now();
+        let res = dht.send_request(ping()?, dead_addr, None, None).await;
+        assert!(matches!(res, Err(RustyDHTError::TimeoutError(_))));
+        let elapsed = start.elapsed();
+        let prune_after = Duration::from_secs(settings.outgoing_request_prune_secs);
+        let check_interval = Duration::from_secs(settings.outgoing_reqiest_check_interval_secs);
+        assert!(elapsed >= prune_after);
+        assert!(elapsed <= prune_after + check_interval);
+
+        // Changing the settings applies to requests sent afterwards
+        settings.outgoing_request_prune_secs = 2;
+        settings.outgoing_reqiest_check_interval_secs = 1;
+        dht.update_settings(settings)?;
+        tokio::time::sleep(check_interval).await;
+        let start = tokio::time::Instant::now();
+        let res = dht.send_request(ping()?, dead_addr, None, None).await;
+        assert!(matches!(res, Err(RustyDHTError::TimeoutError(_))));
+        assert!(start.elapsed() <= Duration::from_secs(3));
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_only_requester_policy() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut read_only_requester = network.endpoint("127.0.0.2:6881".parse().unwrap());
+        let mut other_requester = network.endpoint("127.0.0.3:6881".parse().unwrap());
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+        let read_only_id = Id::from_random(&mut thread_rng());
+        let read_only_ping = || {
+            MessageBuilder::new_ping_request()
+                .sender_id(read_only_id)
+                .read_only(true)
+                .build()
+        };
+        let in_routing_table = |id: Id| {
+            let buckets = dht.buckets.read().unwrap();
+            buckets
+                .get_all_verified()
+                .into_iter()
+                .chain(buckets.get_all_unverified())
+                .any(|node| node.node.id == id)
+        };
+
+        // The read-only node is answered, but only the other one joins the routing table
+        let res = read_only_requester
+            .send_and_receive(read_only_ping()?, dht_addr)
+            .await?;
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::PingResponse(_))
+        ));
+        let other_id = Id::from_random(&mut thread_rng());
+        other_requester
+            .send_and_receive(
+                MessageBuilder::new_ping_request()
+                    .sender_id(other_id)
+                    .build()?,
+                dht_addr,
+            )
+            .await?;
+        assert!(in_routing_table(other_id));
+        assert!(!in_routing_table(read_only_id));
+
+        let mut settings = dht.get_settings();
+        settings.read_only_requester_policy = ReadOnlyRequesterPolicy::Ignore;
+        dht.update_settings(settings)?;
+        assert!(matches!(
+            read_only_requester
+                .send_and_receive(read_only_ping()?, dht_addr)
+                .await,
+            Err(RustyDHTError::TimeoutError(_))
+        ));
+        assert!(!in_routing_table(read_only_id));
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_requester_is_not_in_reply() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let requester_addr: SocketAddr = "127.0.0.2:6881".parse().unwrap();
+        let mut requester = network.endpoint(requester_addr);
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+
+        // The routing table knows the requester, under its current Id and an old one
+        let requester_id = Id::from_random(&mut thread_rng());
+        let other = Node::new(
+            Id::from_random(&mut thread_rng()),
+            "127.0.0.3:6881".parse().unwrap(),
+        );
+        {
+            let mut buckets = dht.buckets.write().unwrap();
+            buckets.add_or_update(Node::new(requester_id, requester_addr), true);
+            buckets.add_or_update(
+                Node::new(Id::from_random(&mut thread_rng()), requester_addr),
+                true,
+            );
+            buckets.add_or_update(other.clone(), true);
+        }
+
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        for target in [requester_id, get_dht_id()] {
+            let find_node = MessageBuilder::new_find_node_request()
+                .sender_id(requester_id)
+                .target(target)
+                .build()?;
+            let res = requester.send_and_receive(find_node, dht_addr).await?;
+            assert!(matches!(
+                res.message_type,
+                packets::MessageType::Response(packets::ResponseSpecific::FindNodeResponse(
+                    packets::FindNodeResponseArguments { nodes, .. }
+                )) if nodes == vec![other.clone()]
+            ));
+        }
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_requester_doesnt_shorten_reply() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let requester_addr: SocketAddr = "127.0.0.2:6881".parse().unwrap();
+        let mut requester = network.endpoint(requester_addr);
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+
+        // The node nearest the target has the requester's address, under an old Id. It's
+        // left out, but the reply still has as many nodes as it can.
+        let target = Id::from_random(&mut thread_rng());
+        {
+            let mut buckets = dht.buckets.write().unwrap();
+            buckets.add_or_update(Node::new(target, requester_addr), true);
+            let mut i = 0;
+            while buckets.count().1 < 12 {
+                i += 1;
+                buckets.add_or_update(
+                    Node::new(
+                        Id::from_random(&mut thread_rng()),
+                        SocketAddr::new([127, 0, 1, i].into(), 6881),
+                    ),
+                    true,
+                );
+            }
+            assert!(buckets
+                .get_nearest_nodes(&target, None)
+                .contains(&Node::new(target, requester_addr)));
+        }
+
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let find_node = MessageBuilder::new_find_node_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .target(target)
+            .build()?;
+        let res = requester.send_and_receive(find_node, dht_addr).await?;
+        match res.message_type {
+            packets::MessageType::Response(packets::ResponseSpecific::FindNodeResponse(args)) => {
+                assert_eq!(args.nodes.len(), 8);
+                assert!(args.nodes.iter().all(|node| node.address != requester_addr));
+            }
+            other => panic!("Unexpected reply {:?}", other),
+        }
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_responds_to_find_node() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx,
+            async move {
+                dht.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let requester_id = Id::from_random(&mut thread_rng());
+        let target = Id::from_random(&mut thread_rng());
+        let request = MessageBuilder::new_find_node_request()
+            .sender_id(requester_id)
+            .target(target)
+            .build()?;
+        let res = requester
+            .send_and_receive(request.clone(), dht_addr)
+            .await
+            .unwrap();
+
+        assert_eq!(res.transaction_id, request.transaction_id);
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::FindNodeResponse(
+                packets::FindNodeResponseArguments { .. }
+            ))
+        ));
+
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_node_reply_honors_want() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let v4_node = Node::new(
+            Id::from_random(&mut thread_rng()),
+            "1.2.3.5:1234".parse().unwrap(),
+        );
+        let v6_node = Node::new(
+            Id::from_random(&mut thread_rng()),
+            "[2001:db8::5]:1234".parse().unwrap(),
+        );
+        {
+            let mut buckets = dht.buckets.write().unwrap();
+            buckets.add_or_update(v4_node.clone(), true);
+            buckets.add_or_update(v6_node.clone(), true);
+        }
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx,
+            async move {
+                dht.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let get_nodes = |res: packets::Message| match res.message_type {
+            packets::MessageType::Response(packets::ResponseSpecific::FindNodeResponse(
+                packets::FindNodeResponseArguments { nodes, .. },
+            )) => nodes,
+            _ => panic!("Unexpected response {:?}", res),
+        };
+
+        // A v4 requester that doesn't say what it wants gets only v4 nodes
+        let request = MessageBuilder::new_find_node_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .target(Id::from_random(&mut thread_rng()))
+            .build()?;
+        let nodes = get_nodes(requester.send_and_receive(request, dht_addr).await?);
+        assert_eq!(nodes, vec![v4_node.clone()]);
+
+        // Asking for both families gets nodes from both
+        let request = MessageBuilder::new_find_node_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .target(Id::from_random(&mut thread_rng()))
+            .want(vec![AddressFamily::V4, AddressFamily::V6])
+            .build()?;
+        let nodes = get_nodes(requester.send_and_receive(request, dht_addr).await?);
+        assert_eq!(nodes, vec![v4_node, v6_node.clone()]);
+
+        // Asking for just v6 gets only v6 nodes
+        let request = MessageBuilder::new_find_node_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .target(Id::from_random(&mut thread_rng()))
+            .want(vec![AddressFamily::V6])
+            .build()?;
+        let nodes = get_nodes(requester.send_and_receive(request, dht_addr).await?);
+        assert_eq!(nodes, vec![v6_node]);
+
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_peers_reply_honors_want() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let info_hash = Id::from_random(&mut thread_rng());
+        let v4_peer: SocketAddr = "1.2.3.5:1234".parse().unwrap();
+        let v6_peer: SocketAddr = "[2001:db8::5]:1234".parse().unwrap();
+        {
+            let mut state = dht.state.lock().unwrap();
+            state.peer_storage.announce_peer(info_hash, v4_peer);
+            state.peer_storage.announce_peer(info_hash, v6_peer);
+        }
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let get_peers_request = |want: Vec<AddressFamily>| {
+            MessageBuilder::new_get_peers_request()
+                .sender_id(Id::from_random(&mut thread_rng()))
+                .target(info_hash)
+                .want(want)
+                .build()
+                .unwrap()
+        };
+        let get_peers = |reply: packets::Message| match reply.message_type {
+            packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
+                packets::GetPeersResponseArguments {
+                    values: packets::GetPeersResponseValues::Peers(mut peers),
+                    ..
+                },
+            )) => {
+                peers.sort();
+                peers
+            }
+            _ => panic!("Unexpected response {:?}", reply),
+        };
+
+        // A v4 requester that doesn't say what it wants gets only v4 peers
+        let reply = requester
+            .send_and_receive(get_peers_request(vec![]), dht_addr)
+            .await?;
+        assert_eq!(get_peers(reply), vec![v4_peer]);
+
+        // Asking for both families gets peers from both
+        let request = get_peers_request(vec![AddressFamily::V4, AddressFamily::V6]);
+        let reply = requester.send_and_receive(request, dht_addr).await?;
+        assert_eq!(get_peers(reply), vec![v4_peer, v6_peer]);
+
+        // Asking for just v6 gets only v6 peers
+        let request = get_peers_request(vec![AddressFamily::V6]);
+        let reply = requester.send_and_receive(request, dht_addr).await?;
+        assert_eq!(get_peers(reply), vec![v6_peer]);
+
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_accept_announces_without_token() -> Result<(), RustyDHTError> {
+        for accept in [false, true] {
+            let network = MockNetwork::new();
+            let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+            let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, dht_addr)
+                    .settings(
+                        DHTSettingsBuilder::new()
+                            .routers(vec![])
+                            .accept_announces_without_token(accept)
+                            .build()
+                            .unwrap(),
+                    )
+                    .build(shutdown_rx.clone())?,
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+
+            let info_hash = Id::from_random(&mut thread_rng());
+            let announce = MessageBuilder::new_announce_peer_request()
+                .sender_id(Id::from_random(&mut thread_rng()))
+                .target(info_hash)
+                .port(1234)
+                .token(Vec::new())
+                .build()?;
+            let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+            let reply = requester.send_and_receive(announce, dht_addr).await;
+            let stored = dht
+                .state
+                .lock()
+                .unwrap()
+                .peer_storage
+                .get_peers(&info_hash, None);
+            if accept {
+                assert!(matches!(
+                    reply?.message_type,
+                    packets::MessageType::Response(packets::ResponseSpecific::PingResponse(_))
+                ));
+                assert_eq!(stored, vec!["10.0.0.2:1234".parse().unwrap()]);
+            } else {
+                assert!(matches!(reply, Err(RustyDHTError::TimeoutError(_))));
+                assert!(stored.is_empty());
+            }
+
+            drop(dht);
+            drop(shutdown_rx);
+            shutdown_tx.shutdown().await;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_responds_to_announce_peer() -> Result<(), RustyDHTError> {
+        let requester_id = Id::from_random(&mut thread_rng());
+        let info_hash = Id::from_random(&mut thread_rng());
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx,
+            async move {
+                dht.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        // Send a get_peers request and get the response
+        let reply = requester
+            .send_and_receive(
+                MessageBuilder::new_get_peers_request()
+                    .sender_id(requester_id)
+                    .target(info_hash)
+                    .build()?,
+                dht_addr,
+            )
+            .await
+            .unwrap();
+
+        // Extract the token from the get_peers response
+        let token = {
+            if let packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
+                packets::GetPeersResponseArguments { token, .. },
+            )) = reply.message_type
+            {
+                token
+            } else {
+                return Err(RustyDHTError::GeneralError(anyhow!("Didn't get token")));
+            }
+        };
+
+        // Send an announce_peer request and get the response
+        let reply = requester
+            .send_and_receive(
+                MessageBuilder::new_announce_peer_request()
+                    .sender_id(requester_id)
+                    .target(info_hash)
+                    .port(1234)
+                    .token(token)
+                    .build()?,
+                dht_addr,
+            )
+            .await
+            .unwrap();
+
+        // The response must be a ping response
+        assert!(matches!(
+            reply.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::PingResponse(
+                packets::PingResponseArguments { .. }
+            ))
+        ));
+
+        // Send get peers again - this time we'll get a peer back (ourselves)
+        let reply = requester
+            .send_and_receive(
+                MessageBuilder::new_get_peers_request()
+                    .sender_id(requester_id)
+                    .target(info_hash)
+                    .build()?,
+                dht_addr,
+            )
+            .await
+            .unwrap();
+
+        eprintln!("Received {:?}", reply);
+
+        // Make sure we got a peer back
+        let peers = {
+            if let packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
+                packets::GetPeersResponseArguments {
+                    values: packets::GetPeersResponseValues::Peers(p),
+                    ..
+                },
+            )) = reply.message_type
+            {
+                p
+            } else {
+                return Err(RustyDHTError::GeneralError(anyhow!("Didn't get peers")));
+            }
+        };
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].port(), 1234);
+        eprintln!("all good!");
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_compact_peers() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let requester_id = Id::from_random(&mut thread_rng());
+        let info_hash = Id::from_random(&mut thread_rng());
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let reply = requester
+            .send_and_receive(
+                MessageBuilder::new_get_peers_request()
+                    .sender_id(requester_id)
+                    .target(info_hash)
+                    .build()?,
+                dht_addr,
+            )
+            .await?;
+        let token = reply.announce_token().unwrap().to_vec();
+        requester
+            .send_and_receive(
+                MessageBuilder::new_announce_peer_request()
+                    .sender_id(requester_id)
+                    .target(info_hash)
+                    .port(1234)
+                    .token(token)
+                    .build()?,
+                dht_addr,
+            )
+            .await?;
+
+        let compact = dht.compact_peers(&info_hash, 10);
+        assert_eq!(compact.len(), 6);
+        let ip = Ipv4Addr::new(compact[0], compact[1], compact[2], compact[3]);
+        let port = u16::from_be_bytes([compact[4], compact[5]]);
+        assert_eq!(
+            SocketAddr::new(IpAddr::V4(ip), port),
+            "10.0.0.2:1234".parse().unwrap()
+        );
+
+        // No more than `max` peers are returned
+        dht.state
+            .lock()
+            .unwrap()
+            .peer_storage
+            .announce_peer(info_hash, "10.0.0.3:1234".parse().unwrap());
+        assert_eq!(dht.compact_peers(&info_hash, 10).len(), 12);
+        assert_eq!(dht.compact_peers(&info_hash, 1).len(), 6);
+        assert!(dht
+            .compact_peers(&Id::from_random(&mut thread_rng()), 10)
+            .is_empty());
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_peers_by_family() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (dht, mut shutdown_tx, shutdown_rx) =
+            make_mock_dht(&network, "10.0.0.1:6881".parse().unwrap());
+        let info_hash = Id::from_random(&mut thread_rng());
+        let peer4: SocketAddr = "10.0.0.2:1234".parse().unwrap();
+        let peer6: SocketAddr = "[fd00::2]:1234".parse().unwrap();
+        {
+            let mut state = dht.state.lock().unwrap();
+            state.peer_storage.announce_peer(info_hash, peer6);
+            state.peer_storage.announce_peer(info_hash, peer4);
+        }
+
+        // Each family has a list of its own, so that they can be split up
+        assert_eq!(
+            dht.compact_peers(&info_hash, 10),
+            packets::sockaddr_to_bytes(&peer4)
+        );
+        assert_eq!(
+            dht.compact_peers6(&info_hash, 10),
+            packets::sockaddr_to_bytes(&peer6)
+        );
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_prefix_update() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+        sleep(Duration::from_millis(100)).await;
+
+        // Once the event loop is running, start throttling each /24 as one source
+        let mut settings = dht.get_settings();
+        settings.throttle_ipv4_prefix_len = 24;
+        dht.update_settings(settings)?;
+        let mut answered = 0;
+        for i in 0..12 {
+            let mut requester = network.endpoint(SocketAddr::new([10, 0, 1, i].into(), 6881));
+            let ping = MessageBuilder::new_ping_request()
+                .sender_id(Id::from_random(&mut thread_rng()))
+                .build()
+                .unwrap();
+            match requester.send_and_receive(ping, dht_addr).await {
+                Ok(_) => answered += 1,
+                Err(RustyDHTError::TimeoutError(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        // The /24 gets the 10 packets that one address would get, no more and no fewer
+        assert_eq!(answered, 10);
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reflection_limits() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let mut settings = dht.get_settings();
+        settings.max_unsolicited_response_bytes_per_min = Some(1000);
+        // Keep our id (and routing table) while timeouts move the clock forward
+        settings.enforce_id_ip_validity = false;
+        dht.update_settings(settings.clone())?;
+        for i in 1..=8 {
+            dht.buckets.write().unwrap().add_or_update(
+                Node::new(
+                    Id::from_random(&mut thread_rng()),
+                    SocketAddr::new([127, 0, 0, i].into(), 6881),
+                ),
+                true,
+            );
+        }
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+        let find_node = || {
+            MessageBuilder::new_find_node_request()
+                .sender_id(Id::from_random(&mut thread_rng()))
+                .target(Id::from_random(&mut thread_rng()))
+                .read_only(true)
+                .build()
+                .unwrap()
+        };
+        let ping = || {
+            MessageBuilder::new_ping_request()
+                .sender_id(Id::from_random(&mut thread_rng()))
+                .read_only(true)
+                .build()
+                .unwrap()
+        };
+
+        // A source that never answered us stops getting replies after its byte allowance
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let mut answered = 0;
+        let mut reply_bytes = 0;
+        for _ in 0..8 {
+            match requester.send_and_receive(find_node(), dht_addr).await {
+                Ok(reply) => {
+                    answered += 1;
+                    reply_bytes += reply.to_bytes()?.len();
+                }
+                Err(RustyDHTError::TimeoutError(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        assert!(answered > 1 && answered < 8, "{} answered", answered);
+        assert!(reply_bytes <= 1000);
+
+        // Once anything at its IP answers one of our requests, it's no longer limited
+        let responder = spawn_nodes_responder(&network, "10.0.0.2:7000".parse().unwrap(), vec![]);
+        dht.send_request(
+            find_node(),
+            "10.0.0.2:7000".parse().unwrap(),
+            None,
+            Some(Duration::from_secs(5)),
+        )
+        .await?;
+        responder.await.unwrap();
+        requester.send_and_receive(find_node(), dht_addr).await?;
+
+        // Others are still answered, but not with replies far bigger than their requests
+        let mut other = network.endpoint("10.0.0.3:6881".parse().unwrap());
+        settings.max_response_amplification = Some(2);
+        dht.update_settings(settings)?;
+        other.send_and_receive(ping(), dht_addr).await?;
+        assert!(matches!(
+            other.send_and_receive(find_node(), dht_addr).await,
+            Err(RustyDHTError::TimeoutError(_))
+        ));
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_router_seeds() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let routers = vec!["127.0.0.2:6881".to_string(), "127.0.0.3:6881".to_string()];
+        let searcher = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, "127.0.0.10:6881".parse().unwrap())
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(routers.clone())
+                        .build()
+                        .unwrap(),
+                )
+                .build(shutdown_rx.clone())?,
+        );
+
+        // Before anything has been sent, the routers are there to start lookups from, but
+        // not in the routing table
+        assert_eq!(searcher.router_seeds(), routers);
+        assert_eq!(searcher.buckets.read().unwrap().count(), (0, 0));
+
+        // Nobody answers the first round of router pings
+        let searcher_clone = searcher.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                searcher_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+        sleep(ROUTER_PING_TIMEOUT + Duration::from_secs(1)).await;
+        assert!(searcher.get_nodes().is_empty());
+
+        // Then one of the routers comes up, and a lookup can start from it
+        let router = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, "127.0.0.2:6881".parse().unwrap())
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())?,
+        );
+        let router_clone = router.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                router_clone.run_event_loop().await.unwrap();
+            },
+            "Test router",
+            None,
+        );
+        let found = crate::dht::operations::find_node(
+            &searcher,
+            Id::from_random(&mut thread_rng()),
+            Duration::from_secs(10),
+        )
+        .await?;
+        assert_eq!(
+            found,
+            vec![Node::new(
+                router.get_id(),
+                "127.0.0.2:6881".parse().unwrap()
+            )]
+        );
+
+        // Routers that answer a ping stop being seeds. The one that never answered isn't
+        // treated as a node.
+        searcher.ping_routers().await?;
+        assert_eq!(searcher.router_seeds(), vec!["127.0.0.3:6881".to_string()]);
+        let addrs: Vec<SocketAddr> = searcher
+            .get_nodes()
+            .iter()
+            .map(|wrapper| wrapper.node.address)
+            .collect();
+        assert_eq!(addrs, vec!["127.0.0.2:6881".parse().unwrap()]);
+
+        drop(searcher);
+        drop(router);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_get_peers_from_router_seeds() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let router_addr: SocketAddr = "127.0.0.2:6881".parse().unwrap();
+        let searcher = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, "127.0.0.10:6881".parse().unwrap())
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![router_addr.to_string()])
+                        .build()
+                        .unwrap(),
+                )
+                .build(shutdown_rx.clone())?,
+        );
+        let searcher_clone = searcher.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                searcher_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+
+        // The router answers lookups (but not pings), with nodes that never answer. Few
+        // enough that the router is always among the nearest nodes that get_peers asks.
+        let nodes: Vec<Node> = (20..27)
+            .map(|i| {
+                Node::new(
+                    Id::from_random(&mut thread_rng()),
+                    SocketAddr::new([127, 0, 0, i].into(), 6881),
+                )
+            })
+            .collect();
+        let router_network = network.clone();
+        let mut router_rx = network.bind(router_addr);
+        let router_id = Id::from_random(&mut thread_rng());
+        tokio::spawn(async move {
+            while let Some((bytes, from)) = router_rx.recv().await {
+                let req = packets::Message::from_bytes(&bytes).unwrap();
+                let builder = match req.message_type {
+                    packets::MessageType::Request(packets::RequestSpecific::GetPeersRequest(_)) => {
+                        MessageBuilder::new_get_peers_response().token(vec![1, 2, 3])
+                    }
+                    packets::MessageType::Request(packets::RequestSpecific::FindNodeRequest(_)) => {
+                        MessageBuilder::new_find_node_response()
+                    }
+                    _ => continue,
+                };
+                let reply = builder
+                    .sender_id(router_id)
+                    .transaction_id(req.transaction_id)
+                    .nodes(nodes.clone())
+                    .build()
+                    .unwrap();
+                router_network.send(router_addr, from, reply.to_bytes().unwrap());
+            }
+        });
+
+        let result = crate::dht::operations::get_peers(
+            &searcher,
+            Id::from_random(&mut thread_rng()),
+            Duration::from_secs(20),
+        )
+        .await?;
+        assert!(result
+            .responders()
+            .into_iter()
+            .any(|responder| responder.node() == Node::new(router_id, router_addr)));
+
+        drop(searcher);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+    #[tokio::test(start_paused = true)]
+    async fn test_replies_with_router_nodes() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht_addr: SocketAddr = "127.0.0.10:6881".parse().unwrap();
+        let router_addr: SocketAddr = "127.0.0.2:6881".parse().unwrap();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, dht_addr)
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![router_addr.to_string()])
+                        .build()
+                        .unwrap(),
+                )
+                .build(shutdown_rx.clone())?,
+        );
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+
+        // The router answers find_node with nodes that never answer us, so they're never
+        // verified
+        let nodes: Vec<Node> = (20..28)
+            .map(|i| {
+                Node::new(
+                    Id::from_random(&mut thread_rng()),
+                    SocketAddr::new([127, 0, 0, i].into(), 6881),
+                )
+            })
+            .collect();
+        let router_network = network.clone();
+        let mut router_rx = network.bind(router_addr);
+        let router_id = Id::from_random(&mut thread_rng());
+        let router_nodes = nodes.clone();
+        tokio::spawn(async move {
+            while let Some((bytes, from)) = router_rx.recv().await {
+                let req = packets::Message::from_bytes(&bytes).unwrap();
+                if let packets::MessageType::Request(packets::RequestSpecific::FindNodeRequest(_)) =
+                    req.message_type
+                {
+                    let reply = MessageBuilder::new_find_node_response()
+                        .sender_id(router_id)
+                        .transaction_id(req.transaction_id)
+                        .nodes(router_nodes.clone())
+                        .build()
+                        .unwrap();
+                    router_network.send(router_addr, from, reply.to_bytes().unwrap());
+                }
+            }
+        });
+        crate::dht::operations::find_node(
+            &dht,
+            Id::from_random(&mut thread_rng()),
+            Duration::from_secs(5),
+        )
+        .await?;
+
+        // With only the router verified, the nodes it told us about are passed along too
+        let mut requester = network.endpoint("127.0.0.3:6881".parse().unwrap());
+        let find_node = MessageBuilder::new_find_node_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .target(Id::from_random(&mut thread_rng()))
+            .build()?;
+        let res = requester.send_and_receive(find_node, dht_addr).await?;
+        match res.message_type {
+            packets::MessageType::Response(packets::ResponseSpecific::FindNodeResponse(args)) => {
+                assert_eq!(args.nodes.len(), MAX_REPLY_NODES);
+                assert!(args.nodes.contains(&Node::new(router_id, router_addr)));
+                assert_eq!(
+                    args.nodes
+                        .iter()
+                        .filter(|node| nodes.contains(node))
+                        .count(),
+                    MAX_REPLY_NODES - 1
+                );
+            }
+            other => panic!("Unexpected reply {:?}", other),
+        }
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_authorizer() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let denied_hash = Id::from_random(&mut thread_rng());
+        let allowed_hash = Id::from_random(&mut thread_rng());
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, dht_addr)
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .announce_authorizer(move |_, args| args.info_hash != denied_hash)
+                .build(shutdown_rx.clone())?,
+        );
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx,
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let requester_id = Id::from_random(&mut thread_rng());
+        let get_peers = MessageBuilder::new_get_peers_request()
+            .sender_id(requester_id)
+            .target(denied_hash)
+            .build()?;
+        let token = match requester
+            .send_and_receive(get_peers, dht_addr)
+            .await?
+            .message_type
+        {
+            packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(args)) => {
+                args.token
+            }
+            other => panic!("Unexpected response {:?}", other),
+        };
+        let announce = |info_hash: Id| {
+            MessageBuilder::new_announce_peer_request()
+                .sender_id(requester_id)
+                .target(info_hash)
+                .port(7000)
+                .token(token.clone())
+                .build()
+                .unwrap()
+        };
+
+        // The authorizer turns down one info_hash with an error...
+        assert!(matches!(
+            requester
+                .send_and_receive(announce(denied_hash), dht_addr)
+                .await?
+                .message_type,
+            packets::MessageType::Error(_)
+        ));
+
+        // ...while announces for others are stored as usual
+        assert!(matches!(
+            requester
+                .send_and_receive(announce(allowed_hash), dht_addr)
+                .await?
+                .message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::PingResponse(_))
+        ));
+        let stored: Vec<Id> = dht
+            .get_info_hashes(None)
+            .into_iter()
+            .map(|(info_hash, _)| info_hash)
+            .collect();
+        assert_eq!(stored, vec![allowed_hash]);
+
+        drop(dht);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pin_info_hash() {
+        let network = MockNetwork::new();
+        let (dht, mut shutdown_tx, shutdown_rx) =
+            make_mock_dht(&network, "10.0.0.1:6881".parse().unwrap());
+        let mut settings = dht.get_settings();
+        settings.max_torrents = 2;
+        dht.update_settings(settings).unwrap();
+
+        let seeded_hash = Id::from_random(&mut thread_rng());
+        let crawled_hash = Id::from_random(&mut thread_rng());
+        dht.pin_info_hash(seeded_hash);
+        let peer: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        {
+            let mut state = dht.state.lock().unwrap();
+            state.peer_storage.announce_peer(seeded_hash, peer);
+            state.peer_storage.announce_peer(crawled_hash, peer);
+            for _ in 0..2 {
+                state
+                    .peer_storage
+                    .announce_peer(Id::from_random(&mut thread_rng()), peer);
+            }
+        }
+        let stored: Vec<Id> = dht
+            .get_info_hashes(None)
+            .into_iter()
+            .map(|(info_hash, _)| info_hash)
+            .collect();
+        assert_eq!(stored.len(), 3);
+        assert!(stored.contains(&seeded_hash));
+        assert!(!stored.contains(&crawled_hash));
+
+        assert!(dht.unpin_info_hash(&seeded_hash));
+        assert!(!dht.unpin_info_hash(&seeded_hash));
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_preallocated_capacity() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, dht_addr)
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![])
+                        .pinned_info_hashes_capacity(10000)
+                        .routing_table_buckets_capacity(160)
+                        .build()
+                        .unwrap(),
+                )
+                .build(shutdown_rx.clone())
+                .unwrap(),
+        );
+        assert!(dht.state.lock().unwrap().peer_storage.pinned_capacity() >= 10000);
+        assert!(matches!(
+            dht.buckets.read().unwrap().buckets_capacity(),
+            Some(capacity) if capacity >= 160
+        ));
+
+        // Storage works as usual
+        let info_hash = Id::from_random(&mut thread_rng());
+        dht.pin_info_hash(info_hash);
+        let peer: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        dht.state
+            .lock()
+            .unwrap()
+            .peer_storage
+            .announce_peer(info_hash, peer);
+        let stored = dht.get_info_hashes(None);
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].0, info_hash);
+
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+        let ping_request = MessageBuilder::new_ping_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .build()?;
+        let res = requester
+            .send_and_receive(ping_request.clone(), dht_addr)
+            .await?;
+        assert_eq!(res.transaction_id, ping_request.transaction_id);
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_info_hash_ttl() {
+        let network = MockNetwork::new();
+        let (dht, mut shutdown_tx, shutdown_rx) =
+            make_mock_dht(&network, "10.0.0.1:6881".parse().unwrap());
+        let live_hash = Id::from_random(&mut thread_rng());
+        let default_hash = Id::from_random(&mut thread_rng());
+        let peer: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        assert!(!dht.set_info_hash_ttl(&live_hash, Some(Duration::from_millis(50))));
+        {
+            let mut state = dht.state.lock().unwrap();
+            state.peer_storage.announce_peer_with_ttl(
+                live_hash,
+                peer,
+                Some(Duration::from_millis(50)),
+            );
+            state.peer_storage.announce_peer(default_hash, peer);
+        }
+        assert_eq!(dht.compact_peers(&live_hash, 10).len(), 6);
+        assert_eq!(dht.compact_peers(&default_hash, 10).len(), 6);
+
+        sleep(Duration::from_millis(100)).await;
+        assert!(dht.compact_peers(&live_hash, 10).is_empty());
+        assert_eq!(dht.compact_peers(&default_hash, 10).len(), 6);
+
+        // Going back to the default freshness window brings the peer back
+        assert!(dht.set_info_hash_ttl(&live_hash, None));
+        assert_eq!(dht.compact_peers(&live_hash, 10).len(), 6);
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_observe() {
+        let network = MockNetwork::new();
+        let (dht, mut shutdown_tx, shutdown_rx) =
+            make_mock_dht(&network, "10.0.0.1:6881".parse().unwrap());
+        let mut observer = dht.observe();
+        let event = |i: usize| DHTEvent {
+            event_type: DHTEventType::TokenValidationFailed(TokenValidationFailedEvent {
+                requester: SocketAddr::new([10, 0, 0, 2].into(), i as u16),
+                info_hash: Id::from_random(&mut thread_rng()),
+            }),
+        };
+        let total = OBSERVER_BUFFER_SIZE + 100;
+        let ports_sent: Vec<u16> = (0..total).map(|i| i as u16).collect();
+        for i in 0..total {
+            dht.send_event_to_subscribers(event(i));
+        }
+
+        // The slow observer missed the earliest events, and gets the most recent ones
+        let mut ports_seen = Vec::new();
+        while let Some(event) = observer.try_recv() {
+            if let DHTEventType::TokenValidationFailed(failed) = event.event_type {
+                ports_seen.push(failed.requester.port());
+            }
+        }
+        assert_eq!(observer.missed(), 100);
+        assert_eq!(ports_seen, ports_sent[100..].to_vec());
+
+        // Once the DHT is gone, so are the events
+        drop(dht);
+        assert_eq!(observer.recv().await, None);
+
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_info_hash_summary() {
+        let network = MockNetwork::new();
+        let (dht, mut shutdown_tx, shutdown_rx) =
+            make_mock_dht(&network, "10.0.0.1:6881".parse().unwrap());
+        {
+            let mut state = dht.state.lock().unwrap();
+            for i in 1..=3 {
+                let info_hash = Id::from_random(&mut thread_rng());
+                for port in 0..i {
+                    state.peer_storage.announce_peer(
+                        info_hash,
+                        SocketAddr::new([1, 2, 3, 4].into(), 6000 + port),
+                    );
+                }
+            }
+        }
+
+        let summary: HashMap<Id, usize> = dht.info_hash_summary(None).into_iter().collect();
+        let full: HashMap<Id, usize> = dht
+            .get_info_hashes(None)
+            .into_iter()
+            .map(|(info_hash, peers)| (info_hash, peers.len()))
+            .collect();
+        assert_eq!(summary.len(), 3);
+        assert_eq!(summary, full);
+
+        // Old peers can be filtered out the same way
+        assert!(dht.info_hash_summary(Some(Instant::now())).is_empty());
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_without_usable_port() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let requester_addr: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let mut requester = network.endpoint(requester_addr);
+        let requester_id = Id::from_random(&mut thread_rng());
+        let info_hash = Id::from_random(&mut thread_rng());
+        let token = requester
+            .send_and_receive(
+                MessageBuilder::new_get_peers_request()
+                    .sender_id(requester_id)
+                    .target(info_hash)
+                    .build()?,
+                dht_addr,
+            )
+            .await?
+            .announce_token()
+            .unwrap()
+            .to_vec();
+
+        // Hand-encoded, since MessageBuilder won't build these
+        let raw_announce = |port: Option<&str>, implied_port: Option<&str>| {
+            let mut bytes = b"d1:ad2:id20:".to_vec();
+            bytes.extend_from_slice(&requester_id.to_vec());
+            if let Some(implied_port) = implied_port {
+                bytes.extend_from_slice(format!("12:implied_porti{}e", implied_port).as_bytes());
+            }
+            bytes.extend_from_slice(b"9:info_hash20:");
+            bytes.extend_from_slice(&info_hash.to_vec());
+            if let Some(port) = port {
+                bytes.extend_from_slice(format!("4:porti{}e", port).as_bytes());
+            }
+            bytes.extend_from_slice(format!("5:token{}:", token.len()).as_bytes());
+            bytes.extend_from_slice(&token);
+            bytes.extend_from_slice(b"e1:q13:announce_peer1:t2:aa1:y1:qe");
+            bytes
+        };
+        let bad_announces = vec![
+            raw_announce(None, None),
+            raw_announce(None, Some("0")),
+            raw_announce(Some("0"), None),
+            raw_announce(Some("0"), Some("0")),
+            raw_announce(Some("-1"), None),
+            raw_announce(Some("65536"), None),
+            raw_announce(Some("99999999999999999999"), Some("0")),
+            raw_announce(Some("x"), None),
+        ];
+        for bytes in bad_announces {
+            network.send(requester_addr, dht_addr, bytes);
+        }
+
+        // Once the DHT answers a ping, it has seen the announces before it
+        requester
+            .send_and_receive(
+                MessageBuilder::new_ping_request()
+                    .sender_id(requester_id)
+                    .build()?,
+                dht_addr,
+            )
+            .await?;
+        assert!(dht.get_info_hashes(None).is_empty());
+
+        // Port 0 is fine when the peer's source port is to be used
+        network.send(requester_addr, dht_addr, raw_announce(Some("0"), Some("1")));
+        requester
+            .send_and_receive(
+                MessageBuilder::new_ping_request()
+                    .sender_id(requester_id)
+                    .build()?,
+                dht_addr,
+            )
+            .await?;
+        let stored = dht.get_info_hashes(None);
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].1[0].addr, requester_addr);
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_buddy_ping_concurrency() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, "10.0.0.1:6881".parse().unwrap())
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![])
+                        .ping_check_interval_secs(1)
+                        .max_concurrent_buddy_pings(2)
+                        .build()
+                        .unwrap(),
+                )
+                .build(shutdown_rx.clone())?,
+        );
+
+        // Nodes that never answer, so each ping stays outstanding until it times out
+        let mut silent_nodes = Vec::new();
+        for i in 2..8 {
+            let addr = SocketAddr::new([10, 0, 0, i].into(), 6881);
+            silent_nodes.push(network.bind(addr));
+            dht.buckets
+                .write()
+                .unwrap()
+                .add_or_update(Node::new(Id::from_random(&mut thread_rng()), addr), false);
+        }
+        let count_pings = |silent_nodes: &mut Vec<mpsc::UnboundedReceiver<_>>| {
+            let mut count = 0;
+            for rx in silent_nodes.iter_mut() {
+                while rx.try_recv().is_ok() {
+                    count += 1;
+                }
+            }
+            count
+        };
+
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+
+        // The first round only sends as many pings as it's allowed to have outstanding...
+        sleep(Duration::from_millis(1500)).await;
+        assert_eq!(count_pings(&mut silent_nodes), 2);
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(count_pings(&mut silent_nodes), 0);
+
+        // ...and sends more as the earlier ones give up (after 5 seconds)
+        sleep(Duration::from_secs(3)).await;
+        assert_eq!(count_pings(&mut silent_nodes), 2);
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_compute_token() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let requester_addr: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let mut requester = network.endpoint(requester_addr);
+        let requester_id = Id::from_random(&mut thread_rng());
+        let info_hash = Id::from_random(&mut thread_rng());
+        let token = dht.compute_token(&requester_addr);
+        assert_ne!(token, dht.compute_token(&"10.0.0.3:6881".parse().unwrap()));
+
+        // It's the token that get_peers hands out...
+        let reply = requester
+            .send_and_receive(
+                MessageBuilder::new_get_peers_request()
+                    .sender_id(requester_id)
+                    .target(info_hash)
+                    .build()?,
+                dht_addr,
+            )
+            .await?;
+        assert_eq!(reply.announce_token(), Some(&token[..]));
+
+        // ...and that announce_peer accepts
+        let reply = requester
+            .send_and_receive(
+                MessageBuilder::new_announce_peer_request()
+                    .sender_id(requester_id)
+                    .target(info_hash)
+                    .port(7000)
+                    .token(token)
+                    .build()?,
+                dht_addr,
+            )
+            .await?;
+        assert!(matches!(
+            reply.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::PingResponse(_))
+        ));
+        assert_eq!(dht.get_info_hashes(None).len(), 1);
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_own_id_collision() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let mut settings = dht.get_settings();
+        settings.enforce_id_ip_validity = false;
+        dht.update_settings(settings.clone())?;
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+        let ping = |sender_id: Id| {
+            MessageBuilder::new_ping_request()
+                .sender_id(sender_id)
+                .build()
+                .unwrap()
+        };
+
+        // By default, a node using our Id is answered, but kept out of the routing table
+        let mut impostor = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        impostor
+            .send_and_receive(ping(dht.get_id()), dht_addr)
+            .await?;
+        assert_eq!(dht.buckets.read().unwrap().count(), (0, 0));
+
+        // Requests with our Id aren't enough to get an IP banned, since they could be spoofed
+        settings.own_id_collision_policy = OwnIdCollisionPolicy::Ban;
+        dht.update_settings(settings)?;
+        let mut impostor = network.endpoint("10.0.0.3:6881".parse().unwrap());
+        impostor
+            .send_and_receive(ping(dht.get_id()), dht_addr)
+            .await?;
+        assert!(dht.state.lock().unwrap().banned_ips.is_empty());
+
+        // A node that answers our request with our Id is ignored from then on
+        let our_id = dht.get_id();
+        let impostor_network = network.clone();
+        let mut impostor_rx = network.bind("10.0.0.3:7000".parse().unwrap());
+        tokio::spawn(async move {
+            let (bytes, from) = impostor_rx.recv().await.unwrap();
+            let req = packets::Message::from_bytes(&bytes).unwrap();
+            let reply = MessageBuilder::new_ping_response()
+                .sender_id(our_id)
+                .transaction_id(req.transaction_id)
+                .build()
+                .unwrap();
+            impostor_network.send(
+                "10.0.0.3:7000".parse().unwrap(),
+                from,
+                reply.to_bytes().unwrap(),
+            );
+        });
+        dht.send_request(
+            ping(Id::from_random(&mut thread_rng())),
+            "10.0.0.3:7000".parse().unwrap(),
+            None,
+            Some(Duration::from_secs(5)),
+        )
+        .await?;
+        assert!(matches!(
+            impostor
+                .send_and_receive(ping(Id::from_random(&mut thread_rng())), dht_addr)
+                .await,
+            Err(RustyDHTError::TimeoutError(_))
+        ));
+        assert_eq!(dht.buckets.read().unwrap().count(), (0, 0));
+
+        // Others are unaffected
+        let mut honest = network.endpoint("10.0.0.4:6881".parse().unwrap());
+        honest
+            .send_and_receive(ping(Id::from_random(&mut thread_rng())), dht_addr)
+            .await?;
+        assert_eq!(dht.buckets.read().unwrap().count(), (1, 0));
+
+        // Every packet with our Id was counted, though only the first was logged
+        assert_eq!(dht.state.lock().unwrap().own_id_collisions.total(), 3);
+
+        // The banned node is answered again once it's unbanned
+        let impostor_ip = "10.0.0.3".parse().unwrap();
+        assert!(dht.unban_ip(&impostor_ip));
+        assert!(!dht.unban_ip(&impostor_ip));
+        impostor
+            .send_and_receive(ping(Id::from_random(&mut thread_rng())), dht_addr)
+            .await?;
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_packet_tap() -> Result<(), RustyDHTError> {
+        /// Lets the test read what the DHT wrote to the tap
+        #[derive(Clone)]
+        struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for SharedBuffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let requester_addr: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, dht_addr)
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .packet_tap(buffer.clone())
+                .build(shutdown_rx.clone())?,
+        );
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let ping = MessageBuilder::new_ping_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .read_only(true)
+            .build()?;
+        let mut requester = network.endpoint(requester_addr);
+        let reply = requester.send_and_receive(ping.clone(), dht_addr).await?;
+
+        dht.flush_packet_tap();
+        let bytes = buffer.0.lock().unwrap().clone();
+        let mut reader = bytes.as_slice();
+        let mut records = Vec::new();
+        while let Some(record) = crate::dht::PacketRecord::read_from(&mut reader)? {
+            records.push(record);
+        }
+        let records: Vec<_> = records
+            .into_iter()
+            .filter(|record| record.addr == requester_addr)
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, crate::dht::PacketDirection::Received);
+        assert_eq!(records[0].bytes, ping.to_bytes()?);
+        assert_eq!(records[1].direction, crate::dht::PacketDirection::Sent);
+        assert_eq!(packets::Message::from_bytes(&records[1].bytes)?, reply);
+        assert!(records[0].timestamp <= records[1].timestamp);
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_external_port() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, "127.0.0.10:6881".parse().unwrap())
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .external_port_votes(3, 10)
+                .build(shutdown_rx.clone())?,
+        );
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+        assert_eq!(dht.get_external_port(), None);
+        assert_eq!(dht.advertised_port(None)?, None);
+
+        // Each responder sees our packets coming from a port rewritten by a NAT. It takes
+        // three of them to agree, and answering more than once doesn't count for more.
+        for i in 11..=13 {
+            assert_eq!(dht.get_external_port(), None);
+            let addr = SocketAddr::new([127, 0, 0, i].into(), 6881);
+            let mut rx = network.bind(addr);
+            let responder_network = network.clone();
+            tokio::spawn(async move {
+                while let Some((bytes, from)) = rx.recv().await {
+                    let req = packets::Message::from_bytes(&bytes).unwrap();
+                    let reply = MessageBuilder::new_ping_response()
+                        .sender_id(Id::from_ip(&addr.ip()))
+                        .transaction_id(req.transaction_id)
+                        .requester_ip("1.2.3.4:40000".parse().unwrap())
+                        .build()
+                        .unwrap();
+                    responder_network.send(addr, from, reply.to_bytes().unwrap());
+                }
+            });
+            for _ in 0..3 {
+                dht.send_request(
+                    MessageBuilder::new_ping_request()
+                        .sender_id(dht.get_id())
+                        .build()?,
+                    addr,
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+            }
+        }
+        assert_eq!(dht.get_external_port(), Some(40000));
+
+        // An implied_port announce announces the external port instead, but explicit ports
+        // are left alone
+        assert_eq!(dht.advertised_port(None)?, Some(40000));
+        assert_eq!(dht.advertised_port(Some(6881))?, Some(6881));
+        assert_eq!(dht.advertised_port(Some(7000))?, Some(7000));
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_freshness_before_clock_start() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = DHTBuilder::new()
+            .mock_network(&network, "10.0.0.1:6881".parse().unwrap())
+            .settings(
+                DHTSettingsBuilder::new()
+                    .routers(vec![])
+                    .get_peers_freshness_secs(u64::MAX)
+                    .build()
+                    .unwrap(),
+            )
+            .build(shutdown_rx.clone())?;
+
+        // A freshness window reaching back before the monotonic clock started covers
+        // every peer we have
+        let info_hash = Id::from_random(&mut thread_rng());
+        assert!(dht.announce_self_locally(info_hash, Some(7000))?);
+        assert_eq!(dht.compact_peers(&info_hash, 10).len(), 6);
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_prune_while_clock_stands_still() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, "10.0.0.1:6881".parse().unwrap())
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![])
+                        .reverify_grace_period_secs(u64::MAX)
+                        .verify_grace_period_secs(0)
+                        .build()
+                        .unwrap(),
+                )
+                .build(shutdown_rx.clone())?,
+        );
+        let verified = Node::new(
+            Id::from_random(&mut thread_rng()),
+            "10.0.0.2:6881".parse().unwrap(),
+        );
+        let quarantined = Node::new(
+            Id::from_random(&mut thread_rng()),
+            "10.0.0.3:6881".parse().unwrap(),
+        );
+        {
+            let mut buckets = dht.buckets.write().unwrap();
+            buckets.add_or_update(verified.clone(), true);
+            buckets.add_or_update(quarantined, false);
+        }
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+
+        // The buckets are pruned while the monotonic clock has hardly moved. The reverify
+        // grace period reaches back before it started, so the verified node is kept, but
+        // that doesn't stop the quarantined one from being pruned for the other period.
+        sleep(Duration::from_secs(
+            dht.get_settings().ping_check_interval_secs + 1,
+        ))
+        .await;
+        assert_eq!(
+            dht.get_nodes()
+                .into_iter()
+                .map(|wrapper| wrapper.node)
+                .collect::<Vec<Node>>(),
+            vec![verified]
+        );
+        assert_eq!(dht.buckets.read().unwrap().count(), (0, 1));
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_length() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = DHTBuilder::new()
+            .mock_network(&network, dht_addr)
+            .settings(
+                DHTSettingsBuilder::new()
+                    .routers(vec![])
+                    .token_secret_size(2)
+                    .token_length(40)
+                    .build()
+                    .unwrap(),
+            )
+            .build(shutdown_rx.clone())?;
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx,
+            async move {
+                dht.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let requester_id = Id::from_random(&mut thread_rng());
+        let info_hash = Id::from_random(&mut thread_rng());
+        let get_peers = MessageBuilder::new_get_peers_request()
+            .sender_id(requester_id)
+            .target(info_hash)
+            .build()?;
+        let token = match requester
+            .send_and_receive(get_peers, dht_addr)
+            .await?
+            .message_type
+        {
+            packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(args)) => {
+                args.token
+            }
+            other => panic!("Unexpected response {:?}", other),
+        };
+        assert_eq!(token.len(), 40);
+
+        let announce = |token: Vec<u8>| {
+            MessageBuilder::new_announce_peer_request()
+                .sender_id(requester_id)
+                .target(info_hash)
+                .port(7000)
+                .token(token)
+                .build()
+                .unwrap()
+        };
+
+        // Only the full token is accepted
+        assert!(matches!(
+            requester
+                .send_and_receive(announce(token[..4].to_vec()), dht_addr)
+                .await,
+            Err(RustyDHTError::TimeoutError(_))
+        ));
+        assert!(matches!(
+            requester
+                .send_and_receive(announce(token), dht_addr)
+                .await?
+                .message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::PingResponse(_))
+        ));
+
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_calculate_token() {
+        let addr: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let token = calculate_token(&addr, b"secret", 100);
+        assert_eq!(token.len(), 100);
+        assert_eq!(calculate_token(&addr, b"secret", 4), token[..4].to_vec());
+        assert_ne!(calculate_token(&addr, b"secret2", 100), token);
+        assert_ne!(
+            calculate_token(&"1.2.3.5:6881".parse().unwrap(), b"secret", 100),
+            token
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_next_event() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        // A ping, then a get_peers. Only the get_peers matches.
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let get_peers = MessageBuilder::new_get_peers_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .target(Id::from_random(&mut thread_rng()))
+            .build()?;
+        let expected_get_peers = get_peers.clone();
+        let (event, _) = tokio::join!(
+            dht.next_event(
+                DHTEventMask::GET_PEERS_REQUEST | DHTEventMask::ANNOUNCE_PEER_REQUEST,
+                Duration::from_secs(10)
+            ),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                let ping = MessageBuilder::new_ping_request()
+                    .sender_id(Id::from_random(&mut thread_rng()))
+                    .build()
+                    .unwrap();
+                requester.send_and_receive(ping, dht_addr).await.unwrap();
+                requester
+                    .send_and_receive(get_peers, dht_addr)
+                    .await
+                    .unwrap();
+            }
+        );
+        match event?.event_type {
+            DHTEventType::MessageReceived(received) => {
+                assert_eq!(received.message, expected_get_peers)
+            }
+            other => panic!("Unexpected event {:?}", other),
+        }
+
+        assert!(matches!(
+            dht.next_event(DHTEventMask::ALL, Duration::from_secs(1))
+                .await,
+            Err(RustyDHTError::TimeoutError(_))
+        ));
+        assert!(dht.state.lock().unwrap().subscribers.is_empty());
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_announce_is_flagged() -> Result<(), RustyDHTError> {
+        let requester_id = Id::from_random(&mut thread_rng());
+        let info_hash = Id::from_random(&mut thread_rng());
+        let port = 2304;
+        let (dht, mut shutdown_tx, shutdown_rx) = make_test_dht(port).await;
+        let dht = Arc::new(dht);
+        let mut events = dht.subscribe();
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx,
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let token = calculate_token(
+            &"127.0.0.1:0".parse().unwrap(),
+            dht.state.lock().unwrap().token_secret.clone(),
+            4,
+        );
+        let announce = MessageBuilder::new_announce_peer_request()
+            .sender_id(requester_id)
+            .target(info_hash)
+            .implied_port(true)
+            .token(token)
+            .build()?
+            .to_bytes()?;
+
+        // Send the same announce twice from the same socket
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut recv_buf = [0; 2048];
+        for _ in 0..2 {
+            sock.send_to(&announce, format!("127.0.0.1:{}", port))
+                .await
+                .unwrap();
+            let num_read = sock.recv_from(&mut recv_buf).await.unwrap().0;
+            let reply = packets::Message::from_bytes(&recv_buf[..num_read])?;
+            assert!(matches!(
+                reply.message_type,
+                packets::MessageType::Response(packets::ResponseSpecific::PingResponse(
+                    packets::PingResponseArguments { .. }
+                ))
+            ));
+        }
+
+        let first = events.recv().await.unwrap();
+        let second = events.recv().await.unwrap();
+        assert!(matches!(
+            first.event_type,
+            DHTEventType::MessageReceived(MessageReceivedEvent {
+                duplicate: false,
+                ..
+            })
+        ));
+        assert!(matches!(
+            second.event_type,
+            DHTEventType::MessageReceived(MessageReceivedEvent {
+                duplicate: true,
+                ..
+            })
+        ));
+
+        let info_hashes = dht.get_info_hashes(None);
+        assert_eq!(info_hashes.len(), 1);
+        assert_eq!(info_hashes[0].1.len(), 1);
+
+        // Shutdown waits for every ShutdownReceiver to drop, including the DHT's
+        drop(dht);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_request_window() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let requester_id = Id::from_random(&mut thread_rng());
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let token = requester
+            .send_and_receive(
+                MessageBuilder::new_get_peers_request()
+                    .sender_id(requester_id)
+                    .target(Id::from_random(&mut thread_rng()))
+                    .build()?,
+                dht_addr,
+            )
+            .await?
+            .announce_token()
+            .unwrap()
+            .to_vec();
+        let transaction_id = vec![0, 1];
+        let announce = |info_hash: Id| {
+            MessageBuilder::new_announce_peer_request()
+                .sender_id(requester_id)
+                .transaction_id(transaction_id.clone())
+                .target(info_hash)
+                .port(1234)
+                .token(token.clone())
+                .build()
+        };
+        let mut events = dht.observe();
+        async fn is_duplicate(
+            requester: &mut MockEndpoint,
+            events: &mut EventObserver,
+            msg: packets::Message,
+            dest: SocketAddr,
+        ) -> Result<bool, RustyDHTError> {
+            requester.send_and_receive(msg, dest).await?;
+            loop {
+                if let DHTEventType::MessageReceived(received) =
+                    events.recv().await.unwrap().event_type
+                {
+                    return Ok(received.duplicate);
+                }
+            }
+        }
+        // Only a retransmission of the same request is a duplicate, not a new request that
+        // reuses the transaction id
+        let first_hash = Id::from_random(&mut thread_rng());
+        let second_hash = Id::from_random(&mut thread_rng());
+        assert!(!is_duplicate(&mut requester, &mut events, announce(first_hash)?, dht_addr).await?);
+        assert!(is_duplicate(&mut requester, &mut events, announce(first_hash)?, dht_addr).await?);
+        assert!(
+            !is_duplicate(
+                &mut requester,
+                &mut events,
+                announce(second_hash)?,
+                dht_addr
+            )
+            .await?
+        );
+        assert_eq!(dht.get_info_hashes(None).len(), 2);
+
+        // Nor is the same request once the window has passed
+        let mut settings = dht.get_settings();
+        settings.duplicate_request_window_secs = 0;
+        dht.update_settings(settings)?;
+        assert!(
+            !is_duplicate(
+                &mut requester,
+                &mut events,
+                announce(second_hash)?,
+                dht_addr
+            )
+            .await?
+        );
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skip_duplicate_announces() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let requester_id = Id::from_random(&mut thread_rng());
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let token = requester
+            .send_and_receive(
+                MessageBuilder::new_get_peers_request()
+                    .sender_id(requester_id)
+                    .target(Id::from_random(&mut thread_rng()))
+                    .build()?,
+                dht_addr,
+            )
+            .await?
+            .announce_token()
+            .unwrap()
+            .to_vec();
+        let info_hash = Id::from_random(&mut thread_rng());
+        let announce = |transaction_id: Vec<u8>| {
+            MessageBuilder::new_announce_peer_request()
+                .sender_id(requester_id)
+                .transaction_id(transaction_id)
+                .target(info_hash)
+                .port(1234)
+                .token(token.clone())
+                .build()
+        };
+        let stored_peers = || {
+            dht.get_info_hashes(None)
+                .into_iter()
+                .find(|(hash, _)| *hash == info_hash)
+                .map(|(_, peers)| peers)
+                .unwrap_or_default()
+        };
+
+        let mut settings = dht.get_settings();
+        settings.skip_duplicate_announces = true;
+        dht.update_settings(settings)?;
+        requester
+            .send_and_receive(announce(vec![0, 1])?, dht_addr)
+            .await?;
+        let stored = stored_peers();
+        assert_eq!(stored.len(), 1);
+
+        // The retransmission is answered, but the stored peer isn't touched
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        requester
+            .send_and_receive(announce(vec![0, 1])?, dht_addr)
+            .await?;
+        let after_duplicate = stored_peers();
+        assert_eq!(after_duplicate.len(), 1);
+        assert_eq!(after_duplicate[0].last_updated, stored[0].last_updated);
+
+        // While a new announce from the same peer refreshes it as usual
+        requester
+            .send_and_receive(announce(vec![0, 2])?, dht_addr)
+            .await?;
+        let after_new = stored_peers();
+        assert_eq!(after_new.len(), 1);
+        assert!(after_new[0].last_updated > stored[0].last_updated);
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_responds_to_sample_infohashes() -> Result<(), RustyDHTError> {
+        let requester_id = Id::from_random(&mut thread_rng());
+        let target = Id::from_random(&mut thread_rng());
+        let request = MessageBuilder::new_sample_infohashes_request()
+            .sender_id(requester_id)
+            .target(target)
+            .build()?;
+
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx,
+            async move {
+                dht.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let res = requester
+            .send_and_receive(request.clone(), dht_addr)
+            .await
+            .unwrap();
+
+        assert_eq!(res.transaction_id, request.transaction_id);
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::SampleInfoHashesResponse(
+                packets::SampleInfoHashesResponseArguments { num: 0, .. }
+            ))
+        ));
+
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_decline_empty_sample_infohashes() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+        let sample = || {
+            MessageBuilder::new_sample_infohashes_request()
+                .sender_id(Id::from_random(&mut thread_rng()))
+                .target(Id::from_random(&mut thread_rng()))
+                .build()
+        };
+
+        // By default, an empty DHT answers with no samples
+        let res = requester.send_and_receive(sample()?, dht_addr).await?;
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::SampleInfoHashesResponse(
+                packets::SampleInfoHashesResponseArguments { num: 0, .. }
+            ))
+        ));
+
+        let mut settings = dht.get_settings();
+        settings.decline_empty_sample_infohashes = true;
+        dht.update_settings(settings)?;
+        let res = requester.send_and_receive(sample()?, dht_addr).await?;
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Error(packets::ErrorSpecific { code: 202, .. })
+        ));
+
+        // Once there's something to sample, it's answered again
+        dht.state.lock().unwrap().peer_storage.announce_peer(
+            Id::from_random(&mut thread_rng()),
+            "10.0.0.3:6881".parse().unwrap(),
+        );
+        let res = requester.send_and_receive(sample()?, dht_addr).await?;
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::SampleInfoHashesResponse(
+                packets::SampleInfoHashesResponseArguments { num: 1, .. }
+            ))
+        ));
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_type_rates() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let requester_addr: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let mut requester = network.endpoint(requester_addr);
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let requester_id = Id::from_random(&mut thread_rng());
+        let target = Id::from_random(&mut thread_rng());
+        let mut requests = Vec::new();
+        for _ in 0..2 {
+            requests.push(
+                MessageBuilder::new_ping_request()
+                    .sender_id(requester_id)
+                    .build()?,
+            );
+        }
+        for _ in 0..3 {
+            requests.push(
+                MessageBuilder::new_get_peers_request()
+                    .sender_id(requester_id)
+                    .target(target)
+                    .build()?,
+            );
+        }
+        requests.push(
+            MessageBuilder::new_sample_infohashes_request()
+                .sender_id(requester_id)
+                .target(target)
+                .build()?,
+        );
+        for request in requests {
+            network.send(requester_addr, dht_addr, request.to_bytes()?);
+        }
+        // Once the last ping is answered, all the requests have been handled
+        requester
+            .send_and_receive(
+                MessageBuilder::new_ping_request()
+                    .sender_id(requester_id)
+                    .build()?,
+                dht_addr,
+            )
+            .await?;
+
+        let rates = dht.query_type_rates();
+        assert_eq!(rates.window, Duration::from_secs(600));
+        assert_eq!(rates.count(QueryType::Ping), 3);
+        assert_eq!(rates.count(QueryType::GetPeers), 3);
+        assert_eq!(rates.count(QueryType::SampleInfoHashes), 1);
+        assert_eq!(rates.count(QueryType::FindNode), 0);
+        assert_eq!(rates.count(QueryType::AnnouncePeer), 0);
+        assert_eq!(rates.total(), 7);
+        assert_eq!(rates.per_minute(QueryType::GetPeers), 0.3);
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_event_loop_pings_routers() {
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let port1 = 2171;
+        let dht1 = Arc::new(
+            DHTBuilder::new()
+                .initial_id(get_dht_id())
+                .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port1))
+                .ip_source(Box::new(StaticIPV4AddrSource::new(Ipv4Addr::new(
+                    1, 2, 3, 4,
+                ))))
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())
+                .unwrap(),
+        );
+
+        // dht2 learns its external IP from the router's response, so its id must already be
+        // valid for it or else it would be replaced (clearing the routing table) right away
+        let dht2 = Arc::new(
+            DHTBuilder::new()
+                .initial_id(Id::from_ip(&IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))))
+                .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+                .ip_source(Box::new(StaticIPV4AddrSource::new(Ipv4Addr::new(
+                    1, 2, 3, 4,
+                ))))
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .router_ping_interval_secs(1)
+                        .routers(vec![format!("127.0.0.1:{}", port1)])
+                        .build()
+                        .unwrap(),
+                )
+                .build(shutdown_rx.clone())
+                .unwrap(),
+        );
+
+        let mut receiver = dht2.subscribe();
+
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht1.run_event_loop().await.unwrap();
+            },
+            "DHT1",
+            None,
+        );
+
+        let dht2_clone = dht2.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx,
+            async move { dht2_clone.run_event_loop().await.unwrap() },
+            "DHT2",
+            None,
+        );
+
+        receiver.recv().await;
+        let (unverified, verified) = dht2.buckets.read().unwrap().count();
+
+        // Must drop dht2 as it contains a ShutdownReceiver channel which will block shutdown
+        drop(dht2);
+
+        shutdown_tx.shutdown().await;
+        assert_eq!(unverified, 0);
+        assert_eq!(verified, 1);
+    }
+
+    #[tokio::test]
+    async fn test_minimal_responses() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        for (addr, minimal_responses) in [("10.0.0.1:6881", false), ("10.0.0.2:6881", true)] {
+            let dht = DHTBuilder::new()
+                .initial_id(get_dht_id())
+                .mock_network(&network, addr.parse().unwrap())
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![])
+                        .minimal_responses(minimal_responses)
+                        .build()
+                        .unwrap(),
+                )
+                .build(shutdown_rx.clone())?;
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+        }
+
+        let requester_addr: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+        let mut requester_rx = network.bind(requester_addr);
+        let find_node = MessageBuilder::new_find_node_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .target(Id::from_random(&mut thread_rng()))
+            .build()?;
+        let mut replies = Vec::new();
+        for dht_addr in ["10.0.0.1:6881", "10.0.0.2:6881"] {
+            network.send(
+                requester_addr,
+                dht_addr.parse().unwrap(),
+                find_node.clone().to_bytes()?,
+            );
+            replies.push(requester_rx.recv().await.unwrap().0);
+        }
+
+        let normal = packets::Message::from_bytes(&replies[0])?;
+        let minimal = packets::Message::from_bytes(&replies[1])?;
+        assert_eq!(normal.requester_ip, Some(requester_addr));
+        assert_eq!(minimal.requester_ip, None);
+        assert_eq!(normal.message_type, minimal.message_type);
+        // The compact "ip" field takes "2:ip6:" plus 6 bytes of address
+        assert_eq!(replies[0].len() - replies[1].len(), 12);
+
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_enforce_id_ip_validity() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let initial_id = Id::from_ip(&IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+        let mut dhts = Vec::new();
+        for (addr, enforce_id_ip_validity) in [("10.0.0.1:6881", true), ("10.0.0.2:6881", false)] {
+            // Our external IP address has changed since the Id was picked
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .initial_id(initial_id)
+                    .mock_network(&network, addr.parse().unwrap())
+                    .ip_source(Box::new(StaticIPV4AddrSource::new(Ipv4Addr::new(
+                        5, 6, 7, 8,
+                    ))))
+                    .settings(
+                        DHTSettingsBuilder::new()
+                            .routers(vec![])
+                            .enforce_id_ip_validity(enforce_id_ip_validity)
+                            .build()
+                            .unwrap(),
+                    )
+                    .build(shutdown_rx.clone())?,
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                None,
+            );
+            dhts.push(dht);
+        }
+
+        sleep(Duration::from_secs(15)).await;
+        assert!(dhts[0]
+            .get_id()
+            .is_valid_for_ip(&IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8))));
+        assert_eq!(dhts[1].get_id(), initial_id);
+
+        // Nodes with Ids that don't match their addresses are taken too
+        let mut requester = network.endpoint("1.1.1.1:6881".parse().unwrap());
+        let requester_id = Id::from_ip(&IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2)));
+        for dht_addr in ["10.0.0.1:6881", "10.0.0.2:6881"] {
+            let ping = MessageBuilder::new_ping_request()
+                .sender_id(requester_id)
+                .build()?;
+            requester
+                .send_and_receive(ping, dht_addr.parse().unwrap())
+                .await?;
+        }
+        let knows_requester = |dht: &DHT| {
+            dht.buckets
+                .read()
+                .unwrap()
+                .get_all_unverified()
+                .iter()
+                .any(|nw| nw.node.id == requester_id)
+        };
+        assert!(!knows_requester(&dhts[0]));
+        assert!(knows_requester(&dhts[1]));
+
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ip4_maintenance_interval() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let make_dht = |addr: &str| {
+            Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, addr.parse().unwrap())
+                    .settings(
+                        DHTSettingsBuilder::new()
+                            .routers(vec![])
+                            .ip4_maintenance_interval_secs(60)
+                            .build()
+                            .unwrap(),
+                    )
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            )
+        };
+        let run = |dht: Arc<DHT>| {
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                None,
+            );
+        };
+        let ip = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+
+        // Votes that arrive behind the DHT's back are only looked at on the configured interval
+        let slow_dht = make_dht("127.0.0.1:6881");
+        for _ in 0..3 {
+            slow_dht
+                .state
+                .lock()
+                .unwrap()
+                .ip4_source
+                .add_vote(Ipv4Addr::new(127, 0, 0, 2), Ipv4Addr::new(1, 2, 3, 4));
+        }
+        run(slow_dht.clone());
+        sleep(Duration::from_secs(30)).await;
+        assert!(!slow_dht.get_id().is_valid_for_ip(&ip));
+        sleep(Duration::from_secs(31)).await;
+        assert!(slow_dht.get_id().is_valid_for_ip(&ip));
+
+        // Votes in responses to our requests are looked at right away
+        let dht = make_dht("127.0.0.10:6881");
+        run(dht.clone());
+        for i in 11..=12 {
+            let addr = SocketAddr::new([127, 0, 0, i].into(), 6881);
+            let mut rx = network.bind(addr);
+            let responder_network = network.clone();
+            tokio::spawn(async move {
+                while let Some((bytes, from)) = rx.recv().await {
+                    let req = packets::Message::from_bytes(&bytes).unwrap();
+                    let reply = MessageBuilder::new_ping_response()
+                        .sender_id(Id::from_ip(&addr.ip()))
+                        .transaction_id(req.transaction_id)
+                        .requester_ip("1.2.3.4:6881".parse().unwrap())
+                        .build()
+                        .unwrap();
+                    responder_network.send(addr, from, reply.to_bytes().unwrap());
+                }
+            });
+            dht.send_request(
+                MessageBuilder::new_ping_request()
+                    .sender_id(dht.get_id())
+                    .build()?,
+                addr,
+                None,
+                Some(Duration::from_secs(5)),
+            )
+            .await?;
+        }
+        sleep(Duration::from_millis(100)).await;
+        assert!(dht.get_id().is_valid_for_ip(&ip));
+
+        drop(slow_dht);
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ip_vote_updated_events() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, "127.0.0.10:6881".parse().unwrap())
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())?,
+        );
+        let mut events = dht.subscribe();
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+
+        // Each responder votes for the same external address
+        for i in 11..=13 {
+            let addr = SocketAddr::new([127, 0, 0, i].into(), 6881);
+            let mut rx = network.bind(addr);
+            let responder_network = network.clone();
+            tokio::spawn(async move {
+                while let Some((bytes, from)) = rx.recv().await {
+                    let req = packets::Message::from_bytes(&bytes).unwrap();
+                    let reply = MessageBuilder::new_ping_response()
+                        .sender_id(Id::from_ip(&addr.ip()))
+                        .transaction_id(req.transaction_id)
+                        .requester_ip("1.2.3.4:6881".parse().unwrap())
+                        .build()
+                        .unwrap();
+                    responder_network.send(addr, from, reply.to_bytes().unwrap());
+                }
+            });
+            dht.send_request(
+                MessageBuilder::new_ping_request()
+                    .sender_id(dht.get_id())
+                    .build()?,
+                addr,
+                None,
+                Some(Duration::from_secs(5)),
+            )
+            .await?;
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        let mut tallies = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let DHTEventType::IpVoteUpdated(tally) = event.event_type {
+                tallies.push(tally);
+            }
+        }
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        assert_eq!(
+            tallies,
+            vec![
+                IpVoteUpdatedEvent {
+                    current_best: None,
+                    votes: 1
+                },
+                IpVoteUpdatedEvent {
+                    current_best: Some(ip),
+                    votes: 2
+                },
+                IpVoteUpdatedEvent {
+                    current_best: Some(ip),
+                    votes: 3
+                },
+            ]
+        );
+        assert!(DHTEventMask::IP_VOTE_UPDATED.matches(&DHTEvent {
+            event_type: DHTEventType::IpVoteUpdated(tallies[0].clone())
+        }));
+
+        drop(events);
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bootstrap_unavailable() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (dht, mut shutdown_tx, shutdown_rx) =
+            make_mock_dht(&network, "10.0.0.1:6881".parse().unwrap());
+        let dht = Arc::new(dht);
+        assert!(!dht.has_bootstrap_source());
+
+        // No routers and no nodes, so starting up raises the alarm
+        let mut events = dht.observe();
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+        let event = events.recv().await.unwrap();
+        assert_eq!(event.event_type, DHTEventType::BootstrapUnavailable);
+        assert!(DHTEventMask::BOOTSTRAP_UNAVAILABLE.matches(&event));
+
+        // Initial nodes work instead of routers, and get verified like any other node
+        let responder_addr: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+        let responder = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, responder_addr)
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())?,
+        );
+        let node = Node::new(responder.get_id(), responder_addr);
+        let seeded = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, "10.0.0.2:6881".parse().unwrap())
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![])
+                        .enforce_id_ip_validity(false)
+                        .build()
+                        .unwrap(),
+                )
+                .initial_nodes(vec![node.clone()])
+                .build(shutdown_rx.clone())?,
+        );
+        assert!(seeded.has_bootstrap_source());
+        for dht in [responder.clone(), seeded.clone()].iter().cloned() {
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                None,
+            );
+        }
+        let ping_check_interval = seeded.get_settings().ping_check_interval_secs;
+        tokio::time::sleep(Duration::from_secs(ping_check_interval + 1)).await;
+        assert!(seeded
+            .get_nodes()
+            .iter()
+            .any(|wrapper| wrapper.node == node));
+
+        // So do routers
+        let routed = DHTBuilder::new()
+            .mock_network(&network, "10.0.0.4:6881".parse().unwrap())
+            .settings(
+                DHTSettingsBuilder::new()
+                    .routers(vec!["10.0.0.5:6881".to_string()])
+                    .build()
+                    .unwrap(),
+            )
+            .build(shutdown_rx.clone())?;
+        assert!(routed.has_bootstrap_source());
+
+        drop(events);
+        drop(responder);
+        drop(seeded);
+        drop(routed);
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bootstrap_unavailable_once() {
+        let network = MockNetwork::new();
+        let (dht, mut shutdown_tx, shutdown_rx) =
+            make_mock_dht(&network, "10.0.0.1:6881".parse().unwrap());
+        let mut events = dht.observe();
+
+        // Run the event loop a few times, as a supervisor restarting it would
+        for _ in 0..3 {
+            assert!(
+                tokio::time::timeout(Duration::from_secs(1), dht.run_event_loop())
+                    .await
+                    .is_err()
+            );
+        }
+        let bootstrap_events = std::iter::from_fn(|| events.try_recv())
+            .filter(|event| event.event_type == DHTEventType::BootstrapUnavailable)
+            .count();
+        assert_eq!(bootstrap_events, 1);
+
+        drop(events);
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_update_settings() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+        let info_hash = Id::from_random(&mut thread_rng());
+        for port in 7000..7003 {
+            dht.state.lock().unwrap().peer_storage.announce_peer(
+                info_hash,
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), port),
+            );
+        }
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let mut settings = dht.get_settings();
+        settings.max_peers_response = 1;
+        dht.update_settings(settings)?;
+        assert_eq!(dht.get_settings().max_peers_response, 1);
+
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let get_peers = MessageBuilder::new_get_peers_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .target(info_hash)
+            .build()?;
+        let res = requester.send_and_receive(get_peers, dht_addr).await?;
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
+                packets::GetPeersResponseArguments {
+                    values: packets::GetPeersResponseValues::Peers(peers),
+                    ..
+                }
+            )) if peers.len() == 1
+        ));
+
+        // Nothing is applied if anything needs a restart
+        let mut settings = dht.get_settings();
+        settings.max_peers_response = 2;
+        settings.token_secret_size += 1;
+        settings.max_torrents = 0;
+        assert!(matches!(
+            dht.update_settings(settings),
+            Err(RustyDHTError::SettingsUpdateError(fields))
+                if fields == vec!["token_secret_size", "max_torrents"]
+        ));
+        assert_eq!(dht.get_settings().max_peers_response, 1);
+
+        // The storage capacities are only used when the DHT is created
+        let mut settings = dht.get_settings();
+        settings.pinned_info_hashes_capacity += 1;
+        settings.routing_table_buckets_capacity += 1;
+        assert!(matches!(
+            dht.update_settings(settings),
+            Err(RustyDHTError::SettingsUpdateError(fields))
+                if fields == vec!["pinned_info_hashes_capacity", "routing_table_buckets_capacity"]
+        ));
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_settings() {
+        let is_invalid = |result: Result<DHTSettings, RustyDHTError>, field: &str| matches!(result, Err(RustyDHTError::InvalidSettingsError(fields)) if fields == vec![field]);
+        for rate in [0, 1, 1499] {
+            assert!(is_invalid(
+                DHTSettingsBuilder::new()
+                    .max_send_bytes_per_sec(Some(rate))
+                    .build(),
+                "max_send_bytes_per_sec"
+            ));
+        }
+        assert!(DHTSettingsBuilder::new()
+            .max_send_bytes_per_sec(Some(1500))
+            .build()
+            .is_ok());
+        assert!(is_invalid(
+            DHTSettingsBuilder::new()
+                .ip4_maintenance_interval_secs(0)
+                .build(),
+            "ip4_maintenance_interval_secs"
+        ));
+        assert!(is_invalid(
+            DHTSettingsBuilder::new()
+                .max_concurrent_buddy_pings(0)
+                .build(),
+            "max_concurrent_buddy_pings"
+        ));
+        assert!(is_invalid(
+            DHTSettingsBuilder::new().token_length(0).build(),
+            "token_length"
+        ));
+        assert!(is_invalid(
+            DHTSettingsBuilder::new()
+                .outgoing_request_prune_secs(0)
+                .build(),
+            "outgoing_request_prune_secs"
+        ));
+        assert!(is_invalid(
+            DHTSettingsBuilder::new()
+                .outgoing_reqiest_check_interval_secs(0)
+                .build(),
+            "outgoing_reqiest_check_interval_secs"
+        ));
+
+        // Settings that didn't come from the builder are checked when the DHT is created
+        let network = MockNetwork::new();
+        let (_, shutdown_rx) = shutdown::create_shutdown();
+        let result = DHTBuilder::new()
+            .mock_network(&network, "10.0.0.1:6881".parse().unwrap())
+            .settings(DHTSettings {
+                max_send_bytes_per_sec: Some(0),
+                ..DHTSettings::default()
+            })
+            .build(shutdown_rx.clone());
+        assert!(matches!(
+            result,
+            Err(RustyDHTError::InvalidSettingsError(fields)) if fields == vec!["max_send_bytes_per_sec"]
+        ));
+
+        // And when they're updated
+        let dht = DHTBuilder::new()
+            .mock_network(&network, "10.0.0.2:6881".parse().unwrap())
+            .build(shutdown_rx)
+            .unwrap();
+        let mut settings = dht.get_settings();
+        settings.max_send_bytes_per_sec = Some(0);
+        assert!(matches!(
+            dht.update_settings(settings),
+            Err(RustyDHTError::InvalidSettingsError(_))
+        ));
+        let mut settings = dht.get_settings();
+        settings.ip4_maintenance_interval_secs = 0;
+        assert!(matches!(
+            dht.update_settings(settings),
+            Err(RustyDHTError::InvalidSettingsError(fields)) if fields == vec!["ip4_maintenance_interval_secs"]
+        ));
+        let mut settings = dht.get_settings();
+        settings.token_length = 0;
+        assert!(matches!(
+            dht.update_settings(settings),
+            Err(RustyDHTError::InvalidSettingsError(fields)) if fields == vec!["token_length"]
+        ));
+        let mut settings = dht.get_settings();
+        settings.outgoing_request_prune_secs = 0;
+        assert!(matches!(
+            dht.update_settings(settings),
+            Err(RustyDHTError::InvalidSettingsError(fields)) if fields == vec!["outgoing_request_prune_secs"]
+        ));
+        let mut settings = dht.get_settings();
+        settings.outgoing_reqiest_check_interval_secs = 0;
+        assert!(matches!(
+            dht.update_settings(settings),
+            Err(RustyDHTError::InvalidSettingsError(fields)) if fields == vec!["outgoing_reqiest_check_interval_secs"]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_token_secret_rotation() {
+        let ipv4 = Ipv4Addr::new(1, 2, 3, 4);
+        let phony_ip4 = Box::new(StaticIPV4AddrSource::new(ipv4));
+        let port = 2244;
+
+        let dht = DHTBuilder::new()
+            .initial_id(get_dht_id())
+            .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))
+            .ip_source(phony_ip4)
+            .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+            .build(shutdown::create_shutdown().1)
+            .unwrap();
+
+        assert_eq!(
+            dht.state.lock().unwrap().token_secret.len(),
+            DHTSettings::default().token_secret_size
+        );
+
+        dht.rotate_token_secrets();
+        assert_eq!(
+            dht.state.lock().unwrap().old_token_secret.len(),
+            DHTSettings::default().token_secret_size
+        );
+        assert_eq!(
+            dht.state.lock().unwrap().token_secret.len(),
+            DHTSettings::default().token_secret_size
+        );
+
+        let state = dht.state.lock().unwrap();
+        assert_ne!(state.old_token_secret, state.token_secret);
+    }
+
+    #[tokio::test]
+    async fn test_error_policy_can_downgrade_fatal_error() {
+        let (_, default_dht_rx) = shutdown::create_shutdown();
+        let default_dht = DHTBuilder::new()
+            .initial_id(get_dht_id())
+            .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2301))
+            .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+            .build(default_dht_rx)
+            .unwrap();
+
+        // By default, a general error stops the loop but a parse error doesn't
+        assert!(default_dht
+            .handle_incoming_error(RustyDHTError::GeneralError(anyhow!("Oh no")))
+            .is_err());
+        assert!(default_dht
+            .handle_incoming_error(RustyDHTError::PacketParseError(anyhow!("Bad packet")))
+            .is_ok());
+
+        let (_, lenient_dht_rx) = shutdown::create_shutdown();
+        let lenient_dht = DHTBuilder::new()
+            .initial_id(get_dht_id())
+            .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2302))
+            .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+            .error_policy(|err| match err {
+                RustyDHTError::GeneralError(_) => ErrorAction::Continue,
+                _ => default_error_policy(err),
+            })
+            .build(lenient_dht_rx)
+            .unwrap();
+
+        assert!(lenient_dht
+            .handle_incoming_error(RustyDHTError::GeneralError(anyhow!("Oh no")))
+            .is_ok());
+        assert!(lenient_dht
+            .handle_incoming_error(RustyDHTError::SocketSendError(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Still fatal"
+            )))
+            .is_err());
+    }
+
+    #[test]
+    fn test_trimmed_reply_bytes() {
+        let addr: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let nodes: Vec<Node> = (0..200u32)
+            .map(|i| {
+                Node::new(
+                    Id::from_random(&mut thread_rng()),
+                    SocketAddr::new(IpAddr::V4(Ipv4Addr::from(0x0a000000 + i)), 6881),
+                )
+            })
+            .collect();
+        let find_node_reply = MessageBuilder::new_find_node_response()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .transaction_id(vec![1, 2, 3])
+            .nodes(nodes.clone())
+            .build()
+            .unwrap();
+        assert!(find_node_reply.clone().to_bytes().unwrap().len() > 1472);
+
+        // As many nodes as fit are kept
+        let bytes = trimmed_reply_bytes(find_node_reply, 1472, addr).unwrap();
+        assert!(bytes.len() <= 1472);
+        match packets::Message::from_bytes(&bytes).unwrap().message_type {
+            packets::MessageType::Response(packets::ResponseSpecific::FindNodeResponse(args)) => {
+                assert!(args.nodes.len() > 40);
+                assert_eq!(args.nodes[..], nodes[..args.nodes.len()]);
+            }
+            other => panic!("Unexpected message {:?}", other),
+        }
+
+        // Nodes go before peers
+        let peers: Vec<SocketAddr> = nodes[..100].iter().map(|node| node.address).collect();
+        let get_peers_reply = MessageBuilder::new_get_peers_response()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .transaction_id(vec![1, 2, 3])
+            .token(vec![4, 5, 6, 7])
+            .peers_and_nodes(peers.clone(), nodes)
+            .build()
+            .unwrap();
+        let bytes = trimmed_reply_bytes(get_peers_reply, 1472, addr).unwrap();
+        assert!(bytes.len() <= 1472);
+        match packets::Message::from_bytes(&bytes).unwrap().message_type {
+            packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(args)) => {
+                match args.values {
+                    packets::GetPeersResponseValues::Peers(kept)
+                    | packets::GetPeersResponseValues::PeersAndNodes(kept, _) => {
+                        assert_eq!(kept, peers);
+                    }
+                    other => panic!("Unexpected values {:?}", other),
+                }
+            }
+            other => panic!("Unexpected message {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_big_replies_are_trimmed() -> Result<(), RustyDHTError> {
+        for max_response_bytes in [Some(1472), None] {
+            let network = MockNetwork::new();
+            let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+            let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+            let dht = DHTBuilder::new()
+                .mock_network(&network, dht_addr)
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![])
+                        .max_peers_per_torrent(500)
+                        .max_peers_response(500)
+                        .max_nodes_with_peers(8)
+                        .max_response_bytes(max_response_bytes)
+                        .build()
+                        .unwrap(),
+                )
+                .build(shutdown_rx.clone())
+                .unwrap();
+            let info_hash = Id::from_random(&mut thread_rng());
+            {
+                let mut state = dht.state.lock().unwrap();
+                for i in 0..300u32 {
+                    let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::from(0x0a010000 + i)), 6881);
+                    state.peer_storage.announce_peer(info_hash, peer);
+                }
+            }
+            for i in 0..8u32 {
+                let node = Node::new(
+                    Id::from_random(&mut thread_rng()),
+                    SocketAddr::new(IpAddr::V4(Ipv4Addr::from(0x0a020000 + i)), 6881),
+                );
+                dht.buckets.write().unwrap().add_or_update(node, true);
+            }
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                None,
+            );
+
+            let requester_addr: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+            let mut requester_rx = network.bind(requester_addr);
+            let request = MessageBuilder::new_get_peers_request()
+                .sender_id(Id::from_random(&mut thread_rng()))
+                .target(info_hash)
+                .build()?;
+            network.send(requester_addr, dht_addr, request.to_bytes()?);
+            let (bytes, _) = requester_rx.recv().await.unwrap();
+            let (peers, nodes) = match packets::Message::from_bytes(&bytes)?.message_type {
+                packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
+                    args,
+                )) => match args.values {
+                    packets::GetPeersResponseValues::Peers(peers) => (peers, vec![]),
+                    packets::GetPeersResponseValues::PeersAndNodes(peers, nodes) => (peers, nodes),
+                    other => panic!("Unexpected values {:?}", other),
+                },
+                other => panic!("Unexpected message {:?}", other),
+            };
+
+            if max_response_bytes.is_some() {
+                // The nodes go first, then as many peers as it takes
+                assert!(bytes.len() <= 1472);
+                assert!(nodes.is_empty());
+                assert!(!peers.is_empty() && peers.len() < 300);
+            } else {
+                assert!(bytes.len() > 1472);
+                assert_eq!(peers.len(), 300);
+                assert_eq!(nodes.len(), 8);
+            }
+
+            drop(shutdown_rx);
+            shutdown_tx.shutdown().await;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_big_replies_to_ipv6_are_trimmed() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "[fd00::1]:6881".parse().unwrap();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = DHTBuilder::new()
+            .mock_network(&network, dht_addr)
+            .settings(
+                DHTSettingsBuilder::new()
+                    .routers(vec![])
+                    .max_peers_per_torrent(500)
+                    .max_peers_response(500)
+                    .build()
+                    .unwrap(),
+            )
+            .build(shutdown_rx.clone())
+            .unwrap();
+        let info_hash = Id::from_random(&mut thread_rng());
+        {
+            let mut state = dht.state.lock().unwrap();
+            for i in 0..300u128 {
+                let ip = Ipv6Addr::from(0xfd01_0000_0000_0000_0000_0000_0000_0000 + i);
+                state
+                    .peer_storage
+                    .announce_peer(info_hash, SocketAddr::new(IpAddr::V6(ip), 6881));
+            }
+        }
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+
+        // Replies to IPv6 addresses have a lower limit of their own
+        let requester_addr: SocketAddr = "[fd00::2]:6881".parse().unwrap();
+        let mut requester_rx = network.bind(requester_addr);
+        let request = MessageBuilder::new_get_peers_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .target(info_hash)
+            .build()?;
+        network.send(requester_addr, dht_addr, request.to_bytes()?);
+        let (bytes, _) = requester_rx.recv().await.unwrap();
+        let peers = match packets::Message::from_bytes(&bytes)?.message_type {
+            packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(args)) => {
+                match args.values {
+                    packets::GetPeersResponseValues::Peers(peers) => peers,
+                    other => panic!("Unexpected values {:?}", other),
+                }
+            }
+            other => panic!("Unexpected message {:?}", other),
+        };
+        assert!(bytes.len() <= 1232 && bytes.len() > 1200);
+        assert!(!peers.is_empty() && peers.len() < 300);
+
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run_event_loop_supervised() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+        let known_node = Node::new(
+            Id::from_random(&mut thread_rng()),
+            "1.2.3.5:1234".parse().unwrap(),
+        );
+        dht.buckets
+            .write()
+            .unwrap()
+            .add_or_update(known_node.clone(), true);
+        let policy = RestartPolicy {
+            max_restarts: 1,
+            initial_backoff: Duration::from_secs(10),
+            stable_period: Duration::from_secs(60),
+            ..RestartPolicy::default()
+        };
+        let dht_clone = dht.clone();
+        let supervisor =
+            tokio::spawn(async move { dht_clone.run_event_loop_supervised(policy).await });
+
+        // A fatal error stops the loop, and it comes back after the backoff
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let ping = || {
+            MessageBuilder::new_ping_request()
+                .sender_id(Id::from_random(&mut thread_rng()))
+                .build()
+                .unwrap()
+        };
+        requester.send_and_receive(ping(), dht_addr).await?;
+        dht.socket
+            .inject_incoming_error(RustyDHTError::GeneralError(anyhow!("Oh no")))
+            .await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!supervisor.is_finished());
+        assert!(requester.send_and_receive(ping(), dht_addr).await.is_err());
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        // The ping sent while it was down is answered late, once it's back
+        requester.send_and_receive(ping(), dht_addr).await?;
+        requester.send_and_receive(ping(), dht_addr).await?;
+        assert!(dht.get_nodes().iter().any(|node| node.node == known_node));
+
+        // After running for the stable period, it gets its restarts back
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        dht.socket
+            .inject_incoming_error(RustyDHTError::GeneralError(anyhow!("Oh no, later")))
+            .await;
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert!(!supervisor.is_finished());
+        requester.send_and_receive(ping(), dht_addr).await?;
+
+        // Once it's out of restarts, the error is returned
+        dht.socket
+            .inject_incoming_error(RustyDHTError::GeneralError(anyhow!("Oh no, again")))
+            .await;
+        assert!(matches!(
+            supervisor.await.unwrap(),
+            Err(RustyDHTError::GeneralError(_))
+        ));
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_address_rewriter() -> Result<(), RustyDHTError> {
+        let ping_request = MessageBuilder::new_ping_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .build()?;
+
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let advertised: SocketAddr = "5.6.7.8:16881".parse().unwrap();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = DHTBuilder::new()
+            .initial_id(get_dht_id())
+            .mock_network(&network, dht_addr)
+            .ip_source(Box::new(StaticIPV4AddrSource::new(Ipv4Addr::new(
+                5, 6, 7, 8,
+            ))))
+            .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+            .address_rewriter(move |addr| SocketAddr::new(addr.ip(), 16881))
+            .build(shutdown_rx.clone())
+            .unwrap();
+
+        // Announcing ourselves uses the rewritten port, whether or not a port is given
+        assert_eq!(dht.advertised_port(None)?, Some(advertised.port()));
+        assert_eq!(dht.advertised_port(Some(6881))?, Some(advertised.port()));
+
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx,
+            async move {
+                dht.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let res = requester
+            .send_and_receive(ping_request, dht_addr)
+            .await
+            .unwrap();
+        assert_eq!(
+            res.requester_ip.map(|addr| addr.port()),
+            Some(advertised.port())
+        );
+
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_raw() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let responder_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (responder, mut responder_shutdown_tx, responder_shutdown_rx) =
+            make_mock_dht(&network, responder_addr);
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            responder_shutdown_rx,
+            async move {
+                responder.run_event_loop().await.unwrap();
+            },
+            "Test responder DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let (sender, mut sender_shutdown_tx, sender_shutdown_rx) =
+            make_mock_dht(&network, "10.0.0.2:6881".parse().unwrap());
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe".to_vec();
+        let response = sender
+            .send_raw(ping, responder_addr, Duration::from_secs(5))
+            .await?;
+        let response = packets::Message::from_bytes(&response)?;
+        assert_eq!(response.transaction_id, b"aa".to_vec());
+        assert_eq!(response.get_author_id(), Some(get_dht_id()));
+
+        // Bytes that aren't a request aren't waited on
+        let garbage = b"not bencode".to_vec();
+        assert!(sender
+            .send_raw(garbage, responder_addr, Duration::from_secs(5))
+            .await?
+            .is_empty());
+
+        drop(sender);
+        drop(sender_shutdown_rx);
+        sender_shutdown_tx.shutdown().await;
+        responder_shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_raw_gets_replies_that_dont_parse() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let responder_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let responder_network = network.clone();
+        let mut responder_rx = network.bind(responder_addr);
+        // A reply with an Id that's too short, and a reply that isn't even a dict
+        let replies = vec![
+            b"d1:rd2:id3:bade1:t2:aa1:y1:re".to_vec(),
+            b"d1:t2:bb1:y1:r1:ri5ee".to_vec(),
+        ];
+        tokio::spawn(async move {
+            let mut replies = replies.into_iter();
+            while let Some((_, from)) = responder_rx.recv().await {
+                if let Some(reply) = replies.next() {
+                    responder_network.send(responder_addr, from, reply);
+                }
+            }
+        });
+
+        let (sender, mut sender_shutdown_tx, sender_shutdown_rx) =
+            make_mock_dht(&network, "10.0.0.2:6881".parse().unwrap());
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe".to_vec();
+        assert_eq!(
+            sender
+                .send_raw(ping, responder_addr, Duration::from_secs(5))
+                .await?,
+            b"d1:rd2:id3:bade1:t2:aa1:y1:re".to_vec()
+        );
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:bb1:y1:qe".to_vec();
+        assert_eq!(
+            sender
+                .send_raw(ping, responder_addr, Duration::from_secs(5))
+                .await?,
+            b"d1:t2:bb1:y1:r1:ri5ee".to_vec()
+        );
+
+        drop(sender);
+        drop(sender_shutdown_rx);
+        sender_shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_request_raw() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let responder_addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let (responder, mut responder_shutdown_tx, responder_shutdown_rx) =
+            make_mock_dht(&network, responder_addr);
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            responder_shutdown_rx,
+            async move {
+                responder.run_event_loop().await.unwrap();
+            },
+            "Test responder DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let (sender, mut sender_shutdown_tx, sender_shutdown_rx) =
+            make_mock_dht(&network, "127.0.0.2:6881".parse().unwrap());
+        let ping = MessageBuilder::new_ping_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .build()?;
+        let mut response_rx = sender
+            .send_request_raw(ping.clone(), responder_addr, Some(get_dht_id()))
+            .await?
+            .unwrap();
+        let response = tokio::time::timeout(Duration::from_secs(5), response_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.transaction_id, ping.transaction_id);
+        assert_eq!(response.get_author_id(), Some(get_dht_id()));
+
+        // The responder didn't make it into the routing table
+        assert_eq!(sender.buckets.read().unwrap().count(), (0, 0));
+
+        drop(sender);
+        drop(sender_shutdown_rx);
+        sender_shutdown_tx.shutdown().await;
+        responder_shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dropped_packets_are_counted() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .initial_id(get_dht_id())
+                .mock_network(&network, dht_addr)
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![])
+                        .drop_log_sample_rate(10)
+                        .build()
+                        .unwrap(),
+                )
+                .build(shutdown_rx.clone())?,
+        );
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let ping = MessageBuilder::new_ping_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .build()?;
+        for i in 0..20 {
+            network.send(
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 1, i)), 6881),
+                dht_addr,
+                b"not bencode".to_vec(),
+            );
+        }
+        network.send(
+            "10.0.0.2:0".parse().unwrap(),
+            dht_addr,
+            ping.clone().to_bytes()?,
+        );
+
+        // Packets are handled in order, so the drops have all been counted once this is answered
+        let mut endpoint = network.endpoint("10.0.0.3:6881".parse().unwrap());
+        let response = endpoint.send_and_receive(ping, dht_addr).await?;
+        assert!(matches!(
+            response.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::PingResponse(_))
+        ));
+        assert_eq!(dht.dropped_packet_count(), 21);
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
     }
 
-    // Helper function that sends a single packet to the test DHT and then returns the response
-    async fn send_and_receive(
-        msg: packets::Message,
-        port: u16,
-    ) -> Result<packets::Message, RustyDHTError> {
-        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
-        sock.send_to(
-            &msg.clone().to_bytes().unwrap(),
-            format!("127.0.0.1:{}", port),
+    #[tokio::test(start_paused = true)]
+    async fn test_requests_are_handled_concurrently() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let reply_delay = Duration::from_secs(1);
+        network.set_send_delay(reply_delay);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+
+        // Every reply takes a second, but they're all sent after about one second
+        let start = tokio::time::Instant::now();
+        let mut requests = Vec::new();
+        for i in 2..7 {
+            let mut requester = network.endpoint(SocketAddr::new([10, 0, 0, i].into(), 6881));
+            let get_peers = MessageBuilder::new_get_peers_request()
+                .sender_id(Id::from_random(&mut thread_rng()))
+                .target(Id::from_random(&mut thread_rng()))
+                .build()?;
+            requests.push(async move { requester.send_and_receive(get_peers, dht_addr).await });
+        }
+        for result in futures::future::join_all(requests).await {
+            result?;
+        }
+        assert!(start.elapsed() < reply_delay * 2);
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_routing_table_reads_are_concurrent() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let known_node = Node::new(
+            Id::from_random(&mut thread_rng()),
+            "1.2.3.5:1234".parse().unwrap(),
+        );
+        dht.buckets
+            .write()
+            .unwrap()
+            .add_or_update(known_node.clone(), true);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        // Another thread reads the routing table for as long as the DHT answers requests
+        let (locked_tx, locked_rx) = tokio::sync::oneshot::channel();
+        let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+        let buckets = dht.buckets.clone();
+        let reader = std::thread::spawn(move || {
+            let _guard = buckets.read().unwrap();
+            locked_tx.send(()).unwrap();
+            let _ = done_rx.recv();
+        });
+        locked_rx.await.unwrap();
+
+        // Read-only requesters aren't added to the routing table, so answering them only
+        // reads it
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let get_peers = MessageBuilder::new_get_peers_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .target(Id::from_random(&mut thread_rng()))
+            .read_only(true)
+            .build()?;
+        let res = requester.send_and_receive(get_peers, dht_addr).await?;
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
+                packets::GetPeersResponseArguments {
+                    values: packets::GetPeersResponseValues::Nodes(nodes),
+                    ..
+                }
+            )) if nodes.contains(&known_node)
+        ));
+        let find_node = MessageBuilder::new_find_node_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .target(Id::from_random(&mut thread_rng()))
+            .read_only(true)
+            .build()?;
+        let res = requester.send_and_receive(find_node, dht_addr).await?;
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::FindNodeResponse(
+                packets::FindNodeResponseArguments { nodes, .. }
+            )) if nodes.contains(&known_node)
+        ));
+
+        done_tx.send(()).unwrap();
+        reader.join().unwrap();
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nodes_with_peers() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let info_hash = Id::from_random(&mut thread_rng());
+        let peer: SocketAddr = "1.2.3.5:1234".parse().unwrap();
+        for (addr, max_nodes_with_peers) in [("10.0.0.1:6881", 0), ("10.0.0.2:6881", 2)] {
+            let dht = DHTBuilder::new()
+                .initial_id(get_dht_id())
+                .mock_network(&network, addr.parse().unwrap())
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![])
+                        .max_nodes_with_peers(max_nodes_with_peers)
+                        .build()
+                        .unwrap(),
+                )
+                .build(shutdown_rx.clone())?;
+            for i in 0..4 {
+                dht.buckets.write().unwrap().add_or_update(
+                    Node::new(
+                        Id::from_random(&mut thread_rng()),
+                        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 4, i)), 6881),
+                    ),
+                    true,
+                );
+            }
+            dht.state
+                .lock()
+                .unwrap()
+                .peer_storage
+                .announce_peer(info_hash, peer);
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+        }
+
+        let mut requester = network.endpoint("10.0.0.3:6881".parse().unwrap());
+        let get_peers = MessageBuilder::new_get_peers_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .target(info_hash)
+            .build()?;
+
+        // By default, only the peers come back
+        let res = requester
+            .send_and_receive(get_peers.clone(), "10.0.0.1:6881".parse().unwrap())
+            .await?;
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
+                packets::GetPeersResponseArguments {
+                    values: packets::GetPeersResponseValues::Peers(peers),
+                    ..
+                }
+            )) if peers == vec![peer]
+        ));
+
+        // When enabled, a few nodes come along with them
+        let res = requester
+            .send_and_receive(get_peers, "10.0.0.2:6881".parse().unwrap())
+            .await?;
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
+                packets::GetPeersResponseArguments {
+                    values: packets::GetPeersResponseValues::PeersAndNodes(peers, nodes),
+                    ..
+                }
+            )) if peers == vec![peer] && nodes.len() == 2
+        ));
+
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_only() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let client_addr: SocketAddr = "127.0.0.10:6881".parse().unwrap();
+        let info_hash = Id::from_random(&mut thread_rng());
+        let peer: SocketAddr = "1.2.3.5:1234".parse().unwrap();
+
+        let client = Arc::new(
+            DHTBuilder::new()
+                .client_only()
+                .mock_network(&network, client_addr)
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())?,
+        );
+        assert!(client.is_client_only());
+        assert!(client.get_settings().read_only);
+        let mut dhts = vec![client.clone()];
+        for i in 1..=8 {
+            let server = DHTBuilder::new()
+                .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())?;
+            server
+                .state
+                .lock()
+                .unwrap()
+                .peer_storage
+                .announce_peer(info_hash, peer);
+            dhts.push(Arc::new(server));
+        }
+        for dht in dhts {
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                None,
+            );
+        }
+
+        // The client can look things up
+        for i in 1..=8 {
+            client
+                .send_request(
+                    MessageBuilder::new_ping_request()
+                        .sender_id(client.get_id())
+                        .read_only(true)
+                        .build()?,
+                    SocketAddr::new([127, 0, 0, i].into(), 6881),
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+        }
+        let result =
+            crate::dht::operations::get_peers(&client, info_hash, Duration::from_secs(10)).await?;
+        assert_eq!(result.peers(), vec![peer]);
+
+        // But doesn't answer anybody
+        let mut requester = network.endpoint("127.0.0.20:6881".parse().unwrap());
+        let ping = MessageBuilder::new_ping_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .build()?;
+        assert!(matches!(
+            requester.send_and_receive(ping, client_addr).await,
+            Err(RustyDHTError::TimeoutError(_))
+        ));
+
+        // And can't be made to
+        let mut settings = client.get_settings();
+        settings.read_only = false;
+        assert!(matches!(
+            client.update_settings(settings),
+            Err(RustyDHTError::SettingsUpdateError(rejected)) if rejected == vec!["read_only"]
+        ));
+
+        drop(client);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rotate_id() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let rotator = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, "127.0.0.10:6881".parse().unwrap())
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())?,
+        );
+        let mut dhts = vec![rotator.clone()];
+        for i in 1..=8 {
+            dhts.push(Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())?,
+            ));
+        }
+        for dht in dhts {
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                None,
+            );
+        }
+        for i in 1..=8 {
+            rotator
+                .send_request(
+                    MessageBuilder::new_ping_request()
+                        .sender_id(rotator.get_id())
+                        .build()?,
+                    SocketAddr::new([127, 0, 0, i].into(), 6881),
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+        }
+        assert_eq!(rotator.buckets.read().unwrap().count().1, 8);
+
+        let mut events = rotator.subscribe();
+        let old_id = rotator.get_id();
+        let new_id = Id::from_random(&mut thread_rng());
+        assert_eq!(rotator.rotate_id(Some(new_id)).await?, new_id);
+        assert_eq!(rotator.get_id(), new_id);
+
+        // The routing table was cleared, and find_node toward the new id fills it back up
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(rotator.buckets.read().unwrap().count().1, 8);
+        let mut id_changes = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let DHTEventType::IdChanged(change) = event.event_type {
+                id_changes.push(change);
+            }
+        }
+        assert_eq!(id_changes, vec![IdChangedEvent { old_id, new_id }]);
+
+        // Without an id, a random one is picked
+        let random_id = rotator.rotate_id(None).await?;
+        assert_ne!(random_id, new_id);
+        assert_eq!(rotator.get_id(), random_id);
+
+        drop(rotator);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        // Ids that don't match our external IP are refused
+        let (_, shutdown_rx) = shutdown::create_shutdown();
+        let dht = DHTBuilder::new()
+            .mock_network(&network, "127.0.0.20:6881".parse().unwrap())
+            .ip_source(Box::new(StaticIPV4AddrSource::new(Ipv4Addr::new(
+                1, 2, 3, 4,
+            ))))
+            .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+            .build(shutdown_rx)?;
+        let ip = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let invalid_id = Id::from_ip(&IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8)));
+        assert!(!invalid_id.is_valid_for_ip(&ip));
+        assert!(matches!(
+            dht.rotate_id(Some(invalid_id)).await,
+            Err(RustyDHTError::GeneralError(_))
+        ));
+        let valid_id = dht.rotate_id(None).await?;
+        assert!(valid_id.is_valid_for_ip(&ip));
+        assert_eq!(dht.get_id(), valid_id);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_and_join() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (dht, mut shutdown_tx, shutdown_rx) = make_mock_dht(&network, dht_addr);
+        let dht = Arc::new(dht);
+        let dht_clone = dht.clone();
+
+        // Sets its flag once the task that owns it is gone
+        struct DoneFlag(Arc<AtomicBool>);
+        impl Drop for DoneFlag {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+        let event_loop_done = Arc::new(AtomicBool::new(false));
+        let done_flag = DoneFlag(event_loop_done.clone());
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                let _done_flag = done_flag;
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+
+        // Leave some pings waiting on nodes that never answer
+        let _silent_nodes = network.bind("10.0.0.2:6881".parse().unwrap());
+        for _ in 0..3 {
+            dht.ping_internal(
+                shutdown_rx.clone(),
+                "10.0.0.2:6881".parse().unwrap(),
+                None,
+                None,
+            )
+            .await?;
+        }
+        assert!(!event_loop_done.load(Ordering::SeqCst));
+
+        // We still hold a DHT and a ShutdownReceiver, but every task has finished
+        // afterwards, so nothing else holds on to the DHT or its state
+        shutdown_tx
+            .shutdown_and_join(Duration::from_secs(5))
+            .await?;
+        assert!(event_loop_done.load(Ordering::SeqCst));
+        assert_eq!(Arc::strong_count(&dht), 1);
+        assert_eq!(Arc::strong_count(&dht.state), 1);
+
+        drop(shutdown_rx);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_implied_port_policy() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let requester_addr: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+        let mut requester = network.endpoint(requester_addr);
+        let info_hash = Id::from_random(&mut thread_rng());
+
+        for (addr, policy, expected_port) in [
+            ("10.0.0.1:6881", ImpliedPortPolicy::ImpliedWins, 6881),
+            ("10.0.0.2:6881", ImpliedPortPolicy::PreferExplicit, 1234),
+        ] {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .initial_id(get_dht_id())
+                    .mock_network(&network, addr.parse().unwrap())
+                    .settings(
+                        DHTSettingsBuilder::new()
+                            .routers(vec![])
+                            .implied_port_policy(policy)
+                            .build()
+                            .unwrap(),
+                    )
+                    .build(shutdown_rx.clone())?,
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+
+            let token = calculate_token(
+                &requester_addr,
+                dht.state.lock().unwrap().token_secret.clone(),
+                4,
+            );
+            let announce = MessageBuilder::new_announce_peer_request()
+                .sender_id(Id::from_random(&mut thread_rng()))
+                .target(info_hash)
+                .implied_port(true)
+                .port(1234)
+                .token(token)
+                .build()?;
+            requester
+                .send_and_receive(announce, addr.parse().unwrap())
+                .await?;
+
+            let info_hashes = dht.get_info_hashes(None);
+            assert_eq!(info_hashes.len(), 1);
+            assert_eq!(
+                info_hashes[0].1[0].addr,
+                SocketAddr::new(requester_addr.ip(), expected_port)
+            );
+        }
+
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_failures_are_counted() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .initial_id(get_dht_id())
+                .mock_network(&network, dht_addr)
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![])
+                        .token_failure_events(true)
+                        .build()
+                        .unwrap(),
+                )
+                .build(shutdown_rx.clone())?,
+        );
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+        let mut events = dht.subscribe();
+
+        let requester_id = Id::from_random(&mut thread_rng());
+        let info_hash = Id::from_random(&mut thread_rng());
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let get_peers = MessageBuilder::new_get_peers_request()
+            .sender_id(requester_id)
+            .target(info_hash)
+            .build()?;
+        let token = requester
+            .send_and_receive(get_peers, dht_addr)
+            .await?
+            .announce_token()
+            .unwrap()
+            .to_vec();
+        let announce = |token: Vec<u8>| {
+            MessageBuilder::new_announce_peer_request()
+                .sender_id(requester_id)
+                .target(info_hash)
+                .port(1234)
+                .token(token)
+                .build()
+                .unwrap()
+        };
+
+        // A garbage token gets no reply
+        assert!(matches!(
+            requester
+                .send_and_receive(announce(vec![1, 2, 3]), dht_addr)
+                .await,
+            Err(RustyDHTError::TimeoutError(_))
+        ));
+        assert_eq!(dht.token_failure_count(), 1);
+        assert_eq!(dht.old_token_count(), 0);
+        let mut failures = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let DHTEventType::TokenValidationFailed(failure) = event.event_type {
+                failures.push(failure);
+            }
+        }
+        assert_eq!(
+            failures,
+            vec![TokenValidationFailedEvent {
+                requester: "10.0.0.2:6881".parse().unwrap(),
+                info_hash,
+            }]
+        );
+
+        // A token from before the last rotation still works, but is counted
+        dht.rotate_token_secrets();
+        requester
+            .send_and_receive(announce(token), dht_addr)
+            .await?;
+        assert_eq!(dht.token_failure_count(), 1);
+        assert_eq!(dht.old_token_count(), 1);
+
+        drop(events);
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_response_verification_policy() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let responder_addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let (responder, mut responder_shutdown_tx, responder_shutdown_rx) =
+            make_mock_dht(&network, responder_addr);
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            responder_shutdown_rx,
+            async move {
+                responder.run_event_loop().await.unwrap();
+            },
+            "Test responder DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        // Pings the responder, expecting a different id than it has
+        let ping_unexpected_id = |policy, addr: &str| {
+            let (tx, rx) = shutdown::create_shutdown();
+            let dht = DHTBuilder::new()
+                .initial_id(Id::from_random(&mut thread_rng()))
+                .mock_network(&network, addr.parse().unwrap())
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![])
+                        .response_verification_policy(policy)
+                        .build()
+                        .unwrap(),
+                )
+                .build(rx)
+                .unwrap();
+            async move {
+                let ping = MessageBuilder::new_ping_request()
+                    .sender_id(dht.get_id())
+                    .build()
+                    .unwrap();
+                let result = dht
+                    .send_request(
+                        ping,
+                        responder_addr,
+                        Some(Id::from_random(&mut thread_rng())),
+                        Some(Duration::from_millis(500)),
+                    )
+                    .await;
+                let num_nodes = dht.get_nodes().len();
+                drop(dht);
+                (result, num_nodes, tx)
+            }
+        };
+
+        let (result, num_nodes, mut tx) =
+            ping_unexpected_id(ResponseVerificationPolicy::Drop, "127.0.0.2:6881").await;
+        assert!(matches!(result, Err(RustyDHTError::TimeoutError(_))));
+        assert_eq!(num_nodes, 0);
+        tx.shutdown().await;
+
+        let (result, num_nodes, mut tx) =
+            ping_unexpected_id(ResponseVerificationPolicy::Accept, "127.0.0.3:6881").await;
+        assert_eq!(result?.get_author_id(), Some(get_dht_id()));
+        assert_eq!(num_nodes, 1);
+        tx.shutdown().await;
+
+        let (result, num_nodes, mut tx) = ping_unexpected_id(
+            ResponseVerificationPolicy::AcceptWithoutVoting,
+            "127.0.0.4:6881",
         )
-        .await
-        .unwrap();
-        let mut recv_buf = [0; 2048];
-        let num_read = sock.recv_from(&mut recv_buf).await.unwrap().0;
-        packets::Message::from_bytes(&recv_buf[..num_read])
+        .await;
+        assert_eq!(result?.get_author_id(), Some(get_dht_id()));
+        assert_eq!(num_nodes, 0);
+        tx.shutdown().await;
+
+        responder_shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_node_results_v6_id_validation() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (dht, mut shutdown_tx, shutdown_rx) =
+            make_mock_dht(&network, "10.0.0.1:6881".parse().unwrap());
+
+        let valid_addr: SocketAddr = "[2001:db8:85a3:8d3::1]:6881".parse().unwrap();
+        let valid_node = Node::new(Id::from_ip(&valid_addr.ip()), valid_addr);
+        let invalid_addr: SocketAddr = "[2001:db8:85a3:8d3::2]:6881".parse().unwrap();
+        let mut invalid_id = Id::from_ip(&invalid_addr.ip()).to_vec();
+        invalid_id[0] ^= 0xff;
+        let invalid_node = Node::new(Id::from_bytes(&invalid_id)?, invalid_addr);
+
+        // A node that answers one find_node with both v6 nodes
+        let responder_addr: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let mut responder_rx = network.bind(responder_addr);
+        let responder_network = network.clone();
+        let nodes = vec![valid_node.clone(), invalid_node.clone()];
+        tokio::spawn(async move {
+            let (bytes, from) = responder_rx.recv().await.unwrap();
+            let req = packets::Message::from_bytes(&bytes).unwrap();
+            let reply = MessageBuilder::new_find_node_response()
+                .sender_id(Id::from_random(&mut thread_rng()))
+                .transaction_id(req.transaction_id)
+                .nodes(nodes)
+                .build()
+                .unwrap();
+            responder_network.send(responder_addr, from, reply.to_bytes().unwrap());
+        });
+
+        let find_node = MessageBuilder::new_find_node_request()
+            .sender_id(get_dht_id())
+            .target(Id::from_random(&mut thread_rng()))
+            .build()?;
+        dht.send_request(
+            find_node,
+            responder_addr,
+            None,
+            Some(Duration::from_secs(5)),
+        )
+        .await?;
+
+        let known_ids: Vec<Id> = dht
+            .buckets
+            .read()
+            .unwrap()
+            .get_all_unverified()
+            .iter()
+            .map(|nw| nw.node.id)
+            .collect();
+        assert!(known_ids.contains(&valid_node.id));
+        assert!(!known_ids.contains(&invalid_node.id));
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    // Dumb helper function because we can't declare a const or static Id
+    fn get_dht_id() -> Id {
+        Id::from_hex("0011223344556677889900112233445566778899").unwrap()
     }
 }
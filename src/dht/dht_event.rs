@@ -1,4 +1,8 @@
-use crate::packets::Message;
+use crate::common::Id;
+use crate::packets::{Message, MessageType, RequestSpecific};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::ops::BitOr;
+use tokio::sync::broadcast;
 
 /// Top-level message that [DHT](crate::dht::DHT) will send to callers that
 /// [subscribe](crate::dht::DHT::subscribe) to events.
@@ -11,6 +15,13 @@ pub struct DHTEvent {
 #[derive(Debug, PartialEq, Clone)]
 pub enum DHTEventType {
     MessageReceived(MessageReceivedEvent),
+    TokenValidationFailed(TokenValidationFailedEvent),
+    IdChanged(IdChangedEvent),
+    IpVoteUpdated(IpVoteUpdatedEvent),
+
+    /// Sent when the event loop starts without any routers or nodes in the routing table
+    /// to bootstrap from. See [has_bootstrap_source](crate::dht::DHT::has_bootstrap_source).
+    BootstrapUnavailable,
 }
 
 /// This struct is used when [DHT](crate::dht::DHT) receives a message from another
@@ -20,4 +31,155 @@ pub enum DHTEventType {
 #[derive(Debug, PartialEq, Clone)]
 pub struct MessageReceivedEvent {
     pub message: Message,
+
+    /// True if the message is a request that we recently received from the same
+    /// address with the same transaction id (e.g. a retransmission).
+    pub duplicate: bool,
+}
+
+/// This struct is used when [DHT](crate::dht::DHT) rejects an announce_peer request
+/// because its token is invalid.
+///
+/// Only sent if [token_failure_events](crate::dht::DHTSettings::token_failure_events)
+/// is enabled.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TokenValidationFailedEvent {
+    /// Address that sent the announce_peer request
+    pub requester: SocketAddr,
+
+    /// The info hash that the requester tried to announce
+    pub info_hash: Id,
+}
+
+/// This struct is used when [DHT](crate::dht::DHT) switches to a new node Id, either
+/// because the old one isn't valid for our external IPv4 address or because of
+/// [rotate_id](crate::dht::DHT::rotate_id).
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdChangedEvent {
+    pub old_id: Id,
+    pub new_id: Id,
+}
+
+/// This struct is used when the votes that other nodes cast on our external IPv4 address
+/// change: when the number of votes counted changes, or a different address wins.
+///
+/// The votes are counted by the DHT's
+/// [IPV4AddrSource](crate::common::ipv4_addr_src::IPV4AddrSource). Watching them shows
+/// a campaign for a bogus address forming before it's able to change our Id.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IpVoteUpdatedEvent {
+    /// The address we're taking as our external IPv4 address, if any has enough votes
+    pub current_best: Option<Ipv4Addr>,
+
+    /// How many votes are counted, for all addresses
+    pub votes: usize,
+}
+
+/// A read-only stream of [DHTEvent]s, returned by [DHT::observe](crate::dht::DHT::observe).
+///
+/// Events are kept in a ring buffer shared by all observers. An observer that falls too
+/// far behind misses the oldest events it hasn't received yet, rather than the newest, so
+/// it always catches up to recent history. Slow observers never hold up the DHT.
+pub struct EventObserver {
+    events: broadcast::Receiver<DHTEvent>,
+    missed: u64,
+}
+
+impl EventObserver {
+    pub(crate) fn new(events: broadcast::Receiver<DHTEvent>) -> EventObserver {
+        EventObserver { events, missed: 0 }
+    }
+
+    /// Waits for the next event. Returns None once the DHT is gone.
+    pub async fn recv(&mut self) -> Option<DHTEvent> {
+        loop {
+            match self.events.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(count)) => self.missed += count,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Returns the next event if there is one already, without waiting
+    pub fn try_recv(&mut self) -> Option<DHTEvent> {
+        loop {
+            match self.events.try_recv() {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::TryRecvError::Lagged(count)) => self.missed += count,
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// Returns the number of events that were dropped before this observer got to them
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+}
+
+/// Selects which events [DHT::next_event](crate::dht::DHT::next_event) waits for.
+///
+/// Masks can be combined with `|`, e.g. `DHTEventMask::PING_REQUEST | DHTEventMask::RESPONSE`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DHTEventMask(u32);
+
+impl DHTEventMask {
+    pub const PING_REQUEST: DHTEventMask = DHTEventMask(1 << 0);
+    pub const FIND_NODE_REQUEST: DHTEventMask = DHTEventMask(1 << 1);
+    pub const GET_PEERS_REQUEST: DHTEventMask = DHTEventMask(1 << 2);
+    pub const ANNOUNCE_PEER_REQUEST: DHTEventMask = DHTEventMask(1 << 3);
+    pub const SAMPLE_INFOHASHES_REQUEST: DHTEventMask = DHTEventMask(1 << 4);
+    pub const RESPONSE: DHTEventMask = DHTEventMask(1 << 5);
+    pub const ERROR: DHTEventMask = DHTEventMask(1 << 6);
+    pub const TOKEN_VALIDATION_FAILED: DHTEventMask = DHTEventMask(1 << 7);
+    pub const ID_CHANGED: DHTEventMask = DHTEventMask(1 << 8);
+    pub const IP_VOTE_UPDATED: DHTEventMask = DHTEventMask(1 << 9);
+    pub const BOOTSTRAP_UNAVAILABLE: DHTEventMask = DHTEventMask(1 << 10);
+    pub const ALL: DHTEventMask = DHTEventMask(u32::MAX);
+
+    /// Returns true if `event` is one of the events selected by this mask
+    pub fn matches(&self, event: &DHTEvent) -> bool {
+        let received = match &event.event_type {
+            DHTEventType::MessageReceived(received) => received,
+            DHTEventType::TokenValidationFailed(_) => {
+                return self.0 & DHTEventMask::TOKEN_VALIDATION_FAILED.0 != 0;
+            }
+            DHTEventType::IdChanged(_) => {
+                return self.0 & DHTEventMask::ID_CHANGED.0 != 0;
+            }
+            DHTEventType::IpVoteUpdated(_) => {
+                return self.0 & DHTEventMask::IP_VOTE_UPDATED.0 != 0;
+            }
+            DHTEventType::BootstrapUnavailable => {
+                return self.0 & DHTEventMask::BOOTSTRAP_UNAVAILABLE.0 != 0;
+            }
+        };
+        let bit = match &received.message.message_type {
+            MessageType::Request(RequestSpecific::PingRequest(_)) => DHTEventMask::PING_REQUEST,
+            MessageType::Request(RequestSpecific::FindNodeRequest(_)) => {
+                DHTEventMask::FIND_NODE_REQUEST
+            }
+            MessageType::Request(RequestSpecific::GetPeersRequest(_)) => {
+                DHTEventMask::GET_PEERS_REQUEST
+            }
+            MessageType::Request(RequestSpecific::AnnouncePeerRequest(_)) => {
+                DHTEventMask::ANNOUNCE_PEER_REQUEST
+            }
+            MessageType::Request(RequestSpecific::SampleInfoHashesRequest(_)) => {
+                DHTEventMask::SAMPLE_INFOHASHES_REQUEST
+            }
+            MessageType::Response(_) => DHTEventMask::RESPONSE,
+            MessageType::Error(_) => DHTEventMask::ERROR,
+        };
+        self.0 & bit.0 != 0
+    }
+}
+
+impl BitOr for DHTEventMask {
+    type Output = DHTEventMask;
+
+    fn bitor(self, rhs: DHTEventMask) -> DHTEventMask {
+        DHTEventMask(self.0 | rhs.0)
+    }
 }
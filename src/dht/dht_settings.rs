@@ -1,3 +1,8 @@
+use crate::dht::bandwidth_limiter::MIN_BYTES_PER_SEC;
+use crate::errors::RustyDHTError;
+use crate::storage::buckets::DEFAULT_BUCKETS_CAPACITY;
+use crate::storage::peer_storage::DuplicatePeerPolicy;
+
 /// Struct that represents configuration for DHT that, in general, does
 /// not change after the DHT is started.
 ///
@@ -11,6 +16,11 @@ pub struct DHTSettings {
     /// Number of bytes for token secrets for get_peers responses
     pub token_secret_size: usize,
 
+    /// Number of bytes in the tokens we give out in get_peers responses (and expect back in
+    /// announce_peer requests). Independent of [token_secret_size](DHTSettings::token_secret_size).
+    /// Must be at least 1.
+    pub token_length: usize,
+
     /// Max number of peers to provide in response to a get_peers.
     /// Shouldn't be much higher than this as the entire response packet needs to be less than 1500
     pub max_peers_response: usize,
@@ -52,12 +62,210 @@ pub struct DHTSettings {
     /// We'll think about pinging and pruning nodes at this interval
     pub ping_check_interval_secs: u64,
 
-    /// Outgoing requests may be pruned after this many seconds
+    /// Outgoing requests are pruned after this many seconds, even if they were sent without
+    /// a timeout (e.g. [send_request](crate::dht::DHT::send_request) with None). Whoever is
+    /// waiting on a pruned request gets a [TimeoutError](crate::errors::RustyDHTError::TimeoutError).
+    /// Requests may linger for up to
+    /// [outgoing_reqiest_check_interval_secs](DHTSettings::outgoing_reqiest_check_interval_secs)
+    /// longer. Must be at least 1.
     pub outgoing_request_prune_secs: u64,
 
-    /// We'll think about pruning outgoing requests at this interval
+    /// We'll think about pruning outgoing requests at this interval. Must be at least 1.
     pub outgoing_reqiest_check_interval_secs: u64,
 
+    /// Number of recently received requests (by source address and transaction id) to remember.
+    /// A request matching one of these is flagged as a duplicate.
+    pub max_recent_requests: usize,
+
+    /// A request only counts as a duplicate of one remembered from
+    /// [max_recent_requests](DHTSettings::max_recent_requests) if it has the same contents and
+    /// arrives within this many seconds of it. Many clients reuse transaction ids (e.g. short
+    /// counters), so a real re-query after this long is treated as new. 0 turns duplicate
+    /// detection off.
+    pub duplicate_request_window_secs: u64,
+
+    /// If true, announce_peer requests flagged as duplicates are answered without storing
+    /// the peer again. Otherwise they're processed like any other announce.
+    pub skip_duplicate_announces: bool,
+
+    /// If set, outgoing IPv4 packets are marked with this IP Type of Service byte (which holds
+    /// the DSCP and ECN bits). E.g. `Some(0x20)` marks them as low priority bulk traffic
+    /// (DSCP CS1). Ignored on platforms that don't support setting it.
+    pub ip_tos: Option<u8>,
+
+    /// If true, queries with a missing or malformed sender id get a KRPC protocol error reply.
+    /// Otherwise they are counted and dropped.
+    pub reply_to_invalid_sender_id: bool,
+
+    /// Info hashes with fewer than this many stored peers are dropped from peer storage
+    /// whenever node buckets are pruned, to make room for active torrents. 0 disables this.
+    pub min_peers_per_torrent: usize,
+
+    /// Maximum number of nodes from a single find_node response to add to the routing table
+    /// (as unverified). Limits how much one malicious responder can flood our routing table.
+    /// Defaults to 16 (there used to be no limit).
+    pub max_nodes_per_response: usize,
+
+    /// If true, nodes from a find_node response are only added to the routing table if they
+    /// are closer to the lookup target than the farthest of the verified nodes we already know
+    /// near it. Makes it harder to poison the routing table with far away nodes.
+    pub require_closer_find_node_results: bool,
+
+    /// How long (in seconds) after receiving a request from an address we never contacted
+    /// first that we still consider ourselves reachable. See [DHT::is_reachable](crate::dht::DHT::is_reachable).
+    pub reachability_window_secs: u64,
+
+    /// If true, the DHT may listen on an IPv6 address. The socket is bound with IPV6_V6ONLY
+    /// disabled so that IPv4 peers, which the rest of the DHT (Id derivation, IP voting) relies on,
+    /// can still reach it. Otherwise the listen address must be IPv4.
+    pub dual_stack: bool,
+
+    /// What to do with a response that comes from a different Id than the one the request was
+    /// sent to. See [ResponseVerificationPolicy].
+    pub response_verification_policy: ResponseVerificationPolicy,
+
+    /// If set, the DHT sends at most this many bytes per second (averaged over a second).
+    /// Packets are delayed to stay under the limit, and dropped if that would take more than a
+    /// couple of seconds. Unlimited by default. Must be at least 1500, so that a full-size
+    /// packet fits in a second's worth of bytes.
+    pub max_send_bytes_per_sec: Option<u64>,
+
+    /// Until we have at least this many verified nodes, find_node requests and get_peers
+    /// requests that we have no peers for go unanswered, so that a freshly started node
+    /// doesn't hand out empty node lists. Pings and other requests are still answered. Zero
+    /// (the default) answers right away.
+    pub min_verified_nodes_to_answer: usize,
+
+    /// If true, optional fields are left out of our responses to save bandwidth. Currently that's
+    /// the `ip` field (bep0042), which tells requesters what address we see them as. Requesters
+    /// then can't use our responses to learn their external IP address.
+    pub minimal_responses: bool,
+
+    /// If true (the default), we follow bep0042: our own Id is changed to match our external IPv4
+    /// address, and nodes whose Ids aren't valid for their IP addresses aren't added to the routing
+    /// table. Turning this off helps when our external address is shared and unstable (e.g. behind
+    /// CGNAT), but nodes that enforce bep0042 may then ignore us, and our routing table is easier
+    /// to fill with Sybil nodes.
+    pub enforce_id_ip_validity: bool,
+
+    /// Dropped packets (ones that can't be parsed, or that come from port 0) are all counted,
+    /// but only the first of every `drop_log_sample_rate` is logged on its own. A summary
+    /// of the drops is logged once a minute. 0 turns off the individual log lines.
+    pub drop_log_sample_rate: u64,
+
+    /// When we have peers for a get_peers request, also include up to this many of the nodes
+    /// nearest to the info hash in the response, so the requester's lookup can keep converging.
+    /// 0 means that only the peers are returned, and nodes only when there are no peers.
+    pub max_nodes_with_peers: usize,
+
+    /// If true, [DHTEvent](crate::dht::dht_event::DHTEvent)s are sent to subscribers for
+    /// announce_peer requests with a token that isn't valid. They're counted either way (see
+    /// [DHT::token_failure_count](crate::dht::DHT::token_failure_count)).
+    pub token_failure_events: bool,
+
+    /// Which port to store for an announce_peer request that sets `implied_port` and also has a
+    /// non-zero `port`. See [ImpliedPortPolicy].
+    pub implied_port_policy: ImpliedPortPolicy,
+
+    /// Number of seconds between decaying the votes for our external IPv4 address (and checking
+    /// whether our Id is still valid for it). New votes are checked right away. Must be at
+    /// least 1.
+    pub ip4_maintenance_interval_secs: u64,
+
+    /// If set, replies that are more than this many times the size of the request they answer
+    /// are dropped, so spoofed requests can't turn us into an amplifier for reflection attacks
+    /// (see [DHT](crate::dht::DHT)). Off by default, since a full get_peers or
+    /// sample_infohashes reply is normally well over ten times the size of the request.
+    pub max_response_amplification: Option<usize>,
+
+    /// If set, addresses that have never answered one of our requests can get at most this
+    /// many bytes of replies from us per minute. Past that, their requests go unanswered for a minute. This
+    /// limits how much traffic a spoofed source address can draw from us (see
+    /// [DHT](crate::dht::DHT)), on top of the limit on incoming packets per IP. Off by
+    /// default; 64 KiB is a reasonable value for a public node. IPs are grouped like the
+    /// incoming packet limit, by [throttle_ipv4_prefix_len](Self::throttle_ipv4_prefix_len)
+    /// and [throttle_ipv6_prefix_len](Self::throttle_ipv6_prefix_len), but an IP that has
+    /// answered one of our requests is never limited.
+    pub max_unsolicited_response_bytes_per_min: Option<usize>,
+
+    /// Decides when an announce_peer refreshes a peer that is already stored for the info_hash,
+    /// instead of adding another one. See [DuplicatePeerPolicy].
+    pub duplicate_peer_policy: DuplicatePeerPolicy,
+
+    /// Max number of pings that the periodic check of our nodes (every
+    /// [ping_check_interval_secs](DHTSettings::ping_check_interval_secs)) has waiting for a
+    /// response at once. Spreads out the pings on a large routing table instead of sending them
+    /// all in one burst. Must be at least 1, and defaults to 64 (there used to be no limit).
+    pub max_concurrent_buddy_pings: usize,
+
+    /// Incoming packets from IPv4 addresses that share this many leading bits are throttled
+    /// together, as if they came from one address. The default of 32 throttles each address on
+    /// its own.
+    pub throttle_ipv4_prefix_len: u8,
+
+    /// Like [throttle_ipv4_prefix_len](DHTSettings::throttle_ipv4_prefix_len), for IPv6. The
+    /// default of 64 throttles each /64 network as one source, since whoever has one can
+    /// usually send from any address in it.
+    pub throttle_ipv6_prefix_len: u8,
+
+    /// What to do about other nodes that use our own Id. See [OwnIdCollisionPolicy].
+    pub own_id_collision_policy: OwnIdCollisionPolicy,
+
+    /// Minimum time in milliseconds between requests that [operations](crate::dht::operations)
+    /// send to the same node. This holds across all the operations running on the DHT at
+    /// once, so that many concurrent lookups don't hammer the nodes they share. See
+    /// [OperationScheduler](crate::dht::operations::OperationScheduler). Defaults to 1000,
+    /// which means an announce right after a get_peers to the same node waits up to a second.
+    /// 0 turns the pacing off.
+    pub operation_request_interval_millis: u64,
+
+    /// If set, replies to IPv4 addresses are trimmed to fit in this many bytes, so that a
+    /// high [max_peers_response](crate::dht::DHTSettings::max_peers_response) or
+    /// [max_sample_response](crate::dht::DHTSettings::max_sample_response) can't make them
+    /// too big for one packet (and fragmented or dropped on the way). Extra nodes are dropped
+    /// first, then peers or samples. Replies to IPv6 addresses are limited by
+    /// [max_ipv6_response_bytes](crate::dht::DHTSettings::max_ipv6_response_bytes) instead.
+    ///
+    /// Defaults to `Some(1472)`, the most that fits in one IPv4 packet on a link with the
+    /// usual 1500 byte MTU, so bigger replies are trimmed unless this is set to None.
+    pub max_response_bytes: Option<usize>,
+
+    /// Like [max_response_bytes](crate::dht::DHTSettings::max_response_bytes), but for
+    /// replies to IPv6 addresses.
+    ///
+    /// Defaults to `Some(1232)`, the most that fits in one IPv6 packet on any IPv6 link
+    /// (whose MTU is at least 1280 bytes). IPv6 routers don't fragment packets, so a bigger
+    /// reply may be dropped on the way unless the whole path has a bigger MTU.
+    pub max_ipv6_response_bytes: Option<usize>,
+
+    /// Testing only: if true, announce_peer requests are accepted without checking their
+    /// token, so clients that don't get a token from get_peers first can still announce. This
+    /// lets anybody store any address as a peer, so it's only available with the `testing`
+    /// feature and must never be used on a real network.
+    #[cfg(any(test, feature = "testing"))]
+    pub accept_announces_without_token: bool,
+
+    /// Number of pinned info_hashes that peer storage makes room for up front. The
+    /// storage for unpinned info_hashes already makes room for max_torrents of them.
+    pub pinned_info_hashes_capacity: usize,
+
+    /// Number of buckets that the default routing table makes room for up front, so that
+    /// it doesn't reallocate as its buckets are split. Only used when the DHT is built
+    /// without its own routing table (see [DHTBuilder](crate::dht::DHTBuilder)).
+    pub routing_table_buckets_capacity: usize,
+
+    /// How far back (in seconds) [DHT::query_type_rates](crate::dht::DHT::query_type_rates)
+    /// counts incoming requests. Changing it starts the counts over.
+    pub query_rates_window_secs: u64,
+
+    /// What to do about requests from read-only nodes (see [ReadOnlyRequesterPolicy])
+    pub read_only_requester_policy: ReadOnlyRequesterPolicy,
+
+    /// If true, sample_infohashes requests get a KRPC server error reply (code 202) while we have
+    /// no info hashes stored, so that crawlers can tell there is nothing to sample here and move
+    /// on. Otherwise they get a response with no samples, as BEP51 specifies.
+    pub decline_empty_sample_infohashes: bool,
+
     /// If true, we will set the read only flag in outgoing requests to prevent
     /// other nodes from adding us to their routing tables. This is useful if
     /// we're behind a restrictive NAT/firewall and can't accept incoming
@@ -76,6 +284,7 @@ impl DHTSettings {
     pub fn default() -> DHTSettings {
         DHTSettings {
             token_secret_size: 10,
+            token_length: 4,
             max_peers_response: 128,
             max_sample_response: 50,
             min_sample_interval_secs: 10,
@@ -91,6 +300,43 @@ impl DHTSettings {
             ping_check_interval_secs: 10,
             outgoing_request_prune_secs: 30,
             outgoing_reqiest_check_interval_secs: 30,
+            max_recent_requests: 1024,
+            duplicate_request_window_secs: 10,
+            skip_duplicate_announces: false,
+            ip_tos: None,
+            reply_to_invalid_sender_id: false,
+            min_peers_per_torrent: 0,
+            max_nodes_per_response: 16,
+            require_closer_find_node_results: false,
+            reachability_window_secs: 15 * 60,
+            dual_stack: false,
+            response_verification_policy: ResponseVerificationPolicy::Drop,
+            max_send_bytes_per_sec: None,
+            min_verified_nodes_to_answer: 0,
+            minimal_responses: false,
+            enforce_id_ip_validity: true,
+            drop_log_sample_rate: 1,
+            max_nodes_with_peers: 0,
+            token_failure_events: false,
+            implied_port_policy: ImpliedPortPolicy::ImpliedWins,
+            ip4_maintenance_interval_secs: 10,
+            max_response_amplification: None,
+            max_unsolicited_response_bytes_per_min: None,
+            duplicate_peer_policy: DuplicatePeerPolicy::SameAddress,
+            max_concurrent_buddy_pings: 64,
+            throttle_ipv4_prefix_len: 32,
+            throttle_ipv6_prefix_len: 64,
+            own_id_collision_policy: OwnIdCollisionPolicy::Log,
+            operation_request_interval_millis: 1000,
+            max_response_bytes: Some(1472),
+            max_ipv6_response_bytes: Some(1232),
+            #[cfg(any(test, feature = "testing"))]
+            accept_announces_without_token: false,
+            pinned_info_hashes_capacity: 0,
+            routing_table_buckets_capacity: DEFAULT_BUCKETS_CAPACITY,
+            query_rates_window_secs: 10 * 60,
+            read_only_requester_policy: ReadOnlyRequesterPolicy::Answer,
+            decline_empty_sample_infohashes: false,
             read_only: false,
             routers: vec![
                 "router.bittorrent.com:6881".to_string(),
@@ -99,6 +345,117 @@ impl DHTSettings {
             ],
         }
     }
+
+    /// Checks for settings that the DHT can't work with, and returns an
+    /// [InvalidSettingsError](RustyDHTError::InvalidSettingsError) listing them if there are any.
+    ///
+    /// [DHTSettingsBuilder::build], [DHT::new](crate::dht::DHT::new) and
+    /// [DHT::update_settings](crate::dht::DHT::update_settings) refuse settings that fail this.
+    pub fn validate(&self) -> Result<(), RustyDHTError> {
+        let mut invalid = Vec::new();
+        if self.token_length == 0 {
+            invalid.push("token_length");
+        }
+        if matches!(self.max_send_bytes_per_sec, Some(rate) if rate < MIN_BYTES_PER_SEC) {
+            invalid.push("max_send_bytes_per_sec");
+        }
+        if self.ip4_maintenance_interval_secs == 0 {
+            invalid.push("ip4_maintenance_interval_secs");
+        }
+        if self.max_concurrent_buddy_pings == 0 {
+            invalid.push("max_concurrent_buddy_pings");
+        }
+        if self.outgoing_request_prune_secs == 0 {
+            invalid.push("outgoing_request_prune_secs");
+        }
+        if self.outgoing_reqiest_check_interval_secs == 0 {
+            invalid.push("outgoing_reqiest_check_interval_secs");
+        }
+        if invalid.is_empty() {
+            Ok(())
+        } else {
+            Err(RustyDHTError::InvalidSettingsError(invalid))
+        }
+    }
+}
+
+/// How [DHT](crate::dht::DHT) treats a response whose sender Id isn't the Id of the node
+/// that the request was sent to.
+///
+/// This only applies when the request was sent to a known Id (e.g. the `dest_id` of
+/// [send_request](crate::dht::DHT::send_request)). Responses are always required to come
+/// from the address the request was sent to and carry its transaction id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseVerificationPolicy {
+    /// Ignore the response, as if it never arrived
+    Drop,
+
+    /// Treat the response like any other: it's returned to the caller, and the responder
+    /// is added to the routing table and votes on our external IPv4 address if its Id is
+    /// valid for its IP
+    Accept,
+
+    /// Return the response to the caller, but don't add the responder (or the nodes it
+    /// returns from find_node) to the routing table or let it vote on our external IPv4 address
+    AcceptWithoutVoting,
+}
+
+/// Which port [DHT](crate::dht::DHT) stores for a peer whose announce_peer request sets
+/// `implied_port` but also has a non-zero `port`.
+///
+/// The spec says that `implied_port` wins and the request's source port is used. That's
+/// the port that the peer's uTP traffic comes from, and is the right one when the peer's
+/// NAT keeps the same mapping for its DHT and torrent traffic. But a NAT that rewrites
+/// source ports per destination makes the source port useless to everybody else, while
+/// an explicit `port` (e.g. one the peer forwarded) may still be reachable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImpliedPortPolicy {
+    /// Use the source port of the request, as the spec says
+    ImpliedWins,
+
+    /// Use the explicit `port`, unless it's zero
+    PreferExplicit,
+}
+
+/// What [DHT](crate::dht::DHT) does about a node that uses the same Id as ours.
+///
+/// That's very unlikely to happen by chance, so it's usually somebody trying to get in
+/// the way of lookups for our Id. Such a node is never added to the routing table,
+/// and never gets to vote on our external IPv4 address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OwnIdCollisionPolicy {
+    /// Don't do anything else
+    Ignore,
+
+    /// Log a warning. Only one of every hundred packets that use our Id is logged.
+    Log,
+
+    /// Log a warning and ignore all further requests from the node's IP address.
+    ///
+    /// Only a node that answers one of our requests with our Id is banned. The source
+    /// address of a request can be spoofed, so banning for those would let anyone get an
+    /// honest node's IP banned; they're only logged.
+    ///
+    /// Bans last until [DHT::unban_ip](crate::dht::DHT::unban_ip) lifts them, or until
+    /// they're forgotten to make room for newer ones (about a thousand are kept).
+    Ban,
+}
+
+/// What [DHT](crate::dht::DHT) does about requests from read-only nodes, i.e. ones with
+/// `ro=1` ([BEP0043](http://bittorrent.org/beps/bep_0043.html)).
+///
+/// Either way, a read-only node is never added to the routing table. Nodes only vote on
+/// our external IPv4 address in responses to our requests, so its requests never count as
+/// votes either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadOnlyRequesterPolicy {
+    /// Answer the request like any other, as BEP0043 allows
+    Answer,
+
+    /// Drop the request without answering it, as if it never arrived. It isn't passed on
+    /// to [subscribers](crate::dht::DHT::subscribe) and doesn't count toward
+    /// [is_reachable](crate::dht::DHT::is_reachable) or [query_type_rates](crate::dht::DHT::query_type_rates).
+    Ignore,
 }
 
 #[derive(Clone)]
@@ -124,6 +481,7 @@ impl DHTSettingsBuilder {
     }
 
     make_builder_method!(token_secret_size, usize);
+    make_builder_method!(token_length, usize);
     make_builder_method!(max_peers_response, usize);
     make_builder_method!(max_sample_response, usize);
     make_builder_method!(min_sample_interval_secs, i32);
@@ -139,10 +497,50 @@ impl DHTSettingsBuilder {
     make_builder_method!(ping_check_interval_secs, u64);
     make_builder_method!(outgoing_request_prune_secs, u64);
     make_builder_method!(outgoing_reqiest_check_interval_secs, u64);
+    make_builder_method!(max_recent_requests, usize);
+    make_builder_method!(duplicate_request_window_secs, u64);
+    make_builder_method!(skip_duplicate_announces, bool);
+    make_builder_method!(ip_tos, Option<u8>);
+    make_builder_method!(reply_to_invalid_sender_id, bool);
+    make_builder_method!(min_peers_per_torrent, usize);
+    make_builder_method!(max_nodes_per_response, usize);
+    make_builder_method!(require_closer_find_node_results, bool);
+    make_builder_method!(reachability_window_secs, u64);
+    make_builder_method!(dual_stack, bool);
+    make_builder_method!(response_verification_policy, ResponseVerificationPolicy);
+    make_builder_method!(max_send_bytes_per_sec, Option<u64>);
+    make_builder_method!(min_verified_nodes_to_answer, usize);
+    make_builder_method!(minimal_responses, bool);
+    make_builder_method!(enforce_id_ip_validity, bool);
+    make_builder_method!(drop_log_sample_rate, u64);
+    make_builder_method!(max_nodes_with_peers, usize);
+    make_builder_method!(token_failure_events, bool);
+    make_builder_method!(implied_port_policy, ImpliedPortPolicy);
+    make_builder_method!(ip4_maintenance_interval_secs, u64);
+    make_builder_method!(max_response_amplification, Option<usize>);
+    make_builder_method!(max_unsolicited_response_bytes_per_min, Option<usize>);
+    make_builder_method!(duplicate_peer_policy, DuplicatePeerPolicy);
+    make_builder_method!(max_concurrent_buddy_pings, usize);
+    make_builder_method!(throttle_ipv4_prefix_len, u8);
+    make_builder_method!(throttle_ipv6_prefix_len, u8);
+    make_builder_method!(own_id_collision_policy, OwnIdCollisionPolicy);
+    make_builder_method!(operation_request_interval_millis, u64);
+    make_builder_method!(max_response_bytes, Option<usize>);
+    make_builder_method!(max_ipv6_response_bytes, Option<usize>);
+    #[cfg(any(test, feature = "testing"))]
+    make_builder_method!(accept_announces_without_token, bool);
+    make_builder_method!(pinned_info_hashes_capacity, usize);
+    make_builder_method!(routing_table_buckets_capacity, usize);
+    make_builder_method!(query_rates_window_secs, u64);
+    make_builder_method!(read_only_requester_policy, ReadOnlyRequesterPolicy);
+    make_builder_method!(decline_empty_sample_infohashes, bool);
     make_builder_method!(read_only, bool);
     make_builder_method!(routers, Vec<String>);
 
-    pub fn build(self) -> DHTSettings {
-        self.settings
+    /// Returns the settings, or an [InvalidSettingsError](RustyDHTError::InvalidSettingsError)
+    /// if some of them can't work (see [DHTSettings::validate]).
+    pub fn build(self) -> Result<DHTSettings, RustyDHTError> {
+        self.settings.validate()?;
+        Ok(self.settings)
     }
 }
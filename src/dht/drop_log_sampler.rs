@@ -0,0 +1,72 @@
+/// Counts the packets dropped by the DHT, and decides which of them get a log line of
+/// their own.
+///
+/// Junk packets can arrive at a high rate, so logging every one of them would flood
+/// the logs. Instead, only a sample is logged individually and the rest are covered by
+/// a periodic summary.
+pub(crate) struct DropLogSampler {
+    sample_rate: u64,
+    total: u64,
+    since_summary: u64,
+}
+
+impl DropLogSampler {
+    pub(crate) fn new(sample_rate: u64) -> DropLogSampler {
+        DropLogSampler {
+            sample_rate,
+            total: 0,
+            since_summary: 0,
+        }
+    }
+
+    /// Changes how many drops there are for every one that's logged. With a `sample_rate`
+    /// of 0, none are.
+    pub(crate) fn set_sample_rate(&mut self, sample_rate: u64) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// Counts a dropped packet. Returns true if it should be logged, which is the case
+    /// for the first of every `sample_rate` drops.
+    pub(crate) fn record(&mut self) -> bool {
+        let should_log = self.total.checked_rem(self.sample_rate) == Some(0);
+        self.total += 1;
+        self.since_summary += 1;
+        should_log
+    }
+
+    /// Returns the number of packets dropped since the DHT started
+    pub(crate) fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns the number of packets dropped since the last call
+    pub(crate) fn take_since_summary(&mut self) -> u64 {
+        std::mem::take(&mut self.since_summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drop_log_sampler() {
+        let mut sampler = DropLogSampler::new(10);
+        let logged = (0..100).filter(|_| sampler.record()).count();
+        assert_eq!(logged, 10);
+        assert_eq!(sampler.total(), 100);
+
+        // Everything gets logged at a rate of 1, and nothing at 0
+        sampler.set_sample_rate(1);
+        assert_eq!((0..5).filter(|_| sampler.record()).count(), 5);
+        sampler.set_sample_rate(0);
+        assert_eq!((0..5).filter(|_| sampler.record()).count(), 0);
+        assert_eq!(sampler.total(), 110);
+
+        assert_eq!(sampler.take_since_summary(), 110);
+        assert_eq!(sampler.take_since_summary(), 0);
+        sampler.record();
+        assert_eq!(sampler.take_since_summary(), 1);
+        assert_eq!(sampler.total(), 111);
+    }
+}
@@ -0,0 +1,33 @@
+use crate::errors::RustyDHTError;
+use std::sync::Arc;
+
+/// What [DHT](crate::dht::DHT)'s event loop should do after it hits an error
+/// while handling incoming packets.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ErrorAction {
+    /// Log the error and keep processing packets
+    Continue,
+
+    /// Stop the event loop, returning the error from
+    /// [run_event_loop](crate::dht::DHT::run_event_loop)
+    Shutdown,
+}
+
+/// A function that decides whether an error encountered in the DHT's packet handling
+/// loop is recoverable.
+///
+/// Install a custom one with [DHTBuilder::error_policy](crate::dht::DHTBuilder::error_policy).
+pub type ErrorPolicy = Arc<dyn Fn(&RustyDHTError) -> ErrorAction + Send + Sync>;
+
+/// The error policy used by DHT unless another one is provided.
+///
+/// Packet parsing errors (including queries with a bad sender id) and connection
+/// tracking errors are recoverable. Everything else shuts down the event loop.
+pub fn default_error_policy(err: &RustyDHTError) -> ErrorAction {
+    match err {
+        RustyDHTError::PacketParseError(_)
+        | RustyDHTError::InvalidSenderIdError { .. }
+        | RustyDHTError::ConntrackError(_) => ErrorAction::Continue,
+        _ => ErrorAction::Shutdown,
+    }
+}
@@ -0,0 +1,200 @@
+use crate::dht::recv_batch::Datagram;
+use crate::errors::RustyDHTError;
+use crate::packets;
+use anyhow::anyhow;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// An in-memory stand-in for the UDP network, for tests.
+///
+/// DHTs built with [DHTBuilder::mock_network](crate::dht::DHTBuilder::mock_network) send
+/// and receive their packets through a MockNetwork instead of a real socket, so tests
+/// don't compete for ports and can control latency and packet loss. Packet loss is
+/// decided by a seeded random number generator, so a test sees the same losses every run.
+#[derive(Clone)]
+pub(crate) struct MockNetwork {
+    inner: Arc<Mutex<MockNetworkInner>>,
+}
+
+struct MockNetworkInner {
+    endpoints: HashMap<SocketAddr, mpsc::UnboundedSender<Datagram>>,
+    latency: Duration,
+    send_delay: Duration,
+    packet_loss: f64,
+    rng: StdRng,
+}
+
+impl MockNetwork {
+    pub(crate) fn new() -> MockNetwork {
+        MockNetwork {
+            inner: Arc::new(Mutex::new(MockNetworkInner {
+                endpoints: HashMap::new(),
+                latency: Duration::ZERO,
+                send_delay: Duration::ZERO,
+                packet_loss: 0.0,
+                rng: StdRng::seed_from_u64(0),
+            })),
+        }
+    }
+
+    /// Sets how long every packet takes to be delivered
+    pub(crate) fn set_latency(&self, latency: Duration) {
+        self.inner.lock().unwrap().latency = latency;
+    }
+
+    /// Sets how long each packet that a DHT sends keeps the sending task busy, like a slow
+    /// link would. Packets sent from [MockEndpoint]s aren't held up.
+    pub(crate) fn set_send_delay(&self, send_delay: Duration) {
+        self.inner.lock().unwrap().send_delay = send_delay;
+    }
+
+    /// Waits for as long as sending a packet from a DHT takes (see
+    /// [set_send_delay](MockNetwork::set_send_delay))
+    pub(crate) async fn wait_to_send(&self) {
+        let send_delay = self.inner.lock().unwrap().send_delay;
+        if !send_delay.is_zero() {
+            tokio::time::sleep(send_delay).await;
+        }
+    }
+
+    /// Sets the fraction (between 0 and 1) of packets that are silently dropped
+    pub(crate) fn set_packet_loss(&self, packet_loss: f64) {
+        self.inner.lock().unwrap().packet_loss = packet_loss;
+    }
+
+    /// Starts receiving the packets sent to `addr`.
+    ///
+    /// Panics if something else already receives on `addr`.
+    pub(crate) fn bind(&self, addr: SocketAddr) -> mpsc::UnboundedReceiver<Datagram> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let previous = self.inner.lock().unwrap().endpoints.insert(addr, tx);
+        assert!(previous.is_none(), "{} is already bound", addr);
+        rx
+    }
+
+    /// Creates an endpoint that tests can use to exchange raw packets with DHTs on the network
+    pub(crate) fn endpoint(&self, addr: SocketAddr) -> MockEndpoint {
+        MockEndpoint {
+            network: self.clone(),
+            addr,
+            rx: self.bind(addr),
+        }
+    }
+
+    /// Delivers `bytes` from `from` to `to`, unless the packet is lost or nobody is bound to `to`
+    pub(crate) fn send(&self, from: SocketAddr, to: SocketAddr, bytes: Vec<u8>) {
+        let (tx, latency) = {
+            let mut inner = self.inner.lock().unwrap();
+            let packet_loss = inner.packet_loss;
+            if packet_loss > 0.0 && inner.rng.gen_bool(packet_loss.min(1.0)) {
+                return;
+            }
+            match inner.endpoints.get(&to) {
+                Some(tx) => (tx.clone(), inner.latency),
+                None => return,
+            }
+        };
+
+        if latency.is_zero() {
+            let _ = tx.send((bytes, from));
+        } else {
+            tokio::spawn(async move {
+                tokio::time::sleep(latency).await;
+                let _ = tx.send((bytes, from));
+            });
+        }
+    }
+}
+
+/// A raw endpoint on a [MockNetwork], standing in for a remote node in tests
+pub(crate) struct MockEndpoint {
+    network: MockNetwork,
+    addr: SocketAddr,
+    rx: mpsc::UnboundedReceiver<Datagram>,
+}
+
+impl MockEndpoint {
+    /// Sends a single message to `dest` and returns the first message that comes back
+    pub(crate) async fn send_and_receive(
+        &mut self,
+        msg: packets::Message,
+        dest: SocketAddr,
+    ) -> Result<packets::Message, RustyDHTError> {
+        self.network.send(self.addr, dest, msg.to_bytes()?);
+        match tokio::time::timeout(Duration::from_secs(5), self.rx.recv()).await {
+            Ok(Some((bytes, _))) => packets::Message::from_bytes(&bytes),
+            Ok(None) => Err(RustyDHTError::GeneralError(anyhow!(
+                "Mock network endpoint was closed"
+            ))),
+            Err(e) => Err(RustyDHTError::TimeoutError(e.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Id;
+    use crate::packets::MessageBuilder;
+    use rand::thread_rng;
+
+    fn ping() -> packets::Message {
+        MessageBuilder::new_ping_request()
+            .sender_id(Id::from_random(&mut thread_rng()))
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_latency() {
+        let network = MockNetwork::new();
+        network.set_latency(Duration::from_millis(300));
+        let mut receiver = network.bind("10.0.0.1:6881".parse().unwrap());
+
+        let start = tokio::time::Instant::now();
+        network.send(
+            "10.0.0.2:6881".parse().unwrap(),
+            "10.0.0.1:6881".parse().unwrap(),
+            ping().to_bytes().unwrap(),
+        );
+        let (_, sender) = receiver.recv().await.unwrap();
+        assert_eq!(sender, "10.0.0.2:6881".parse().unwrap());
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_packet_loss() {
+        let network = MockNetwork::new();
+        network.set_packet_loss(0.5);
+        let mut receiver = network.bind("10.0.0.1:6881".parse().unwrap());
+
+        for _ in 0..100 {
+            network.send(
+                "10.0.0.2:6881".parse().unwrap(),
+                "10.0.0.1:6881".parse().unwrap(),
+                ping().to_bytes().unwrap(),
+            );
+        }
+        let mut received = 0;
+        while receiver.try_recv().is_ok() {
+            received += 1;
+        }
+        assert!(received > 25 && received < 75, "{} received", received);
+
+        // Nothing gets through a completely lossy network
+        network.set_packet_loss(1.0);
+        let mut endpoint = network.endpoint("10.0.0.2:6882".parse().unwrap());
+        assert!(matches!(
+            endpoint
+                .send_and_receive(ping(), "10.0.0.1:6881".parse().unwrap())
+                .await,
+            Err(RustyDHTError::TimeoutError(_))
+        ));
+        assert!(receiver.try_recv().is_err());
+    }
+}
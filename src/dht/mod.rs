@@ -1,13 +1,48 @@
 mod dht;
 pub use dht::*;
 
+mod address_rewrite;
+pub use address_rewrite::*;
+
+mod announce_authorizer;
+pub use announce_authorizer::*;
+
+mod bandwidth_limiter;
+
+mod coverage;
+pub use coverage::CoverageReport;
+
 mod dht_settings;
 pub use dht_settings::*;
 
+mod drop_log_sampler;
+
+mod error_policy;
+pub use error_policy::*;
+
 /// [DHT](crate::dht::DHT) allows callers to [subscribe](crate::dht::DHT::subscribe) to receive
 /// realtime events via a channel. This module contains the enums/structs for the events.
 pub mod dht_event;
 
+#[cfg(test)]
+mod mock_network;
+
+mod packet_tap;
+pub use packet_tap::{PacketDirection, PacketRecord};
+
+mod port_consensus;
+
+mod query_rates;
+pub use query_rates::{QueryType, QueryTypeRates};
+
+mod recv_batch;
+
+mod restart_policy;
+pub use restart_policy::*;
+
+mod router_stats;
+pub use router_stats::RouterStats;
+
 mod socket;
 
 /// Functions that use [DHT](crate::dht::DHT) to perform high-level operations on the network.
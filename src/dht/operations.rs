@@ -1,43 +1,152 @@
-use crate::common::{Id, Node};
+use crate::common::{AddressFamily, Id, InfoHash, Node};
 use crate::dht::DHT;
 use crate::errors::RustyDHTError;
 use crate::packets;
 use crate::packets::MessageBuilder;
+use crate::shutdown::ShutdownReceiver;
 use crate::storage::buckets::Buckets;
 use crate::storage::node_wrapper::NodeWrapper;
+use anyhow::anyhow;
 use futures::StreamExt;
 use log::{debug, error, info, trace, warn};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
 use std::time::{Duration, Instant};
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::{AbortHandle, JoinHandle, JoinSet};
+
+/// How long operations wait for each individual node to respond, unless
+/// [OperationOptions::request_timeout] says otherwise.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Time limit for each announce made by [announce_peer_periodic]
+const PERIODIC_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Shorter intervals given to [announce_peer_periodic] are raised to this
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// [announce_peer_periodic] waits at most this many intervals between announces while
+/// backing off from failures
+const MAX_ANNOUNCE_BACKOFF_FACTOR: u32 = 8;
+
+/// Maximum number of info_hashes that a [PresenceManager] announces at the same time
+const MAX_CONCURRENT_PRESENCE_ANNOUNCES: usize = 4;
+
+/// Number of rounds of get_peers requests that [swarm_health] sends at most
+const SWARM_HEALTH_MAX_ROUNDS: usize = 3;
+
+/// A DHT that knows this many nodes or fewer is probably still bootstrapping
+pub(crate) const FEW_NODES: usize = 5;
 
 /// Announce that you are a peer for a specific info_hash, returning the nodes
 /// that were successfully announced to.
 ///
 /// # Arguments
 /// * `dht` - DHT instance that will be used to communicate
-/// * `info_hash` - Id of the torrent
+/// * `info_hash` - [InfoHash](crate::common::InfoHash) (or plain Id) of the torrent
 /// * `port` - optional port that other peers should use to contact your peer.
-/// If omitted, `implied_port` will be set true on the announce messages and
+///   If omitted, `implied_port` will be set true on the announce messages and
+///   other nodes will use the source port of our packets. If the DHT has an
+///   [AddressRewriter](crate::dht::AddressRewriter), the port it maps our address
+///   to is announced instead. Otherwise, if other nodes report that the DHT's own port
+///   shows up as a different [external port](crate::dht::DHT::get_external_port),
+///   that's announced explicitly in place of the DHT's own port.
 /// * `timeout` - the maximum amount of time that will be spent searching for
 /// peers close to `info_hash` before announcing to them. This means that this
 /// function can actually take a bit longer than `timeout`, since it will take
 /// a moment after `timeout` has elapsed to announce to the nodes.
 pub async fn announce_peer(
     dht: &DHT,
-    info_hash: Id,
+    info_hash: impl Into<InfoHash>,
     port: Option<u16>,
     timeout: Duration,
 ) -> Result<Vec<Node>, RustyDHTError> {
-    let mut to_ret = Vec::new();
+    announce_peer_with_options(dht, info_hash, port, timeout, &OperationOptions::default()).await
+}
+
+/// Same as [announce_peer](crate::dht::operations::announce_peer), but with
+/// [OperationOptions].
+pub async fn announce_peer_with_options(
+    dht: &DHT,
+    info_hash: impl Into<InfoHash>,
+    port: Option<u16>,
+    timeout: Duration,
+    options: &OperationOptions,
+) -> Result<Vec<Node>, RustyDHTError> {
+    let info_hash = info_hash.into().0;
+    let stored_locally = options.announce_locally && dht.announce_self_locally(info_hash, port)?;
 
-    // Figure out which nodes we want to announce to
-    let get_peers_result = get_peers(dht, info_hash, timeout).await?;
+    // Figure out which nodes we want to announce to. Having nobody to announce to isn't a
+    // failure if we've at least stored ourselves.
+    let get_peers_result =
+        match get_peers_with_options(dht, info_hash, timeout, options, |_| {}).await {
+            Err(RustyDHTError::NoNodesAvailableError) if stored_locally => return Ok(Vec::new()),
+            result => result?,
+        };
 
     trace!(target:"rustydht_lib::operations::announce_peer", "{} nodes responded to get_peers", get_peers_result.responders.len());
 
+    // Announce to the nearest 8
+    let responders = get_peers_result
+        .responders()
+        .into_iter()
+        .take(8)
+        .map(|responder| (responder.node, responder.token));
+    send_announces(dht, info_hash, port, responders, options).await
+}
+
+/// Announce that you are a peer for `info_hash` to the given nodes, using the tokens they
+/// gave us, and return the nodes that were successfully announced to.
+///
+/// Unlike [announce_peer](crate::dht::operations::announce_peer), this doesn't look up
+/// the nodes near `info_hash` first. It's meant for announcing repeatedly to nodes that
+/// are already known, e.g. from [find_storage_nodes](crate::dht::operations::find_storage_nodes).
+/// A node's token expires after a while (usually 5 to 10 minutes), after which it has to
+/// be looked up again.
+///
+/// # Arguments
+/// * `dht` - DHT instance that will be used to communicate
+/// * `info_hash` - [InfoHash](crate::common::InfoHash) (or plain Id) of the torrent
+/// * `port` - optional port that other peers should use to contact your peer. Treated the
+///   same way as by [announce_peer](crate::dht::operations::announce_peer).
+/// * `responders` - the nodes to announce to, each with the token it replied with
+/// * `timeout` - how long the whole operation may take. The announces are sent at the
+///   same time, but one to a node that another operation is busy with first waits its
+///   turn (see [operation_request_interval_millis](crate::dht::DHTSettings::operation_request_interval_millis)),
+///   and that wait counts against the timeout.
+pub async fn announce_to_nodes(
+    dht: &DHT,
+    info_hash: impl Into<InfoHash>,
+    port: Option<u16>,
+    responders: &[GetPeersResponder],
+    timeout: Duration,
+) -> Result<Vec<Node>, RustyDHTError> {
+    let options = OperationOptions {
+        request_timeout: Some(timeout),
+        ..OperationOptions::default()
+    };
+    let responders = responders
+        .iter()
+        .map(|responder| (responder.node.clone(), responder.token.clone()));
+    send_announces(dht, info_hash.into().0, port, responders, &options).await
+}
+
+/// Sends announce_peer for `info_hash` to each of the nodes in `responders` (with the
+/// token it gave us), and returns the nodes that responded.
+async fn send_announces(
+    dht: &DHT,
+    info_hash: Id,
+    port: Option<u16>,
+    responders: impl IntoIterator<Item = (Node, Vec<u8>)>,
+    options: &OperationOptions,
+) -> Result<Vec<Node>, RustyDHTError> {
+    let mut to_ret = Vec::new();
+
+    let port: Option<u16> = dht.advertised_port(port)?;
     let announce_builder = MessageBuilder::new_announce_peer_request()
-        .sender_id(dht.get_id())
+        .sender_id(options.sender_id(dht))
         .read_only(dht.get_settings().read_only)
         .target(info_hash)
         .port(match port {
@@ -49,25 +158,28 @@ pub async fn announce_peer(
             None => true,
         });
 
-    // Prepare to send packets to the nearest 8
+    // Announces to nodes that other operations are busy with have to wait their turn, but
+    // that counts against the request timeout so that the whole thing stays bounded by it
+    let deadline = tokio::time::Instant::now() + options.request_timeout();
     let mut todos = futures::stream::FuturesUnordered::new();
-    for responder in get_peers_result.responders().into_iter().take(8) {
+    for (node, token) in responders {
         let builder = announce_builder.clone();
         todos.push(async move {
             let announce_req = builder
-                .token(responder.token)
+                .token(token)
                 .build()
                 .expect("Failed to build announce_peer request");
-            match dht
-                .send_request(
-                    announce_req,
-                    responder.node.address,
-                    Some(responder.node.id),
-                    Some(Duration::from_secs(5)),
-                )
-                .await
+            match scheduled_request(
+                dht,
+                announce_req,
+                node.address,
+                Some(node.id),
+                options,
+                Some(deadline),
+            )
+            .await
             {
-                Ok(_) => Ok(responder.node),
+                Ok(_) => Ok(node),
                 Err(e) => Err(e),
             }
         });
@@ -95,26 +207,769 @@ pub async fn announce_peer(
     Ok(to_ret)
 }
 
+/// Keeps re-announcing that you are a peer for `info_hash`, so that you stay discoverable
+/// as other nodes expire old peers.
+///
+/// Runs [announce_peer](crate::dht::operations::announce_peer) right away and then again
+/// every `interval`, until `cancel` is signalled. If announces keep failing (or reach no
+/// nodes), the time between them doubles each time, up to 8 times `interval`. An `interval`
+/// shorter than a second is treated as a second.
+///
+/// The announcing runs in a task on the tokio runtime. Await the returned handle to wait
+/// for it to stop after cancelling. The task doesn't keep the DHT alive (so it doesn't hold
+/// up shutting the DHT down), and stops by itself once every other reference to the DHT is
+/// dropped.
+pub fn announce_peer_periodic(
+    dht: Arc<DHT>,
+    info_hash: impl Into<InfoHash>,
+    port: Option<u16>,
+    interval: Duration,
+    cancel: ShutdownReceiver,
+) -> JoinHandle<()> {
+    let info_hash = info_hash.into();
+    let dht = Arc::downgrade(&dht);
+    tokio::spawn(announce_periodically(
+        info_hash.0,
+        move || {
+            let dht = dht.upgrade();
+            async move {
+                match dht {
+                    Some(dht) => {
+                        announce_peer(&dht, info_hash, port, PERIODIC_ANNOUNCE_TIMEOUT).await
+                    }
+                    None => Err(RustyDHTError::ShutdownError(anyhow!("The DHT was dropped"))),
+                }
+            }
+        },
+        interval,
+        None,
+        cancel,
+    ))
+}
+
+/// Calls `announce` (which announces `info_hash`) at `first_at` (or right away) and then
+/// every `interval` (longer after failures) until `cancel` is signalled, or `announce` fails
+/// with a [ShutdownError](crate::errors::RustyDHTError::ShutdownError).
+async fn announce_periodically<F, Fut>(
+    info_hash: Id,
+    mut announce: F,
+    interval: Duration,
+    first_at: Option<tokio::time::Instant>,
+    mut cancel: ShutdownReceiver,
+) where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Vec<Node>, RustyDHTError>>,
+{
+    let interval = interval.max(MIN_ANNOUNCE_INTERVAL);
+    if let Some(first_at) = first_at {
+        tokio::select! {
+            _ = cancel.watch() => return,
+            _ = tokio::time::sleep_until(first_at) => {}
+        }
+    }
+    let mut backoff_factor = 1;
+    loop {
+        tokio::select! {
+            _ = cancel.watch() => return,
+            result = announce() => match result {
+                Ok(nodes) if !nodes.is_empty() => {
+                    debug!(target: "rustydht_lib::operations::announce_peer_periodic", "Announced {} to {} nodes", info_hash, nodes.len());
+                    backoff_factor = 1;
+                }
+                Ok(_) => {
+                    warn!(target: "rustydht_lib::operations::announce_peer_periodic", "Announce of {} didn't reach any nodes", info_hash);
+                    backoff_factor = (backoff_factor * 2).min(MAX_ANNOUNCE_BACKOFF_FACTOR);
+                }
+                Err(RustyDHTError::ShutdownError(e)) => {
+                    debug!(target: "rustydht_lib::operations::announce_peer_periodic", "Stopping announcing {}: {}", info_hash, e);
+                    return;
+                }
+                Err(e) => {
+                    warn!(target: "rustydht_lib::operations::announce_peer_periodic", "Announce of {} failed: {}", info_hash, e);
+                    backoff_factor = (backoff_factor * 2).min(MAX_ANNOUNCE_BACKOFF_FACTOR);
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = cancel.watch() => return,
+            _ = tokio::time::sleep(interval * backoff_factor) => {}
+        }
+    }
+}
+
+/// Keeps a changing set of info_hashes announced, like [announce_peer_periodic] does for
+/// a single one.
+///
+/// Each info_hash is announced as soon as it's added and then every `interval`, backing off
+/// (up to 8 times `interval`) while its announces fail or reach no nodes. Only a few announces
+/// run at the same time, so that a large set of info_hashes doesn't flood the network.
+///
+/// Changing the port of an info_hash doesn't announce it again right away, but once
+/// `interval` has passed since its last announce, so that changes in quick succession
+/// are sent as one announce.
+///
+/// The announcing runs in tasks on the tokio runtime until `cancel` is signalled. Like with
+/// [announce_peer_periodic], they don't keep the DHT alive.
+pub struct PresenceManager {
+    entries: Arc<Mutex<HashMap<Id, PresenceEntry>>>,
+    wakeup: Arc<Notify>,
+    handle: JoinHandle<()>,
+}
+
+/// How announcing an info_hash managed by a [PresenceManager] has been going.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PresenceStatus {
+    /// The port being announced. None means `implied_port`.
+    pub port: Option<u16>,
+
+    /// When the most recent announce finished, if any has yet (possibly with a previous port)
+    pub last_announced: Option<Instant>,
+
+    /// Number of nodes the most recent announce reached
+    pub nodes_reached: usize,
+
+    /// Number of announces in a row that failed or reached no nodes
+    pub failures: u32,
+}
+
+struct PresenceEntry {
+    status: PresenceStatus,
+
+    /// The task that announces the info_hash, once [PresenceManager::run] has started it
+    task: Option<AbortHandle>,
+}
+
+impl PresenceManager {
+    /// Creates a manager that announces the info_hashes [added](PresenceManager::add) to it
+    /// on `dht` every `interval` (at least a second), until `cancel` is signalled.
+    pub fn new(dht: Arc<DHT>, interval: Duration, cancel: ShutdownReceiver) -> PresenceManager {
+        let interval = interval.max(MIN_ANNOUNCE_INTERVAL);
+        let entries = Arc::new(Mutex::new(HashMap::new()));
+        let wakeup = Arc::new(Notify::new());
+        let handle = tokio::spawn(PresenceManager::run(
+            Arc::downgrade(&dht),
+            entries.clone(),
+            wakeup.clone(),
+            interval,
+            cancel,
+        ));
+        PresenceManager {
+            entries,
+            wakeup,
+            handle,
+        }
+    }
+
+    /// Starts announcing `info_hash` on `port` (see [announce_peer]). If it's already
+    /// managed with a different port, it's announced with the new port from then on, starting
+    /// once the interval since its last announce has passed.
+    pub fn add(&self, info_hash: impl Into<InfoHash>, port: Option<u16>) {
+        let info_hash = info_hash.into().0;
+        let mut entries = self.entries.lock().unwrap();
+        let last_announced = match entries.get(&info_hash) {
+            Some(entry) if entry.status.port == port => return,
+            Some(entry) => entry.status.last_announced,
+            None => None,
+        };
+        let previous = entries.insert(
+            info_hash,
+            PresenceEntry {
+                status: PresenceStatus {
+                    port,
+                    last_announced,
+                    nodes_reached: 0,
+                    failures: 0,
+                },
+                task: None,
+            },
+        );
+        if let Some(task) = previous.and_then(|entry| entry.task) {
+            task.abort();
+        }
+        self.wakeup.notify_one();
+    }
+
+    /// Stops announcing `info_hash`. Returns false if it wasn't being announced.
+    pub fn remove(&self, info_hash: impl Into<InfoHash>) -> bool {
+        match self.entries.lock().unwrap().remove(&info_hash.into().0) {
+            Some(entry) => {
+                if let Some(task) = entry.task {
+                    task.abort();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns how announcing `info_hash` has been going, or None if it isn't managed.
+    pub fn status(&self, info_hash: impl Into<InfoHash>) -> Option<PresenceStatus> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&info_hash.into().0)
+            .map(|entry| entry.status.clone())
+    }
+
+    /// Returns the status of every managed info_hash
+    pub fn statuses(&self) -> Vec<(Id, PresenceStatus)> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(info_hash, entry)| (*info_hash, entry.status.clone()))
+            .collect()
+    }
+
+    /// Waits for the announcing task to stop after `cancel` is signalled
+    pub async fn join(self) {
+        if let Err(e) = self.handle.await {
+            error!(target: "rustydht_lib::operations::PresenceManager", "Announcing task failed: {}", e);
+        }
+    }
+
+    /// Starts a task running [announce_periodically] for every entry that doesn't have one
+    /// yet, whenever `wakeup` says entries were added
+    async fn run(
+        dht: Weak<DHT>,
+        entries: Arc<Mutex<HashMap<Id, PresenceEntry>>>,
+        wakeup: Arc<Notify>,
+        interval: Duration,
+        mut cancel: ShutdownReceiver,
+    ) {
+        let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_PRESENCE_ANNOUNCES));
+        let mut tasks = JoinSet::new();
+        loop {
+            while tasks.try_join_next().is_some() {}
+            for (info_hash, entry) in entries.lock().unwrap().iter_mut() {
+                if entry.task.is_some() {
+                    continue;
+                }
+                let info_hash = *info_hash;
+                let port = entry.status.port;
+                let first_at = entry.status.last_announced.map(|last_announced| {
+                    tokio::time::Instant::from_std(last_announced) + interval
+                });
+                let dht = dht.clone();
+                let entries = entries.clone();
+                let permits = permits.clone();
+                let announce = move || {
+                    let dht = dht.upgrade();
+                    let entries = entries.clone();
+                    let permits = permits.clone();
+                    async move {
+                        let dht = dht.ok_or_else(|| {
+                            RustyDHTError::ShutdownError(anyhow!("The DHT was dropped"))
+                        })?;
+                        let _permit = permits.acquire().await.map_err(|e| {
+                            RustyDHTError::ShutdownError(anyhow!("Announcing was stopped: {}", e))
+                        })?;
+                        let result =
+                            announce_peer(&dht, info_hash, port, PERIODIC_ANNOUNCE_TIMEOUT).await;
+                        PresenceManager::record_result(
+                            &mut entries.lock().unwrap(),
+                            info_hash,
+                            port,
+                            &result,
+                        );
+                        result
+                    }
+                };
+                entry.task = Some(tasks.spawn(announce_periodically(
+                    info_hash,
+                    announce,
+                    interval,
+                    first_at,
+                    cancel.clone(),
+                )));
+            }
+
+            tokio::select! {
+                _ = cancel.watch() => break,
+                _ = wakeup.notified() => {}
+            }
+        }
+        while tasks.join_next().await.is_some() {}
+    }
+
+    fn record_result(
+        entries: &mut HashMap<Id, PresenceEntry>,
+        info_hash: Id,
+        port: Option<u16>,
+        result: &Result<Vec<Node>, RustyDHTError>,
+    ) {
+        // It may have been re-added with another port while we were announcing
+        let entry = match entries.get_mut(&info_hash) {
+            Some(entry) if entry.status.port == port => entry,
+            _ => return,
+        };
+        match result {
+            Ok(nodes) if !nodes.is_empty() => {
+                entry.status.nodes_reached = nodes.len();
+                entry.status.failures = 0;
+            }
+            _ => {
+                entry.status.nodes_reached = 0;
+                entry.status.failures = entry.status.failures.saturating_add(1);
+            }
+        }
+        entry.status.last_announced = Some(tokio::time::Instant::now().into_std());
+    }
+}
+
+/// Spaces out the requests that operations send to each node, so that all the operations
+/// running on a DHT at once stay under a shared per-node rate.
+///
+/// Every [DHT] has one (see [DHT::operation_scheduler]), which the operations in this module
+/// wait on before each request. Each lookup already waits a second between its own rounds,
+/// but ten lookups running at once would otherwise send ten requests to a node they share in
+/// the same instant.
+pub struct OperationScheduler {
+    state: Mutex<SchedulerState>,
+
+    /// Woken whenever a node's queue of waiting requests moves along
+    turns: Notify,
+}
+
+struct SchedulerState {
+    min_interval: Duration,
+    next_ticket: u64,
+    nodes: HashMap<SocketAddr, NodeQueue>,
+}
+
+/// The requests waiting for their turn to be sent to one node
+#[derive(Default)]
+struct NodeQueue {
+    /// When the node was last sent a request
+    last_sent: Option<tokio::time::Instant>,
+
+    /// Tickets of the requests waiting for their turn, in the order they asked for it
+    waiting: VecDeque<u64>,
+}
+
+/// A request's place in a node's queue. Leaves the queue when dropped, so that a request
+/// whose operation gave up doesn't hold up the ones behind it.
+struct SchedulerTicket<'a> {
+    scheduler: &'a OperationScheduler,
+    addr: SocketAddr,
+    ticket: Option<u64>,
+}
+
+impl Drop for SchedulerTicket<'_> {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket {
+            if let Some(queue) = self
+                .scheduler
+                .state
+                .lock()
+                .unwrap()
+                .nodes
+                .get_mut(&self.addr)
+            {
+                queue.waiting.retain(|waiting| *waiting != ticket);
+            }
+            self.scheduler.turns.notify_waiters();
+        }
+    }
+}
+
+impl OperationScheduler {
+    /// Creates a scheduler that lets at most one request per `min_interval` through to
+    /// each node.
+    pub fn new(min_interval: Duration) -> OperationScheduler {
+        OperationScheduler {
+            state: Mutex::new(SchedulerState {
+                min_interval,
+                next_ticket: 0,
+                nodes: HashMap::new(),
+            }),
+            turns: Notify::new(),
+        }
+    }
+
+    /// Returns the minimum time between requests to the same node
+    pub fn min_interval(&self) -> Duration {
+        self.state.lock().unwrap().min_interval
+    }
+
+    /// Changes the minimum time between requests to the same node. A shorter interval
+    /// only speeds up the requests that start waiting after the change.
+    pub fn set_min_interval(&self, min_interval: Duration) {
+        self.state.lock().unwrap().min_interval = min_interval;
+    }
+
+    /// Waits until it's our turn to send a request to `addr`. Turns are handed out in the
+    /// order they're asked for.
+    ///
+    /// Returns a [TimeoutError](RustyDHTError::TimeoutError) if our turn hasn't come by
+    /// `deadline`. A wait that times out or is cancelled gives up its place in the queue.
+    pub async fn wait_turn(
+        &self,
+        addr: SocketAddr,
+        deadline: Option<tokio::time::Instant>,
+    ) -> Result<(), RustyDHTError> {
+        let ticket = {
+            let mut state = self.state.lock().unwrap();
+            let now = tokio::time::Instant::now();
+            let min_interval = state.min_interval;
+            // Nodes that nobody is waiting for and that can be sent to right away needn't be kept
+            state.nodes.retain(|_, queue| {
+                !queue.waiting.is_empty()
+                    || matches!(queue.last_sent, Some(last_sent) if last_sent + min_interval > now)
+            });
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+            state
+                .nodes
+                .entry(addr)
+                .or_default()
+                .waiting
+                .push_back(ticket);
+            ticket
+        };
+        let mut ticket = SchedulerTicket {
+            scheduler: self,
+            addr,
+            ticket: Some(ticket),
+        };
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, self.take_turn(&mut ticket))
+                .await
+                .map_err(|_| {
+                    RustyDHTError::TimeoutError(anyhow!(
+                        "Timed out waiting for our turn to send a request to {}",
+                        addr
+                    ))
+                }),
+            None => {
+                self.take_turn(&mut ticket).await;
+                Ok(())
+            }
+        }
+    }
+
+    /// Waits until `ticket` is first in its node's queue and the node's interval has
+    /// passed, then takes it out of the queue.
+    async fn take_turn(&self, ticket: &mut SchedulerTicket<'_>) {
+        let id = ticket.ticket.expect("Ticket already took its turn");
+        loop {
+            // Created before checking the queue, so that a change right after isn't missed
+            let moved_along = self.turns.notified();
+            let turn_at = {
+                let mut state = self.state.lock().unwrap();
+                let min_interval = state.min_interval;
+                let queue = state
+                    .nodes
+                    .get_mut(&ticket.addr)
+                    .expect("Queue of a waiting ticket was removed");
+                let now = tokio::time::Instant::now();
+                if queue.waiting.front() == Some(&id) {
+                    let turn_at = match queue.last_sent {
+                        Some(last_sent) => last_sent + min_interval,
+                        None => now,
+                    };
+                    if turn_at <= now {
+                        queue.waiting.pop_front();
+                        queue.last_sent = Some(now);
+                        ticket.ticket = None;
+                        break;
+                    }
+                    Some(turn_at)
+                } else {
+                    None
+                }
+            };
+            match turn_at {
+                Some(turn_at) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(turn_at) => {}
+                        _ = moved_along => {}
+                    }
+                }
+                None => moved_along.await,
+            }
+        }
+        self.turns.notify_waiters();
+    }
+}
+
+/// Sends `req` to `dest` once the DHT's [OperationScheduler] says it's our turn, and
+/// records what happened in the [LookupTrace] of `options`, if any.
+///
+/// If there's a `deadline`, both waiting for our turn and waiting for the response have to
+/// be done by then. Otherwise only the response wait is bounded, by the request timeout of
+/// `options`, and the caller is expected to bound the whole operation.
+async fn scheduled_request(
+    dht: &DHT,
+    req: packets::Message,
+    dest: SocketAddr,
+    dest_id: Option<Id>,
+    options: &OperationOptions,
+    deadline: Option<tokio::time::Instant>,
+) -> Result<packets::Message, RustyDHTError> {
+    dht.operation_scheduler().wait_turn(dest, deadline).await?;
+    let traced_request = match (&options.trace, &req.message_type) {
+        (Some(_), packets::MessageType::Request(request)) => Some(request.clone()),
+        _ => None,
+    };
+    let started = tokio::time::Instant::now();
+    let timeout = match deadline {
+        Some(deadline) => deadline.saturating_duration_since(started),
+        None => options.request_timeout(),
+    };
+    let result = dht.send_request(req, dest, dest_id, Some(timeout)).await;
+    if let (Some(trace), Some(request)) = (&options.trace, traced_request) {
+        let outcome = match &result {
+            Ok(reply) => LookupOutcome::Responded(reply.message_type.clone()),
+            Err(RustyDHTError::TimeoutError(_)) => LookupOutcome::TimedOut,
+            Err(e) => LookupOutcome::Failed(e.to_string()),
+        };
+        trace.record(LookupStep {
+            addr: dest,
+            id: dest_id,
+            request,
+            outcome,
+            latency: started.elapsed(),
+        });
+    }
+    result
+}
+
+/// What happened to one request that an operation sent, as recorded in a [LookupTrace]
+#[derive(Debug, PartialEq, Clone)]
+pub struct LookupStep {
+    /// The address of the node that was asked
+    pub addr: SocketAddr,
+
+    /// The Id of the node that was asked, if we knew it
+    pub id: Option<Id>,
+
+    /// What we asked
+    pub request: packets::RequestSpecific,
+
+    /// What came of it
+    pub outcome: LookupOutcome,
+
+    /// How long it took for the node to respond (or for us to give up on it)
+    pub latency: Duration,
+}
+
+/// How a node answered a request in a [LookupStep]
+#[derive(Debug, PartialEq, Clone)]
+pub enum LookupOutcome {
+    /// The node replied with this (which may be an error message)
+    Responded(packets::MessageType),
+
+    /// The node didn't reply within the request timeout
+    TimedOut,
+
+    /// The request couldn't be sent, or the reply couldn't be used
+    Failed(String),
+}
+
+/// Collects a [LookupStep] for every request an operation sends, for diagnosing lookups
+/// that come up short (e.g. a get_peers that found nothing).
+///
+/// Pass a clone of it in [OperationOptions::trace], and read the [steps](LookupTrace::steps)
+/// from the original after the operation returns. The steps are in the order the requests
+/// finished. Operations without a trace don't record anything.
+#[derive(Debug, Clone, Default)]
+pub struct LookupTrace {
+    steps: Arc<Mutex<Vec<LookupStep>>>,
+}
+
+impl LookupTrace {
+    /// Creates a trace with no steps recorded yet
+    pub fn new() -> LookupTrace {
+        LookupTrace::default()
+    }
+
+    /// Returns the steps recorded so far
+    pub fn steps(&self) -> Vec<LookupStep> {
+        self.steps.lock().unwrap().clone()
+    }
+
+    fn record(&self, step: LookupStep) {
+        self.steps.lock().unwrap().push(step);
+    }
+}
+
+/// Two traces are equal if they're clones of each other, and so record the same steps
+impl PartialEq for LookupTrace {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.steps, &other.steps)
+    }
+}
+
+/// Options that change how an operation (e.g. [find_node_with_options](crate::dht::operations::find_node_with_options))
+/// runs. The defaults behave the same as the plain operations.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct OperationOptions {
+    /// Sender Id to put in outgoing requests instead of the DHT's own Id. Useful for
+    /// crawlers that want to see how other nodes respond to different Ids.
+    pub sender_id: Option<Id>,
+
+    /// Maximum number of rounds of requests to send. If the lookup hasn't stopped making
+    /// progress by then, it returns what it has found so far. Unlimited by default (only
+    /// the timeout applies).
+    pub max_rounds: Option<usize>,
+
+    /// If true, [announce_peer_with_options](crate::dht::operations::announce_peer_with_options)
+    /// also stores our own peer in the DHT's peer storage, so that we serve ourselves in
+    /// get_peers responses. Skipped if we don't know a usable address for ourselves (e.g.
+    /// our external IPv4 address is unknown and the socket is bound to 0.0.0.0). Once our
+    /// peer is stored, the announce succeeds (with no nodes) even if there were no other
+    /// nodes to announce to.
+    pub announce_locally: bool,
+
+    /// How long to wait for each node to respond before giving up on it. This is separate
+    /// from the timeout of the whole operation, so that a node that never responds only
+    /// holds up its round of the lookup for this long. Defaults to [DEFAULT_REQUEST_TIMEOUT].
+    pub request_timeout: Option<Duration>,
+
+    /// Maximum number of unique peers that [get_peers_with_options](crate::dht::operations::get_peers_with_options)
+    /// collects. Once it has this many, it ignores any more and stops the lookup at the end
+    /// of the current round. Unlimited by default, which can take a lot of memory for a
+    /// huge swarm.
+    pub max_total_peers: Option<usize>,
+
+    /// If set, every request the operation sends (and what came of it) is recorded here.
+    /// Off by default, so that operations don't pay for recording.
+    pub trace: Option<LookupTrace>,
+
+    /// Which address families [find_node_with_options](crate::dht::operations::find_node_with_options)
+    /// and [get_peers_with_options](crate::dht::operations::get_peers_with_options) work with.
+    /// Only nodes of those families are queried, their requests ask for nodes (and peers)
+    /// of those families, and only peers of those families are returned. With
+    /// [FamilyPreference::Both], the results of both families are merged.
+    ///
+    /// By default, the operation queries any node it knows of and leaves it to the
+    /// responders to pick families based on our address.
+    pub family_preference: Option<FamilyPreference>,
+}
+
+impl OperationOptions {
+    fn sender_id(&self, dht: &DHT) -> Id {
+        self.sender_id.unwrap_or_else(|| dht.get_id())
+    }
+
+    fn request_timeout(&self) -> Duration {
+        self.request_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT)
+    }
+
+    /// Returns true if the operation should deal with a node or peer at `addr`
+    fn allows_addr(&self, addr: &SocketAddr) -> bool {
+        match self.family_preference {
+            Some(preference) => preference.families().contains(&AddressFamily::from(addr)),
+            None => true,
+        }
+    }
+
+    /// Asks for nodes of the preferred families, if there are any
+    fn with_want(&self, builder: MessageBuilder) -> MessageBuilder {
+        match self.family_preference {
+            Some(preference) => builder.want(preference.families().to_vec()),
+            None => builder,
+        }
+    }
+}
+
+/// Address families that an operation works with. See [OperationOptions::family_preference].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FamilyPreference {
+    V4Only,
+    V6Only,
+    Both,
+}
+
+impl FamilyPreference {
+    /// The address families included in this preference
+    pub fn families(&self) -> &'static [AddressFamily] {
+        match self {
+            FamilyPreference::V4Only => &[AddressFamily::V4],
+            FamilyPreference::V6Only => &[AddressFamily::V6],
+            FamilyPreference::Both => &[AddressFamily::V4, AddressFamily::V6],
+        }
+    }
+}
+
+/// Statistics about one round of an iterative lookup (e.g. [find_node](crate::dht::operations::find_node)
+/// or [get_peers](crate::dht::operations::get_peers)), reported to the `progress` callback of
+/// [find_node_with_progress](crate::dht::operations::find_node_with_progress) and
+/// [get_peers_with_progress](crate::dht::operations::get_peers_with_progress).
+#[derive(Debug, PartialEq, Clone)]
+pub struct OperationProgress {
+    /// Which round of the lookup this is, starting from 1
+    pub round: usize,
+
+    /// Number of nodes that were sent a request this round
+    pub nodes_queried: usize,
+
+    /// Number of nodes that replied with a valid response this round
+    pub responses_received: usize,
+
+    /// Number of unique peers found so far. Always 0 for find_node.
+    pub peers_found: usize,
+
+    /// XOR distance between the target and the closest node known so far
+    pub closest_distance: Option<Id>,
+}
+
 /// Use the DHT to find the closest nodes to the target as possible.
 ///
-/// This runs until it stops making progress or `timeout` has elapsed.
+/// This runs until it stops making progress or `timeout` has elapsed. If it never had any
+/// nodes to query, it fails with [NoNodesAvailableError](RustyDHTError::NoNodesAvailableError)
+/// rather than returning an empty result.
 pub async fn find_node(
     dht: &DHT,
-    target: Id,
+    target: impl Into<Id>,
+    timeout: Duration,
+) -> Result<Vec<Node>, RustyDHTError> {
+    find_node_with_progress(dht, target, timeout, |_| {}).await
+}
+
+/// Same as [find_node](crate::dht::operations::find_node), but calls `progress` at the end
+/// of every round of requests.
+pub async fn find_node_with_progress(
+    dht: &DHT,
+    target: impl Into<Id>,
+    timeout: Duration,
+    progress: impl FnMut(OperationProgress),
+) -> Result<Vec<Node>, RustyDHTError> {
+    find_node_with_options(dht, target, timeout, &OperationOptions::default(), progress).await
+}
+
+/// Same as [find_node_with_progress](crate::dht::operations::find_node_with_progress), but
+/// with [OperationOptions].
+pub async fn find_node_with_options(
+    dht: &DHT,
+    target: impl Into<Id>,
     timeout: Duration,
+    options: &OperationOptions,
+    mut progress: impl FnMut(OperationProgress),
 ) -> Result<Vec<Node>, RustyDHTError> {
+    let target = target.into();
     let mut buckets = Buckets::new(target, 8);
-    let dht_settings = dht.get_settings();
+    let mut had_nodes = false;
 
     if let Err(_) = tokio::time::timeout(timeout, async {
         let mut best_ids = Vec::new();
+        let mut round = 0;
+        let mut asked_router_seeds = false;
         loop {
             // Seed our buckets with the main buckets from the DHT
             for node_wrapper in dht.get_nodes() {
-                if !buckets.contains(&node_wrapper.node.id) {
+                if options.allows_addr(&node_wrapper.node.address)
+                    && !buckets.contains(&node_wrapper.node.id)
+                {
                     buckets.add(node_wrapper, None);
                 }
             }
+            if buckets.count() == 0 && !asked_router_seeds {
+                asked_router_seeds = true;
+                query_router_seeds(dht, target, options, &mut buckets).await;
+            }
 
             // Grab a few nodes closest to our target
             let nearest = buckets.get_nearest_nodes(&target, None);
@@ -123,27 +978,40 @@ pub async fn find_node(
                 tokio::time::sleep(Duration::from_secs(1)).await;
                 continue;
             }
+            had_nodes = true;
             let best_ids_current: Vec<Id> = nearest.iter().map(|nw| nw.node.id.clone()).collect();
             if best_ids == best_ids_current {
                 break;
             }
             best_ids = best_ids_current;
+            if matches!(options.max_rounds, Some(max_rounds) if round >= max_rounds) {
+                debug!(target: "rustydht_lib::operations::find_node", "Stopping after {} rounds", round);
+                break;
+            }
 
-            // Get ready to send get_peers to all of those closest nodes
-            let request_builder = MessageBuilder::new_find_node_request()
-                .target(target)
-                .read_only(dht_settings.read_only)
-                .sender_id(dht.get_id());
+            // Get ready to send get_peers to all of those closest nodes. Read-only mode can
+            // be toggled at runtime, so check it for every round.
+            let request_builder = options.with_want(
+                MessageBuilder::new_find_node_request()
+                    .target(target)
+                    .read_only(dht.get_settings().read_only)
+                    .sender_id(options.sender_id(dht)),
+            );
+            round += 1;
+            let nodes_queried = nearest.len();
+            let mut responses_received = 0;
             let mut todos = futures::stream::FuturesUnordered::new();
             for node in nearest {
-                todos.push(dht.send_request(
+                todos.push(scheduled_request(
+                    dht,
                     request_builder
                         .clone()
                         .build()
                         .expect("Failed to build find_node request"),
                     node.node.address,
                     Some(node.node.id),
-                    Some(Duration::from_secs(5))
+                    options,
+                    None,
                 ));
             }
 
@@ -155,8 +1023,9 @@ pub async fn find_node(
                         packets::MessageType::Response(
                             packets::ResponseSpecific::FindNodeResponse(args),
                         ) => {
+                            responses_received += 1;
                             for node in args.nodes {
-                                if !buckets.contains(&node.id) {
+                                if options.allows_addr(&node.address) && !buckets.contains(&node.id) {
                                     trace!(target: "rustydht_lib::operations::find_node", "Node {:?} is a candidate for buckets", node);
                                     buckets.add(NodeWrapper::new(node), None);
                                 }
@@ -173,6 +1042,14 @@ pub async fn find_node(
                 }
             }
 
+            progress(OperationProgress {
+                round,
+                nodes_queried,
+                responses_received,
+                peers_found: 0,
+                closest_distance: closest_distance(&buckets, &target),
+            });
+
             // Ensure that our next round of packet sending starts at least 1s from the last
             // to prevent us from hitting other nodes too hard.
             // i.e. don't be a jerk.
@@ -188,6 +1065,9 @@ pub async fn find_node(
     {
         debug!(target: "rustydht_lib::operations::find_node", "Timed out after {:?}", timeout);
     }
+    if !had_nodes {
+        return Err(RustyDHTError::NoNodesAvailableError);
+    }
 
     Ok(buckets
         .get_nearest_nodes(&target, None)
@@ -196,36 +1076,156 @@ pub async fn find_node(
         .collect())
 }
 
-/// Use the DHT to retrieve peers for the given info_hash.
+/// Asks the DHT's [router seeds](crate::dht::DHT::router_seeds) for nodes near `target`,
+/// and adds the routers that answer (and the nodes they return) to `buckets`.
+///
+/// Routers that can't be resolved or don't answer are skipped.
+async fn query_router_seeds(
+    dht: &DHT,
+    target: Id,
+    options: &OperationOptions,
+    buckets: &mut Buckets<NodeWrapper>,
+) {
+    let read_only = dht.get_settings().read_only;
+    let replies = futures::future::join_all(dht.router_seeds().into_iter().map(|router| async move {
+        let addr = dht.resolve_router(&router).await?;
+        if !options.allows_addr(&addr) {
+            return None;
+        }
+        let req = options
+            .with_want(
+                MessageBuilder::new_find_node_request()
+                    .target(target)
+                    .read_only(read_only)
+                    .sender_id(options.sender_id(dht)),
+            )
+            .build()
+            .expect("Failed to build find_node request");
+        match scheduled_request(dht, req, addr, None, options, None).await {
+            Ok(reply) => Some((addr, reply)),
+            Err(e) => {
+                debug!(target: "rustydht_lib::operations::find_node", "Router {} didn't answer find_node: {}", router, e);
+                None
+            }
+        }
+    }))
+    .await;
+
+    for (addr, reply) in replies.into_iter().flatten() {
+        if let packets::MessageType::Response(packets::ResponseSpecific::FindNodeResponse(args)) =
+            reply.message_type
+        {
+            let router = Node::new(args.responder_id, addr);
+            for node in std::iter::once(router).chain(args.nodes) {
+                if options.allows_addr(&node.address) && !buckets.contains(&node.id) {
+                    buckets.add(NodeWrapper::new(node), None);
+                }
+            }
+        }
+    }
+}
+
+/// Looks up the closest nodes to our own Id, and returns them (without us).
+///
+/// This is the usual maintenance lookup for the neighborhood of our Id, e.g. for
+/// deciding whether we're among the nodes that should store an item. It's a
+/// [find_node](crate::dht::operations::find_node) toward [DHT::get_id], so like any
+/// find_node the nodes from the responses are added to the routing table as unverified
+/// along the way.
+pub async fn refresh_self(dht: &DHT, timeout: Duration) -> Result<Vec<Node>, RustyDHTError> {
+    let our_id = dht.get_id();
+    let mut nodes = find_node(dht, our_id, timeout).await?;
+    nodes.retain(|node| node.id != our_id);
+    Ok(nodes)
+}
+
+/// Use the DHT to retrieve peers for the given info_hash, which can be an
+/// [InfoHash](crate::common::InfoHash) or a plain Id.
 ///
 /// Returns the all the results so far after `timeout` has elapsed
-/// or the operation stops making progress (whichever happens first).
+/// or the operation stops making progress (whichever happens first). If it never had any
+/// nodes to query, it fails with
+/// [NoNodesAvailableError](RustyDHTError::NoNodesAvailableError) instead.
 pub async fn get_peers(
     dht: &DHT,
-    info_hash: Id,
+    info_hash: impl Into<InfoHash>,
     timeout: Duration,
 ) -> Result<GetPeersResult, RustyDHTError> {
-    let mut unique_peers = HashSet::new();
-    let mut responders = Vec::new();
-    let mut buckets = Buckets::new(info_hash, 8);
-    let dht_settings = dht.get_settings();
+    get_peers_with_progress(dht, info_hash, timeout, |_| {}).await
+}
 
-    // Hack to aid in bootstrapping
-    find_node(dht, info_hash, Duration::from_secs(5)).await?;
+/// Same as [get_peers](crate::dht::operations::get_peers), but calls `progress` at the end
+/// of every round of requests.
+pub async fn get_peers_with_progress(
+    dht: &DHT,
+    info_hash: impl Into<InfoHash>,
+    timeout: Duration,
+    progress: impl FnMut(OperationProgress),
+) -> Result<GetPeersResult, RustyDHTError> {
+    get_peers_with_options(
+        dht,
+        info_hash,
+        timeout,
+        &OperationOptions::default(),
+        progress,
+    )
+    .await
+}
+
+/// Same as [get_peers_with_progress](crate::dht::operations::get_peers_with_progress), but
+/// with [OperationOptions].
+pub async fn get_peers_with_options(
+    dht: &DHT,
+    info_hash: impl Into<InfoHash>,
+    timeout: Duration,
+    options: &OperationOptions,
+    mut progress: impl FnMut(OperationProgress),
+) -> Result<GetPeersResult, RustyDHTError> {
+    let info_hash = info_hash.into().0;
+    let mut unique_peers = HashSet::new();
+    let mut responders = Vec::new();
+    let mut discovered_nodes = HashMap::new();
+    let mut buckets = Buckets::new(info_hash, 8);
+    let mut had_nodes = false;
+
+    // Hack to aid in bootstrapping, when we don't know enough nodes to start from. Otherwise
+    // there's no need, as the nodes in get_peers responses serve the lookup (and the routing
+    // table) just as well as find_node would. Nodes may still turn up later, so carry on
+    // even if there weren't any yet.
+    let known_nodes = dht
+        .get_nodes()
+        .iter()
+        .filter(|node| options.allows_addr(&node.node.address))
+        .count();
+    if known_nodes <= FEW_NODES {
+        match find_node_with_options(dht, info_hash, Duration::from_secs(5), options, |_| {}).await
+        {
+            Ok(_) | Err(RustyDHTError::NoNodesAvailableError) => {}
+            Err(e) => return Err(e),
+        }
+    }
 
     if let Err(_) = tokio::time::timeout(timeout,
     async {
         let mut best_ids = Vec::new();
+        let mut round = 0;
+        let mut asked_router_seeds = false;
         loop {
             // Populate our buckets with the main buckets from the DHT
             for node_wrapper in dht.get_nodes() {
-                if !buckets.contains(&node_wrapper.node.id) {
+                if options.allows_addr(&node_wrapper.node.address) && !buckets.contains(&node_wrapper.node.id) {
                     buckets.add(node_wrapper, None);
                 }
             }
+            // Too few nodes to start from, so ask the routers too
+            if buckets.count() <= FEW_NODES && !asked_router_seeds {
+                asked_router_seeds = true;
+                query_router_seeds(dht, info_hash, options, &mut buckets).await;
+            }
 
             // Grab a few nodes closest to our target info_hash
             let nearest = buckets.get_nearest_nodes(&info_hash, None);
+            had_nodes |= !nearest.is_empty();
             if nearest.len() <= 5 {
                 // If there are no/few nodes in the buckets yet, DHT may still be bootstrapping. Give it a moment and try again
                 tokio::time::sleep(Duration::from_secs(1)).await;
@@ -236,24 +1236,34 @@ pub async fn get_peers(
                 break;
             }
             best_ids = best_ids_current;
+            if matches!(options.max_rounds, Some(max_rounds) if round >= max_rounds) {
+                debug!(target: "rustydht_lib::operations::get_peers", "Stopping after {} rounds, returning current results", round);
+                break;
+            }
 
-            // Get ready to send get_peers to all of those closest nodes
-            let request_builder = MessageBuilder::new_get_peers_request()
+            // Get ready to send get_peers to all of those closest nodes. Read-only mode can
+            // be toggled at runtime, so check it for every round.
+            let request_builder = options.with_want(MessageBuilder::new_get_peers_request()
                 .target(info_hash)
-                .read_only(dht_settings.read_only)
-                .sender_id(dht.get_id());
+                .read_only(dht.get_settings().read_only)
+                .sender_id(options.sender_id(dht)));
+            round += 1;
+            let nodes_queried = nearest.len();
+            let mut responses_received = 0;
             let mut todos = futures::stream::FuturesUnordered::new();
             for node in nearest {
                 let node_clone = node.clone();
                 let request_builder_clone = request_builder.clone();
                 todos.push(async move {
-                    match dht.send_request(
+                    match scheduled_request(
+                        dht,
                         request_builder_clone
                             .build()
                             .expect("Failed to build get_peers request"),
                         node_clone.node.address,
                         Some(node_clone.node.id),
-                        Some(Duration::from_secs(5))
+                        options,
+                        None,
                     ).await {
                         Ok(reply) => Ok((node_clone.node, reply)),
                         Err(e) => Err(e)
@@ -269,28 +1279,38 @@ pub async fn get_peers(
                         packets::MessageType::Response(
                             packets::ResponseSpecific::GetPeersResponse(args),
                         ) => {
+                            responses_received += 1;
+                            discovered_nodes.insert(result.0.id, result.0.clone());
                             responders.push(GetPeersResponder{
                                 node: result.0,
                                 token: args.token
                             });
 
-                            match args.values {
-                            packets::GetPeersResponseValues::Nodes(n) => {
+                            let (p, n) = match args.values {
+                                packets::GetPeersResponseValues::Nodes(n) => (vec![], n),
+                                packets::GetPeersResponseValues::Peers(p) => (p, vec![]),
+                                packets::GetPeersResponseValues::PeersAndNodes(p, n) => (p, n),
+                            };
+                            if !n.is_empty() {
                                 debug!(target: "rustydht_lib::operations::get_peers", "Got {} nodes", n.len());
-                                for node in n {
+                                for node in n.into_iter().filter(|node| options.allows_addr(&node.address)) {
+                                    discovered_nodes.entry(node.id).or_insert_with(|| node.clone());
                                     if !buckets.contains(&node.id) {
                                         trace!(target: "rustydht_lib::operations::get_peers", "Node {:?} is a candidate for buckets", node);
                                         buckets.add(NodeWrapper::new(node), None);
                                     }
                                 }
                             }
-                            packets::GetPeersResponseValues::Peers(p) => {
+                            if !p.is_empty() {
                                 info!(target: "rustydht_lib::operations::get_peers", "Got {} peers", p.len());
-                                for peer in p {
+                                for peer in p.into_iter().filter(|peer| options.allows_addr(peer)) {
+                                    if matches!(options.max_total_peers, Some(max) if unique_peers.len() >= max) {
+                                        break;
+                                    }
                                     unique_peers.insert(peer);
                                 }
                             }
-                        }},
+                        },
                         _ => {
                             error!(target: "rustydht_lib::operations::get_peers", "Got wrong packet type back: {:?}", result.1);
                         }
@@ -301,6 +1321,18 @@ pub async fn get_peers(
                 }
             }
 
+            progress(OperationProgress {
+                round,
+                nodes_queried,
+                responses_received,
+                peers_found: unique_peers.len(),
+                closest_distance: closest_distance(&buckets, &info_hash),
+            });
+            if matches!(options.max_total_peers, Some(max) if unique_peers.len() >= max) {
+                debug!(target: "rustydht_lib::operations::get_peers", "Stopping with {} peers, returning current results", unique_peers.len());
+                break;
+            }
+
             // Ensure that our next round of packet sending starts at least 1s from the last
             // to prevent us from hitting other nodes too hard.
             // i.e. don't be a jerk.
@@ -314,19 +1346,354 @@ pub async fn get_peers(
     }).await {
         debug!(target: "rustydht_lib::operations::get_peers", "Timed out after {:?}, returning current results", timeout);
     }
+    if !had_nodes {
+        return Err(RustyDHTError::NoNodesAvailableError);
+    }
 
-    Ok(GetPeersResult::new(
-        info_hash,
-        unique_peers.into_iter().collect(),
-        responders,
+    let our_id = dht.get_id();
+    let mut result = GetPeersResult::new(info_hash, unique_peers.into_iter().collect(), responders);
+    result.nodes = discovered_nodes
+        .into_values()
+        .filter(|node| node.id != our_id)
+        .collect();
+    result.nodes.sort_unstable_by(|a, b| {
+        let a_dist = a.id.xor(&info_hash);
+        let b_dist = b.id.xor(&info_hash);
+        a_dist.cmp(&b_dist)
+    });
+    Ok(result)
+}
+
+/// Runs an iterative lookup toward `target`, yielding each node that responds as soon as
+/// its round of the lookup is done (or the lookup times out), along with the token it gave us.
+///
+/// Each round sends get_peers to the closest nodes that haven't been asked yet, so every
+/// node is yielded at most once, and each finished round's nodes come out nearest first. Since the
+/// lookup closes in on `target`, later rounds generally yield closer nodes. The lookup ends
+/// when there are no closer nodes left to ask, or `timeout` has elapsed.
+///
+/// This is the building block underneath lookups like [find_node](crate::dht::operations::find_node)
+/// and [get_peers](crate::dht::operations::get_peers), for callers that want to send their
+/// own follow-up queries to each node. Dropping the stream stops the lookup.
+pub fn iterative_lookup(
+    dht: &DHT,
+    target: impl Into<Id>,
+    timeout: Duration,
+) -> impl futures::Stream<Item = GetPeersResponder> + Unpin + '_ {
+    let target = target.into();
+    let lookup = IterativeLookup {
+        target,
+        buckets: Buckets::new(target, 8),
+        asked: HashSet::new(),
+        ready: VecDeque::new(),
+        deadline: tokio::time::Instant::now() + timeout,
+        last_round: None,
+        done: false,
+    };
+    Box::pin(futures::stream::unfold(
+        lookup,
+        move |mut lookup| async move {
+            while lookup.ready.is_empty() && !lookup.done {
+                let deadline = lookup.deadline;
+                if tokio::time::timeout_at(deadline, lookup.next_round(dht))
+                    .await
+                    .is_err()
+                {
+                    debug!(target: "rustydht_lib::operations::iterative_lookup", "Timed out looking up {}", lookup.target);
+                    lookup.done = true;
+                }
+            }
+            let responder = lookup.ready.pop_front()?;
+            Some((responder, lookup))
+        },
     ))
 }
 
+/// The state of an [iterative_lookup] between rounds
+struct IterativeLookup {
+    target: Id,
+    buckets: Buckets<NodeWrapper>,
+
+    /// Nodes that have been sent a request
+    asked: HashSet<Id>,
+
+    /// Nodes that responded, waiting to be yielded
+    ready: VecDeque<GetPeersResponder>,
+
+    deadline: tokio::time::Instant,
+
+    /// When the last round started sending
+    last_round: Option<tokio::time::Instant>,
+
+    done: bool,
+}
+
+impl IterativeLookup {
+    /// Asks the closest nodes that haven't been asked yet, and queues up the ones that
+    /// respond as their replies arrive, so they aren't lost if the round is cut short. Sets
+    /// `done` if there's nobody left to ask.
+    async fn next_round(&mut self, dht: &DHT) {
+        // Don't start a round less than 1s after the last, like the other lookups
+        if let Some(last_round) = self.last_round {
+            tokio::time::sleep_until(last_round + Duration::from_secs(1)).await;
+        }
+        for node_wrapper in dht.get_nodes() {
+            if !self.buckets.contains(&node_wrapper.node.id) {
+                self.buckets.add(node_wrapper, None);
+            }
+        }
+        let nearest = self.buckets.get_nearest_nodes(&self.target, None);
+        if nearest.is_empty() {
+            // The DHT may still be bootstrapping. Give it a moment and try again
+            self.last_round = Some(tokio::time::Instant::now());
+            return;
+        }
+        let to_ask: Vec<Node> = nearest
+            .into_iter()
+            .filter(|nw| !self.asked.contains(&nw.node.id))
+            .map(|nw| nw.node.clone())
+            .collect();
+        if to_ask.is_empty() {
+            self.done = true;
+            return;
+        }
+
+        self.last_round = Some(tokio::time::Instant::now());
+        let options = OperationOptions::default();
+        let request_builder = MessageBuilder::new_get_peers_request()
+            .target(self.target)
+            .read_only(dht.get_settings().read_only)
+            .sender_id(dht.get_id());
+        let mut todos = futures::stream::FuturesUnordered::new();
+        for node in to_ask {
+            self.asked.insert(node.id);
+            let request = request_builder
+                .clone()
+                .build()
+                .expect("Failed to build get_peers request");
+            let options = &options;
+            todos.push(async move {
+                let reply =
+                    scheduled_request(dht, request, node.address, Some(node.id), options, None)
+                        .await;
+                (node, reply)
+            });
+        }
+
+        let round_start = self.ready.len();
+        while let Some((node, reply)) = todos.next().await {
+            match reply.map(|reply| reply.message_type) {
+                Ok(packets::MessageType::Response(
+                    packets::ResponseSpecific::GetPeersResponse(args),
+                )) => {
+                    let nodes = match args.values {
+                        packets::GetPeersResponseValues::Nodes(nodes)
+                        | packets::GetPeersResponseValues::PeersAndNodes(_, nodes) => nodes,
+                        packets::GetPeersResponseValues::Peers(_) => Vec::new(),
+                    };
+                    for node in nodes {
+                        if !self.buckets.contains(&node.id) {
+                            self.buckets.add(NodeWrapper::new(node), None);
+                        }
+                    }
+                    self.ready
+                        .push_back(GetPeersResponder::new(node, args.token));
+                }
+
+                Ok(other) => {
+                    error!(target: "rustydht_lib::operations::iterative_lookup", "Got wrong packet type back: {:?}", other);
+                }
+
+                Err(e) => {
+                    warn!(target: "rustydht_lib::operations::iterative_lookup", "Error sending get_peers request: {}", e);
+                }
+            }
+        }
+        let target = self.target;
+        self.ready.make_contiguous()[round_start..].sort_unstable_by(|a, b| {
+            let a_dist = a.node.id.xor(&target);
+            let b_dist = b.node.id.xor(&target);
+            a_dist.cmp(&b_dist)
+        });
+    }
+}
+
+/// Use the DHT to find the nodes responsible for storing data under `target`, along with
+/// the write tokens they gave us.
+///
+/// This runs the same iterative lookup as [get_peers](crate::dht::operations::get_peers),
+/// but ignores any peers found. Each node appears once, with the most recent token it
+/// replied with, and the results are sorted by distance to `target` from nearest to
+/// farthest.
+pub async fn find_storage_nodes(
+    dht: &DHT,
+    target: Id,
+    timeout: Duration,
+) -> Result<Vec<GetPeersResponder>, RustyDHTError> {
+    let result = get_peers(dht, target, timeout).await?;
+
+    // Later rounds come last, so walk backwards to keep each node's freshest token
+    let mut seen = HashSet::new();
+    let mut responders: Vec<GetPeersResponder> = result
+        .responders()
+        .into_iter()
+        .rev()
+        .filter(|responder| seen.insert(responder.node.id))
+        .collect();
+    responders.sort_unstable_by(|a, b| {
+        let a_dist = a.node.id.xor(&target);
+        let b_dist = b.node.id.xor(&target);
+        a_dist.cmp(&b_dist)
+    });
+    Ok(responders)
+}
+
+/// A rough estimate of the number of nodes in the DHT, returned by
+/// [estimate_network_size](crate::dht::operations::estimate_network_size).
+#[derive(Debug, PartialEq, Clone)]
+pub struct NetworkSizeEstimate {
+    /// The estimated number of nodes
+    pub estimate: u64,
+
+    /// Lower bound of a rough 95% confidence interval around `estimate`
+    pub low: u64,
+
+    /// Upper bound of a rough 95% confidence interval around `estimate`
+    pub high: u64,
+
+    /// Number of lookups that the estimate is based on
+    pub samples: usize,
+}
+
+/// Estimates the number of nodes in the DHT from how densely they're packed in the keyspace.
+///
+/// Runs `samples` [find_node](crate::dht::operations::find_node) lookups toward random
+/// targets, splitting `timeout` between them. Node Ids are spread evenly across the
+/// keyspace, so with N nodes, the k-th closest node to a random target is expected to be
+/// k/N of the keyspace away from it. Each lookup fits N to the distances of the nodes it
+/// found by least squares: `N = sum(k^2) / sum(k * d_k)`, where `d_k` is the XOR distance
+/// to the k-th closest node as a fraction of the keyspace. The estimate is the mean over
+/// all lookups, and the interval is the mean plus or minus 1.96 standard errors.
+///
+/// This is only a ballpark figure. It gets better with more samples, and it counts only
+/// the nodes that lookups can reach.
+pub async fn estimate_network_size(
+    dht: &DHT,
+    samples: usize,
+    timeout: Duration,
+) -> Result<NetworkSizeEstimate, RustyDHTError> {
+    let our_id = dht.get_id();
+    let sample_timeout = timeout / samples.max(1) as u32;
+    let mut estimates = Vec::with_capacity(samples);
+    for _ in 0..samples {
+        let target = Id::from_random(&mut rand::thread_rng());
+        let mut distances: Vec<f64> = find_node(dht, target, sample_timeout)
+            .await?
+            .iter()
+            .filter(|node| node.id != our_id)
+            .map(|node| keyspace_fraction(&node.id.xor(&target)))
+            .collect();
+        distances.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        let (numerator, denominator) = distances.iter().enumerate().fold(
+            (0.0, 0.0),
+            |(numerator, denominator), (i, distance)| {
+                let k = (i + 1) as f64;
+                (numerator + k * k, denominator + k * distance)
+            },
+        );
+        if denominator > 0.0 {
+            estimates.push(numerator / denominator);
+        }
+    }
+
+    if estimates.is_empty() {
+        return Err(RustyDHTError::GeneralError(anyhow!(
+            "Couldn't find any nodes to estimate the network size from"
+        )));
+    }
+
+    let count = estimates.len() as f64;
+    let mean = estimates.iter().sum::<f64>() / count;
+    let margin = if estimates.len() > 1 {
+        let variance = estimates
+            .iter()
+            .map(|estimate| (estimate - mean).powi(2))
+            .sum::<f64>()
+            / (count - 1.0);
+        1.96 * (variance / count).sqrt()
+    } else {
+        0.0
+    };
+    debug!(target: "rustydht_lib::operations::estimate_network_size", "Per-lookup estimates: {:?}", estimates);
+
+    Ok(NetworkSizeEstimate {
+        estimate: mean.round() as u64,
+        low: (mean - margin).max(0.0).round() as u64,
+        high: (mean + margin).round() as u64,
+        samples: estimates.len(),
+    })
+}
+
+/// A quick look at the swarm of an info_hash, returned by
+/// [swarm_health](crate::dht::operations::swarm_health).
+#[derive(Debug, PartialEq, Clone)]
+pub struct SwarmHealth {
+    /// Number of unique peers found for the info_hash
+    pub peers: usize,
+
+    /// Number of unique nodes that responded to our get_peers requests
+    pub responders: usize,
+}
+
+/// Quickly checks whether the swarm of `info_hash` looks alive, e.g. before starting a
+/// download.
+///
+/// This is a short [get_peers](crate::dht::operations::get_peers) lookup, capped at a
+/// few rounds of requests (and `timeout`) so that it returns fast. It doesn't tell seeds
+/// from leechers, since BEP0033 scrapes aren't supported.
+pub async fn swarm_health(
+    dht: &DHT,
+    info_hash: impl Into<InfoHash>,
+    timeout: Duration,
+) -> Result<SwarmHealth, RustyDHTError> {
+    let options = OperationOptions {
+        max_rounds: Some(SWARM_HEALTH_MAX_ROUNDS),
+        ..Default::default()
+    };
+    let result = get_peers_with_options(dht, info_hash, timeout, &options, |_| {}).await?;
+    let responders: HashSet<Id> = result
+        .responders
+        .iter()
+        .map(|responder| responder.node.id)
+        .collect();
+    Ok(SwarmHealth {
+        peers: result.peers.len(),
+        responders: responders.len(),
+    })
+}
+
+/// Converts an XOR distance to a fraction of the whole keyspace, using its top 64 bits
+fn keyspace_fraction(distance: &Id) -> f64 {
+    let mut top_bytes = [0; 8];
+    top_bytes.copy_from_slice(&distance.to_vec()[..8]);
+    u64::from_be_bytes(top_bytes) as f64 / 2f64.powi(64)
+}
+
+/// XOR distance between `target` and the closest node in `buckets`
+fn closest_distance(buckets: &Buckets<NodeWrapper>, target: &Id) -> Option<Id> {
+    buckets
+        .get_nearest_nodes(target, None)
+        .first()
+        .map(|nw| nw.node.id.xor(target))
+}
+
 /// Represents the results of a [get_peers](crate::dht::operations::get_peers) operation
 pub struct GetPeersResult {
     info_hash: Id,
     peers: Vec<SocketAddr>,
     responders: Vec<GetPeersResponder>,
+    nodes: Vec<Node>,
 }
 
 impl GetPeersResult {
@@ -338,12 +1705,13 @@ impl GetPeersResult {
         responders.sort_unstable_by(|a, b| {
             let a_dist = a.node.id.xor(&info_hash);
             let b_dist = b.node.id.xor(&info_hash);
-            a_dist.partial_cmp(&b_dist).unwrap()
+            a_dist.cmp(&b_dist)
         });
         GetPeersResult {
             info_hash: info_hash,
             peers: peers,
             responders: responders,
+            nodes: Vec::new(),
         }
     }
 
@@ -363,6 +1731,17 @@ impl GetPeersResult {
     pub fn responders(self) -> Vec<GetPeersResponder> {
         self.responders
     }
+
+    /// Vector of every DHT node that get_peers heard of: the ones that responded, and the
+    /// ones they returned in the `nodes` of their responses.
+    ///
+    /// These are the same nodes that a find_node toward the info_hash would turn up, so
+    /// callers can use them (e.g. to seed a routing table) instead of doing a separate
+    /// find_node. This is sorted by distance of the Node to the info_hash, from nearest to
+    /// farthest, and doesn't include our own node.
+    pub fn nodes(self) -> Vec<Node> {
+        self.nodes
+    }
 }
 
 /// Represents the response of a node to a get_peers request, including its Id, IP address,
@@ -389,3 +1768,2177 @@ impl GetPeersResponder {
         self.token
     }
 }
+
+/// Pings the node at `addr` and returns the Id it replied with.
+///
+/// This is useful for turning a bare address, such as a router's `host:port`, into a
+/// [Node](crate::common::Node) with a known Id. Like any response to the DHT, the reply
+/// also adds the node to the routing table if its Id is valid for its address.
+pub async fn discover_id(
+    dht: &DHT,
+    addr: SocketAddr,
+    timeout: Duration,
+) -> Result<Id, RustyDHTError> {
+    let settings = dht.get_settings();
+    let req = MessageBuilder::new_ping_request()
+        .sender_id(dht.get_id())
+        .read_only(settings.read_only)
+        .build()?;
+    let res = dht.send_request(req, addr, None, Some(timeout)).await?;
+    res.get_author_id().ok_or_else(|| {
+        RustyDHTError::GeneralError(anyhow!("Ping response from {} had no Id", addr))
+    })
+}
+
+/// Resolves the `hostname:port` of a DHT router to the IPv4 address that DHT would use
+/// to contact it.
+///
+/// This can be used to validate a list of routers up front, e.g. before passing it to
+/// [DHTSettings::routers](crate::dht::DHTSettings::routers). DHT itself tolerates routers
+/// that fail to resolve and tries them again later.
+pub async fn resolve_router(hostname: &str) -> Result<SocketAddr, RustyDHTError> {
+    let mut addrs = tokio::net::lookup_host(hostname)
+        .await
+        .map_err(|e| RustyDHTError::ResolutionError(hostname.to_string(), e.into()))?;
+    addrs
+        .find(|socket_addr| socket_addr.is_ipv4())
+        .ok_or_else(|| {
+            RustyDHTError::ResolutionError(hostname.to_string(), anyhow!("No IPv4 address found"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ipv4_addr_src::StaticIPV4AddrSource;
+    use crate::dht::dht_event::{DHTEventType, MessageReceivedEvent};
+    use crate::dht::mock_network::MockNetwork;
+    use crate::dht::{DHTBuilder, DHTSettingsBuilder};
+    use crate::shutdown;
+    use crate::storage::node_bucket_storage::{NodeBucketStorage, NodeStorage};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_find_node_reports_progress() -> Result<(), RustyDHTError> {
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let make_dht = |id: &str, port: u16| {
+            DHTBuilder::new()
+                .initial_id(Id::from_hex(id).unwrap())
+                .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())
+                .unwrap()
+        };
+        let searcher = Arc::new(make_dht("0000000000000000000000000000000000000001", 2307));
+        let other = Arc::new(make_dht("f000000000000000000000000000000000000002", 2308));
+        for dht in [searcher.clone(), other.clone()] {
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+        }
+
+        // Ping the other node so that the searcher has it in its routing table
+        searcher
+            .send_request(
+                MessageBuilder::new_ping_request()
+                    .sender_id(searcher.get_id())
+                    .build()?,
+                "127.0.0.1:2308".parse().unwrap(),
+                None,
+                Some(Duration::from_secs(5)),
+            )
+            .await?;
+
+        let target = Id::from_hex("f000000000000000000000000000000000000000").unwrap();
+        let mut reports = Vec::new();
+        find_node_with_progress(&searcher, target, Duration::from_secs(10), |p| {
+            reports.push(p)
+        })
+        .await?;
+
+        assert!(!reports.is_empty());
+        for (i, report) in reports.iter().enumerate() {
+            assert_eq!(report.round, i + 1);
+            assert!(report.responses_received <= report.nodes_queried);
+            assert_eq!(report.peers_found, 0);
+        }
+        assert_eq!(reports[0].nodes_queried, 1);
+        assert_eq!(reports[0].responses_received, 1);
+        assert_eq!(
+            reports[0].closest_distance,
+            Some(other.get_id().xor(&target))
+        );
+
+        // Shutdown waits for every ShutdownReceiver to drop, including the DHTs'
+        drop(searcher);
+        drop(other);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_peers_reports_progress() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let mut dhts = Vec::new();
+        for i in 1..=8 {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+            dhts.push(dht);
+        }
+        for i in 2..=8 {
+            dhts[0]
+                .send_request(
+                    MessageBuilder::new_ping_request()
+                        .sender_id(dhts[0].get_id())
+                        .build()?,
+                    SocketAddr::new([127, 0, 0, i].into(), 6881),
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+        }
+
+        // One node has a few peers for the info_hash
+        let info_hash = Id::from_random(&mut rand::thread_rng());
+        for port in 1000..1003 {
+            assert!(dhts[1].announce_self_locally(info_hash, Some(port))?);
+        }
+        let closest = dhts[1..]
+            .iter()
+            .map(|dht| dht.get_id().xor(&info_hash))
+            .min();
+
+        let mut reports = Vec::new();
+        let result = get_peers_with_progress(&dhts[0], info_hash, Duration::from_secs(5), |p| {
+            reports.push(p)
+        })
+        .await?;
+        assert_eq!(result.peers.len(), 3);
+
+        // Every node we know is in the first round, so the lookup has all it will find by
+        // the end of it
+        assert!(!reports.is_empty());
+        for (i, report) in reports.iter().enumerate() {
+            assert_eq!(report.round, i + 1);
+            assert!(report.responses_received <= report.nodes_queried);
+            assert_eq!(report.peers_found, 3);
+            assert_eq!(report.closest_distance, closest);
+        }
+        assert_eq!(reports[0].nodes_queried, 7);
+        assert_eq!(reports[0].responses_received, 7);
+
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_sender_id() -> Result<(), RustyDHTError> {
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2316))
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())
+                .unwrap(),
+        );
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        // A fake node that answers the ping and find_node, and reports who they claimed to be from
+        let responder = tokio::net::UdpSocket::bind("127.0.0.1:2317").await.unwrap();
+        let responder_id = Id::from_random(&mut rand::thread_rng());
+        let responder_task = tokio::spawn(async move {
+            let mut authors = Vec::new();
+            let mut buf = [0; 2048];
+            for _ in 0..2 {
+                let (num_read, from) = responder.recv_from(&mut buf).await.unwrap();
+                let req = packets::Message::from_bytes(&buf[..num_read]).unwrap();
+                authors.push(req.get_author_id().unwrap());
+                let reply = match req.message_type {
+                    packets::MessageType::Request(packets::RequestSpecific::PingRequest(_)) => {
+                        MessageBuilder::new_ping_response()
+                    }
+                    _ => MessageBuilder::new_find_node_response().nodes(vec![]),
+                }
+                .sender_id(responder_id)
+                .transaction_id(req.transaction_id)
+                .build()
+                .unwrap();
+                responder
+                    .send_to(&reply.to_bytes().unwrap(), from)
+                    .await
+                    .unwrap();
+            }
+            authors
+        });
+
+        let spoofed_id = Id::from_hex("0123456789abcdef0123456789abcdef01234567").unwrap();
+        dht.send_request(
+            MessageBuilder::new_ping_request()
+                .sender_id(spoofed_id)
+                .build()?,
+            "127.0.0.1:2317".parse().unwrap(),
+            None,
+            Some(Duration::from_secs(5)),
+        )
+        .await?;
+
+        let options = OperationOptions {
+            sender_id: Some(spoofed_id),
+            ..Default::default()
+        };
+        let found = find_node_with_options(
+            &dht,
+            Id::from_random(&mut rand::thread_rng()),
+            Duration::from_secs(10),
+            &options,
+            |_| {},
+        )
+        .await?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, responder_id);
+
+        assert_eq!(responder_task.await.unwrap(), vec![spoofed_id, spoofed_id]);
+
+        // Shutdown waits for every ShutdownReceiver to drop, including the DHT's
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_discover_id() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let make_dht = |addr: &str| {
+            Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, addr.parse().unwrap())
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            )
+        };
+        let searcher = make_dht("127.0.0.1:6881");
+        let other = make_dht("127.0.0.2:6881");
+        for dht in [searcher.clone(), other.clone()] {
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+        }
+
+        let id = discover_id(
+            &searcher,
+            "127.0.0.2:6881".parse().unwrap(),
+            Duration::from_secs(5),
+        )
+        .await?;
+        assert_eq!(id, other.get_id());
+
+        // Nobody answers on an unused address
+        let _silent = network.bind("127.0.0.3:6881".parse().unwrap());
+        assert!(matches!(
+            discover_id(
+                &searcher,
+                "127.0.0.3:6881".parse().unwrap(),
+                Duration::from_secs(1)
+            )
+            .await,
+            Err(RustyDHTError::TimeoutError(_))
+        ));
+
+        drop(searcher);
+        drop(other);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_locally() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let dht_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, dht_addr)
+                .ip_source(Box::new(StaticIPV4AddrSource::new(Ipv4Addr::new(
+                    1, 2, 3, 4,
+                ))))
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())
+                .unwrap(),
+        );
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let info_hash = Id::from_random(&mut rand::thread_rng());
+        let options = OperationOptions {
+            announce_locally: true,
+            ..Default::default()
+        };
+        announce_peer_with_options(
+            &dht,
+            info_hash,
+            Some(7000),
+            Duration::from_millis(100),
+            &options,
+        )
+        .await?;
+
+        // Other nodes asking us for peers find us
+        let mut requester = network.endpoint("10.0.0.2:6881".parse().unwrap());
+        let get_peers = MessageBuilder::new_get_peers_request()
+            .sender_id(Id::from_random(&mut rand::thread_rng()))
+            .target(info_hash)
+            .build()?;
+        let res = requester.send_and_receive(get_peers, dht_addr).await?;
+        assert!(matches!(
+            res.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
+                packets::GetPeersResponseArguments {
+                    values: packets::GetPeersResponseValues::Peers(peers),
+                    ..
+                }
+            )) if peers == vec!["1.2.3.4:7000".parse().unwrap()]
+        ));
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        // Without a known external address, a DHT listening on 0.0.0.0 can't tell others
+        // how to reach it
+        let (_, shutdown_rx) = shutdown::create_shutdown();
+        let unreachable_dht = DHTBuilder::new()
+            .mock_network(&network, "0.0.0.0:6881".parse().unwrap())
+            .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+            .build(shutdown_rx)
+            .unwrap();
+        assert!(!unreachable_dht.announce_self_locally(info_hash, None)?);
+        assert!(unreachable_dht.get_info_hashes(None).is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_request_timeout() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let mut dhts = Vec::new();
+        for i in 1..=8 {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+            dhts.push(dht);
+        }
+
+        // A node that answers the first ping and then never responds again
+        let hanging_addr: SocketAddr = "127.0.0.9:6881".parse().unwrap();
+        let mut hanging_rx = network.bind(hanging_addr);
+        let hanging_network = network.clone();
+        tokio::spawn(async move {
+            let (bytes, from) = hanging_rx.recv().await.unwrap();
+            let req = packets::Message::from_bytes(&bytes).unwrap();
+            let reply = MessageBuilder::new_ping_response()
+                .sender_id(Id::from_random(&mut rand::thread_rng()))
+                .transaction_id(req.transaction_id)
+                .build()
+                .unwrap();
+            hanging_network.send(hanging_addr, from, reply.to_bytes().unwrap());
+            while hanging_rx.recv().await.is_some() {}
+        });
+
+        let searcher = dhts[0].clone();
+        for i in 2..=9 {
+            searcher
+                .send_request(
+                    MessageBuilder::new_ping_request()
+                        .sender_id(searcher.get_id())
+                        .build()?,
+                    SocketAddr::new([127, 0, 0, i].into(), 6881),
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+        }
+
+        // The hanging node only holds up its round for a second, so the round completes
+        // well within the operation's timeout
+        let target = Id::from_random(&mut rand::thread_rng());
+        let run_lookup = |request_timeout| {
+            let searcher = searcher.clone();
+            async move {
+                let options = OperationOptions {
+                    request_timeout: Some(request_timeout),
+                    ..Default::default()
+                };
+                let mut reports = Vec::new();
+                let result = get_peers_with_options(
+                    &searcher,
+                    target,
+                    Duration::from_secs(5),
+                    &options,
+                    |p| reports.push(p),
+                )
+                .await
+                .unwrap();
+                (reports, result)
+            }
+        };
+        let (reports, result) = run_lookup(Duration::from_secs(1)).await;
+        assert!(!reports.is_empty());
+        for report in reports {
+            assert_eq!(report.responses_received, report.nodes_queried - 1);
+        }
+        assert_eq!(result.responders().len(), 7);
+
+        // If a single request may take as long as the whole operation, no round ever completes
+        let (reports, _) = run_lookup(Duration::from_secs(10)).await;
+        assert!(reports.is_empty());
+
+        drop(searcher);
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_get_peers_result_nodes() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let mut dhts = Vec::new();
+        for i in 1..=8 {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+            dhts.push(dht);
+        }
+        let searcher = dhts[0].clone();
+        for i in 2..=8 {
+            searcher
+                .send_request(
+                    MessageBuilder::new_ping_request()
+                        .sender_id(searcher.get_id())
+                        .build()?,
+                    SocketAddr::new([127, 0, 0, i].into(), 6881),
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+        }
+
+        let info_hash = Id::from_random(&mut rand::thread_rng());
+        let mut events = dhts[1].observe();
+        let nodes = get_peers(&searcher, info_hash, Duration::from_secs(5))
+            .await?
+            .nodes();
+        let mut found: Vec<Id> = nodes.iter().map(|node| node.id).collect();
+        assert!(found.windows(2).all(|pair| {
+            pair[0].xor(&info_hash).cmp(&pair[1].xor(&info_hash)) == std::cmp::Ordering::Less
+        }));
+        found.sort_unstable_by_key(|id| id.to_vec());
+        let mut expected: Vec<Id> = dhts[1..].iter().map(|dht| dht.get_id()).collect();
+        expected.sort_unstable_by_key(|id| id.to_vec());
+        assert_eq!(found, expected);
+
+        // They're enough to seed a routing table without a separate find_node
+        let mut storage = NodeBucketStorage::new(Id::from_random(&mut rand::thread_rng()), 8);
+        for node in nodes {
+            storage.add_or_update(node, false);
+        }
+        assert_eq!(storage.get_all_unverified().len(), 7);
+
+        // The searcher knew enough nodes to start with, so it didn't send find_node first
+        let mut find_nodes = 0;
+        while let Some(event) = events.try_recv() {
+            if let DHTEventType::MessageReceived(MessageReceivedEvent { message, .. }) =
+                event.event_type
+            {
+                if let packets::MessageType::Request(packets::RequestSpecific::FindNodeRequest(
+                    args,
+                )) = message.message_type
+                {
+                    if args.target == info_hash {
+                        find_nodes += 1;
+                    }
+                }
+            }
+        }
+        assert_eq!(find_nodes, 0);
+
+        drop(searcher);
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_swarm_health() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let mut dhts = Vec::new();
+        for i in 1..=8 {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+            dhts.push(dht);
+        }
+        // Everybody knows everybody
+        for (index, dht) in dhts.iter().enumerate() {
+            for i in (1..=8).filter(|i| *i as usize != index + 1) {
+                dht.send_request(
+                    MessageBuilder::new_ping_request()
+                        .sender_id(dht.get_id())
+                        .build()?,
+                    SocketAddr::new([127, 0, 0, i].into(), 6881),
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+            }
+        }
+
+        let info_hash = Id::from_random(&mut rand::thread_rng());
+        announce_peer(&dhts[1], info_hash, Some(1000), Duration::from_secs(5)).await?;
+        announce_peer(&dhts[2], info_hash, Some(2000), Duration::from_secs(5)).await?;
+
+        let health = swarm_health(&dhts[0], info_hash, Duration::from_secs(5)).await?;
+        assert_eq!(health.peers, 2);
+        assert_eq!(health.responders, 7);
+
+        // Nobody has peers for another info_hash, but the nodes still respond
+        let health = swarm_health(
+            &dhts[0],
+            Id::from_random(&mut rand::thread_rng()),
+            Duration::from_secs(5),
+        )
+        .await?;
+        assert_eq!(health.peers, 0);
+        assert_eq!(health.responders, 7);
+
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_get_peers_max_total_peers() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let mut dhts = Vec::new();
+        for i in 1..=8 {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+            dhts.push(dht);
+        }
+        for i in 2..=8 {
+            dhts[0]
+                .send_request(
+                    MessageBuilder::new_ping_request()
+                        .sender_id(dhts[0].get_id())
+                        .build()?,
+                    SocketAddr::new([127, 0, 0, i].into(), 6881),
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+        }
+
+        // One node has a flood of peers for the info_hash
+        let info_hash = Id::from_random(&mut rand::thread_rng());
+        for port in 1000..1100 {
+            assert!(dhts[1].announce_self_locally(info_hash, Some(port))?);
+        }
+
+        let options = OperationOptions {
+            max_total_peers: Some(30),
+            ..Default::default()
+        };
+        let result = get_peers_with_options(
+            &dhts[0],
+            info_hash,
+            Duration::from_secs(5),
+            &options,
+            |_| {},
+        )
+        .await?;
+        assert_eq!(result.peers.len(), 30);
+
+        // They're all there without the cap
+        let result = get_peers(&dhts[0], info_hash, Duration::from_secs(5)).await?;
+        assert_eq!(result.peers.len(), 100);
+
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_get_peers_family_preference() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let make_dht = |addr: SocketAddr, id: Id| {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .initial_id(id)
+                    .mock_network(&network, addr)
+                    .settings(
+                        DHTSettingsBuilder::new()
+                            .routers(vec![])
+                            .dual_stack(true)
+                            // Our IPv6 test addresses aren't loopback, so random Ids
+                            // aren't valid for them
+                            .enforce_id_ip_validity(false)
+                            .build()
+                            .unwrap(),
+                    )
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+            dht
+        };
+
+        // Half of the nodes are IPv4 and half are IPv6, and each stores itself as a peer.
+        // Their ids start with 0x10 to 0xc0 and the searchers' with 0x00, so that none of a
+        // searcher's buckets overflows and drops nodes.
+        let info_hash = Id::from_random(&mut rand::thread_rng());
+        let make_id = |first_byte: u8| {
+            let mut bytes = Id::from_random(&mut rand::thread_rng()).to_vec();
+            bytes[0] = first_byte;
+            Id::from_bytes(bytes).unwrap()
+        };
+        let mut node_addrs = Vec::new();
+        for i in 1..=6 {
+            node_addrs.push(SocketAddr::new([127, 0, 0, i].into(), 6881));
+            node_addrs.push(SocketAddr::new(
+                [0xfd00, 0, 0, 0, 0, 0, 0, i.into()].into(),
+                6881,
+            ));
+        }
+        let mut dhts = Vec::new();
+        for (i, addr) in node_addrs.iter().enumerate() {
+            let dht = make_dht(*addr, make_id((i as u8 + 1) << 4));
+            assert!(dht.announce_self_locally(info_hash, None)?);
+            dhts.push(dht);
+        }
+
+        // Each lookup gets its own searcher, so that none of them is throttled by the nodes
+        let mut searcher_ip = 0;
+        let mut lookup = |family_preference| {
+            searcher_ip += 1;
+            let searcher = make_dht(
+                SocketAddr::new([127, 0, 1, searcher_ip].into(), 6881),
+                make_id(0),
+            );
+            let node_addrs = node_addrs.clone();
+            async move {
+                for addr in node_addrs {
+                    searcher
+                        .send_request(
+                            MessageBuilder::new_ping_request()
+                                .sender_id(searcher.get_id())
+                                .build()?,
+                            addr,
+                            None,
+                            Some(Duration::from_secs(5)),
+                        )
+                        .await?;
+                }
+                let options = OperationOptions {
+                    family_preference,
+                    ..Default::default()
+                };
+                get_peers_with_options(
+                    &searcher,
+                    info_hash,
+                    Duration::from_secs(5),
+                    &options,
+                    |_| {},
+                )
+                .await
+            }
+        };
+
+        // Peers of both families are merged
+        let result = lookup(Some(FamilyPreference::Both)).await?;
+        assert!(result.peers.iter().any(|peer| peer.is_ipv4()));
+        assert!(result.peers.iter().any(|peer| peer.is_ipv6()));
+        assert!(result.peers.iter().all(|peer| node_addrs.contains(peer)));
+        let unique: HashSet<_> = result.peers.iter().collect();
+        assert_eq!(unique.len(), result.peers.len());
+
+        let result = lookup(Some(FamilyPreference::V4Only)).await?;
+        assert!(!result.peers.is_empty());
+        assert!(result.peers.iter().all(|peer| peer.is_ipv4()));
+        assert!(result.nodes.iter().all(|node| node.address.is_ipv4()));
+
+        // Without a preference, the IPv6 nodes only give our IPv4 searcher IPv4 peers
+        let result = lookup(None).await?;
+        assert!(!result.peers.is_empty());
+        assert!(result.peers.iter().all(|peer| peer.is_ipv4()));
+
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_nodes_available() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, "127.0.0.1:6881".parse().unwrap())
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())
+                .unwrap(),
+        );
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            None,
+        );
+
+        let target = Id::from_random(&mut rand::thread_rng());
+        assert!(matches!(
+            find_node(&dht, target, Duration::from_secs(5)).await,
+            Err(RustyDHTError::NoNodesAvailableError)
+        ));
+        assert!(matches!(
+            get_peers(&dht, target, Duration::from_secs(5)).await,
+            Err(RustyDHTError::NoNodesAvailableError)
+        ));
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_few_nodes_available() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let mut dhts = Vec::new();
+        for i in 1..=2 {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                None,
+            );
+            dhts.push(dht);
+        }
+        dhts[0]
+            .send_request(
+                MessageBuilder::new_ping_request()
+                    .sender_id(dhts[0].get_id())
+                    .build()?,
+                "127.0.0.2:6881".parse().unwrap(),
+                None,
+                Some(Duration::from_secs(5)),
+            )
+            .await?;
+
+        // Knowing a single node is too few to start a get_peers lookup, but it's not none
+        let info_hash = Id::from_random(&mut rand::thread_rng());
+        let result = get_peers(&dhts[0], info_hash, Duration::from_secs(5)).await?;
+        assert!(result.peers().is_empty());
+
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lookup_trace() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let mut dhts = Vec::new();
+        for i in 1..=2 {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+            dhts.push(dht);
+        }
+        // A node that answers the first ping and then never responds again
+        let silent_addr: SocketAddr = "127.0.0.3:6881".parse().unwrap();
+        let silent_id = Id::from_random(&mut rand::thread_rng());
+        let mut silent_rx = network.bind(silent_addr);
+        let silent_network = network.clone();
+        tokio::spawn(async move {
+            let (bytes, from) = silent_rx.recv().await.unwrap();
+            let req = packets::Message::from_bytes(&bytes).unwrap();
+            let reply = MessageBuilder::new_ping_response()
+                .sender_id(silent_id)
+                .transaction_id(req.transaction_id)
+                .build()
+                .unwrap();
+            silent_network.send(silent_addr, from, reply.to_bytes().unwrap());
+            while silent_rx.recv().await.is_some() {}
+        });
+
+        let responsive_addr: SocketAddr = "127.0.0.2:6881".parse().unwrap();
+        for addr in [responsive_addr, silent_addr].iter().copied() {
+            dhts[0]
+                .send_request(
+                    MessageBuilder::new_ping_request()
+                        .sender_id(dhts[0].get_id())
+                        .read_only(true)
+                        .build()?,
+                    addr,
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+        }
+
+        let trace = LookupTrace::new();
+        let options = OperationOptions {
+            request_timeout: Some(Duration::from_secs(1)),
+            trace: Some(trace.clone()),
+            ..OperationOptions::default()
+        };
+        let target = Id::from_random(&mut rand::thread_rng());
+        find_node_with_options(&dhts[0], target, Duration::from_secs(10), &options, |_| {}).await?;
+
+        let steps = trace.steps();
+        assert_eq!(steps.len(), 2);
+        for step in &steps {
+            assert!(matches!(
+                &step.request,
+                packets::RequestSpecific::FindNodeRequest(args) if args.target == target
+            ));
+        }
+        let responsive = steps
+            .iter()
+            .find(|step| step.addr == responsive_addr)
+            .unwrap();
+        assert_eq!(responsive.id, Some(dhts[1].get_id()));
+        assert!(matches!(
+            responsive.outcome,
+            LookupOutcome::Responded(packets::MessageType::Response(
+                packets::ResponseSpecific::FindNodeResponse(_)
+            ))
+        ));
+        assert!(responsive.latency < Duration::from_secs(1));
+        let silent = steps.iter().find(|step| step.addr == silent_addr).unwrap();
+        assert_eq!(silent.id, Some(silent_id));
+        assert_eq!(silent.outcome, LookupOutcome::TimedOut);
+        assert_eq!(silent.latency, Duration::from_secs(1));
+
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_iterative_lookup() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let mut dhts = Vec::new();
+        for i in 1..=8 {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+            dhts.push(dht);
+        }
+        // The second DHT knows everybody, the first one only knows the second one
+        for i in (1..=8).filter(|i| *i != 2) {
+            dhts[1]
+                .send_request(
+                    MessageBuilder::new_ping_request()
+                        .sender_id(dhts[1].get_id())
+                        .build()?,
+                    SocketAddr::new([127, 0, 0, i].into(), 6881),
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+        }
+        dhts[0]
+            .send_request(
+                MessageBuilder::new_ping_request()
+                    .sender_id(dhts[0].get_id())
+                    .read_only(true)
+                    .build()?,
+                "127.0.0.2:6881".parse().unwrap(),
+                None,
+                Some(Duration::from_secs(5)),
+            )
+            .await?;
+
+        let target = Id::from_random(&mut rand::thread_rng());
+        let responders: Vec<GetPeersResponder> =
+            iterative_lookup(&dhts[0], target, Duration::from_secs(5))
+                .collect()
+                .await;
+        assert!(responders
+            .iter()
+            .all(|responder| !responder.token.is_empty()));
+
+        // The node we knew comes first, then the ones it told us about, nearest first
+        let ids: Vec<Id> = responders
+            .into_iter()
+            .map(|responder| responder.node.id)
+            .collect();
+        assert_eq!(ids.len(), 7);
+        assert_eq!(ids[0], dhts[1].get_id());
+        let found: HashSet<Id> = ids.iter().copied().collect();
+        let others: HashSet<Id> = dhts[1..].iter().map(|dht| dht.get_id()).collect();
+        assert_eq!(found, others);
+        for pair in ids[1..].windows(2) {
+            assert!(pair[0].xor(&target) < pair[1].xor(&target));
+        }
+
+        // Stopping early is just a matter of not asking for more
+        let first = iterative_lookup(&dhts[0], target, Duration::from_secs(5))
+            .next()
+            .await
+            .unwrap();
+        assert!(others.contains(&first.node.id));
+
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_iterative_lookup_keeps_responders_on_timeout() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let mut dhts = Vec::new();
+        for i in 1..=2 {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+            dhts.push(dht);
+        }
+        dhts[0]
+            .send_request(
+                MessageBuilder::new_ping_request()
+                    .sender_id(dhts[0].get_id())
+                    .build()?,
+                "127.0.0.2:6881".parse().unwrap(),
+                None,
+                Some(Duration::from_secs(5)),
+            )
+            .await?;
+
+        // A node that answers pings, but never get_peers
+        let silent_addr: SocketAddr = "127.0.0.3:6881".parse().unwrap();
+        let mut silent_rx = network.bind(silent_addr);
+        let silent_network = network.clone();
+        tokio::spawn(async move {
+            while let Some((bytes, from)) = silent_rx.recv().await {
+                let req = packets::Message::from_bytes(&bytes).unwrap();
+                if let packets::MessageType::Request(packets::RequestSpecific::PingRequest(_)) =
+                    req.message_type
+                {
+                    let reply = MessageBuilder::new_ping_response()
+                        .sender_id(Id::from_random(&mut rand::thread_rng()))
+                        .transaction_id(req.transaction_id)
+                        .build()
+                        .unwrap();
+                    silent_network.send(silent_addr, from, reply.to_bytes().unwrap());
+                }
+            }
+        });
+        dhts[0]
+            .send_request(
+                MessageBuilder::new_ping_request()
+                    .sender_id(dhts[0].get_id())
+                    .build()?,
+                silent_addr,
+                None,
+                Some(Duration::from_secs(5)),
+            )
+            .await?;
+
+        // The lookup times out waiting for the silent node, but still yields the one that replied
+        let target = Id::from_random(&mut rand::thread_rng());
+        let lookup_timeout = DEFAULT_REQUEST_TIMEOUT / 2;
+        let ids: Vec<Id> = iterative_lookup(&dhts[0], target, lookup_timeout)
+            .map(|responder| responder.node.id)
+            .collect()
+            .await;
+        assert_eq!(ids, vec![dhts[1].get_id()]);
+
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refresh_self() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let mut dhts = Vec::new();
+        for i in 1..=8 {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+            dhts.push(dht);
+        }
+        // The second DHT knows everybody, the first one only knows the second one
+        for i in (1..=8).filter(|i| *i != 2) {
+            dhts[1]
+                .send_request(
+                    MessageBuilder::new_ping_request()
+                        .sender_id(dhts[1].get_id())
+                        .build()?,
+                    SocketAddr::new([127, 0, 0, i].into(), 6881),
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+        }
+        dhts[0]
+            .send_request(
+                MessageBuilder::new_ping_request()
+                    .sender_id(dhts[0].get_id())
+                    .read_only(true)
+                    .build()?,
+                "127.0.0.2:6881".parse().unwrap(),
+                None,
+                Some(Duration::from_secs(5)),
+            )
+            .await?;
+        assert_eq!(dhts[0].get_nodes().len(), 1);
+
+        let nodes = refresh_self(&dhts[0], Duration::from_secs(5)).await?;
+        let found: HashSet<Id> = nodes.iter().map(|node| node.id).collect();
+        let others: HashSet<Id> = dhts[1..].iter().map(|dht| dht.get_id()).collect();
+        assert_eq!(found, others);
+
+        // The nodes we found are in our routing table now
+        let known: HashSet<Id> = dhts[0]
+            .get_nodes()
+            .iter()
+            .map(|node| node.node.id)
+            .collect();
+        assert_eq!(known, others);
+
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_operation_scheduler() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let shared_addr: SocketAddr = "127.0.0.2:6881".parse().unwrap();
+        let mut dhts = Vec::new();
+        for addr in ["127.0.0.1:6881".parse().unwrap(), shared_addr] {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, addr)
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())?,
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                None,
+            );
+            dhts.push(dht);
+        }
+        dhts[0]
+            .send_request(
+                MessageBuilder::new_ping_request()
+                    .sender_id(dhts[0].get_id())
+                    .build()?,
+                shared_addr,
+                None,
+                Some(Duration::from_secs(5)),
+            )
+            .await?;
+
+        // Notes when the shared node receives each find_node
+        let received_times = Arc::new(Mutex::new(Vec::new()));
+        let mut events = dhts[1].observe();
+        let received_times_clone = received_times.clone();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                if let DHTEventType::MessageReceived(MessageReceivedEvent { message, .. }) =
+                    event.event_type
+                {
+                    if let packets::MessageType::Request(
+                        packets::RequestSpecific::FindNodeRequest(_),
+                    ) = message.message_type
+                    {
+                        received_times_clone
+                            .lock()
+                            .unwrap()
+                            .push(tokio::time::Instant::now());
+                    }
+                }
+            }
+        });
+
+        // Each lookup asks the one node it knows, at the same time as the others
+        let lookups = (0..4).map(|_| {
+            find_node(
+                &dhts[0],
+                Id::from_random(&mut rand::thread_rng()),
+                Duration::from_secs(30),
+            )
+        });
+        for result in futures::future::join_all(lookups).await {
+            result?;
+        }
+
+        let sent_times = received_times.lock().unwrap().clone();
+        assert!(sent_times.len() >= 4);
+        for pair in sent_times.windows(2) {
+            assert!(pair[1] - pair[0] >= Duration::from_secs(1));
+        }
+
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_operation_scheduler_gives_up_turns() -> Result<(), RustyDHTError> {
+        let scheduler = OperationScheduler::new(Duration::from_secs(1));
+        let addr: SocketAddr = "127.0.0.2:6881".parse().unwrap();
+        let start = tokio::time::Instant::now();
+        scheduler.wait_turn(addr, None).await?;
+
+        // A wait whose turn won't come in time fails, and leaves the queue
+        assert!(matches!(
+            scheduler
+                .wait_turn(addr, Some(start + Duration::from_millis(500)))
+                .await,
+            Err(RustyDHTError::TimeoutError(_))
+        ));
+
+        // So does one that's cancelled
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), scheduler.wait_turn(addr, None))
+                .await
+                .is_err()
+        );
+
+        // Neither of them holds up the next request
+        scheduler.wait_turn(addr, None).await?;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_requests_carry_read_only() -> Result<(), RustyDHTError> {
+        #[derive(Clone)]
+        struct Tap(Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for Tap {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        /// Returns the requests that the tapped DHT has sent so far
+        fn sent_requests(tap: &Tap) -> Vec<packets::Message> {
+            let bytes = tap.0.lock().unwrap().clone();
+            let mut reader = bytes.as_slice();
+            let mut requests = Vec::new();
+            while let Some(record) = crate::dht::PacketRecord::read_from(&mut reader).unwrap() {
+                if record.direction != crate::dht::PacketDirection::Sent {
+                    continue;
+                }
+                let msg = packets::Message::from_bytes(&record.bytes).unwrap();
+                if matches!(msg.message_type, packets::MessageType::Request(_)) {
+                    requests.push(msg);
+                }
+            }
+            requests
+        }
+
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let tap = Tap(Arc::new(Mutex::new(Vec::new())));
+        let mut dhts = Vec::new();
+        for i in 1..=8 {
+            let mut builder = DHTBuilder::new()
+                .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                .settings(
+                    DHTSettingsBuilder::new()
+                        .routers(vec![])
+                        .read_only(i == 1)
+                        .build()
+                        .unwrap(),
+                );
+            if i == 1 {
+                builder = builder.packet_tap(tap.clone());
+            }
+            let dht = Arc::new(builder.build(shutdown_rx.clone())?);
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+            dhts.push(dht);
+        }
+
+        // Requests that don't say whether they're read-only get the flag too
+        for i in 2..=8 {
+            dhts[0]
+                .send_request(
+                    MessageBuilder::new_ping_request()
+                        .sender_id(dhts[0].get_id())
+                        .build()?,
+                    SocketAddr::new([127, 0, 0, i].into(), 6881),
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+        }
+        let info_hash = Id::from_random(&mut rand::thread_rng());
+        find_node(&dhts[0], info_hash, Duration::from_secs(5)).await?;
+        get_peers(&dhts[0], info_hash, Duration::from_secs(5)).await?;
+
+        dhts[0].flush_packet_tap();
+        let requests = sent_requests(&tap);
+        let has_request =
+            |requests: &[packets::Message], is_kind: fn(&packets::RequestSpecific) -> bool| {
+                requests.iter().any(|msg| {
+                matches!(&msg.message_type, packets::MessageType::Request(req) if is_kind(req))
+            })
+            };
+        assert!(has_request(&requests, |req| matches!(
+            req,
+            packets::RequestSpecific::FindNodeRequest(_)
+        )));
+        assert!(has_request(&requests, |req| matches!(
+            req,
+            packets::RequestSpecific::GetPeersRequest(_)
+        )));
+        assert!(requests.iter().all(|msg| msg.read_only == Some(true)));
+
+        // Once read-only mode is turned off, the requests say so
+        let mut settings = dhts[0].get_settings();
+        settings.read_only = false;
+        dhts[0].update_settings(settings)?;
+        let sent_before = requests.len();
+        find_node(&dhts[0], info_hash, Duration::from_secs(5)).await?;
+        dhts[0].flush_packet_tap();
+        let requests = sent_requests(&tap);
+        assert!(requests.len() > sent_before);
+        assert!(requests[sent_before..]
+            .iter()
+            .all(|msg| msg.read_only != Some(true)));
+
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_presence_manager() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let mut dhts = Vec::new();
+        for i in 1..=8 {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                // The DHTs have to keep running for a couple of announce intervals
+                None,
+            );
+            dhts.push(dht);
+        }
+        let announcer = dhts[0].clone();
+        for i in 2..=8 {
+            announcer
+                .send_request(
+                    MessageBuilder::new_ping_request()
+                        .sender_id(announcer.get_id())
+                        .build()?,
+                    SocketAddr::new([127, 0, 0, i].into(), 6881),
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+        }
+
+        let interval = Duration::from_secs(20);
+        let manager = PresenceManager::new(announcer.clone(), interval, shutdown_rx.clone());
+        let info_hashes = [
+            Id::from_random(&mut rand::thread_rng()),
+            Id::from_random(&mut rand::thread_rng()),
+        ];
+        manager.add(info_hashes[0], Some(7000));
+        manager.add(info_hashes[1], Some(7001));
+
+        tokio::time::sleep(interval / 2).await;
+        let mut first_announces = Vec::new();
+        for info_hash in info_hashes {
+            let status = manager.status(info_hash).unwrap();
+            assert!(status.nodes_reached > 0);
+            assert_eq!(status.failures, 0);
+            first_announces.push(status.last_announced.unwrap());
+        }
+        for dht in &dhts[1..] {
+            let mut stored = dht.get_info_hashes(None);
+            stored.sort_by_key(|(_, peers)| peers[0].addr.port());
+            let stored: Vec<(Id, Vec<SocketAddr>)> = stored
+                .into_iter()
+                .map(|(info_hash, peers)| (info_hash, peers.iter().map(|p| p.addr).collect()))
+                .collect();
+            assert_eq!(
+                stored,
+                vec![
+                    (info_hashes[0], vec!["127.0.0.1:7000".parse().unwrap()]),
+                    (info_hashes[1], vec!["127.0.0.1:7001".parse().unwrap()]),
+                ]
+            );
+        }
+
+        // Once one is removed, only the other gets announced again after the interval
+        assert!(manager.remove(info_hashes[0]));
+        assert!(!manager.remove(info_hashes[0]));
+        assert_eq!(manager.status(info_hashes[0]), None);
+        assert_eq!(manager.statuses().len(), 1);
+        let mut events = dhts[1].observe();
+        tokio::time::sleep(interval + interval / 2).await;
+        let last_announced = manager
+            .status(info_hashes[1])
+            .unwrap()
+            .last_announced
+            .unwrap();
+        assert!(last_announced >= first_announces[1] + interval);
+        let mut looked_up = Vec::new();
+        while let Some(event) = events.try_recv() {
+            if let DHTEventType::MessageReceived(MessageReceivedEvent { message, .. }) =
+                event.event_type
+            {
+                if let packets::MessageType::Request(packets::RequestSpecific::GetPeersRequest(
+                    args,
+                )) = message.message_type
+                {
+                    looked_up.push(args.info_hash);
+                }
+            }
+        }
+        assert_eq!(events.missed(), 0);
+        assert_eq!(looked_up, vec![info_hashes[1]]);
+
+        drop(announcer);
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        manager.join().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_presence_manager_debounces_changes() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let mut dhts = Vec::new();
+        for i in 1..=2 {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                None,
+            );
+            dhts.push(dht);
+        }
+        let dht = dhts[0].clone();
+        dht.send_request(
+            MessageBuilder::new_ping_request()
+                .sender_id(dht.get_id())
+                .build()?,
+            "127.0.0.2:6881".parse().unwrap(),
+            None,
+            Some(Duration::from_secs(5)),
+        )
+        .await?;
+
+        let interval = Duration::from_secs(60);
+        let manager = PresenceManager::new(dht.clone(), interval, shutdown_rx.clone());
+        let info_hash = Id::from_random(&mut rand::thread_rng());
+        let start = tokio::time::Instant::now();
+        manager.add(info_hash, Some(7000));
+        // The announce gives up after PERIODIC_ANNOUNCE_TIMEOUT waiting for more nodes
+        tokio::time::sleep(PERIODIC_ANNOUNCE_TIMEOUT + Duration::from_secs(5)).await;
+        let first_announce = manager.status(info_hash).unwrap().last_announced.unwrap();
+
+        // Port changes within the interval wait for it to pass, and are announced once
+        manager.add(info_hash, Some(7001));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        manager.add(info_hash, Some(7002));
+        tokio::time::sleep_until(start + interval + PERIODIC_ANNOUNCE_TIMEOUT / 2).await;
+        assert_eq!(
+            manager.status(info_hash).unwrap().last_announced,
+            Some(first_announce)
+        );
+        tokio::time::sleep_until(start + interval + PERIODIC_ANNOUNCE_TIMEOUT * 3).await;
+        let status = manager.status(info_hash).unwrap();
+        assert_eq!(status.port, Some(7002));
+        assert!(status.last_announced.unwrap() >= first_announce + interval);
+
+        drop(dht);
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        manager.join().await;
+
+        Ok(())
+    }
+    #[tokio::test(start_paused = true)]
+    async fn test_find_storage_nodes() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let mut dhts = Vec::new();
+        for i in 1..=9 {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+            dhts.push(dht);
+        }
+
+        // Introduce the searcher to everyone else
+        let searcher = dhts[0].clone();
+        for i in 2..=9 {
+            searcher
+                .send_request(
+                    MessageBuilder::new_ping_request()
+                        .sender_id(searcher.get_id())
+                        .build()?,
+                    SocketAddr::new([127, 0, 0, i].into(), 6881),
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+        }
+
+        let target = Id::from_random(&mut rand::thread_rng());
+        let responders = find_storage_nodes(&searcher, target, Duration::from_secs(10)).await?;
+        assert_eq!(responders.len(), 8);
+        let mut ids = HashSet::new();
+        for pair in responders.windows(2) {
+            assert!(pair[0].node.id.xor(&target) <= pair[1].node.id.xor(&target));
+        }
+        for responder in responders {
+            assert!(!responder.token.is_empty());
+            assert!(ids.insert(responder.node.id));
+        }
+
+        drop(searcher);
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_to_nodes() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let mut dhts = Vec::new();
+        for i in 1..=4 {
+            let dht = Arc::new(
+                DHTBuilder::new()
+                    .mock_network(&network, SocketAddr::new([127, 0, 0, i].into(), 6881))
+                    .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                    .build(shutdown_rx.clone())
+                    .unwrap(),
+            );
+            let dht_clone = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht_clone.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(10)),
+            );
+            dhts.push(dht);
+        }
+
+        // Get tokens from two of the nodes
+        let info_hash = Id::from_random(&mut rand::thread_rng());
+        let mut responders = Vec::new();
+        for dht in &dhts[1..3] {
+            let addr = dht.local_addr()?;
+            let reply = dhts[0]
+                .send_request(
+                    MessageBuilder::new_get_peers_request()
+                        .sender_id(dhts[0].get_id())
+                        .target(info_hash)
+                        .build()?,
+                    addr,
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+            match reply.message_type {
+                packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
+                    args,
+                )) => responders.push(GetPeersResponder::new(
+                    Node::new(args.responder_id, addr),
+                    args.token,
+                )),
+                _ => panic!("Unexpected reply {:?}", reply),
+            }
+        }
+
+        // A node that never answers
+        let silent_addr: SocketAddr = "127.0.0.9:6881".parse().unwrap();
+        let _silent_rx = network.bind(silent_addr);
+        let silent_node = Node::new(Id::from_random(&mut rand::thread_rng()), silent_addr);
+        responders.push(GetPeersResponder::new(silent_node, vec![1, 2, 3, 4]));
+
+        let announced = announce_to_nodes(
+            &dhts[0],
+            info_hash,
+            Some(1234),
+            &responders,
+            Duration::from_secs(1),
+        )
+        .await?;
+        let announced_ids: HashSet<Id> = announced.iter().map(|node| node.id).collect();
+        let expected_ids: HashSet<Id> = dhts[1..3].iter().map(|dht| dht.get_id()).collect();
+        assert_eq!(announced_ids, expected_ids);
+        assert_eq!(announced.len(), 2);
+
+        // Only the nodes we announced to store our peer
+        let our_peer: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        for dht in &dhts[1..3] {
+            let stored = dht.get_info_hashes(None);
+            assert_eq!(stored.len(), 1);
+            assert_eq!(stored[0].0, info_hash);
+            assert_eq!(stored[0].1[0].addr, our_peer);
+        }
+        assert!(dhts[3].get_info_hashes(None).is_empty());
+
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_to_nodes_waits_within_timeout() -> Result<(), RustyDHTError> {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, "127.0.0.1:6881".parse().unwrap())
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())?,
+        );
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        // Two announces to the same node, which never answers. The second one has to wait
+        // its turn, but still gives up when the timeout is up.
+        let silent_addr: SocketAddr = "127.0.0.9:6881".parse().unwrap();
+        let _silent_rx = network.bind(silent_addr);
+        let responders = vec![GetPeersResponder::new(
+            Node::new(Id::from_random(&mut rand::thread_rng()), silent_addr),
+            vec![1, 2, 3, 4],
+        )];
+        let info_hash = Id::from_random(&mut rand::thread_rng());
+        let timeout = Duration::from_secs(2);
+        let start = tokio::time::Instant::now();
+        let (first, second) = tokio::join!(
+            announce_to_nodes(&dht, info_hash, Some(1234), &responders, timeout),
+            announce_to_nodes(&dht, info_hash, Some(1234), &responders, timeout),
+        );
+        assert!(first?.is_empty());
+        assert!(second?.is_empty());
+        assert_eq!(start.elapsed(), timeout);
+
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_router() {
+        assert_eq!(
+            resolve_router("127.0.0.1:6881").await.unwrap(),
+            "127.0.0.1:6881".parse().unwrap()
+        );
+
+        // Missing the port
+        assert!(matches!(
+            resolve_router("router.bittorrent.com").await,
+            Err(RustyDHTError::ResolutionError(host, _)) if host == "router.bittorrent.com"
+        ));
+
+        // The .invalid TLD never resolves
+        assert!(matches!(
+            resolve_router("router.bittorrent.invalid:6881").await,
+            Err(RustyDHTError::ResolutionError(host, _)) if host == "router.bittorrent.invalid:6881"
+        ));
+
+        // Only IPv6
+        assert!(matches!(
+            resolve_router("[::1]:6881").await,
+            Err(RustyDHTError::ResolutionError(_, _))
+        ));
+    }
+
+    /// Starts a fake node that answers find_node with the next node in an endless chain,
+    /// each closer to `target` than the last. Lookups through it never converge.
+    fn spawn_endless_node(target: Id, depth: usize) -> Node {
+        let std_sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        std_sock.set_nonblocking(true).unwrap();
+        let socket = tokio::net::UdpSocket::from_std(std_sock).unwrap();
+        let mut id_bytes = target.to_vec();
+        id_bytes[depth / 8] ^= 0x80 >> (depth % 8);
+        let node = Node::new(
+            Id::from_bytes(&id_bytes).unwrap(),
+            socket.local_addr().unwrap(),
+        );
+
+        let id = node.id;
+        tokio::spawn(async move {
+            let mut next = None;
+            let mut buf = [0; 2048];
+            while let Ok((num_read, from)) = socket.recv_from(&mut buf).await {
+                let req = packets::Message::from_bytes(&buf[..num_read]).unwrap();
+                let builder = match req.message_type {
+                    packets::MessageType::Request(packets::RequestSpecific::PingRequest(_)) => {
+                        MessageBuilder::new_ping_response()
+                    }
+                    _ => {
+                        let next =
+                            next.get_or_insert_with(|| spawn_endless_node(target, depth + 1));
+                        MessageBuilder::new_find_node_response().nodes(vec![next.clone()])
+                    }
+                };
+                let reply = builder
+                    .sender_id(id)
+                    .transaction_id(req.transaction_id)
+                    .build()
+                    .unwrap();
+                let _ = socket.send_to(&reply.to_bytes().unwrap(), from).await;
+            }
+        });
+
+        node
+    }
+
+    #[tokio::test]
+    async fn test_find_node_max_rounds() -> Result<(), RustyDHTError> {
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2318))
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())
+                .unwrap(),
+        );
+        let dht_clone = dht.clone();
+        shutdown::ShutdownReceiver::spawn_with_shutdown(
+            shutdown_rx.clone(),
+            async move {
+                dht_clone.run_event_loop().await.unwrap();
+            },
+            "Test DHT",
+            Some(Duration::from_secs(10)),
+        );
+
+        let target = Id::from_random(&mut rand::thread_rng());
+        let first = spawn_endless_node(target, 0);
+        dht.send_request(
+            MessageBuilder::new_ping_request()
+                .sender_id(dht.get_id())
+                .build()?,
+            first.address,
+            Some(first.id),
+            Some(Duration::from_secs(5)),
+        )
+        .await?;
+
+        let options = OperationOptions {
+            max_rounds: Some(3),
+            ..Default::default()
+        };
+        let mut rounds = 0;
+        let found = find_node_with_options(&dht, target, Duration::from_secs(30), &options, |p| {
+            rounds = p.round
+        })
+        .await?;
+        assert_eq!(rounds, 3);
+        // Each round turned up one more node
+        assert_eq!(found.len(), 4);
+
+        // Shutdown waits for every ShutdownReceiver to drop, including the DHT's
+        drop(dht);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    /// Returns an announce function that counts its calls and reaches `num_nodes` nodes
+    fn counting_announce(
+        calls: Arc<AtomicUsize>,
+        num_nodes: usize,
+    ) -> impl FnMut() -> futures::future::Ready<Result<Vec<Node>, RustyDHTError>> {
+        move || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            let node = Node::new(Id::ZERO, "1.2.3.4:5678".parse().unwrap());
+            futures::future::ready(Ok(vec![node; num_nodes]))
+        }
+    }
+
+    /// Sleeps until `secs` after `start`, then returns the number of calls so far
+    async fn calls_at(start: tokio::time::Instant, secs: u64, calls: &AtomicUsize) -> usize {
+        tokio::time::sleep_until(start + Duration::from_secs(secs)).await;
+        calls.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_periodically_repeats() {
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let start = tokio::time::Instant::now();
+        let handle = tokio::spawn(announce_periodically(
+            Id::ZERO,
+            counting_announce(calls.clone(), 1),
+            Duration::from_secs(60),
+            None,
+            shutdown_rx,
+        ));
+
+        assert_eq!(calls_at(start, 1, &calls).await, 1);
+        assert_eq!(calls_at(start, 59, &calls).await, 1);
+        assert_eq!(calls_at(start, 61, &calls).await, 2);
+        assert_eq!(calls_at(start, 121, &calls).await, 3);
+
+        shutdown_tx.shutdown().await;
+        handle.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_periodically_backs_off() {
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let start = tokio::time::Instant::now();
+        // Reaching no nodes counts as a failure
+        let handle = tokio::spawn(announce_periodically(
+            Id::ZERO,
+            counting_announce(calls.clone(), 0),
+            Duration::from_secs(60),
+            None,
+            shutdown_rx,
+        ));
+
+        // Waits 120s, then 240s, then 480s, then 480s
+        assert_eq!(calls_at(start, 119, &calls).await, 1);
+        assert_eq!(calls_at(start, 121, &calls).await, 2);
+        assert_eq!(calls_at(start, 359, &calls).await, 2);
+        assert_eq!(calls_at(start, 361, &calls).await, 3);
+        assert_eq!(calls_at(start, 841, &calls).await, 4);
+        assert_eq!(calls_at(start, 1321, &calls).await, 5);
+
+        shutdown_tx.shutdown().await;
+        handle.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_periodically_clamps_interval() {
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let start = tokio::time::Instant::now();
+        let handle = tokio::spawn(announce_periodically(
+            Id::ZERO,
+            counting_announce(calls.clone(), 1),
+            Duration::ZERO,
+            None,
+            shutdown_rx,
+        ));
+
+        tokio::time::sleep_until(start + Duration::from_millis(1500)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        shutdown_tx.shutdown().await;
+        handle.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_peer_periodic_stops_when_cancelled() {
+        let (_, dht_shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2319))
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(dht_shutdown_rx)
+                .unwrap(),
+        );
+
+        let (mut cancel_tx, cancel_rx) = shutdown::create_shutdown();
+        let handle = announce_peer_periodic(
+            dht,
+            Id::from_random(&mut rand::thread_rng()),
+            Some(1234),
+            Duration::from_secs(60),
+            cancel_rx,
+        );
+        tokio::time::sleep(Duration::from_secs(300)).await;
+        cancel_tx.shutdown().await;
+        handle.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_peer_periodic_doesnt_block_shutdown() {
+        let network = MockNetwork::new();
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dht = Arc::new(
+            DHTBuilder::new()
+                .mock_network(&network, "10.0.0.1:6881".parse().unwrap())
+                .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                .build(shutdown_rx.clone())
+                .unwrap(),
+        );
+
+        // Never cancelled
+        let (_cancel_tx, cancel_rx) = shutdown::create_shutdown();
+        let handle = announce_peer_periodic(
+            dht.clone(),
+            Id::from_random(&mut rand::thread_rng()),
+            Some(1234),
+            Duration::from_secs(60),
+            cancel_rx,
+        );
+        tokio::time::sleep(Duration::from_secs(300)).await;
+
+        drop(dht);
+        drop(shutdown_rx);
+        tokio::time::timeout(Duration::from_secs(60), shutdown_tx.shutdown())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(600), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_estimate_network_size() -> Result<(), RustyDHTError> {
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let dhts: Vec<Arc<DHT>> = (2320..2328)
+            .map(|port| {
+                Arc::new(
+                    DHTBuilder::new()
+                        .initial_id(Id::from_random(&mut rand::thread_rng()))
+                        .listen_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))
+                        .settings(DHTSettingsBuilder::new().routers(vec![]).build().unwrap())
+                        .build(shutdown_rx.clone())
+                        .unwrap(),
+                )
+            })
+            .collect();
+        for dht in &dhts {
+            let dht = dht.clone();
+            shutdown::ShutdownReceiver::spawn_with_shutdown(
+                shutdown_rx.clone(),
+                async move {
+                    dht.run_event_loop().await.unwrap();
+                },
+                "Test DHT",
+                Some(Duration::from_secs(30)),
+            );
+        }
+
+        // The first DHT knows about all the others
+        let searcher = dhts[0].clone();
+        for port in 2321..2328 {
+            searcher
+                .send_request(
+                    MessageBuilder::new_ping_request()
+                        .sender_id(searcher.get_id())
+                        .build()?,
+                    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)),
+                    None,
+                    Some(Duration::from_secs(5)),
+                )
+                .await?;
+        }
+
+        let estimate = estimate_network_size(&searcher, 3, Duration::from_secs(9)).await?;
+        assert_eq!(estimate.samples, 3);
+        assert!(estimate.low <= estimate.estimate && estimate.estimate <= estimate.high);
+        // 7 other nodes. The estimate is rough, but should be in the right ballpark
+        assert!(
+            estimate.estimate >= 3 && estimate.estimate <= 40,
+            "{:?}",
+            estimate
+        );
+
+        // Shutdown waits for every ShutdownReceiver to drop, including the DHTs'
+        drop(searcher);
+        drop(dhts);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,293 @@
+use crate::dht::drop_log_sampler::DropLogSampler;
+use crate::errors::RustyDHTError;
+use crate::packets::{bytes_to_sockaddr, sockaddr_to_bytes};
+use anyhow::anyhow;
+use log::warn;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Whether a [PacketRecord] was sent or received by the DHT
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PacketDirection {
+    Sent,
+    Received,
+}
+
+/// One packet captured by a packet tap (see [DHTBuilder::packet_tap](crate::dht::DHTBuilder::packet_tap)).
+///
+/// On the wire, each record is:
+/// * The length of the rest of the record, as a big-endian u32
+/// * The capture time in microseconds since the Unix epoch, as a big-endian u64
+/// * The direction: 0 for sent, 1 for received
+/// * The length of the remote address (6 for IPv4, 18 for IPv6), as a u8
+/// * The remote address in compact format (IP followed by a big-endian port)
+/// * The raw bytes of the packet
+#[derive(Debug, PartialEq, Clone)]
+pub struct PacketRecord {
+    /// When the packet was sent or received
+    pub timestamp: SystemTime,
+
+    /// Whether the packet was sent or received
+    pub direction: PacketDirection,
+
+    /// The address the packet was sent to or received from
+    pub addr: SocketAddr,
+
+    /// The raw bytes of the packet, exactly as they were on the network
+    pub bytes: Vec<u8>,
+}
+
+impl PacketRecord {
+    /// Writes the record to `writer`.
+    pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> std::io::Result<()> {
+        let micros = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_micros() as u64;
+        let addr = sockaddr_to_bytes(&self.addr);
+        let direction: u8 = match self.direction {
+            PacketDirection::Sent => 0,
+            PacketDirection::Received => 1,
+        };
+
+        let mut record = Vec::with_capacity(14 + addr.len() + self.bytes.len());
+        record.extend_from_slice(&((10 + addr.len() + self.bytes.len()) as u32).to_be_bytes());
+        record.extend_from_slice(&micros.to_be_bytes());
+        record.push(direction);
+        record.push(addr.len() as u8);
+        record.extend_from_slice(&addr);
+        record.extend_from_slice(&self.bytes);
+        writer.write_all(&record)
+    }
+
+    /// Reads the next record from `reader`, or returns None if it's at the end of its
+    /// records.
+    pub fn read_from<R: Read + ?Sized>(
+        reader: &mut R,
+    ) -> Result<Option<PacketRecord>, RustyDHTError> {
+        let mut length = [0u8; 4];
+        match reader.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(RustyDHTError::GeneralError(e.into())),
+        }
+        let mut record = vec![0u8; u32::from_be_bytes(length) as usize];
+        reader
+            .read_exact(&mut record)
+            .map_err(|e| RustyDHTError::PacketParseError(e.into()))?;
+
+        if record.len() < 10 {
+            return Err(RustyDHTError::PacketParseError(anyhow!(
+                "Packet record is too short ({} bytes)",
+                record.len()
+            )));
+        }
+        let mut micros = [0u8; 8];
+        micros.copy_from_slice(&record[..8]);
+        let direction = match record[8] {
+            0 => PacketDirection::Sent,
+            1 => PacketDirection::Received,
+            other => {
+                return Err(RustyDHTError::PacketParseError(anyhow!(
+                    "Unknown packet direction {}",
+                    other
+                )))
+            }
+        };
+        let addr_end = 10 + record[9] as usize;
+        if record.len() < addr_end {
+            return Err(RustyDHTError::PacketParseError(anyhow!(
+                "Packet record is too short for its address"
+            )));
+        }
+
+        Ok(Some(PacketRecord {
+            timestamp: UNIX_EPOCH + Duration::from_micros(u64::from_be_bytes(micros)),
+            direction,
+            addr: bytes_to_sockaddr(&record[10..addr_end])?,
+            bytes: record[addr_end..].to_vec(),
+        }))
+    }
+}
+
+/// Number of records that can wait to be written before new ones are dropped
+const PACKET_TAP_QUEUE_LEN: usize = 1024;
+
+/// One of every this many records dropped because the queue was full gets logged
+const DROPPED_RECORDS_LOG_RATE: u64 = 1000;
+
+enum TapMessage {
+    Record(PacketRecord),
+
+    /// Asks the writer thread to say when it has written everything sent before this
+    #[cfg(test)]
+    Flush(mpsc::SyncSender<()>),
+}
+
+/// Writes a [PacketRecord] for every packet that a DHTSocket sends or receives.
+///
+/// The records are queued for a thread of their own that does the writing, so that a slow
+/// writer doesn't hold up the DHT's tasks. When the queue is full, records are dropped. See
+/// [DHTBuilder::packet_tap](crate::dht::DHTBuilder::packet_tap).
+#[derive(Clone)]
+pub(crate) struct PacketTap {
+    queue: mpsc::SyncSender<TapMessage>,
+    dropped: Arc<Mutex<DropLogSampler>>,
+}
+
+impl PacketTap {
+    pub(crate) fn new(writer: impl Write + Send + 'static) -> PacketTap {
+        let (queue, records) = mpsc::sync_channel(PACKET_TAP_QUEUE_LEN);
+        thread::Builder::new()
+            .name("rustydht packet tap".to_string())
+            .spawn(move || PacketTap::write_records(writer, records))
+            .expect("Failed to start the packet tap thread");
+        PacketTap {
+            queue,
+            dropped: Arc::new(Mutex::new(DropLogSampler::new(DROPPED_RECORDS_LOG_RATE))),
+        }
+    }
+
+    /// Records a packet. Returns false if the writer failed, in which case the tap should
+    /// be removed.
+    pub(crate) fn record(
+        &self,
+        direction: PacketDirection,
+        addr: SocketAddr,
+        bytes: &[u8],
+    ) -> bool {
+        let record = PacketRecord {
+            timestamp: SystemTime::now(),
+            direction,
+            addr,
+            bytes: bytes.to_vec(),
+        };
+        match self.queue.try_send(TapMessage::Record(record)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                let mut dropped = self.dropped.lock().unwrap();
+                if dropped.record() {
+                    warn!(target: "rustydht_lib::DHTSocket", "Packet tap can't keep up, dropped {} records so far", dropped.total());
+                }
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    /// Blocks until every record queued so far has been written
+    #[cfg(test)]
+    pub(crate) fn flush(&self) {
+        let (done_tx, done_rx) = mpsc::sync_channel(1);
+        if self.queue.send(TapMessage::Flush(done_tx)).is_ok() {
+            let _ = done_rx.recv();
+        }
+    }
+
+    /// Writes the queued records to `writer` until every PacketTap is dropped or writing fails
+    fn write_records(mut writer: impl Write, records: mpsc::Receiver<TapMessage>) {
+        for message in records {
+            match message {
+                TapMessage::Record(record) => {
+                    if let Err(e) = record.write_to(&mut writer) {
+                        warn!(target: "rustydht_lib::DHTSocket", "Failed to write to packet tap, removing it: {:?}", e);
+                        return;
+                    }
+                }
+                #[cfg(test)]
+                TapMessage::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_record_round_trip() {
+        let records = vec![
+            PacketRecord {
+                timestamp: UNIX_EPOCH + Duration::from_micros(1_600_000_000_123_456),
+                direction: PacketDirection::Sent,
+                addr: "1.2.3.4:6881".parse().unwrap(),
+                bytes: b"d1:y1:qe".to_vec(),
+            },
+            PacketRecord {
+                timestamp: UNIX_EPOCH + Duration::from_micros(1_600_000_001_000_000),
+                direction: PacketDirection::Received,
+                addr: "[2001:db8::1]:1234".parse().unwrap(),
+                bytes: Vec::new(),
+            },
+        ];
+        let mut buf = Vec::new();
+        for record in &records {
+            record.write_to(&mut buf).unwrap();
+        }
+
+        let mut reader = buf.as_slice();
+        for record in &records {
+            assert_eq!(
+                PacketRecord::read_from(&mut reader).unwrap().as_ref(),
+                Some(record)
+            );
+        }
+        assert_eq!(PacketRecord::read_from(&mut reader).unwrap(), None);
+
+        // A truncated record is an error, not the end
+        let mut reader = &buf[..buf.len() - 1];
+        PacketRecord::read_from(&mut reader).unwrap();
+        assert!(PacketRecord::read_from(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_packet_tap_doesnt_wait_for_writer() {
+        /// Holds up every write until the test lets it through
+        struct StuckWriter(mpsc::Receiver<()>);
+        impl Write for StuckWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                let _ = self.0.recv();
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        /// Fails every write
+        struct BrokenWriter;
+        impl Write for BrokenWriter {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // Records that don't fit in the queue while the writer is stuck are dropped
+        let addr: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let (unstick_tx, unstick_rx) = mpsc::channel();
+        let tap = PacketTap::new(StuckWriter(unstick_rx));
+        for _ in 0..PACKET_TAP_QUEUE_LEN * 2 {
+            assert!(tap.record(PacketDirection::Sent, addr, b"d1:y1:qe"));
+        }
+        assert!(tap.dropped.lock().unwrap().total() >= PACKET_TAP_QUEUE_LEN as u64 - 1);
+        drop(unstick_tx);
+        tap.flush();
+
+        // Once the writer fails, the tap asks to be removed
+        let tap = PacketTap::new(BrokenWriter);
+        assert!(tap.record(PacketDirection::Sent, addr, b"d1:y1:qe"));
+        tap.flush();
+        assert!(!tap.record(PacketDirection::Sent, addr, b"d1:y1:qe"));
+    }
+}
@@ -0,0 +1,147 @@
+use lru::LruCache;
+use std::net::IpAddr;
+
+/// Tallies the ports that other nodes say our packets come from (the port of the
+/// `requester_ip` in their responses), to learn our external port behind a NAT that
+/// rewrites ports.
+///
+/// Works like [IPV4Consensus](crate::common::ipv4_addr_src::IPV4Consensus): each port
+/// collects up to `max_votes` votes, which decay over time, and the port with the most
+/// votes wins once it has at least `min_votes`. Only [MAX_TRACKED_PORTS] ports are
+/// tracked at once; a new port pushes out the one with the fewest votes.
+///
+/// Each voter (by IP address) only gets one vote between decays, so a single node can't
+/// decide the outcome by answering lots of requests.
+pub(crate) struct PortConsensus {
+    min_votes: usize,
+    max_votes: usize,
+    /// Sorted by votes, most first
+    votes: Vec<(u16, usize)>,
+    /// Who voted since the last decay
+    voters: LruCache<IpAddr, ()>,
+}
+
+/// Number of ports that [PortConsensus] keeps votes for
+pub(crate) const MAX_TRACKED_PORTS: usize = 16;
+
+/// Number of voters that [PortConsensus] remembers between decays
+const MAX_TRACKED_VOTERS: usize = 256;
+
+impl PortConsensus {
+    pub(crate) fn new(min_votes: usize, max_votes: usize) -> PortConsensus {
+        PortConsensus {
+            min_votes,
+            max_votes,
+            votes: Vec::new(),
+            voters: LruCache::new(MAX_TRACKED_VOTERS),
+        }
+    }
+
+    /// Returns the port with the most votes, if it has enough of them
+    pub(crate) fn get_best_port(&self) -> Option<u16> {
+        match self.votes.first() {
+            Some((port, votes)) if *votes >= self.min_votes => Some(*port),
+            _ => None,
+        }
+    }
+
+    /// Counts `voter`'s vote for `port`, unless it already voted since the last decay
+    pub(crate) fn add_vote(&mut self, voter: IpAddr, port: u16) {
+        if self.voters.put(voter, ()).is_some() {
+            return;
+        }
+        match self.votes.iter().position(|(p, _)| *p == port) {
+            Some(mut i) => {
+                self.votes[i].1 = (self.votes[i].1 + 1).min(self.max_votes);
+                // Move it up past the ports it now has more votes than
+                while i > 0 && self.votes[i - 1].1 < self.votes[i].1 {
+                    self.votes.swap(i - 1, i);
+                    i -= 1;
+                }
+            }
+            None => {
+                if self.votes.len() >= MAX_TRACKED_PORTS {
+                    self.votes.pop();
+                }
+                self.votes.push((port, 1));
+            }
+        }
+    }
+
+    /// Takes a vote away from every port, forgetting ports that have none left, and lets
+    /// everybody vote again
+    pub(crate) fn decay(&mut self) {
+        self.voters.clear();
+        for (_, votes) in self.votes.iter_mut() {
+            *votes -= 1;
+        }
+        self.votes.retain(|(_, votes)| *votes > 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// Votes for `port` from a voter that hasn't voted yet
+    fn vote(consensus: &mut PortConsensus, port: u16) {
+        let voter = IpAddr::V4(Ipv4Addr::from(consensus.voters.len() as u32 + 1));
+        consensus.add_vote(voter, port);
+    }
+
+    #[test]
+    fn test_port_consensus() {
+        let mut consensus = PortConsensus::new(2, 3);
+        vote(&mut consensus, 40000);
+        assert_eq!(consensus.get_best_port(), None);
+        vote(&mut consensus, 40000);
+        assert_eq!(consensus.get_best_port(), Some(40000));
+
+        // Votes are capped, so a new port can take over
+        for _ in 0..5 {
+            vote(&mut consensus, 40000);
+        }
+        for _ in 0..4 {
+            vote(&mut consensus, 50000);
+        }
+        assert_eq!(consensus.get_best_port(), Some(40000));
+        consensus.decay();
+        consensus.decay();
+        vote(&mut consensus, 50000);
+        assert_eq!(consensus.get_best_port(), Some(50000));
+
+        consensus.decay();
+        consensus.decay();
+        consensus.decay();
+        assert_eq!(consensus.get_best_port(), None);
+    }
+
+    #[test]
+    fn test_port_consensus_is_bounded() {
+        let mut consensus = PortConsensus::new(2, 10);
+        vote(&mut consensus, 40000);
+        vote(&mut consensus, 40000);
+        for port in 0..100 {
+            vote(&mut consensus, port);
+        }
+        assert_eq!(consensus.votes.len(), MAX_TRACKED_PORTS);
+        assert_eq!(consensus.get_best_port(), Some(40000));
+    }
+
+    #[test]
+    fn test_port_consensus_one_vote_per_voter() {
+        let mut consensus = PortConsensus::new(2, 10);
+        let voter = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        consensus.add_vote(voter, 40000);
+        consensus.add_vote(voter, 40000);
+        consensus.add_vote(voter, 50000);
+        assert_eq!(consensus.votes, vec![(40000, 1)]);
+        assert_eq!(consensus.get_best_port(), None);
+
+        // It can vote again after a decay
+        consensus.decay();
+        consensus.add_vote(voter, 40000);
+        assert_eq!(consensus.votes, vec![(40000, 1)]);
+    }
+}
@@ -0,0 +1,156 @@
+use crate::packets::RequestSpecific;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The window is counted in this many slots, which expire one at a time
+const SLOTS_PER_WINDOW: u32 = 10;
+
+/// A type of request that other nodes send us
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum QueryType {
+    Ping,
+    FindNode,
+    GetPeers,
+    AnnouncePeer,
+    SampleInfoHashes,
+}
+
+impl QueryType {
+    pub(crate) fn of(request: &RequestSpecific) -> QueryType {
+        match request {
+            RequestSpecific::PingRequest(_) => QueryType::Ping,
+            RequestSpecific::FindNodeRequest(_) => QueryType::FindNode,
+            RequestSpecific::GetPeersRequest(_) => QueryType::GetPeers,
+            RequestSpecific::AnnouncePeerRequest(_) => QueryType::AnnouncePeer,
+            RequestSpecific::SampleInfoHashesRequest(_) => QueryType::SampleInfoHashes,
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            QueryType::Ping => 0,
+            QueryType::FindNode => 1,
+            QueryType::GetPeers => 2,
+            QueryType::AnnouncePeer => 3,
+            QueryType::SampleInfoHashes => 4,
+        }
+    }
+}
+
+/// How many requests of each [QueryType] the DHT received recently, returned by
+/// [DHT::query_type_rates](crate::dht::DHT::query_type_rates).
+#[derive(Debug, PartialEq, Clone)]
+pub struct QueryTypeRates {
+    /// How far back the counts go, per [query_rates_window_secs](crate::dht::DHTSettings::query_rates_window_secs)
+    pub window: Duration,
+
+    counts: [usize; 5],
+}
+
+impl QueryTypeRates {
+    /// Number of requests of `query_type` received within the window
+    pub fn count(&self, query_type: QueryType) -> usize {
+        self.counts[query_type.index()]
+    }
+
+    /// Number of requests of any type received within the window
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    /// Average number of requests of `query_type` received per minute over the window.
+    ///
+    /// While the DHT has been running for less than the window, this is lower than the
+    /// actual rate.
+    pub fn per_minute(&self, query_type: QueryType) -> f64 {
+        let minutes = self.window.as_secs_f64() / 60.0;
+        if minutes > 0.0 {
+            self.count(query_type) as f64 / minutes
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Counts incoming requests by [QueryType] in a sliding window
+pub(crate) struct QueryTypeCounter {
+    window: Duration,
+
+    /// Start time and counts of each slot, oldest first
+    slots: VecDeque<(Instant, [usize; 5])>,
+}
+
+impl QueryTypeCounter {
+    pub(crate) fn new(window: Duration) -> QueryTypeCounter {
+        QueryTypeCounter {
+            window,
+            slots: VecDeque::with_capacity(SLOTS_PER_WINDOW as usize + 1),
+        }
+    }
+
+    pub(crate) fn record(&mut self, query_type: QueryType, now: Instant) {
+        self.expire(now);
+        let slot_length = self.window / SLOTS_PER_WINDOW;
+        match self.slots.back_mut() {
+            Some((start, counts)) if now.saturating_duration_since(*start) < slot_length => {
+                counts[query_type.index()] += 1;
+            }
+            _ => {
+                let mut counts = [0; 5];
+                counts[query_type.index()] = 1;
+                self.slots.push_back((now, counts));
+            }
+        }
+    }
+
+    pub(crate) fn rates(&mut self, now: Instant) -> QueryTypeRates {
+        self.expire(now);
+        let mut counts = [0; 5];
+        for (_, slot_counts) in &self.slots {
+            for (count, slot_count) in counts.iter_mut().zip(slot_counts.iter()) {
+                *count += slot_count;
+            }
+        }
+        QueryTypeRates {
+            window: self.window,
+            counts,
+        }
+    }
+
+    /// Drops the slots that started before the window
+    fn expire(&mut self, now: Instant) {
+        while matches!(self.slots.front(), Some((start, _)) if now.saturating_duration_since(*start) >= self.window)
+        {
+            self.slots.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        let window = Duration::from_secs(600);
+        let mut counter = QueryTypeCounter::new(window);
+        let start = Instant::now();
+        counter.record(QueryType::Ping, start);
+        counter.record(QueryType::Ping, start);
+        counter.record(QueryType::GetPeers, start + Duration::from_secs(300));
+
+        let rates = counter.rates(start + Duration::from_secs(300));
+        assert_eq!(rates.count(QueryType::Ping), 2);
+        assert_eq!(rates.count(QueryType::GetPeers), 1);
+        assert_eq!(rates.count(QueryType::AnnouncePeer), 0);
+        assert_eq!(rates.total(), 3);
+        assert_eq!(rates.per_minute(QueryType::Ping), 0.2);
+
+        // The pings fall out of the window first
+        let rates = counter.rates(start + Duration::from_secs(600));
+        assert_eq!(rates.count(QueryType::Ping), 0);
+        assert_eq!(rates.count(QueryType::GetPeers), 1);
+        let rates = counter.rates(start + Duration::from_secs(900));
+        assert_eq!(rates.total(), 0);
+    }
+}
@@ -0,0 +1,128 @@
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+/// The most datagrams that will be read from the socket at once
+pub(crate) const RECV_BATCH_SIZE: usize = 32;
+
+/// Size of the buffer each datagram is read into. Bigger datagrams get truncated.
+pub(crate) const RECV_BUFFER_SIZE: usize = 2048;
+
+/// The bytes of a datagram and the address it came from
+pub(crate) type Datagram = (Vec<u8>, SocketAddr);
+
+/// Reads up to [RECV_BATCH_SIZE] datagrams that are already queued on `socket`, without waiting.
+///
+/// Returns an empty vector if nothing is queued. An error is only returned if it happens
+/// before any datagrams are read. Otherwise, the datagrams read so far are returned.
+#[cfg(not(all(target_os = "linux", feature = "recvmmsg")))]
+pub(crate) fn try_recv_batch(socket: &UdpSocket) -> io::Result<Vec<Datagram>> {
+    let mut batch = Vec::new();
+    let mut buf = [0; RECV_BUFFER_SIZE];
+    while batch.len() < RECV_BATCH_SIZE {
+        match socket.try_recv_from(&mut buf) {
+            Ok((num_bytes, sender)) => batch.push((buf[..num_bytes].to_vec(), sender)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) if batch.is_empty() => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(batch)
+}
+
+/// Reads up to [RECV_BATCH_SIZE] datagrams that are already queued on `socket` with a
+/// single `recvmmsg` syscall, without waiting.
+///
+/// Returns an empty vector if nothing is queued.
+#[cfg(all(target_os = "linux", feature = "recvmmsg"))]
+pub(crate) fn try_recv_batch(socket: &UdpSocket) -> io::Result<Vec<Datagram>> {
+    use std::os::unix::io::AsRawFd;
+    match socket.try_io(tokio::io::Interest::READABLE, || {
+        recvmmsg(socket.as_raw_fd())
+    }) {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "recvmmsg"))]
+fn recvmmsg(fd: std::os::unix::io::RawFd) -> io::Result<Vec<Datagram>> {
+    let mut bufs = vec![[0u8; RECV_BUFFER_SIZE]; RECV_BATCH_SIZE];
+    // Safety: sockaddr_storage is plain old data, so all zeroes is a valid value
+    let mut addrs: Vec<libc::sockaddr_storage> =
+        vec![unsafe { std::mem::zeroed() }; RECV_BATCH_SIZE];
+    let mut iovecs: Vec<libc::iovec> = bufs
+        .iter_mut()
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(addrs.iter_mut())
+        .map(|(iovec, addr)| {
+            // Safety: mmsghdr is plain old data, so all zeroes is a valid value
+            let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            msg.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen =
+                std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+
+    // Safety: each mmsghdr points to a buffer and address that outlive the call
+    let num_received = unsafe {
+        libc::recvmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            msgs.len() as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            std::ptr::null_mut(),
+        )
+    };
+    if num_received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(msgs
+        .iter()
+        .zip(bufs.iter())
+        .zip(addrs.iter())
+        .take(num_received as usize)
+        .filter_map(|((msg, buf), addr)| {
+            sockaddr_to_socket_addr(addr)
+                .map(|sender| (buf[..msg.msg_len as usize].to_vec(), sender))
+        })
+        .collect())
+}
+
+#[cfg(all(target_os = "linux", feature = "recvmmsg"))]
+fn sockaddr_to_socket_addr(addr: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // Safety: ss_family says this is a sockaddr_in, which fits inside sockaddr_storage
+            let addr =
+                unsafe { &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+            Some(SocketAddr::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into(),
+                u16::from_be(addr.sin_port),
+            ))
+        }
+        libc::AF_INET6 => {
+            // Safety: ss_family says this is a sockaddr_in6, which fits inside sockaddr_storage
+            let addr =
+                unsafe { &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
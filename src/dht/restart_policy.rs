@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+/// How [run_event_loop_supervised](crate::dht::DHT::run_event_loop_supervised) restarts the
+/// event loop after it stops with a fatal error.
+///
+/// The first restart waits `initial_backoff`, and each one after that waits twice as long as
+/// the one before, up to `max_backoff`. A loop that runs for `stable_period` before
+/// failing starts over with a fresh count of restarts.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RestartPolicy {
+    /// How many times the event loop may be restarted before the error is returned
+    pub max_restarts: usize,
+
+    /// How long to wait before the first restart
+    pub initial_backoff: Duration,
+
+    /// The longest to wait before a restart
+    pub max_backoff: Duration,
+
+    /// How long the event loop has to run before a failure no longer counts towards
+    /// `max_restarts` (and the backoff goes back to `initial_backoff`)
+    pub stable_period: Duration,
+
+    /// If true, the DHT closes its socket and binds a new one to the same address before
+    /// restarting after a socket error. If binding fails, that error is returned.
+    /// Otherwise, the loop restarts on the old socket.
+    pub rebind_on_socket_error: bool,
+}
+
+impl RestartPolicy {
+    /// Returns how long to wait before the `restart`th restart (starting from 0)
+    pub(crate) fn backoff(&self, restart: usize) -> Duration {
+        let factor = 2u32.saturating_pow(restart.min(u32::MAX as usize) as u32);
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            max_restarts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            stable_period: Duration::from_secs(600),
+            rebind_on_socket_error: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RestartPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            ..RestartPolicy::default()
+        };
+        assert_eq!(policy.backoff(0), Duration::from_secs(1));
+        assert_eq!(policy.backoff(1), Duration::from_secs(2));
+        assert_eq!(policy.backoff(3), Duration::from_secs(8));
+        assert_eq!(policy.backoff(4), Duration::from_secs(10));
+        assert_eq!(policy.backoff(1000), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,136 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Every this many rounds of router pings, all routers are pinged regardless of their
+/// history. This keeps routers that were unreliable in the past from being abandoned.
+pub(crate) const ROUTER_PROBE_INTERVAL: usize = 4;
+
+/// How long to wait for a router to respond to a ping before giving up on it.
+pub(crate) const ROUTER_PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for a router to respond before also pinging the next one.
+pub(crate) const ROUTER_PING_STAGGER: Duration = Duration::from_secs(1);
+
+/// How responsive a DHT router has been to our pings.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouterStats {
+    /// Number of pings the router responded to
+    pub successes: u32,
+
+    /// Number of pings the router didn't respond to (including failures to resolve it)
+    pub failures: u32,
+
+    /// Moving average of the router's response time, if it has ever responded
+    pub average_latency: Option<Duration>,
+}
+
+impl RouterStats {
+    pub(crate) fn record_success(&mut self, latency: Duration) {
+        self.successes = self.successes.saturating_add(1);
+        self.average_latency = Some(match self.average_latency {
+            Some(average) => (average * 7 + latency) / 8,
+            None => latency,
+        });
+    }
+
+    pub(crate) fn record_failure(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    /// Fraction of pings the router has responded to. Routers we haven't pinged yet
+    /// are given the benefit of the doubt and start at 0.5.
+    pub fn success_rate(&self) -> f64 {
+        (self.successes as f64 + 1.0) / (self.successes as f64 + self.failures as f64 + 2.0)
+    }
+}
+
+/// Sorts router hostnames from most to least preferred, based on their success rate and
+/// then their latency. Ties keep their configured order.
+pub(crate) fn order_routers(routers: &mut [String], stats: &HashMap<String, RouterStats>) {
+    let default_stats = RouterStats::default();
+    routers.sort_by(|a, b| {
+        let a = stats.get(a).unwrap_or(&default_stats);
+        let b = stats.get(b).unwrap_or(&default_stats);
+        b.success_rate()
+            .partial_cmp(&a.success_rate())
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| match (a.average_latency, b.average_latency) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            })
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routers() -> Vec<String> {
+        vec![
+            "failing.example.org:6881".to_string(),
+            "unknown.example.org:6881".to_string(),
+            "responsive.example.org:6881".to_string(),
+        ]
+    }
+
+    #[test]
+    fn test_failing_router_is_deprioritized() {
+        let mut stats = HashMap::new();
+        let failing = stats
+            .entry("failing.example.org:6881".to_string())
+            .or_insert_with(RouterStats::default);
+        for _ in 0..5 {
+            failing.record_failure();
+        }
+        let responsive = stats
+            .entry("responsive.example.org:6881".to_string())
+            .or_insert_with(RouterStats::default);
+        for _ in 0..5 {
+            responsive.record_success(Duration::from_millis(80));
+        }
+
+        let mut ordered = routers();
+        order_routers(&mut ordered, &stats);
+        assert_eq!(
+            ordered,
+            vec![
+                "responsive.example.org:6881".to_string(),
+                "unknown.example.org:6881".to_string(),
+                "failing.example.org:6881".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_faster_router_is_preferred() {
+        let mut stats = HashMap::new();
+        stats
+            .entry("slow.example.org:6881".to_string())
+            .or_insert_with(RouterStats::default)
+            .record_success(Duration::from_millis(400));
+        stats
+            .entry("fast.example.org:6881".to_string())
+            .or_insert_with(RouterStats::default)
+            .record_success(Duration::from_millis(20));
+
+        let mut ordered = vec![
+            "slow.example.org:6881".to_string(),
+            "fast.example.org:6881".to_string(),
+        ];
+        order_routers(&mut ordered, &stats);
+        assert_eq!(ordered[0], "fast.example.org:6881");
+        assert_eq!(ordered[1], "slow.example.org:6881");
+    }
+
+    #[test]
+    fn test_average_latency() {
+        let mut stats = RouterStats::default();
+        stats.record_success(Duration::from_millis(100));
+        assert_eq!(stats.average_latency, Some(Duration::from_millis(100)));
+        stats.record_success(Duration::from_millis(900));
+        assert_eq!(stats.average_latency, Some(Duration::from_millis(200)));
+    }
+}
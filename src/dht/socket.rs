@@ -1,60 +1,360 @@
 use crate::common::Id;
+use crate::dht::bandwidth_limiter::BandwidthLimiter;
+use crate::dht::drop_log_sampler::DropLogSampler;
+#[cfg(test)]
+use crate::dht::mock_network::MockNetwork;
+use crate::dht::packet_tap::{PacketDirection, PacketTap};
+use crate::dht::recv_batch::try_recv_batch;
 use crate::errors::RustyDHTError;
 use crate::packets;
 use crate::shutdown::ShutdownReceiver;
 use crate::storage::outbound_request_storage::{OutboundRequestStorage, RequestInfo};
 use anyhow::anyhow;
-use log::{error, trace, warn};
+use log::{debug, error, trace, warn};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard, RwLock};
 use std::time::Duration;
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
-use tokio::time::interval;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+/// A received message, who sent it, and the size of the datagram it came in
+type MessagePair = (packets::Message, SocketAddr, usize);
+
+/// A received message, or an error that the DHT needs to see (e.g. to reply to the sender)
+type IncomingMessage = Result<MessagePair, RustyDHTError>;
+
+/// A datagram waiting to be sent, and when the bandwidth limit lets it go out (if it's
+/// held back at all)
+type OutgoingDatagram = (Vec<u8>, SocketAddr, Option<Instant>);
+
+/// How long a socket replaced by [rebind](DHTSocket::rebind) keeps receiving. Long enough
+/// for responses to requests sent with a typical timeout to arrive.
+const OLD_SOCKET_DRAIN_TIME: Duration = Duration::from_secs(10);
+
+/// How long [reopen](DHTSocket::reopen) waits for the background I/O tasks to let go of
+/// the old socket.
+const SOCKET_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The shortest interval that outbound requests are checked for pruning at, so that a check
+/// interval of 0 doesn't keep the cleanup task spinning.
+const MIN_REQUEST_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
-type MessagePair = (packets::Message, SocketAddr);
+/// Where a [DHTSocket]'s packets actually go
+enum Backend {
+    /// None while [reopen](DHTSocket::reopen) is between sockets
+    Udp(watch::Sender<Option<Arc<UdpSocket>>>),
+    #[cfg(test)]
+    Mock(SocketAddr, MockNetwork),
+}
 
 pub struct DHTSocket {
-    recv_from_rx: Arc<tokio::sync::Mutex<mpsc::Receiver<MessagePair>>>,
-    send_to_tx: mpsc::Sender<MessagePair>,
+    recv_from_rx: Arc<tokio::sync::Mutex<mpsc::Receiver<IncomingMessage>>>,
+    send_to_tx: mpsc::Sender<OutgoingDatagram>,
     request_storage: Arc<Mutex<OutboundRequestStorage>>,
+    backend: Backend,
+    bandwidth_limiter: Option<Mutex<BandwidthLimiter>>,
+    dropped_packets: Arc<Mutex<DropLogSampler>>,
+    packet_tap: Arc<RwLock<Option<PacketTap>>>,
+
+    /// Lets tests hand errors to [recv_from](DHTSocket::recv_from) of a mock DHTSocket
+    #[cfg(test)]
+    injected_incoming_tx: Option<mpsc::Sender<IncomingMessage>>,
 }
 
 impl DHTSocket {
-    pub fn new(shutdown: ShutdownReceiver, socket: UdpSocket) -> DHTSocket {
-        let (send_to_tx, send_to_rx) = mpsc::channel(128);
-        let (recv_from_tx, recv_from_rx) = mpsc::channel(128);
-        let request_storage = Arc::new(Mutex::new(OutboundRequestStorage::new()));
-        let socket = Arc::new(socket);
+    pub fn new(shutdown: ShutdownReceiver, socket: impl Into<Arc<UdpSocket>>) -> DHTSocket {
+        let (socket_tx, socket_rx) = watch::channel(Some(socket.into()));
+        #[allow(unused_mut)]
+        let (mut dht_socket, send_to_rx, recv_from_tx) =
+            DHTSocket::new_with_backend(shutdown.clone(), Backend::Udp(socket_tx));
+        #[cfg(test)]
+        {
+            dht_socket.injected_incoming_tx = Some(recv_from_tx.clone());
+        }
         ShutdownReceiver::spawn_with_shutdown(
             shutdown.clone(),
-            DHTSocket::background_io_outgoing(socket.clone(), send_to_rx),
+            DHTSocket::background_io_outgoing(socket_rx.clone(), send_to_rx),
             "DHTSocket background outgoing I/O task",
             None,
         );
         ShutdownReceiver::spawn_with_shutdown(
             shutdown.clone(),
-            DHTSocket::background_io_incoming(socket, recv_from_tx, request_storage.clone()),
+            DHTSocket::background_io_incoming(
+                shutdown,
+                socket_rx,
+                recv_from_tx,
+                dht_socket.request_storage.clone(),
+                dht_socket.dropped_packets.clone(),
+                dht_socket.packet_tap.clone(),
+            ),
             "DHTSocket background incoming I/O task",
             None,
         );
+        dht_socket
+    }
+
+    /// Creates a DHTSocket that sends and receives on `addr` of a [MockNetwork] rather
+    /// than a UDP socket.
+    #[cfg(test)]
+    pub(crate) fn new_mock(
+        shutdown: ShutdownReceiver,
+        network: MockNetwork,
+        addr: SocketAddr,
+    ) -> DHTSocket {
+        let mut incoming_rx = network.bind(addr);
+        let (mut dht_socket, mut send_to_rx, recv_from_tx) =
+            DHTSocket::new_with_backend(shutdown.clone(), Backend::Mock(addr, network.clone()));
+        dht_socket.injected_incoming_tx = Some(recv_from_tx.clone());
+        ShutdownReceiver::spawn_with_shutdown(
+            shutdown.clone(),
+            async move {
+                while let Some((bytes, dest, send_at)) = send_to_rx.recv().await {
+                    if let Some(send_at) = send_at {
+                        tokio::time::sleep_until(send_at).await;
+                    }
+                    network.send(addr, dest, bytes);
+                }
+            },
+            "DHTSocket mock outgoing I/O task",
+            None,
+        );
+        let request_storage = dht_socket.request_storage.clone();
+        let dropped_packets = dht_socket.dropped_packets.clone();
+        let packet_tap = dht_socket.packet_tap.clone();
+        ShutdownReceiver::spawn_with_shutdown(
+            shutdown,
+            async move {
+                while let Some((bytes, sender)) = incoming_rx.recv().await {
+                    if let Err(e) = DHTSocket::handle_incoming_datagram(
+                        &bytes,
+                        sender,
+                        &recv_from_tx,
+                        &request_storage,
+                        &packet_tap,
+                    )
+                    .await
+                    {
+                        if DHTSocket::check_incoming_error(e, &dropped_packets).is_err() {
+                            break;
+                        }
+                    }
+                }
+            },
+            "DHTSocket mock incoming I/O task",
+            None,
+        );
+        dht_socket
+    }
+
+    /// Sets up the parts of a DHTSocket that don't depend on how packets are sent and
+    /// received. The caller must spawn tasks that send what comes out of the returned
+    /// receiver, and pass received packets to the returned sender.
+    fn new_with_backend(
+        shutdown: ShutdownReceiver,
+        backend: Backend,
+    ) -> (
+        DHTSocket,
+        mpsc::Receiver<OutgoingDatagram>,
+        mpsc::Sender<IncomingMessage>,
+    ) {
+        let (send_to_tx, send_to_rx) = mpsc::channel(128);
+        let (recv_from_tx, recv_from_rx) = mpsc::channel(128);
+        let request_storage = Arc::new(Mutex::new(OutboundRequestStorage::new()));
         ShutdownReceiver::spawn_with_shutdown(
             shutdown,
             DHTSocket::request_cleanup(request_storage.clone()),
             "DHTSocket background request cleanup task",
             None,
         );
-        DHTSocket {
+        let dht_socket = DHTSocket {
             recv_from_rx: Arc::new(tokio::sync::Mutex::new(recv_from_rx)),
-            send_to_tx: send_to_tx,
-            request_storage: request_storage,
+            send_to_tx,
+            request_storage,
+            backend,
+            bandwidth_limiter: None,
+            dropped_packets: Arc::new(Mutex::new(DropLogSampler::new(1))),
+            packet_tap: Arc::new(RwLock::new(None)),
+            #[cfg(test)]
+            injected_incoming_tx: None,
+        };
+        (dht_socket, send_to_rx, recv_from_tx)
+    }
+
+    /// Makes [recv_from](DHTSocket::recv_from) return `err`, as if receiving had failed.
+    #[cfg(test)]
+    pub(crate) async fn inject_incoming_error(&self, err: RustyDHTError) {
+        self.injected_incoming_tx
+            .as_ref()
+            .expect("DHTSocket has no injected error channel")
+            .send(Err(err))
+            .await
+            .unwrap();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn udp_socket(&self) -> Arc<UdpSocket> {
+        match &self.backend {
+            Backend::Udp(socket_tx) => socket_tx
+                .borrow()
+                .clone()
+                .expect("DHTSocket is being reopened"),
+            Backend::Mock(..) => panic!("Mock DHTSocket doesn't have a UdpSocket"),
+        }
+    }
+
+    /// Sets how long outbound requests wait for a response before they're pruned, no
+    /// matter what timeout they were sent with, and how often pruning happens (at most once
+    /// a second). Waiters of pruned requests see their response channels close.
+    pub fn set_request_max_lifetime(&self, max_lifetime: Duration, check_interval: Duration) {
+        self.request_storage
+            .lock()
+            .unwrap()
+            .set_max_lifetime(max_lifetime, check_interval);
+    }
+
+    /// Limits outgoing traffic to `bytes_per_sec`, or removes the limit if None.
+    ///
+    /// Packets are held back in the outgoing queue to stay under the limit (senders don't
+    /// wait for them to go out), and dropped if they would have to wait longer than [MAX_SEND_DELAY](crate::dht::bandwidth_limiter::MAX_SEND_DELAY).
+    pub fn set_send_rate_limit(&mut self, bytes_per_sec: Option<u64>) {
+        self.bandwidth_limiter = bytes_per_sec.map(|rate| Mutex::new(BandwidthLimiter::new(rate)));
+    }
+
+    /// Starts writing a record of every packet sent or received to `packet_tap`.
+    pub(crate) fn set_packet_tap(&self, packet_tap: PacketTap) {
+        *self.packet_tap.write().unwrap() = Some(packet_tap);
+    }
+
+    /// Waits for the packet tap, if any, to write everything recorded so far
+    #[cfg(test)]
+    pub(crate) fn flush_packet_tap(&self) {
+        if let Some(tap) = self.packet_tap.read().unwrap().as_ref() {
+            tap.flush();
+        }
+    }
+
+    /// Records a packet to the packet tap, if there is one. A tap that fails to write is
+    /// removed, so that it isn't retried for every packet.
+    fn tap_packet(
+        packet_tap: &RwLock<Option<PacketTap>>,
+        direction: PacketDirection,
+        addr: SocketAddr,
+        bytes: &[u8],
+    ) {
+        let ok = match packet_tap.read().unwrap().as_ref() {
+            Some(tap) => tap.record(direction, addr, bytes),
+            None => true,
+        };
+        if !ok {
+            *packet_tap.write().unwrap() = None;
+        }
+    }
+
+    /// Returns the counter for dropped incoming packets. The socket records the packets
+    /// that it can't parse, and the DHT records the ones it drops itself.
+    pub(crate) fn dropped_packets(&self) -> MutexGuard<'_, DropLogSampler> {
+        self.dropped_packets.lock().unwrap()
+    }
+
+    /// Returns the address that the current UdpSocket is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, RustyDHTError> {
+        match &self.backend {
+            Backend::Udp(socket_tx) => match socket_tx.borrow().as_ref() {
+                Some(socket) => socket
+                    .local_addr()
+                    .map_err(|e| RustyDHTError::GeneralError(e.into())),
+                None => Err(RustyDHTError::GeneralError(anyhow!(
+                    "DHTSocket has no UdpSocket, as reopening it failed"
+                ))),
+            },
+            #[cfg(test)]
+            Backend::Mock(addr, _) => Ok(*addr),
+        }
+    }
+
+    /// Replaces the UdpSocket used to send and receive packets.
+    ///
+    /// Pending outbound requests are kept. The old socket keeps receiving for a
+    /// little while so that responses to requests already sent from it can still
+    /// be delivered.
+    pub fn rebind(&self, socket: UdpSocket) -> Result<(), RustyDHTError> {
+        match &self.backend {
+            Backend::Udp(socket_tx) => socket_tx
+                .send(Some(Arc::new(socket)))
+                .map_err(|e| RustyDHTError::GeneralError(e.into())),
+            #[cfg(test)]
+            Backend::Mock(..) => Err(RustyDHTError::GeneralError(anyhow!(
+                "Can't rebind a mock DHTSocket"
+            ))),
+        }
+    }
+
+    /// Closes the current UdpSocket, then replaces it with the one `bind` returns for the
+    /// same address.
+    ///
+    /// This is for getting rid of a socket that failed. Unlike [rebind](DHTSocket::rebind),
+    /// nothing more is received on the old socket, since it has to be closed before its
+    /// address can be bound again. If `bind` fails, the DHTSocket has no socket until the
+    /// next successful [rebind](DHTSocket::rebind).
+    pub(crate) async fn reopen(
+        &self,
+        bind: impl FnOnce(SocketAddr) -> Result<UdpSocket, RustyDHTError>,
+    ) -> Result<(), RustyDHTError> {
+        match &self.backend {
+            Backend::Udp(socket_tx) => {
+                let addr = self.local_addr()?;
+                let mut old_socket = socket_tx
+                    .send_replace(None)
+                    .expect("local_addr succeeded, so there's a socket");
+
+                // The background I/O tasks drop their references once they see the socket is gone
+                let deadline = Instant::now() + SOCKET_CLOSE_TIMEOUT;
+                loop {
+                    match Arc::try_unwrap(old_socket) {
+                        Ok(socket) => {
+                            drop(socket);
+                            break;
+                        }
+                        Err(socket) if Instant::now() < deadline => {
+                            old_socket = socket;
+                            tokio::time::sleep(Duration::from_millis(10)).await;
+                        }
+                        Err(socket) => {
+                            socket_tx.send_replace(Some(socket));
+                            return Err(RustyDHTError::GeneralError(anyhow!(
+                                "Timed out waiting for the socket bound to {} to close",
+                                addr
+                            )));
+                        }
+                    }
+                }
+
+                let socket = bind(addr)?;
+                socket_tx.send_replace(Some(Arc::new(socket)));
+                Ok(())
+            }
+            #[cfg(test)]
+            Backend::Mock(..) => Err(RustyDHTError::GeneralError(anyhow!(
+                "Can't reopen a mock DHTSocket"
+            ))),
+        }
+    }
+
+    /// Makes the DHTSocket start receiving again after it stopped because of an error,
+    /// without replacing its socket.
+    pub(crate) fn resume_receiving(&self) {
+        match &self.backend {
+            Backend::Udp(socket_tx) => socket_tx.send_modify(|_| {}),
+            #[cfg(test)]
+            Backend::Mock(..) => {}
         }
     }
 
     pub async fn recv_from(&self) -> Result<MessagePair, RustyDHTError> {
         match self.recv_from_rx.lock().await.recv().await {
-            Some(message_pair) => Ok(message_pair),
+            Some(incoming) => incoming,
             None => Err(RustyDHTError::GeneralError(anyhow!(
                 "Can't recv_from as background I/O task channel has closed"
             ))),
@@ -69,7 +369,7 @@ impl DHTSocket {
     ) -> Result<Option<mpsc::Receiver<packets::Message>>, RustyDHTError> {
         let mut to_ret = None;
         // optimization to only store notification stuff on requests (not on replies too)
-        if let packets::MessageType::Request(_) = to_send.message_type {
+        if let packets::MessageType::Request(_) = &to_send.message_type {
             let (notify_tx, notify_rx) = mpsc::channel(1);
             to_ret = Some(notify_rx);
             self.request_storage
@@ -83,19 +383,77 @@ impl DHTSocket {
                 ));
         }
 
+        self.send_datagram(to_send.to_bytes()?, dest).await?;
+        Ok(to_ret)
+    }
+
+    /// Sends `bytes` to `dest` verbatim.
+    ///
+    /// If the bytes parse as a request, returns a channel that receives the raw bytes
+    /// of its response.
+    pub async fn send_raw(
+        &self,
+        bytes: Vec<u8>,
+        dest: SocketAddr,
+    ) -> Result<Option<mpsc::Receiver<Vec<u8>>>, RustyDHTError> {
+        let mut to_ret = None;
+        if let Ok(
+            request @ packets::Message {
+                message_type: packets::MessageType::Request(_),
+                ..
+            },
+        ) = packets::Message::from_bytes(&bytes)
+        {
+            let (notify_tx, notify_rx) = mpsc::channel(1);
+            to_ret = Some(notify_rx);
+            self.request_storage.lock().unwrap().add_request(
+                RequestInfo::new(dest, None, request, None).with_raw_response_channel(notify_tx),
+            );
+        }
+
+        self.send_datagram(bytes, dest).await?;
+        Ok(to_ret)
+    }
+
+    /// Queues `bytes` to be sent to `dest`. The outgoing task holds them back for as long
+    /// as the bandwidth limit requires, so this doesn't wait for that.
+    pub(crate) async fn send_datagram(
+        &self,
+        bytes: Vec<u8>,
+        dest: SocketAddr,
+    ) -> Result<(), RustyDHTError> {
+        let mut send_at = None;
+        if let Some(limiter) = &self.bandwidth_limiter {
+            let now = Instant::now();
+            let delay = limiter.lock().unwrap().reserve(bytes.len(), now);
+            match delay {
+                Some(delay) if delay.is_zero() => {}
+                Some(delay) => send_at = Some(now + delay),
+                None => {
+                    debug!(target: "rustydht_lib::DHTSocket", "Dropping {} bytes to {} to stay under the bandwidth limit", bytes.len(), dest);
+                    return Ok(());
+                }
+            }
+        }
+
+        #[cfg(test)]
+        if let Backend::Mock(_, network) = &self.backend {
+            network.wait_to_send().await;
+        }
+
+        DHTSocket::tap_packet(&self.packet_tap, PacketDirection::Sent, dest, &bytes);
         self.send_to_tx
-            .send((to_send, dest))
+            .send((bytes, dest, send_at))
             .await
-            .map_err(|e| RustyDHTError::GeneralError(e.into()))?;
-        Ok(to_ret)
+            .map_err(|e| RustyDHTError::GeneralError(e.into()))
     }
 
     async fn background_io_outgoing(
-        socket: Arc<UdpSocket>,
-        mut send_to_rx: mpsc::Receiver<MessagePair>,
+        mut socket_rx: watch::Receiver<Option<Arc<UdpSocket>>>,
+        mut send_to_rx: mpsc::Receiver<OutgoingDatagram>,
     ) {
         loop {
-            match DHTSocket::background_io_outgoing_single(&socket, &mut send_to_rx).await {
+            match DHTSocket::background_io_outgoing_single(&mut socket_rx, &mut send_to_rx).await {
                 Ok(_) => { /* Keep on truckin'!*/ }
                 Err(e) => match e {
                     RustyDHTError::ConntrackError(_) => {
@@ -111,16 +469,32 @@ impl DHTSocket {
     }
 
     async fn background_io_outgoing_single(
-        socket: &UdpSocket,
-        send_to_rx: &mut mpsc::Receiver<MessagePair>,
+        socket_rx: &mut watch::Receiver<Option<Arc<UdpSocket>>>,
+        send_to_rx: &mut mpsc::Receiver<OutgoingDatagram>,
     ) -> Result<(), RustyDHTError> {
         match send_to_rx.recv().await {
             None => Err(RustyDHTError::GeneralError(anyhow!(
                 "send_to_rx channel is empty and closed"
             ))),
-            Some((msg, dest)) => {
-                let bytes = msg.to_bytes()?;
+            Some((bytes, dest, send_at)) => {
+                if let Some(send_at) = send_at {
+                    tokio::time::sleep_until(send_at).await;
+                }
                 trace!(target:"rustydht_lib::DHTSocket", "Sending {} bytes to {}", bytes.len(), dest);
+                // Packets wait while the socket is being reopened
+                let socket = socket_rx
+                    .wait_for(Option::is_some)
+                    .await
+                    .map_err(|e| RustyDHTError::GeneralError(e.into()))?
+                    .clone()
+                    .expect("wait_for only returns once there's a socket");
+                let dest = match (dest, socket.local_addr()) {
+                    // A dual-stack IPv6 socket reaches IPv4 peers through IPv4-mapped addresses
+                    (SocketAddr::V4(v4), Ok(SocketAddr::V6(_))) => {
+                        SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+                    }
+                    _ => dest,
+                };
                 match socket.send_to(&bytes, dest).await {
                     Ok(_) => Ok(()),
                     Err(e) => {
@@ -138,43 +512,185 @@ impl DHTSocket {
     }
 
     async fn background_io_incoming(
-        socket: Arc<UdpSocket>,
-        recv_from_tx: mpsc::Sender<MessagePair>,
+        shutdown: ShutdownReceiver,
+        mut socket_rx: watch::Receiver<Option<Arc<UdpSocket>>>,
+        recv_from_tx: mpsc::Sender<IncomingMessage>,
         request_storage: Arc<Mutex<OutboundRequestStorage>>,
+        dropped_packets: Arc<Mutex<DropLogSampler>>,
+        packet_tap: Arc<RwLock<Option<PacketTap>>>,
     ) {
+        let mut socket = socket_rx.borrow_and_update().clone();
         loop {
-            match DHTSocket::background_io_incoming_single(&socket, &recv_from_tx, &request_storage)
-                .await
-            {
-                Ok(_) => { /* Keep on truckin'!*/ }
-                Err(e) => match e {
-                    RustyDHTError::PacketParseError(_) => {
-                        warn!(target: "rustydht_lib::DHTSocket", "Failed to parse incoming packet: {:?}", e);
+            let changed = match &socket {
+                Some(current) => tokio::select! {
+                    result = DHTSocket::background_io_incoming_batch(current, &recv_from_tx, &request_storage, &dropped_packets, &packet_tap) => {
+                        if let Err(e) = result {
+                            // Let the DHT decide what to do about it, and stop receiving until
+                            // the socket is replaced or we're told to resume
+                            if recv_from_tx.send(Err(e)).await.is_err() {
+                                break;
+                            }
+                            socket = None;
+                        }
                         continue;
                     }
 
-                    RustyDHTError::SocketRecvError(e) if DHTSocket::should_ignore_error(&e) => {}
-                    _ => {
-                        error!(target: "rustydht_lib::DHTSocket", "Error in background incoming I/O task:{:?}", e);
-                        break;
-                    }
+                    changed = socket_rx.changed() => changed,
                 },
+                None => socket_rx.changed().await,
+            };
+            if changed.is_err() {
+                // The DHTSocket is gone
+                break;
+            }
+
+            let new_socket = socket_rx.borrow_and_update().clone();
+            let old_socket = std::mem::replace(&mut socket, new_socket);
+            // A socket replaced by rebind keeps receiving for a while. One closed by reopen doesn't.
+            if let (Some(old_socket), Some(new_socket)) = (old_socket, &socket) {
+                if !Arc::ptr_eq(&old_socket, new_socket) {
+                    ShutdownReceiver::spawn_with_shutdown(
+                        shutdown.clone(),
+                        DHTSocket::drain_old_socket(
+                            old_socket,
+                            recv_from_tx.clone(),
+                            request_storage.clone(),
+                            dropped_packets.clone(),
+                            packet_tap.clone(),
+                        ),
+                        "DHTSocket old socket drain task",
+                        None,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Keeps receiving on a socket that was replaced by [rebind](DHTSocket::rebind) until
+    /// [OLD_SOCKET_DRAIN_TIME] passes.
+    async fn drain_old_socket(
+        socket: Arc<UdpSocket>,
+        recv_from_tx: mpsc::Sender<IncomingMessage>,
+        request_storage: Arc<Mutex<OutboundRequestStorage>>,
+        dropped_packets: Arc<Mutex<DropLogSampler>>,
+        packet_tap: Arc<RwLock<Option<PacketTap>>>,
+    ) {
+        let drain = async {
+            while DHTSocket::background_io_incoming_batch(
+                &socket,
+                &recv_from_tx,
+                &request_storage,
+                &dropped_packets,
+                &packet_tap,
+            )
+            .await
+            .is_ok()
+            {}
+        };
+        let _ = tokio::time::timeout(OLD_SOCKET_DRAIN_TIME, drain).await;
+        trace!(target: "rustydht_lib::DHTSocket", "Done draining old socket {:?}", socket.local_addr());
+    }
+
+    /// Logs an error from receiving a packet. Returns the error if we should stop receiving.
+    ///
+    /// Packets that fail to parse are counted in `dropped_packets`, and only logged if it
+    /// picks them.
+    fn check_incoming_error(
+        e: RustyDHTError,
+        dropped_packets: &Mutex<DropLogSampler>,
+    ) -> Result<(), RustyDHTError> {
+        match e {
+            RustyDHTError::PacketParseError(_) => {
+                if dropped_packets.lock().unwrap().record() {
+                    warn!(target: "rustydht_lib::DHTSocket", "Failed to parse incoming packet: {:?}", e);
+                }
+                Ok(())
+            }
+
+            RustyDHTError::SocketRecvError(e) if DHTSocket::should_ignore_error(&e) => Ok(()),
+            _ => {
+                error!(target: "rustydht_lib::DHTSocket", "Error in background incoming I/O task:{:?}", e);
+                Err(e)
             }
         }
     }
 
-    async fn background_io_incoming_single(
+    /// Waits for the socket to be readable, then handles all the datagrams queued on it
+    /// (up to a limit).
+    ///
+    /// Each datagram is handled separately, so an error with one doesn't affect the rest
+    /// of the batch. Returns an error if we should stop receiving.
+    async fn background_io_incoming_batch(
         socket: &Arc<UdpSocket>,
-        recv_from_tx: &mpsc::Sender<MessagePair>,
+        recv_from_tx: &mpsc::Sender<IncomingMessage>,
         request_storage: &Arc<Mutex<OutboundRequestStorage>>,
+        dropped_packets: &Mutex<DropLogSampler>,
+        packet_tap: &RwLock<Option<PacketTap>>,
     ) -> Result<(), RustyDHTError> {
-        let mut buf = [0; 2048];
-        let (num_bytes, sender) = socket
-            .recv_from(&mut buf)
+        let batch = match socket.readable().await.and_then(|_| try_recv_batch(socket)) {
+            Ok(batch) => batch,
+            Err(e) => {
+                return DHTSocket::check_incoming_error(
+                    RustyDHTError::SocketRecvError(e),
+                    dropped_packets,
+                )
+            }
+        };
+
+        for (bytes, sender) in batch {
+            // Report IPv4 peers of a dual-stack socket by their plain IPv4 address
+            let sender = SocketAddr::new(sender.ip().to_canonical(), sender.port());
+            if let Err(e) = DHTSocket::handle_incoming_datagram(
+                &bytes,
+                sender,
+                recv_from_tx,
+                request_storage,
+                packet_tap,
+            )
             .await
-            .map_err(|e| RustyDHTError::SocketRecvError(e.into()))?;
-        trace!(target:"rustydht_lib::DHTSocket", "Receiving {} bytes from {}", num_bytes, sender);
-        let message = packets::Message::from_bytes(&buf[..num_bytes])?;
+            {
+                DHTSocket::check_incoming_error(e, dropped_packets)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_incoming_datagram(
+        bytes: &[u8],
+        sender: SocketAddr,
+        recv_from_tx: &mpsc::Sender<IncomingMessage>,
+        request_storage: &Arc<Mutex<OutboundRequestStorage>>,
+        packet_tap: &RwLock<Option<PacketTap>>,
+    ) -> Result<(), RustyDHTError> {
+        trace!(target:"rustydht_lib::DHTSocket", "Receiving {} bytes from {}", bytes.len(), sender);
+        DHTSocket::tap_packet(packet_tap, PacketDirection::Received, sender, bytes);
+        let message = match packets::Message::from_bytes(bytes) {
+            // The DHT decides what to do about these, so pass them along
+            Err(RustyDHTError::InvalidSenderIdError { transaction_id, .. }) => {
+                DHTSocket::forward_raw_response(&transaction_id, bytes, sender, request_storage)
+                    .await;
+                return recv_from_tx
+                    .send(Err(RustyDHTError::InvalidSenderIdError {
+                        transaction_id,
+                        sender: Some(sender),
+                    }))
+                    .await
+                    .map_err(|e| RustyDHTError::GeneralError(e.into()));
+            }
+            Err(e) => {
+                if let Some(transaction_id) = raw_transaction_id(bytes) {
+                    DHTSocket::forward_raw_response(
+                        &transaction_id,
+                        bytes,
+                        sender,
+                        request_storage,
+                    )
+                    .await;
+                }
+                return Err(e);
+            }
+            Ok(message) => message,
+        };
 
         match message.message_type {
             packets::MessageType::Response(_) => {
@@ -187,6 +703,9 @@ impl DHTSocket {
 
                 match request_info {
                     Some(request_info) => {
+                        if let Some(raw_response_channel) = request_info.raw_response_channel {
+                            let _ = raw_response_channel.send(bytes.to_vec()).await;
+                        }
                         match request_info.response_channel {
                             Some(response_channel) => {
                                 if let Err(e) = response_channel.send(message.clone()).await {
@@ -200,21 +719,39 @@ impl DHTSocket {
 
                         // Since the response is to a valid request, send it to the general recv channel
                         recv_from_tx
-                            .send((message, sender))
+                            .send(Ok((message, sender, bytes.len())))
                             .await
                             .map_err(|e| RustyDHTError::GeneralError(e.into()))?;
                     }
 
                     None => {
-                        warn!(target: "rusydht_lib::DHTSocket", "Received spurious response {:?} from {}", message, sender);
+                        if !DHTSocket::forward_raw_response(
+                            &message.transaction_id,
+                            bytes,
+                            sender,
+                            request_storage,
+                        )
+                        .await
+                        {
+                            warn!(target: "rusydht_lib::DHTSocket", "Received spurious response {:?} from {}", message, sender);
+                        }
                     }
                 }
             }
 
             _ => {
+                if let packets::MessageType::Error(_) = &message.message_type {
+                    DHTSocket::forward_raw_response(
+                        &message.transaction_id,
+                        bytes,
+                        sender,
+                        request_storage,
+                    )
+                    .await;
+                }
                 // Request and Error messages always get sent to the general recv channel
                 recv_from_tx
-                    .send((message, sender))
+                    .send(Ok((message, sender, bytes.len())))
                     .await
                     .map_err(|e| RustyDHTError::GeneralError(e.into()))?;
             }
@@ -223,15 +760,38 @@ impl DHTSocket {
         Ok(())
     }
 
-    async fn request_cleanup(request_storage: Arc<Mutex<OutboundRequestStorage>>) {
-        let mut interval = interval(Duration::from_secs(10));
+    /// Passes the raw bytes along to a request sent with [send_raw](DHTSocket::send_raw) that
+    /// the normal response matching didn't deliver them to. Returns true if there was one.
+    async fn forward_raw_response(
+        transaction_id: &[u8],
+        bytes: &[u8],
+        sender: SocketAddr,
+        request_storage: &Arc<Mutex<OutboundRequestStorage>>,
+    ) -> bool {
+        let request_info = {
+            request_storage
+                .lock()
+                .unwrap()
+                .take_raw_request_info(transaction_id, sender)
+        };
+        match request_info.and_then(|request_info| request_info.raw_response_channel) {
+            Some(raw_response_channel) => {
+                let _ = raw_response_channel.send(bytes.to_vec()).await;
+                true
+            }
+            None => false,
+        }
+    }
 
+    async fn request_cleanup(request_storage: Arc<Mutex<OutboundRequestStorage>>) {
         loop {
-            interval.tick().await;
-            request_storage
+            let check_interval = request_storage
                 .lock()
                 .unwrap()
-                .prune_older_than(Duration::from_secs(10));
+                .check_interval()
+                .max(MIN_REQUEST_CHECK_INTERVAL);
+            tokio::time::sleep(check_interval).await;
+            request_storage.lock().unwrap().prune_expired();
         }
     }
 
@@ -256,9 +816,21 @@ impl DHTSocket {
     }
 }
 
+/// Pulls the transaction id out of a datagram that may not be a valid KRPC message.
+fn raw_transaction_id(bytes: &[u8]) -> Option<Vec<u8>> {
+    match serde_bencode::from_bytes::<serde_bencode::value::Value>(bytes).ok()? {
+        serde_bencode::value::Value::Dict(dict) => match dict.get(&b"t"[..])? {
+            serde_bencode::value::Value::Bytes(transaction_id) => Some(transaction_id.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::dht::mock_network::MockNetwork;
     use crate::packets::MessageBuilder;
     use crate::shutdown;
     use std::net::{IpAddr, Ipv4Addr};
@@ -303,4 +875,118 @@ mod test {
         shutdown_tx.shutdown().await;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_dhtsocket_receives_all_queued_datagrams() -> Result<(), RustyDHTError> {
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_sockaddr = server.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // Queue up more datagrams than fit in one batch before anything reads them,
+        // with a bad one in the middle
+        let num_pings = 50;
+        for i in 0..num_pings {
+            if i == num_pings / 2 {
+                client.send_to(b"garbage", server_sockaddr).await.unwrap();
+            }
+            let ping_req = MessageBuilder::new_ping_request()
+                .sender_id(Id::from_random(&mut rand::thread_rng()))
+                .transaction_id(vec![i as u8])
+                .build()?;
+            client
+                .send_to(&ping_req.to_bytes()?, server_sockaddr)
+                .await
+                .unwrap();
+        }
+
+        let socket = DHTSocket::new(shutdown_rx, server);
+        for i in 0..num_pings {
+            let (msg, _, _) = tokio::time::timeout(Duration::from_secs(1), socket.recv_from())
+                .await
+                .unwrap()?;
+            assert_eq!(msg.transaction_id, vec![i as u8]);
+        }
+
+        drop(socket);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_send_rate_limit() -> Result<(), RustyDHTError> {
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let network = MockNetwork::new();
+        let mut receiver = network.bind("10.0.0.2:6881".parse().unwrap());
+        let mut socket = DHTSocket::new_mock(
+            shutdown_rx.clone(),
+            network,
+            "10.0.0.1:6881".parse().unwrap(),
+        );
+        socket.set_send_rate_limit(Some(1000));
+
+        let ping = MessageBuilder::new_ping_response()
+            .sender_id(Id::from_random(&mut rand::thread_rng()))
+            .transaction_id(vec![0, 0])
+            .requester_ip("10.0.0.1:6881".parse().unwrap())
+            .build()?;
+        let ping_size = ping.clone().to_bytes()?.len();
+
+        // Send 3 seconds' worth of traffic. Queueing it doesn't wait for the limit
+        let num_pings = 3000 / ping_size;
+        let start = tokio::time::Instant::now();
+        for _ in 0..num_pings {
+            socket
+                .send_to(ping.clone(), "10.0.0.2:6881".parse().unwrap(), None)
+                .await?;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // Everything is delivered, the first second's worth immediately and the rest paced
+        for _ in 0..num_pings {
+            receiver.recv().await.unwrap();
+        }
+        let expected = Duration::from_secs_f64((num_pings * ping_size - 1000) as f64 / 1000.0);
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= expected.mul_f64(0.9) && elapsed <= expected.mul_f64(1.1),
+            "Took {:?}, expected about {:?}",
+            elapsed,
+            expected
+        );
+
+        drop(socket);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_zero_request_check_interval() -> Result<(), RustyDHTError> {
+        let (mut shutdown_tx, shutdown_rx) = shutdown::create_shutdown();
+        let network = MockNetwork::new();
+        let _receiver = network.bind("10.0.0.2:6881".parse().unwrap());
+        let socket = DHTSocket::new_mock(
+            shutdown_rx.clone(),
+            network,
+            "10.0.0.1:6881".parse().unwrap(),
+        );
+        socket.set_request_max_lifetime(Duration::from_secs(2), Duration::ZERO);
+
+        // Requests are still pruned, without the cleanup task hogging the runtime
+        let ping = MessageBuilder::new_ping_request()
+            .sender_id(Id::from_random(&mut rand::thread_rng()))
+            .build()?;
+        socket
+            .send_to(ping, "10.0.0.2:6881".parse().unwrap(), None)
+            .await?;
+        assert_eq!(socket.request_storage.lock().unwrap().len(), 1);
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(socket.request_storage.lock().unwrap().len(), 0);
+
+        drop(socket);
+        drop(shutdown_rx);
+        shutdown_tx.shutdown().await;
+        Ok(())
+    }
 }
@@ -1,3 +1,4 @@
+use std::net::SocketAddr;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -21,9 +22,42 @@ pub enum RustyDHTError {
     #[error("Socket recv error: {0}")]
     SocketRecvError(#[source] std::io::Error),
 
+    /// A query's sender Id was missing or malformed. `sender` is filled in once the
+    /// query's source address is known.
+    #[error("Query from {sender:?} has a missing or malformed sender id")]
+    InvalidSenderIdError {
+        transaction_id: Vec<u8>,
+        sender: Option<SocketAddr>,
+    },
+
+    /// The DHT was asked to listen on an address of a family it can't use.
+    #[error("Can't listen on {0}: the DHT needs an IPv4 socket unless dual_stack is enabled")]
+    AddressFamilyError(SocketAddr),
+
+    /// A hostname (e.g. of a DHT router) couldn't be resolved to an IPv4 address.
+    #[error("Failed to resolve {0}: {1}")]
+    ResolutionError(String, #[source] anyhow::Error),
+
+    /// Some of the settings passed to [DHT::update_settings](crate::dht::DHT::update_settings)
+    /// can't be applied to the running DHT. Lists the names of the offending fields.
+    #[error("Can't change these settings on a running DHT: {}", .0.join(", "))]
+    SettingsUpdateError(Vec<&'static str>),
+
+    /// Some settings have values that the DHT can't work with (see
+    /// [DHTSettings::validate](crate::dht::DHTSettings::validate)). Lists the names of the
+    /// offending fields.
+    #[error("Invalid settings: {}", .0.join(", "))]
+    InvalidSettingsError(Vec<&'static str>),
+
     #[error("Operation timed out: {0}")]
     TimeoutError(#[source] anyhow::Error),
 
+    /// A lookup (e.g. [find_node](crate::dht::operations::find_node)) never had any nodes to
+    /// query: the routing table stayed empty and no router answered. This usually means
+    /// that the DHT can't reach the network.
+    #[error("No nodes were available to query")]
+    NoNodesAvailableError,
+
     /// This error is a hack for signaling shutdown.
     /// Don't use unless you're sure you know what you're doing.
     #[error("It's time to shutdown tasks: {0}")]
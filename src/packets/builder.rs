@@ -1,4 +1,4 @@
-use crate::common::{Id, Node};
+use crate::common::{AddressFamily, Id, Node};
 use crate::errors::RustyDHTError;
 use crate::packets;
 use rand::prelude::*;
@@ -45,8 +45,10 @@ pub struct MessageBuilder {
     token: Option<Vec<u8>>,
     nodes: Option<Vec<Node>>,
     peers: Option<Vec<SocketAddr>>,
+    nodes_with_peers: bool,
     interval: Option<Duration>,
     samples: Option<Vec<Id>>,
+    want: Option<Vec<AddressFamily>>,
     num_infohashes: Option<usize>,
     code: Option<i32>,
     description: Option<String>,
@@ -138,8 +140,10 @@ impl MessageBuilder {
             token: None,
             nodes: None,
             peers: None,
+            nodes_with_peers: false,
             interval: None,
             samples: None,
+            want: None,
             num_infohashes: None,
             code: None,
             description: None,
@@ -237,6 +241,17 @@ impl MessageBuilder {
         self
     }
 
+    /// Set both the peers and some nodes for a get_peers response, to keep the
+    /// requester's lookup going even though we have peers for it.
+    ///
+    /// Unlike with [peers](Self::peers) and [nodes](Self::nodes), neither is ignored.
+    pub fn peers_and_nodes(mut self, peers: Vec<SocketAddr>, nodes: Vec<Node>) -> Self {
+        self.peers = Some(peers);
+        self.nodes = Some(nodes);
+        self.nodes_with_peers = true;
+        self
+    }
+
     /// Set the interval used in sample_infohashes responses.
     pub fn interval(mut self, interval: Duration) -> Self {
         self.interval = Some(interval);
@@ -249,6 +264,16 @@ impl MessageBuilder {
         self
     }
 
+    /// Set the address families of nodes wanted in the response to
+    /// find_node and get_peers requests (see bep0032).
+    ///
+    /// If not specified, the want field will be omitted and responders
+    /// will send nodes of the same family as the request.
+    pub fn want(mut self, want: Vec<AddressFamily>) -> Self {
+        self.want = Some(want);
+        self
+    }
+
     /// Set the number of info_hashes as reported in sample_infohashes
     /// responses.
     pub fn num_infohashes(mut self, num: usize) -> Self {
@@ -354,6 +379,7 @@ impl MessageBuilder {
             packets::RequestSpecific::FindNodeRequest(packets::FindNodeRequestArguments {
                 requester_id: required_or_error!(self, sender_id),
                 target: required_or_error!(self, target),
+                want: self.want,
             },)
         ))
     }
@@ -374,6 +400,7 @@ impl MessageBuilder {
             packets::RequestSpecific::GetPeersRequest(packets::GetPeersRequestArguments {
                 requester_id: required_or_error!(self, sender_id),
                 info_hash: required_or_error!(self, target),
+                want: self.want,
             })
         ))
     }
@@ -386,6 +413,12 @@ impl MessageBuilder {
                 token: required_or_error!(self, token),
 
                 values: match self.peers {
+                    Some(peers) if self.nodes_with_peers => {
+                        packets::GetPeersResponseValues::PeersAndNodes(
+                            peers,
+                            self.nodes.unwrap_or_default(),
+                        )
+                    }
                     Some(peers) => packets::GetPeersResponseValues::Peers(peers),
                     None => match self.nodes {
                         Some(nodes) => packets::GetPeersResponseValues::Nodes(nodes),
@@ -655,7 +688,8 @@ mod test {
                 message_type: packets::MessageType::Request(
                     packets::RequestSpecific::FindNodeRequest(packets::FindNodeRequestArguments {
                         requester_id: our_id,
-                        target: target
+                        target: target,
+                        want: None,
                     })
                 ),
                 read_only: None,
@@ -711,7 +745,8 @@ mod test {
                 message_type: packets::MessageType::Request(
                     packets::RequestSpecific::GetPeersRequest(packets::GetPeersRequestArguments {
                         requester_id: our_id,
-                        info_hash: target
+                        info_hash: target,
+                        want: None,
                     })
                 ),
                 read_only: None,
@@ -817,6 +852,29 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_get_peers_response_peers_and_nodes() {
+        let our_id = Id::from_hex("0000000000000000000011111111111111111111").unwrap();
+        let nodes = vec![Node::new(our_id.clone(), "1.2.3.4:53".parse().unwrap())];
+        let peers = vec!["1.2.3.4:53".parse().unwrap()];
+        let msg = MessageBuilder::new_get_peers_response()
+            .sender_id(our_id)
+            .transaction_id(vec![0, 1, 2, 3])
+            .peers_and_nodes(peers.clone(), nodes.clone())
+            .token(vec![45, 56])
+            .build()
+            .expect("Failed to build message");
+        assert!(matches!(
+            msg.message_type,
+            packets::MessageType::Response(packets::ResponseSpecific::GetPeersResponse(
+                packets::GetPeersResponseArguments {
+                    values: packets::GetPeersResponseValues::PeersAndNodes(p, n),
+                    ..
+                }
+            )) if p == peers && n == nodes
+        ));
+    }
+
     #[test]
     fn test_announce_peer_request() {
         let our_id = Id::from_hex("0000000000000000000011111111111111111111").unwrap();
@@ -1,9 +1,9 @@
 use super::internal;
-use crate::common::{Id, Node, ID_SIZE};
+use crate::common::{AddressFamily, Id, Node, ID_SIZE};
 use crate::errors;
 use anyhow::anyhow;
 use std::convert::TryInto;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::time::Duration;
 
 const MAX_SCRAPE_INTERVAL: u64 = 21600; // 6 hours
@@ -33,6 +33,7 @@ const MAX_SCRAPE_INTERVAL: u64 = 21600; // 6 hours
 ///         FindNodeRequestArguments {
 ///             target: Id::from_hex("1234123412341234123412341234123412341234").unwrap(),
 ///             requester_id: Id::from_hex("5678567856785678567856785678567856785678").unwrap(),
+///             want: None,
 ///         },
 ///     )),
 /// };
@@ -84,6 +85,9 @@ pub struct Message {
 
     /// The IP address and port ("SocketAddr") of the requester as seen from the responder's point of view.
     /// This should be set only on response, but is defined at this level with the other common fields to avoid defining yet another layer on the response objects.
+    ///
+    /// Either family works: it's encoded in the compact format of bep0042's `ip` field, which is
+    /// 6 bytes for IPv4 and 18 bytes for IPv6.
     pub requester_ip: Option<SocketAddr>,
 
     pub message_type: MessageType,
@@ -136,12 +140,20 @@ pub struct PingRequestArguments {
 pub struct FindNodeRequestArguments {
     pub target: Id,
     pub requester_id: Id,
+
+    /// For bep0032. The address families of the nodes that the requester wants in the response.
+    /// When None, responders should send nodes of the same family as the request was sent over.
+    pub want: Option<Vec<AddressFamily>>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct GetPeersRequestArguments {
     pub info_hash: Id,
     pub requester_id: Id,
+
+    /// For bep0032. The address families of the nodes that the requester wants in the response.
+    /// When None, responders should send nodes of the same family as the request was sent over.
+    pub want: Option<Vec<AddressFamily>>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -163,6 +175,13 @@ pub struct AnnouncePeerRequestArguments {
 pub enum GetPeersResponseValues {
     Nodes(Vec<Node>),
     Peers(Vec<SocketAddr>),
+
+    /// Peers, along with some nodes close to the info hash to keep the lookup going.
+    ///
+    /// Received responses that carry both `values` and `nodes` parse as this variant
+    /// (they used to parse as [Peers](GetPeersResponseValues::Peers), dropping the nodes),
+    /// so code that looks for peers should match both.
+    PeersAndNodes(Vec<SocketAddr>, Vec<Node>),
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -183,6 +202,13 @@ pub struct GetPeersResponseArguments {
     pub values: GetPeersResponseValues,
 }
 
+impl GetPeersResponseArguments {
+    /// The token that must be sent back in an announce_peer request to the responder
+    pub fn token(&self) -> &[u8] {
+        &self.token
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct SampleInfoHashesResponseArguments {
     pub responder_id: Id,
@@ -226,6 +252,7 @@ impl Message {
                             arguments: internal::DHTFindNodeArguments {
                                 id: find_node_args.requester_id.to_vec(),
                                 target: find_node_args.target.to_vec(),
+                                want: want_to_bytes(&find_node_args.want),
                             },
                         }
                     }
@@ -235,6 +262,7 @@ impl Message {
                             arguments: internal::DHTGetPeersArguments {
                                 id: get_peers_args.requester_id.to_vec(),
                                 info_hash: get_peers_args.info_hash.to_vec(),
+                                want: want_to_bytes(&get_peers_args.want),
                             },
                         }
                     }
@@ -273,6 +301,7 @@ impl Message {
                             arguments: internal::DHTFindNodeResponseArguments {
                                 id: find_node_args.responder_id.to_vec(),
                                 nodes: nodes4_to_bytes(&find_node_args.nodes),
+                                nodes6: nodes6_to_bytes(&find_node_args.nodes),
                             },
                         }
                     }
@@ -283,13 +312,22 @@ impl Message {
                                 id: get_peers_args.responder_id.to_vec(),
                                 token: get_peers_args.token.clone(),
                                 nodes: match &get_peers_args.values {
-                                    GetPeersResponseValues::Nodes(nodes) => {
+                                    GetPeersResponseValues::Nodes(nodes)
+                                    | GetPeersResponseValues::PeersAndNodes(_, nodes) => {
                                         Some(nodes4_to_bytes(&nodes))
                                     }
                                     _ => None,
                                 },
+                                nodes6: match &get_peers_args.values {
+                                    GetPeersResponseValues::Nodes(nodes)
+                                    | GetPeersResponseValues::PeersAndNodes(_, nodes) => {
+                                        nodes6_to_bytes(nodes)
+                                    }
+                                    _ => None,
+                                },
                                 values: match &get_peers_args.values {
-                                    GetPeersResponseValues::Peers(peers) => {
+                                    GetPeersResponseValues::Peers(peers)
+                                    | GetPeersResponseValues::PeersAndNodes(peers, _) => {
                                         Some(peers_to_bytes(peers))
                                     }
                                     _ => None,
@@ -316,6 +354,7 @@ impl Message {
                                 ) as i32,
                                 num: sample_info_hashes_args.num,
                                 nodes: nodes4_to_bytes(&sample_info_hashes_args.nodes),
+                                nodes6: nodes6_to_bytes(&sample_info_hashes_args.nodes),
                                 samples: {
                                     let mut a = Vec::with_capacity(
                                         sample_info_hashes_args.samples.len() * ID_SIZE,
@@ -343,8 +382,8 @@ impl Message {
     }
 
     fn from_serde_message(msg: internal::DHTMessage) -> Result<Message, errors::RustyDHTError> {
+        let transaction_id = msg.transaction_id;
         Ok(Message {
-            transaction_id: msg.transaction_id,
             version: msg.version,
             requester_ip: match msg.ip {
                 Some(ip) => Some(bytes_to_sockaddr(ip)?),
@@ -359,15 +398,26 @@ impl Message {
                 internal::DHTMessageVariant::DHTRequest(req_variant) => {
                     MessageType::Request(match req_variant {
                         internal::DHTRequestSpecific::DHTAnnouncePeerRequest { arguments } => {
+                            let implied_port = if arguments.implied_port.is_none() {
+                                None
+                            } else if arguments.implied_port.unwrap() != 0 {
+                                Some(true)
+                            } else {
+                                Some(false)
+                            };
+                            // Without implied_port, port is the only way to know where the
+                            // peer is. Port 0 can't be connected to.
+                            if implied_port != Some(true) && arguments.port == 0 {
+                                return Err(errors::RustyDHTError::PacketParseError(anyhow!(
+                                    "announce_peer request has port 0 and no implied_port"
+                                )));
+                            }
                             RequestSpecific::AnnouncePeerRequest(AnnouncePeerRequestArguments {
-                                requester_id: Id::from_bytes(arguments.id)?,
-                                implied_port: if arguments.implied_port.is_none() {
-                                    None
-                                } else if arguments.implied_port.unwrap() != 0 {
-                                    Some(true)
-                                } else {
-                                    Some(false)
-                                },
+                                requester_id: requester_id_from_bytes(
+                                    &arguments.id,
+                                    &transaction_id,
+                                )?,
+                                implied_port,
                                 info_hash: Id::from_bytes(&arguments.info_hash)?,
                                 port: arguments.port,
                                 token: arguments.token.clone(),
@@ -376,28 +426,42 @@ impl Message {
 
                         internal::DHTRequestSpecific::DHTFindNodeRequest { arguments } => {
                             RequestSpecific::FindNodeRequest(FindNodeRequestArguments {
-                                requester_id: Id::from_bytes(arguments.id)?,
+                                requester_id: requester_id_from_bytes(
+                                    &arguments.id,
+                                    &transaction_id,
+                                )?,
                                 target: Id::from_bytes(&arguments.target)?,
+                                want: bytes_to_want(&arguments.want),
                             })
                         }
 
                         internal::DHTRequestSpecific::DHTGetPeersRequest { arguments } => {
                             RequestSpecific::GetPeersRequest(GetPeersRequestArguments {
-                                requester_id: Id::from_bytes(arguments.id)?,
+                                requester_id: requester_id_from_bytes(
+                                    &arguments.id,
+                                    &transaction_id,
+                                )?,
                                 info_hash: Id::from_bytes(&arguments.info_hash)?,
+                                want: bytes_to_want(&arguments.want),
                             })
                         }
 
                         internal::DHTRequestSpecific::DHTPingRequest { arguments } => {
                             RequestSpecific::PingRequest(PingRequestArguments {
-                                requester_id: Id::from_bytes(&arguments.id)?,
+                                requester_id: requester_id_from_bytes(
+                                    &arguments.id,
+                                    &transaction_id,
+                                )?,
                             })
                         }
 
                         internal::DHTRequestSpecific::DHTSampleInfoHashesRequest { arguments } => {
                             RequestSpecific::SampleInfoHashesRequest(
                                 SampleInfoHashesRequestArguments {
-                                    requester_id: Id::from_bytes(&arguments.id)?,
+                                    requester_id: requester_id_from_bytes(
+                                        &arguments.id,
+                                        &transaction_id,
+                                    )?,
                                     target: Id::from_bytes(&arguments.target)?,
                                 },
                             )
@@ -410,7 +474,7 @@ impl Message {
                         internal::DHTResponseSpecific::DHTFindNodeResponse { arguments } => {
                             ResponseSpecific::FindNodeResponse(FindNodeResponseArguments {
                                 responder_id: Id::from_bytes(&arguments.id)?,
-                                nodes: bytes_to_nodes4(&arguments.nodes)?,
+                                nodes: bytes_to_nodes(&arguments.nodes, &arguments.nodes6)?,
                             })
                         }
 
@@ -418,13 +482,24 @@ impl Message {
                             ResponseSpecific::GetPeersResponse(GetPeersResponseArguments {
                                 responder_id: Id::from_bytes(&arguments.id)?,
                                 token: arguments.token.clone(),
-                                values: if arguments.values.is_some() {
+                                values: if arguments.values.is_some()
+                                    && (arguments.nodes.is_some() || arguments.nodes6.is_some())
+                                {
+                                    GetPeersResponseValues::PeersAndNodes(
+                                        bytes_to_peers(arguments.values.as_ref().unwrap())?,
+                                        bytes_to_nodes(
+                                            arguments.nodes.as_deref().unwrap_or_default(),
+                                            &arguments.nodes6,
+                                        )?,
+                                    )
+                                } else if arguments.values.is_some() {
                                     GetPeersResponseValues::Peers(bytes_to_peers(
                                         &arguments.values.as_ref().unwrap(),
                                     )?)
-                                } else if arguments.nodes.is_some() {
-                                    GetPeersResponseValues::Nodes(bytes_to_nodes4(
-                                        &arguments.nodes.as_ref().unwrap(),
+                                } else if arguments.nodes.is_some() || arguments.nodes6.is_some() {
+                                    GetPeersResponseValues::Nodes(bytes_to_nodes(
+                                        arguments.nodes.as_deref().unwrap_or_default(),
+                                        &arguments.nodes6,
                                     )?)
                                 } else {
                                     GetPeersResponseValues::Nodes(vec![])
@@ -445,7 +520,7 @@ impl Message {
                                 responder_id: Id::from_bytes(&arguments.id)?,
                                 interval: Duration::from_secs(arguments.interval as u64),
                                 num: arguments.num,
-                                nodes: bytes_to_nodes4(&arguments.nodes)?,
+                                nodes: bytes_to_nodes(&arguments.nodes, &arguments.nodes6)?,
                                 samples: {
                                     if arguments.samples.len() % ID_SIZE != 0 {
                                         return Err(anyhow!(
@@ -512,6 +587,7 @@ impl Message {
                     })
                 }
             },
+            transaction_id,
         })
     }
 
@@ -552,6 +628,17 @@ impl Message {
 
         return Some(id);
     }
+
+    /// Return the token that the sender of this Message wants to see in an announce_peer
+    /// request, if it's a response that carries one (currently only get_peers responses).
+    pub fn announce_token(&self) -> Option<&[u8]> {
+        match &self.message_type {
+            MessageType::Response(ResponseSpecific::GetPeersResponse(arguments)) => {
+                Some(arguments.token())
+            }
+            _ => None,
+        }
+    }
 }
 
 /// Returns true if the response and request types specified match.
@@ -590,7 +677,20 @@ pub fn response_matches_request(res: &ResponseSpecific, req: &RequestSpecific) -
     return false;
 }
 
-fn bytes_to_sockaddr<T: AsRef<[u8]>>(bytes: T) -> Result<SocketAddr, errors::RustyDHTError> {
+/// Parses the Id of a query's sender.
+///
+/// Failure is reported as [InvalidSenderIdError](errors::RustyDHTError::InvalidSenderIdError)
+/// rather than a generic parse error so that the sender can be told about it.
+fn requester_id_from_bytes(id: &[u8], transaction_id: &[u8]) -> Result<Id, errors::RustyDHTError> {
+    Id::from_bytes(id).map_err(|_| errors::RustyDHTError::InvalidSenderIdError {
+        transaction_id: transaction_id.to_vec(),
+        sender: None,
+    })
+}
+
+pub(crate) fn bytes_to_sockaddr<T: AsRef<[u8]>>(
+    bytes: T,
+) -> Result<SocketAddr, errors::RustyDHTError> {
     let bytes = bytes.as_ref();
     match bytes.len() {
         6 => {
@@ -608,9 +708,26 @@ fn bytes_to_sockaddr<T: AsRef<[u8]>>(bytes: T) -> Result<SocketAddr, errors::Rus
             Ok(SocketAddr::new(IpAddr::V4(ip), port))
         }
 
-        18 => Err(errors::RustyDHTError::PacketParseError(anyhow!(
-            "IPv6 is not yet implemented"
-        ))),
+        18 => {
+            let ip_bytes_as_array: [u8; 16] =
+                bytes[0..16]
+                    .try_into()
+                    .map_err(|err: std::array::TryFromSliceError| {
+                        errors::RustyDHTError::PacketParseError(err.into())
+                    })?;
+            let ip = Ipv6Addr::from(ip_bytes_as_array);
+
+            let port_bytes_as_array: [u8; 2] =
+                bytes[16..18]
+                    .try_into()
+                    .map_err(|err: std::array::TryFromSliceError| {
+                        errors::RustyDHTError::PacketParseError(err.into())
+                    })?;
+
+            let port: u16 = u16::from_be_bytes(port_bytes_as_array);
+
+            Ok(SocketAddr::new(IpAddr::V6(ip), port))
+        }
 
         _ => Err(errors::RustyDHTError::PacketParseError(anyhow!(
             "Wrong number of bytes for sockaddr"
@@ -644,19 +761,33 @@ pub fn sockaddr_to_bytes(sockaddr: &SocketAddr) -> Vec<u8> {
     return to_ret;
 }
 
-fn bytes_to_nodes4<T: AsRef<[u8]>>(bytes: T) -> Result<Vec<Node>, errors::RustyDHTError> {
+fn bytes_to_nodes<T: AsRef<[u8]>>(
+    nodes: T,
+    nodes6: &Option<Vec<u8>>,
+) -> Result<Vec<Node>, errors::RustyDHTError> {
+    let mut to_ret = bytes_to_compact_nodes(nodes, 6)?;
+    if let Some(nodes6) = nodes6 {
+        to_ret.append(&mut bytes_to_compact_nodes(nodes6, 18)?);
+    }
+    Ok(to_ret)
+}
+
+fn bytes_to_compact_nodes<T: AsRef<[u8]>>(
+    bytes: T,
+    sockaddr_size: usize,
+) -> Result<Vec<Node>, errors::RustyDHTError> {
     let bytes = bytes.as_ref();
-    let node4_byte_size: usize = ID_SIZE + 6;
-    if bytes.len() % node4_byte_size != 0 {
+    let node_byte_size: usize = ID_SIZE + sockaddr_size;
+    if bytes.len() % node_byte_size != 0 {
         return Err(anyhow!("Wrong number of bytes for nodes message ({})", bytes.len()).into());
     }
 
-    let expected_num = bytes.len() / node4_byte_size;
+    let expected_num = bytes.len() / node_byte_size;
     let mut to_ret = Vec::with_capacity(expected_num);
-    for i in 0..bytes.len() / node4_byte_size {
-        let i = i * node4_byte_size;
+    for i in 0..expected_num {
+        let i = i * node_byte_size;
         let id = Id::from_bytes(&bytes[i..i + ID_SIZE])?;
-        let sockaddr = bytes_to_sockaddr(&bytes[i + ID_SIZE..i + node4_byte_size])?;
+        let sockaddr = bytes_to_sockaddr(&bytes[i + ID_SIZE..i + node_byte_size])?;
         let node = Node::new(id, sockaddr);
         to_ret.push(node);
     }
@@ -664,16 +795,60 @@ fn bytes_to_nodes4<T: AsRef<[u8]>>(bytes: T) -> Result<Vec<Node>, errors::RustyD
     Ok(to_ret)
 }
 
-fn nodes4_to_bytes(nodes: &Vec<Node>) -> Vec<u8> {
-    let node4_byte_size: usize = ID_SIZE + 6;
-    let mut to_ret = Vec::with_capacity(node4_byte_size * nodes.len());
-    for node in nodes {
+/// Serializes the IPv4 Nodes in `nodes`, skipping any IPv6 ones
+fn nodes4_to_bytes(nodes: &[Node]) -> Vec<u8> {
+    nodes_of_family_to_bytes(nodes, AddressFamily::V4)
+}
+
+/// Serializes the IPv6 Nodes in `nodes`, or returns None if there aren't any
+fn nodes6_to_bytes(nodes: &[Node]) -> Option<Vec<u8>> {
+    let to_ret = nodes_of_family_to_bytes(nodes, AddressFamily::V6);
+    if to_ret.is_empty() {
+        None
+    } else {
+        Some(to_ret)
+    }
+}
+
+fn nodes_of_family_to_bytes(nodes: &[Node], family: AddressFamily) -> Vec<u8> {
+    let sockaddr_size = match family {
+        AddressFamily::V4 => 6,
+        AddressFamily::V6 => 18,
+    };
+    let mut to_ret = Vec::with_capacity((ID_SIZE + sockaddr_size) * nodes.len());
+    for node in nodes.iter().filter(|node| node.family() == family) {
         to_ret.append(&mut node.id.to_vec());
         to_ret.append(&mut sockaddr_to_bytes(&node.address));
     }
     to_ret
 }
 
+fn want_to_bytes(want: &Option<Vec<AddressFamily>>) -> Option<Vec<serde_bytes::ByteBuf>> {
+    want.as_ref().map(|want| {
+        want.iter()
+            .map(|family| {
+                serde_bytes::ByteBuf::from(match family {
+                    AddressFamily::V4 => b"n4".to_vec(),
+                    AddressFamily::V6 => b"n6".to_vec(),
+                })
+            })
+            .collect()
+    })
+}
+
+/// Parses the `want` argument of a request. Unrecognized values are ignored, as bep0032 requires.
+fn bytes_to_want(want: &Option<Vec<serde_bytes::ByteBuf>>) -> Option<Vec<AddressFamily>> {
+    want.as_ref().map(|want| {
+        want.iter()
+            .filter_map(|family| match family.as_slice() {
+                b"n4" => Some(AddressFamily::V4),
+                b"n6" => Some(AddressFamily::V6),
+                _ => None,
+            })
+            .collect()
+    })
+}
+
 fn peers_to_bytes<T: AsRef<[SocketAddr]>>(peers: T) -> Vec<serde_bytes::ByteBuf> {
     let peers = peers.as_ref();
     peers
@@ -736,6 +911,42 @@ mod tests {
         assert_eq!(parsed_msg, original_msg);
     }
 
+    #[test]
+    fn test_requester_ip_families() {
+        for (requester_ip, encoded_len) in [
+            ("99.100.101.102:1030".parse().unwrap(), 6),
+            ("[2001:db8::1234:5678]:1030".parse().unwrap(), 18),
+        ] {
+            let original_msg = Message {
+                transaction_id: vec![1, 2, 3],
+                version: None,
+                requester_ip: Some(requester_ip),
+                read_only: None,
+                message_type: MessageType::Response(ResponseSpecific::PingResponse(
+                    PingResponseArguments {
+                        responder_id: Id::from_hex("beefbeefbeefbeefbeefbeefbeefbeefbeefbeef")
+                            .unwrap(),
+                    },
+                )),
+            };
+
+            let serde_msg = original_msg.clone().to_serde_message();
+            assert_eq!(serde_msg.ip.as_ref().map(|ip| ip.len()), Some(encoded_len));
+            let bytes = original_msg.clone().to_bytes().unwrap();
+            assert_eq!(Message::from_bytes(&bytes).unwrap(), original_msg);
+        }
+
+        // Anything else isn't an address
+        let mut serde_msg = crate::packets::MessageBuilder::new_ping_response()
+            .sender_id(Id::from_hex("beefbeefbeefbeefbeefbeefbeefbeefbeefbeef").unwrap())
+            .transaction_id(vec![1, 2, 3])
+            .build()
+            .unwrap()
+            .to_serde_message();
+        serde_msg.ip = Some(vec![1, 2, 3, 4, 5]);
+        assert!(Message::from_serde_message(serde_msg).is_err());
+    }
+
     #[test]
     fn test_get_peers_request() {
         let original_msg = Message {
@@ -747,6 +958,7 @@ mod tests {
                 GetPeersRequestArguments {
                     info_hash: Id::from_hex("deaddeaddeaddeaddeaddeaddeaddeaddeaddead").unwrap(),
                     requester_id: Id::from_hex("beefbeefbeefbeefbeefbeefbeefbeefbeefbeef").unwrap(),
+                    want: None,
                 },
             )),
         };
@@ -784,6 +996,37 @@ mod tests {
         assert_eq!(parsed_msg, original_msg);
     }
 
+    #[test]
+    fn test_announce_token() {
+        let response = Message {
+            transaction_id: vec![1, 2, 3],
+            version: None,
+            requester_ip: None,
+            read_only: None,
+            message_type: MessageType::Response(ResponseSpecific::GetPeersResponse(
+                GetPeersResponseArguments {
+                    responder_id: Id::from_hex("0505050505050505050505050505050505050505").unwrap(),
+                    token: vec![99, 100, 101, 102],
+                    values: GetPeersResponseValues::Peers(vec![]),
+                },
+            )),
+        };
+        assert_eq!(response.announce_token(), Some(&[99, 100, 101, 102][..]));
+
+        let ping_response = Message {
+            transaction_id: vec![1, 2, 3],
+            version: None,
+            requester_ip: None,
+            read_only: None,
+            message_type: MessageType::Response(ResponseSpecific::PingResponse(
+                PingResponseArguments {
+                    responder_id: Id::from_hex("0505050505050505050505050505050505050505").unwrap(),
+                },
+            )),
+        };
+        assert_eq!(ping_response.announce_token(), None);
+    }
+
     #[test]
     fn test_get_peers_response_peers() {
         let original_msg = Message {
@@ -809,6 +1052,35 @@ mod tests {
         assert_eq!(parsed_msg, original_msg);
     }
 
+    #[test]
+    fn test_get_peers_response_peers_and_nodes() {
+        let original_msg = Message {
+            transaction_id: vec![1, 2, 3],
+            version: Some(vec![1]),
+            requester_ip: None,
+            read_only: None,
+            message_type: MessageType::Response(ResponseSpecific::GetPeersResponse(
+                GetPeersResponseArguments {
+                    responder_id: Id::from_hex("0505050505050505050505050505050505050505").unwrap(),
+                    token: vec![99, 100, 101, 102],
+                    values: GetPeersResponseValues::PeersAndNodes(
+                        vec!["123.123.123.123:123".parse().unwrap()],
+                        vec![Node::new(
+                            Id::from_hex("0606060606060606060606060606060606060606").unwrap(),
+                            "49.50.52.52:5354".parse().unwrap(),
+                        )],
+                    ),
+                },
+            )),
+        };
+
+        let serde_msg = original_msg.clone().to_serde_message();
+        let bytes = serde_msg.to_bytes().unwrap();
+        let parsed_serde_msg = internal::DHTMessage::from_bytes(bytes).unwrap();
+        let parsed_msg = Message::from_serde_message(parsed_serde_msg).unwrap();
+        assert_eq!(parsed_msg, original_msg);
+    }
+
     #[test]
     fn test_get_peers_response_neither() {
         let serde_message = internal::DHTMessage {
@@ -824,6 +1096,7 @@ mod tests {
                             .to_vec(),
                         token: vec![0, 1],
                         nodes: None,
+                        nodes6: None,
                         values: None,
                     },
                 },
@@ -849,6 +1122,7 @@ mod tests {
                 FindNodeRequestArguments {
                     target: Id::from_hex("1234123412341234123412341234123412341234").unwrap(),
                     requester_id: Id::from_hex("5678567856785678567856785678567856785678").unwrap(),
+                    want: None,
                 },
             )),
         };
@@ -871,6 +1145,7 @@ mod tests {
                 FindNodeRequestArguments {
                     target: Id::from_hex("1234123412341234123412341234123412341234").unwrap(),
                     requester_id: Id::from_hex("5678567856785678567856785678567856785678").unwrap(),
+                    want: None,
                 },
             )),
         };
@@ -907,6 +1182,92 @@ mod tests {
         assert_eq!(parsed_msg, original_msg);
     }
 
+    #[test]
+    fn test_request_without_sender_id() {
+        match Message::from_bytes(b"d1:ade1:q4:ping1:t2:aa1:y1:qe") {
+            Err(errors::RustyDHTError::InvalidSenderIdError {
+                transaction_id,
+                sender,
+            }) => {
+                assert_eq!(transaction_id, b"aa".to_vec());
+                assert_eq!(sender, None);
+            }
+            other => panic!("Expected InvalidSenderIdError, got {:?}", other),
+        }
+
+        // Other malformed fields are still plain parse errors
+        assert!(matches!(
+            Message::from_bytes(
+                b"d1:ad2:id20:abcdefghij01234567896:target3:abce1:q9:find_node1:t2:aa1:y1:qe"
+            ),
+            Err(errors::RustyDHTError::PacketParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_find_node_request_want() {
+        let original_msg = Message {
+            transaction_id: vec![1, 2, 3],
+            version: None,
+            requester_ip: None,
+            read_only: None,
+            message_type: MessageType::Request(RequestSpecific::FindNodeRequest(
+                FindNodeRequestArguments {
+                    target: Id::from_hex("1234123412341234123412341234123412341234").unwrap(),
+                    requester_id: Id::from_hex("5678567856785678567856785678567856785678").unwrap(),
+                    want: Some(vec![AddressFamily::V4, AddressFamily::V6]),
+                },
+            )),
+        };
+
+        let serde_msg = original_msg.clone().to_serde_message();
+        let bytes = serde_msg.to_bytes().unwrap();
+        let parsed_serde_msg = internal::DHTMessage::from_bytes(bytes).unwrap();
+        let parsed_msg = Message::from_serde_message(parsed_serde_msg).unwrap();
+        assert_eq!(parsed_msg, original_msg);
+    }
+
+    #[test]
+    fn test_find_node_response_nodes6() {
+        let v4_node = Node::new(
+            Id::from_hex("0606060606060606060606060606060606060606").unwrap(),
+            "49.50.52.52:5354".parse().unwrap(),
+        );
+        let v6_node = Node::new(
+            Id::from_hex("0707070707070707070707070707070707070707").unwrap(),
+            "[2001:db8::1]:5354".parse().unwrap(),
+        );
+        let original_msg = Message {
+            transaction_id: vec![1, 2, 3],
+            version: None,
+            requester_ip: Some("50.51.52.53:5455".parse().unwrap()),
+            read_only: None,
+            message_type: MessageType::Response(ResponseSpecific::FindNodeResponse(
+                FindNodeResponseArguments {
+                    responder_id: Id::from_hex("0505050505050505050505050505050505050505").unwrap(),
+                    nodes: vec![v4_node.clone(), v6_node.clone()],
+                },
+            )),
+        };
+
+        let serde_msg = original_msg.clone().to_serde_message();
+        match &serde_msg.variant {
+            internal::DHTMessageVariant::DHTResponse(
+                internal::DHTResponseSpecific::DHTFindNodeResponse { arguments },
+            ) => {
+                assert_eq!(arguments.nodes, nodes4_to_bytes(&[v4_node]));
+                assert_eq!(arguments.nodes6, nodes6_to_bytes(&[v6_node]));
+                assert_eq!(arguments.nodes6.as_ref().unwrap().len(), ID_SIZE + 18);
+            }
+            _ => panic!("Wrong message type"),
+        }
+
+        let bytes = serde_msg.to_bytes().unwrap();
+        let parsed_serde_msg = internal::DHTMessage::from_bytes(bytes).unwrap();
+        let parsed_msg = Message::from_serde_message(parsed_serde_msg).unwrap();
+        assert_eq!(parsed_msg, original_msg);
+    }
+
     #[test]
     fn test_announce_peer_request() {
         let original_msg = Message {
@@ -932,6 +1293,34 @@ mod tests {
         assert_eq!(parsed_msg, original_msg);
     }
 
+    #[test]
+    fn test_announce_peer_request_without_port() {
+        for implied_port in [None, Some(false)] {
+            let msg = Message {
+                transaction_id: vec![1, 2, 3],
+                version: None,
+                requester_ip: None,
+                read_only: None,
+                message_type: MessageType::Request(RequestSpecific::AnnouncePeerRequest(
+                    AnnouncePeerRequestArguments {
+                        requester_id: Id::from_hex("5678567856785678567856785678567856785678")
+                            .unwrap(),
+                        port: 0,
+                        implied_port,
+                        token: vec![42, 42, 42, 42],
+                        info_hash: Id::from_hex("9899989998999899989998999899989998999899")
+                            .unwrap(),
+                    },
+                )),
+            };
+            let bytes = msg.to_bytes().unwrap();
+            assert!(matches!(
+                Message::from_bytes(&bytes),
+                Err(errors::RustyDHTError::PacketParseError(_))
+            ));
+        }
+    }
+
     #[test]
     fn test_sample_info_hashes_request() {
         let original_msg = Message {
@@ -1018,6 +1407,7 @@ mod tests {
         let req = RequestSpecific::FindNodeRequest(FindNodeRequestArguments {
             requester_id: Id::from_random(&mut thread_rng()),
             target: Id::from_random(&mut thread_rng()),
+            want: None,
         });
         assert_eq!(true, response_matches_request(&res, &req));
     }
@@ -1041,6 +1431,7 @@ mod tests {
         let req = RequestSpecific::FindNodeRequest(FindNodeRequestArguments {
             requester_id: Id::from_random(&mut thread_rng()),
             target: Id::from_random(&mut thread_rng()),
+            want: None,
         });
         assert_eq!(false, response_matches_request(&res, &req));
     }
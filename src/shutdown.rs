@@ -1,14 +1,23 @@
+use crate::errors::RustyDHTError;
+use anyhow::anyhow;
 use log::{error, info, trace, warn};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::sync::broadcast;
 use tokio::sync::watch;
+use tokio::task::JoinSet;
 use tokio::time::sleep;
 
+/// The tasks started with [spawn_with_shutdown](ShutdownReceiver::spawn_with_shutdown),
+/// shared by a ShutdownSender and all of its ShutdownReceivers
+type TaskSet = Arc<Mutex<JoinSet<()>>>;
+
 /// Contains methods to wait for a "clean shutdown" signal in asynchronous tasks.
 #[derive(Clone)]
 pub struct ShutdownReceiver {
     shutdown_rx: watch::Receiver<bool>,
     _shutdown_confirm_tx: broadcast::Sender<bool>,
+    tasks: TaskSet,
 }
 
 impl ShutdownReceiver {
@@ -24,6 +33,8 @@ impl ShutdownReceiver {
 
     /// Spawn a new async task that will automatically be dropped when the provided
     /// ShutdownReceiver is signaled.
+    ///
+    /// The task is tracked, so that [ShutdownSender::shutdown_and_join] can wait for it.
     pub fn spawn_with_shutdown<T>(
         mut shutdown: ShutdownReceiver,
         todo: T,
@@ -33,7 +44,11 @@ impl ShutdownReceiver {
         T: std::future::Future + Send + 'static,
         T::Output: Send + 'static,
     {
-        tokio::spawn(async move {
+        let tasks = shutdown.tasks.clone();
+        let mut tasks = tasks.lock().unwrap();
+        // Forget about the tasks that already finished, so the set doesn't keep growing
+        while tasks.try_join_next().is_some() {}
+        tasks.spawn(async move {
             trace!(target: "rustydht_lib::ShutdownReceiver", "Task '{}' starting up", task_name);
             tokio::select! {
                 _ = shutdown.watch() => {}
@@ -57,6 +72,7 @@ impl ShutdownReceiver {
 pub struct ShutdownSender {
     shutdown_tx: watch::Sender<bool>,
     shutdown_confirm_rx: broadcast::Receiver<bool>,
+    tasks: TaskSet,
 }
 
 impl ShutdownSender {
@@ -73,6 +89,52 @@ impl ShutdownSender {
         }
         info!(target: "rustydht_lib::ShutdownSender","All tasks have stopped");
     }
+
+    /// Signals all async tasks waiting on the corresponding [ShutdownReceiver](crate::shutdown::ShutdownReceiver) to stop.
+    ///
+    /// Unlike [shutdown](ShutdownSender::shutdown), this doesn't wait for every ShutdownReceiver
+    /// to be dropped (so it's fine to still hold a [DHT](crate::dht::DHT)). Instead, it awaits
+    /// until every task started with [spawn_with_shutdown](ShutdownReceiver::spawn_with_shutdown)
+    /// has finished, including pings and other requests that the DHT sent in the background.
+    ///
+    /// Tasks that are still running after `timeout` are aborted, and a
+    /// [TimeoutError](RustyDHTError::TimeoutError) is returned.
+    pub async fn shutdown_and_join(&mut self, timeout: Duration) -> Result<(), RustyDHTError> {
+        info!(target: "rustydht_lib::ShutdownSender", "Sending shutdown signal to tasks");
+        if let Err(e) = self.shutdown_tx.send(true) {
+            warn!(target: "rustydht_lib::ShutdownSender","Failed to send shutdown signal - likely all tasks are already stopped. Error: {:?}", e);
+        }
+
+        let mut joining = JoinSet::new();
+        let joined = tokio::time::timeout(timeout, async {
+            // Tasks can spawn more tasks as they wind down, so keep going until there are none
+            loop {
+                std::mem::swap(&mut joining, &mut *self.tasks.lock().unwrap());
+                if joining.is_empty() {
+                    break;
+                }
+                while joining.join_next().await.is_some() {}
+            }
+        })
+        .await;
+
+        match joined {
+            Ok(()) => {
+                info!(target: "rustydht_lib::ShutdownSender","All tasks have stopped");
+                Ok(())
+            }
+            Err(_) => {
+                let remaining = joining.len() + self.tasks.lock().unwrap().len();
+                joining.abort_all();
+                self.tasks.lock().unwrap().abort_all();
+                Err(RustyDHTError::TimeoutError(anyhow!(
+                    "Aborted {} tasks that were still running {:?} after shutdown",
+                    remaining,
+                    timeout
+                )))
+            }
+        }
+    }
 }
 
 /// Create a linked ShutdownSender and ShutdownReceiver pair. The receiver's
@@ -88,14 +150,19 @@ pub fn create_shutdown() -> (ShutdownSender, ShutdownReceiver) {
     // We use this channel to determine when all tasks have shutdown
     let (shutdown_confirm_tx, shutdown_confirm_rx) = broadcast::channel::<bool>(1);
 
+    // We keep track of spawned tasks here, to be able to wait for them
+    let tasks = Arc::new(Mutex::new(JoinSet::new()));
+
     (
         ShutdownSender {
             shutdown_tx: shutdown_tx,
             shutdown_confirm_rx: shutdown_confirm_rx,
+            tasks: tasks.clone(),
         },
         ShutdownReceiver {
             shutdown_rx: shutdown_rx,
             _shutdown_confirm_tx: shutdown_confirm_tx,
+            tasks,
         },
     )
 }
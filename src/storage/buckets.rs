@@ -1,6 +1,15 @@
+use super::metric::{Metric, XorMetric};
 use crate::common::Id;
+use std::cmp::Ordering;
+use std::sync::Arc;
 use std::time::Instant;
 
+/// Sorts items from the most to the least worth keeping in a full bucket
+type EvictionOrder<T> = Arc<dyn Fn(&T, &T) -> Ordering + Send + Sync>;
+
+/// Number of buckets that [Buckets::new] makes room for up front
+pub const DEFAULT_BUCKETS_CAPACITY: usize = 32;
+
 /// Anything that implements this trait can be stored in Buckets
 pub trait Bucketable {
     fn get_id(&self) -> Id;
@@ -15,14 +24,27 @@ pub struct Buckets<T: Bucketable> {
     buckets: Vec<Vec<T>>,
 
     k: usize,
+
+    metric: Arc<dyn Metric>,
+
+    /// Sorts the items of an overflowing bucket, from the most to the least worth keeping.
+    /// Oldest first if None.
+    eviction_order: Option<EvictionOrder<T>>,
 }
 
 impl<T: Bucketable> Buckets<T> {
     pub fn new(our_id: Id, k: usize) -> Buckets<T> {
+        Buckets::with_capacity(our_id, k, DEFAULT_BUCKETS_CAPACITY)
+    }
+
+    /// Same as [new](Buckets::new), but makes room for `buckets` buckets up front
+    pub fn with_capacity(our_id: Id, k: usize, buckets: usize) -> Buckets<T> {
         let mut to_ret = Buckets {
             our_id: our_id,
-            buckets: Vec::with_capacity(32),
+            buckets: Vec::with_capacity(buckets),
             k: k,
+            metric: Arc::new(XorMetric),
+            eviction_order: None,
         };
 
         to_ret.buckets.push(Vec::new());
@@ -71,6 +93,19 @@ impl<T: Bucketable> Buckets<T> {
         self.buckets.len()
     }
 
+    /// Returns how many buckets there's room for without reallocating
+    pub fn buckets_capacity(&self) -> usize {
+        self.buckets.capacity()
+    }
+
+    /// Returns the index of the bucket that an item with the given Id belongs in.
+    ///
+    /// Buckets are split as they fill up, so this is the number of leading bits that `id`
+    /// shares with our Id, capped at the deepest bucket split off so far.
+    pub fn bucket_index(&self, id: &Id) -> usize {
+        self.get_dest_bucket_idx_for_id(id)
+    }
+
     pub fn get_mut(&mut self, id: &Id) -> Option<&mut T> {
         let dest_bucket_idx = self.get_dest_bucket_idx_for_id(&id);
         if let Some(bucket) = self.buckets.get_mut(dest_bucket_idx) {
@@ -83,20 +118,48 @@ impl<T: Bucketable> Buckets<T> {
         None
     }
 
+    /// Sets the [Metric] used to find the nearest nodes/items. The default is [XorMetric].
+    pub fn set_metric(&mut self, metric: Arc<dyn Metric>) {
+        self.metric = metric;
+    }
+
+    /// Sets how the items of an overflowing bucket are sorted, from the most to the least
+    /// worth keeping. The items that sort after the first `k` are evicted. By default, the
+    /// items seen first are kept.
+    pub fn set_eviction_order(&mut self, order: EvictionOrder<T>) {
+        self.eviction_order = Some(order);
+    }
+
     /// Get the `k` nearest nodes/items stored in the buckets
     ///
     /// The returned vector is sorted by distance, from nearest to farthest.
     pub fn get_nearest_nodes(&self, id: &Id, exclude: Option<&Id>) -> Vec<&T> {
+        self.get_nearest_nodes_matching(id, exclude, |_| true)
+    }
+
+    /// Get the `k` nearest nodes/items stored in the buckets for which `predicate` returns true
+    ///
+    /// The returned vector is sorted by distance, from nearest to farthest.
+    pub fn get_nearest_nodes_matching<F>(
+        &self,
+        id: &Id,
+        exclude: Option<&Id>,
+        predicate: F,
+    ) -> Vec<&T>
+    where
+        F: Fn(&T) -> bool,
+    {
         let mut all: Vec<&T> = self
             .values()
             .iter()
             .filter(|item| exclude.is_none() || *exclude.unwrap() != item.get_id())
+            .filter(|item| predicate(item))
             .map(|item| *item)
             .collect();
 
         all.sort_unstable_by(|a, b| {
-            let a_dist = a.get_id().xor(id);
-            let b_dist = b.get_id().xor(id);
+            let a_dist = self.metric.distance(&a.get_id(), id);
+            let b_dist = self.metric.distance(&b.get_id(), id);
             a_dist.partial_cmp(&b_dist).unwrap()
         });
 
@@ -174,10 +237,14 @@ impl<T: Bucketable> Buckets<T> {
                     }
                 }
 
-                // Sort by oldest. Move the newest excess to the chump list
+                // Sort by the eviction order (oldest first by default). Move the excess to
+                // the chump list
                 if self.buckets[bucket_index].len() > self.k {
-                    self.buckets[bucket_index]
-                        .sort_unstable_by(|a, b| a.get_first_seen().cmp(&b.get_first_seen()));
+                    match &self.eviction_order {
+                        Some(order) => self.buckets[bucket_index].sort_by(|a, b| order(a, b)),
+                        None => self.buckets[bucket_index]
+                            .sort_unstable_by(|a, b| a.get_first_seen().cmp(&b.get_first_seen())),
+                    }
                     let mut remainder = self.buckets[bucket_index].split_off(self.k);
 
                     if let Some(chump_list) = &mut chump_list {
@@ -447,6 +514,58 @@ mod tests {
         );
     }
 
+    /// Ranks Ids from farthest to nearest by XOR distance
+    struct FarthestFirst;
+
+    impl Metric for FarthestFirst {
+        fn distance(&self, a: &Id, b: &Id) -> Id {
+            let xor = a.xor(b).to_vec();
+            let inverted: Vec<u8> = xor.iter().map(|byte| !byte).collect();
+            Id::from_bytes(&inverted).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_custom_metric() {
+        let our_id = Id::from_hex("0000000000000000000000000000000000000000").unwrap();
+        let mut storage = Buckets::new(our_id, 3);
+        for hex in [
+            "0000000000000000000000000000000000000001",
+            "0000000000000000000000000000000000000010",
+            "8000000000000000000000000000000000000000",
+            "c000000000000000000000000000000000000000",
+        ] {
+            storage.add(TestWrapper::new(Id::from_hex(hex).unwrap(), None), None);
+        }
+        let target = Id::from_hex("0000000000000000000000000000000000000000").unwrap();
+        let nearest_ids = |storage: &Buckets<TestWrapper>| -> Vec<Id> {
+            storage
+                .get_nearest_nodes(&target, None)
+                .iter()
+                .map(|item| item.get_id())
+                .collect()
+        };
+
+        assert_eq!(
+            nearest_ids(&storage),
+            vec![
+                Id::from_hex("0000000000000000000000000000000000000001").unwrap(),
+                Id::from_hex("0000000000000000000000000000000000000010").unwrap(),
+                Id::from_hex("8000000000000000000000000000000000000000").unwrap(),
+            ]
+        );
+
+        storage.set_metric(Arc::new(FarthestFirst));
+        assert_eq!(
+            nearest_ids(&storage),
+            vec![
+                Id::from_hex("c000000000000000000000000000000000000000").unwrap(),
+                Id::from_hex("8000000000000000000000000000000000000000").unwrap(),
+                Id::from_hex("0000000000000000000000000000000000000010").unwrap(),
+            ]
+        );
+    }
+
     #[test]
     fn test_get_nearest_nodes2() {
         let our_id = Id::from_hex("0000000000000000000000000000000000000000").unwrap();
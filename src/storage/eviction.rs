@@ -0,0 +1,30 @@
+use super::node_wrapper::NodeWrapper;
+use std::cmp::Ordering;
+
+/// Decides which nodes a full bucket of a
+/// [NodeBucketStorage](crate::storage::node_bucket_storage::NodeBucketStorage) keeps.
+///
+/// When a bucket overflows, its nodes are sorted with [compare](EvictionPolicy::compare)
+/// and the ones that sort last are evicted. Evicted verified nodes move to the storage's
+/// unverified buckets, where they can still be pinged and verified again.
+///
+/// The policy only ranks nodes within one set of buckets. Nodes that have never been
+/// verified are kept in separate quarantine buckets, so a new unverified node can never
+/// evict a verified one, whatever the policy.
+pub trait EvictionPolicy: Send + Sync {
+    /// Returns [Ordering::Less] if `a` should be kept over `b`.
+    fn compare(&self, a: &NodeWrapper, b: &NodeWrapper) -> Ordering;
+}
+
+/// The default [EvictionPolicy], which keeps the nodes we've known the longest.
+///
+/// As in BEP0005, good nodes that have been around for a while are never replaced by
+/// newer ones: nodes that have been up for long tend to stay up.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LongestLivedFirst;
+
+impl EvictionPolicy for LongestLivedFirst {
+    fn compare(&self, a: &NodeWrapper, b: &NodeWrapper) -> Ordering {
+        a.first_seen.cmp(&b.first_seen)
+    }
+}
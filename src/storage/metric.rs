@@ -0,0 +1,22 @@
+use crate::common::Id;
+
+/// Measures how far apart two Ids are, for picking the nearest nodes to a target.
+///
+/// Distances are returned as Ids and compared byte by byte, so smaller distances sort
+/// first. BEP0005 uses [XorMetric], which is what [Buckets](crate::storage::buckets::Buckets)
+/// use unless told otherwise. Other metrics are meant for experimenting with alternative
+/// topologies: they only change which stored nodes are considered nearest to a target.
+/// Nodes are still assigned to buckets by the prefix they share with our own Id.
+pub trait Metric: Send + Sync {
+    fn distance(&self, a: &Id, b: &Id) -> Id;
+}
+
+/// The XOR distance metric from BEP0005
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XorMetric;
+
+impl Metric for XorMetric {
+    fn distance(&self, a: &Id, b: &Id) -> Id {
+        a.xor(b)
+    }
+}
@@ -1,4 +1,6 @@
 pub mod buckets;
+pub mod eviction;
+pub mod metric;
 pub mod node_bucket_storage;
 pub mod node_wrapper;
 pub mod outbound_request_storage;
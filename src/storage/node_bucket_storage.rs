@@ -1,7 +1,10 @@
-use super::buckets::Buckets;
+use super::buckets::{Buckets, DEFAULT_BUCKETS_CAPACITY};
+use super::eviction::EvictionPolicy;
+use super::metric::Metric;
 use super::node_wrapper::NodeWrapper;
-use crate::common::{Id, Node};
+use crate::common::{AddressFamily, Id, Node};
 use dyn_clone::DynClone;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use log::trace;
@@ -39,6 +42,22 @@ pub trait NodeStorage: DynClone + Send {
     /// Return a copy of the records for all verified Nodes
     fn get_all_verified(&self) -> Vec<NodeWrapper>;
 
+    /// Return a copy of the records for quarantined Nodes: those that have been seen
+    /// (e.g. in a find_node response) but have never been verified.
+    ///
+    /// Quarantined Nodes are included in
+    /// [get_all_unverified()](crate::storage::node_bucket_storage::NodeStorage::get_all_unverified)
+    /// so that they get pinged, but must never be returned by
+    /// [get_nearest_nodes()](crate::storage::node_bucket_storage::NodeStorage::get_nearest_nodes),
+    /// so we don't pass along nodes that might not exist. The default implementation
+    /// returns the unverified Nodes that have never been verified.
+    fn get_quarantined(&self) -> Vec<NodeWrapper> {
+        self.get_all_unverified()
+            .into_iter()
+            .filter(|nw| nw.last_verified.is_none())
+            .collect()
+    }
+
     /// Return a copy of the nearest nodes to the provided Id.
     ///
     /// # Parameters
@@ -49,6 +68,57 @@ pub trait NodeStorage: DynClone + Send {
     /// providing its Id here.
     fn get_nearest_nodes(&self, id: &Id, exclude: Option<&Id>) -> Vec<Node>;
 
+    /// Return the index of the bucket that a verified Node with the provided Id would be
+    /// stored in, for implementations that use buckets. The default implementation
+    /// returns None.
+    fn bucket_index(&self, _id: &Id) -> Option<usize> {
+        None
+    }
+
+    /// Return the number of buckets that there's room for without reallocating, for
+    /// implementations that use buckets. The default implementation returns None.
+    fn buckets_capacity(&self) -> Option<usize> {
+        None
+    }
+
+    /// Return a copy of the nearest nodes to the provided Id that have an address
+    /// of the given family.
+    ///
+    /// This is used to answer requests that ask for IPv4 nodes, IPv6 nodes, or both
+    /// (see [BEP0032](http://bittorrent.org/beps/bep_0032.html)). The default implementation
+    /// filters the results of [get_nearest_nodes()](crate::storage::node_bucket_storage::NodeStorage::get_nearest_nodes),
+    /// so implementations that keep the two families together should override it.
+    fn get_nearest_nodes_for_family(
+        &self,
+        id: &Id,
+        exclude: Option<&Id>,
+        family: AddressFamily,
+    ) -> Vec<Node> {
+        self.get_nearest_nodes(id, exclude)
+            .into_iter()
+            .filter(|node| node.family() == family)
+            .collect()
+    }
+
+    /// Same as [get_nearest_nodes_for_family()](crate::storage::node_bucket_storage::NodeStorage::get_nearest_nodes_for_family),
+    /// but nodes that `keep` returns false for are left out before the nearest ones are
+    /// picked, so that they don't take the place of others.
+    ///
+    /// The default implementation filters the results of get_nearest_nodes_for_family(),
+    /// so it may return fewer nodes than there are. Implementations should override it.
+    fn get_nearest_nodes_for_family_matching(
+        &self,
+        id: &Id,
+        exclude: Option<&Id>,
+        family: AddressFamily,
+        keep: &dyn Fn(&Node) -> bool,
+    ) -> Vec<Node> {
+        self.get_nearest_nodes_for_family(id, exclude, family)
+            .into_iter()
+            .filter(|node| keep(node))
+            .collect()
+    }
+
     /// Prune (remove) records of Nodes tht haven't been seen/verified recently.node_wrapper
     ///
     /// # Parameters
@@ -84,10 +154,18 @@ dyn_clone::clone_trait_object!(NodeStorage);
 /// The first bucket stores Nodes that have a zero (or more) bit prefix in common with the DHT Id.
 /// The second bucket stores Nodes that have a one (or more) bit prefix in common with the DHT Id.
 /// Two or more bits in the third. Three or more bits in the 4th. And so on.
+///
+/// Nodes that have never been verified are kept in a separate set of quarantine buckets
+/// until they are, so they can't crowd out nodes that were verified in the past.
+///
+/// When a bucket of verified nodes is full, the nodes we've known the longest are kept and
+/// the newest are moved to the unverified buckets, as BEP0005 suggests (good nodes aren't
+/// replaced). That can be changed with [set_eviction_policy](NodeBucketStorage::set_eviction_policy).
 #[derive(Clone)]
 pub struct NodeBucketStorage {
     verified: Buckets<NodeWrapper>,
     unverified: Buckets<NodeWrapper>,
+    quarantine: Buckets<NodeWrapper>,
 }
 
 impl NodeBucketStorage {
@@ -98,12 +176,58 @@ impl NodeBucketStorage {
     /// be assigned to buckets based on the XOR distance between their Id and this one.
     /// * `k` - the number of nodes that can be stored in a bucket.
     pub fn new(our_id: Id, k: usize) -> NodeBucketStorage {
+        NodeBucketStorage::with_quarantine_size(our_id, k, k)
+    }
+
+    /// Create a new NodeBucketStorage with a different bucket size for quarantined Nodes.
+    ///
+    /// # Parameters
+    /// * `our_id` - the current Id of the DHT node that will use this object for storage.
+    /// * `k` - the number of verified (or previously verified) nodes that can be stored in a bucket.
+    /// * `quarantine_k` - the number of never-verified nodes that can be stored in a bucket.
+    pub fn with_quarantine_size(our_id: Id, k: usize, quarantine_k: usize) -> NodeBucketStorage {
+        NodeBucketStorage::with_capacity(our_id, k, quarantine_k, DEFAULT_BUCKETS_CAPACITY)
+    }
+
+    /// Create a new NodeBucketStorage that makes room for `buckets` buckets up front, so
+    /// that a routing table that's expected to grow large doesn't reallocate as its
+    /// buckets are split.
+    ///
+    /// # Parameters
+    /// * `our_id` - the current Id of the DHT node that will use this object for storage.
+    /// * `k` - the number of verified (or previously verified) nodes that can be stored in a bucket.
+    /// * `quarantine_k` - the number of never-verified nodes that can be stored in a bucket.
+    /// * `buckets` - the number of buckets to make room for.
+    pub fn with_capacity(
+        our_id: Id,
+        k: usize,
+        quarantine_k: usize,
+        buckets: usize,
+    ) -> NodeBucketStorage {
         NodeBucketStorage {
-            verified: Buckets::new(our_id, k),
-            unverified: Buckets::new(our_id, k),
+            verified: Buckets::with_capacity(our_id, k, buckets),
+            unverified: Buckets::with_capacity(our_id, k, buckets),
+            quarantine: Buckets::with_capacity(our_id, quarantine_k, buckets),
         }
     }
 
+    /// Sets the [Metric] used to pick the nearest nodes to a target. The default is
+    /// [XorMetric](crate::storage::metric::XorMetric), as in BEP0005.
+    pub fn set_metric(&mut self, metric: Arc<dyn Metric>) {
+        self.verified.set_metric(metric.clone());
+        self.unverified.set_metric(metric.clone());
+        self.quarantine.set_metric(metric);
+    }
+
+    /// Sets the [EvictionPolicy] that decides which nodes a full bucket keeps. The
+    /// default is [LongestLivedFirst](crate::storage::eviction::LongestLivedFirst).
+    pub fn set_eviction_policy(&mut self, policy: Arc<dyn EvictionPolicy>) {
+        let order = Arc::new(move |a: &NodeWrapper, b: &NodeWrapper| policy.compare(a, b));
+        self.verified.set_eviction_order(order.clone());
+        self.unverified.set_eviction_order(order.clone());
+        self.quarantine.set_eviction_order(order);
+    }
+
     fn add_or_update_last_seen(&mut self, node: Node) {
         if let Some(existing) = self.verified.get_mut(&node.id) {
             trace!(target: "rustydht_lib::NodeBucketStorage", "Updating existing verified {:?} last seen", node);
@@ -111,19 +235,26 @@ impl NodeBucketStorage {
         } else if let Some(existing) = self.unverified.get_mut(&node.id) {
             trace!(target: "rustydht_lib::NodeBucketStorage", "Updating existing unverified {:?} last seen", node);
             existing.last_seen = std::time::Instant::now();
+        } else if let Some(existing) = self.quarantine.get_mut(&node.id) {
+            trace!(target: "rustydht_lib::NodeBucketStorage", "Updating quarantined {:?} last seen", node);
+            existing.last_seen = std::time::Instant::now();
         } else {
-            trace!(target: "rustydht_lib::NodeBucketStorage", "Attempting to add unverified {:?}", node);
-            self.unverified.add(NodeWrapper::new(node), None);
+            trace!(target: "rustydht_lib::NodeBucketStorage", "Attempting to quarantine {:?}", node);
+            self.quarantine.add(NodeWrapper::new(node), None);
         }
     }
 
     fn add_or_update_verified(&mut self, node: Node) {
         let now = std::time::Instant::now();
 
-        // Already exists in unverified.
+        // Already exists in unverified or quarantine.
         // Remove it and try to add it to Verified.
         // If verified is full, add whatever overflows back to unverified (if it fits)
-        if let Some(mut item) = self.unverified.remove(&node.id) {
+        if let Some(mut item) = self
+            .unverified
+            .remove(&node.id)
+            .or_else(|| self.quarantine.remove(&node.id))
+        {
             trace!(target: "rustydht_lib::NodeBucketStorage", "Attempting to move {:?} from unverified to verified", node);
             item.last_seen = now;
             item.last_verified = Some(now);
@@ -170,19 +301,23 @@ impl NodeStorage for NodeBucketStorage {
 
     fn clear(&mut self) {
         self.unverified.clear();
+        self.quarantine.clear();
         self.verified.clear();
     }
 
     fn count(&self) -> (usize, usize) {
-        (self.unverified.count(), self.verified.count())
+        (
+            self.unverified.count() + self.quarantine.count(),
+            self.verified.count(),
+        )
     }
 
     fn get_all_unverified(&self) -> Vec<NodeWrapper> {
         self.unverified
             .values()
-            .iter()
-            .copied()
-            .map(|nw| nw.clone())
+            .into_iter()
+            .chain(self.quarantine.values())
+            .cloned()
             .collect()
     }
 
@@ -195,6 +330,10 @@ impl NodeStorage for NodeBucketStorage {
             .collect()
     }
 
+    fn get_quarantined(&self) -> Vec<NodeWrapper> {
+        self.quarantine.values().into_iter().cloned().collect()
+    }
+
     fn get_nearest_nodes(&self, id: &Id, exclude: Option<&Id>) -> Vec<Node> {
         self.verified
             .get_nearest_nodes(id, exclude)
@@ -203,35 +342,86 @@ impl NodeStorage for NodeBucketStorage {
             .collect()
     }
 
+    fn get_nearest_nodes_for_family(
+        &self,
+        id: &Id,
+        exclude: Option<&Id>,
+        family: AddressFamily,
+    ) -> Vec<Node> {
+        self.get_nearest_nodes_for_family_matching(id, exclude, family, &|_| true)
+    }
+
+    fn get_nearest_nodes_for_family_matching(
+        &self,
+        id: &Id,
+        exclude: Option<&Id>,
+        family: AddressFamily,
+        keep: &dyn Fn(&Node) -> bool,
+    ) -> Vec<Node> {
+        self.verified
+            .get_nearest_nodes_matching(id, exclude, |nw| {
+                nw.node.family() == family && keep(&nw.node)
+            })
+            .iter()
+            .map(|nw| nw.node.clone())
+            .collect()
+    }
+
     fn prune(&mut self, grace_period: Duration, unverified_grace_period: Duration) {
-        if let Some(time) = Instant::now().checked_sub(grace_period) {
-            if let Some(unverified_time) = Instant::now().checked_sub(unverified_grace_period) {
-                self.verified.retain(|nw| {
-                    if let Some(last_verified) = nw.last_verified {
-                        return last_verified >= time;
-                    }
-                    trace!(target: "rustydht_lib::NodeBucketStorage", "Verified {:?} hasn't verified recently. Removing.", nw.node);
-                    return false;
-                });
-                self.unverified.retain(|nw| {
-                    if let Some(last_verified) = nw.last_verified {
-                        if last_verified >= time {
-                            return true;
-                        }
-                    }
-                    if nw.last_seen >= time && nw.last_seen >= unverified_time {
-                        return true;
-                    }
-                    trace!(target: "rustydht_lib::NodeBucketStorage", "Unverified {:?} is dead. Removing", nw.node);
-                    return false;
-                });
+        // A grace period can reach back before the monotonic clock started (e.g. shortly
+        // after boot), in which case nothing is old enough to be pruned for it. Each
+        // period is checked on its own, so one of them reaching back that far doesn't
+        // stop pruning for the other.
+        let now = Instant::now();
+        let time = now.checked_sub(grace_period);
+        let unverified_time = now.checked_sub(unverified_grace_period);
+        let is_recent = |instant: Instant, since: Option<Instant>| match since {
+            Some(since) => instant >= since,
+            None => true,
+        };
+
+        self.verified.retain(|nw| {
+            if let Some(last_verified) = nw.last_verified {
+                if is_recent(last_verified, time) {
+                    return true;
+                }
             }
-        }
+            trace!(target: "rustydht_lib::NodeBucketStorage", "Verified {:?} hasn't verified recently. Removing.", nw.node);
+            false
+        });
+        self.unverified.retain(|nw| {
+            if let Some(last_verified) = nw.last_verified {
+                if is_recent(last_verified, time) {
+                    return true;
+                }
+            }
+            if is_recent(nw.last_seen, time) && is_recent(nw.last_seen, unverified_time) {
+                return true;
+            }
+            trace!(target: "rustydht_lib::NodeBucketStorage", "Unverified {:?} is dead. Removing", nw.node);
+            false
+        });
+        self.quarantine.retain(|nw| {
+            let keep = is_recent(nw.last_seen, time) && is_recent(nw.last_seen, unverified_time);
+            if !keep {
+                trace!(target: "rustydht_lib::NodeBucketStorage", "Quarantined {:?} never verified. Removing", nw.node);
+            }
+            keep
+        });
+    }
+
+    fn bucket_index(&self, id: &Id) -> Option<usize> {
+        Some(self.verified.bucket_index(id))
+    }
+
+    fn buckets_capacity(&self) -> Option<usize> {
+        Some(self.verified.buckets_capacity())
     }
 
     fn set_id(&mut self, new_id: Id) {
         self.verified.set_id(new_id);
         self.unverified.set_id(new_id);
+        self.quarantine.set_id(new_id);
     }
 }
 
@@ -364,6 +554,145 @@ mod tests {
         assert_eq!(closer_id, result[0].id);
     }
 
+    #[test]
+    fn test_get_nearest_nodes_for_family() {
+        let our_id = Id::from_hex("0000000000000000000000000000000000000000").unwrap();
+        let mut storage = NodeBucketStorage::new(our_id, 8);
+
+        let v4_node = Node::new(
+            Id::from_hex("8000000000000000000000000000000000000001").unwrap(),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
+        );
+        let v6_node = Node::new(
+            Id::from_hex("8000000000000000000000000000000000000002").unwrap(),
+            "[::1]:8080".parse().unwrap(),
+        );
+        storage.add_or_update(v4_node.clone(), true);
+        storage.add_or_update(v6_node.clone(), true);
+
+        let seeking_id = Id::from_hex("8000000000000000000000000000000000000000").unwrap();
+        assert_eq!(2, storage.get_nearest_nodes(&seeking_id, None).len());
+        assert_eq!(
+            vec![v4_node],
+            storage.get_nearest_nodes_for_family(&seeking_id, None, AddressFamily::V4)
+        );
+        assert_eq!(
+            vec![v6_node],
+            storage.get_nearest_nodes_for_family(&seeking_id, None, AddressFamily::V6)
+        );
+    }
+
+    #[test]
+    fn test_quarantined_node_is_not_shared() {
+        let our_id = Id::from_hex("0000000000000000000000000000000000000000").unwrap();
+        let mut storage = NodeBucketStorage::with_quarantine_size(our_id, 8, 2);
+        let node = Node::new(
+            Id::from_hex("8000000000000000000000000000000000000001").unwrap(),
+            "1.2.3.4:1234".parse().unwrap(),
+        );
+        let seeking_id = Id::from_hex("8000000000000000000000000000000000000000").unwrap();
+
+        // Seen, but never verified
+        storage.add_or_update(node.clone(), false);
+        storage.add_or_update(node.clone(), false);
+        assert!(storage.get_nearest_nodes(&seeking_id, None).is_empty());
+        assert_eq!(storage.get_quarantined().len(), 1);
+        assert_eq!(storage.get_all_unverified().len(), 1);
+
+        // The quarantine buckets are bounded separately
+        for i in 2..6 {
+            storage.add_or_update(
+                Node::new(
+                    Id::from_hex(&format!("800000000000000000000000000000000000000{}", i)).unwrap(),
+                    "1.2.3.4:1234".parse().unwrap(),
+                ),
+                false,
+            );
+        }
+        assert_eq!(storage.get_quarantined().len(), 2);
+        assert!(storage.get_nearest_nodes(&seeking_id, None).is_empty());
+
+        // Once verified, it leaves quarantine and can be shared
+        storage.add_or_update(node.clone(), true);
+        assert_eq!(storage.get_nearest_nodes(&seeking_id, None), vec![node]);
+        assert_eq!(storage.get_quarantined().len(), 1);
+    }
+
+    #[test]
+    fn test_full_bucket_keeps_verified_nodes() {
+        let our_id = Id::from_hex("0000000000000000000000000000000000000000").unwrap();
+        let mut storage = NodeBucketStorage::new(our_id, 2);
+        let node = |i: usize| {
+            Node::new(
+                Id::from_hex(&format!("800000000000000000000000000000000000000{}", i)).unwrap(),
+                "1.2.3.4:1234".parse().unwrap(),
+            )
+        };
+        let verified_ids = |storage: &NodeBucketStorage| {
+            let mut ids: Vec<String> = storage
+                .get_all_verified()
+                .iter()
+                .map(|nw| nw.node.id.to_string())
+                .collect();
+            ids.sort();
+            ids
+        };
+        storage.add_or_update(node(1), true);
+        storage.add_or_update(node(2), true);
+        let expected = verified_ids(&storage);
+        assert_eq!(expected.len(), 2);
+
+        // Unverified nodes don't evict anything
+        for i in 3..6 {
+            storage.add_or_update(node(i), false);
+        }
+        assert_eq!(verified_ids(&storage), expected);
+
+        // Neither do newer verified ones
+        std::thread::sleep(Duration::from_millis(1));
+        storage.add_or_update(node(3), true);
+        assert_eq!(verified_ids(&storage), expected);
+        assert!(storage
+            .get_all_unverified()
+            .iter()
+            .any(|nw| nw.node == node(3) && nw.last_verified.is_some()));
+    }
+
+    /// Keeps the most recently verified nodes
+    struct NewestFirst;
+
+    impl EvictionPolicy for NewestFirst {
+        fn compare(&self, a: &NodeWrapper, b: &NodeWrapper) -> std::cmp::Ordering {
+            b.last_verified.cmp(&a.last_verified)
+        }
+    }
+
+    #[test]
+    fn test_eviction_policy() {
+        let our_id = Id::from_hex("0000000000000000000000000000000000000000").unwrap();
+        let mut storage = NodeBucketStorage::new(our_id, 1);
+        storage.set_eviction_policy(Arc::new(NewestFirst));
+        let old = Node::new(
+            Id::from_hex("8000000000000000000000000000000000000001").unwrap(),
+            "1.2.3.4:1234".parse().unwrap(),
+        );
+        let new = Node::new(
+            Id::from_hex("8000000000000000000000000000000000000002").unwrap(),
+            "1.2.3.5:1234".parse().unwrap(),
+        );
+        storage.add_or_update(old.clone(), true);
+        std::thread::sleep(Duration::from_millis(1));
+        storage.add_or_update(new.clone(), true);
+
+        let verified: Vec<Node> = storage
+            .get_all_verified()
+            .into_iter()
+            .map(|nw| nw.node)
+            .collect();
+        assert_eq!(verified, vec![new]);
+        assert_eq!(storage.get_all_unverified()[0].node, old);
+    }
+
     #[test]
     fn test_empty_prune() {
         let our_id = Id::from_hex("0000000000000000000000000000000000000000").unwrap();
@@ -373,6 +702,32 @@ mod tests {
         storage.prune(period, period);
     }
 
+    #[test]
+    fn test_prune_grace_period_before_clock_start() {
+        let our_id = Id::from_hex("0000000000000000000000000000000000000000").unwrap();
+        let mut storage = NodeBucketStorage::new(our_id, 8);
+        let verified = Node::new(
+            Id::from_hex("8000000000000000000000000000000000000001").unwrap(),
+            "1.2.3.4:1234".parse().unwrap(),
+        );
+        let quarantined = Node::new(
+            Id::from_hex("8000000000000000000000000000000000000002").unwrap(),
+            "1.2.3.5:1234".parse().unwrap(),
+        );
+        storage.add_or_update(verified.clone(), true);
+        storage.add_or_update(quarantined.clone(), false);
+
+        // Nothing can be older than grace periods that reach back before the clock started
+        let forever = Duration::from_secs(u64::MAX);
+        storage.prune(forever, forever);
+        assert_eq!(storage.count(), (1, 1));
+
+        // One grace period that reaches back that far doesn't stop pruning for the other
+        std::thread::sleep(Duration::from_millis(1));
+        storage.prune(Duration::ZERO, forever);
+        assert_eq!(storage.count(), (0, 0));
+    }
+
     #[test]
     fn test_clear() {
         let our_id = Id::from_hex("0000000000000000000000000000000000000000").unwrap();
@@ -1,6 +1,6 @@
 use super::buckets::Bucketable;
 use crate::common::{Id, Node};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 /// Wraps a Node with information about when the DHT first saw, last saw it, and last verified it.
 /// This is used by [NodeStorage](crate::storage::node_bucket_storage::NodeStorage) implementations
@@ -33,6 +33,26 @@ impl NodeWrapper {
             last_verified: None,
         }
     }
+
+    /// Returns how long ago this Node was last seen.
+    pub fn age_since_seen(&self) -> Duration {
+        Instant::now().saturating_duration_since(self.last_seen)
+    }
+
+    /// Returns how long ago this Node was last verified, or None if it never was.
+    pub fn age_since_verified(&self) -> Option<Duration> {
+        self.last_verified
+            .map(|last_verified| Instant::now().saturating_duration_since(last_verified))
+    }
+
+    /// Returns true if this Node hasn't been verified within the last `verify_grace`
+    /// (including if it was never verified at all).
+    pub fn is_stale(&self, verify_grace: Duration) -> bool {
+        match self.age_since_verified() {
+            Some(age) => age > verify_grace,
+            None => true,
+        }
+    }
 }
 
 impl Bucketable for NodeWrapper {
@@ -44,3 +64,44 @@ impl Bucketable for NodeWrapper {
         self.first_seen
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Offsets are kept small, as subtracting from a monotonic clock that started recently
+    // (e.g. right after boot) panics
+    fn make_wrapper(seen_secs_ago: u64, verified_secs_ago: Option<u64>) -> NodeWrapper {
+        let now = Instant::now();
+        let mut wrapper = NodeWrapper::new(Node::new(
+            Id::from_hex("0000000000000000000000000000000000000001").unwrap(),
+            "127.0.0.1:6881".parse().unwrap(),
+        ));
+        wrapper.first_seen =
+            now - Duration::from_secs(seen_secs_ago.max(verified_secs_ago.unwrap_or(0)));
+        wrapper.last_seen = now - Duration::from_secs(seen_secs_ago);
+        wrapper.last_verified = verified_secs_ago.map(|secs| now - Duration::from_secs(secs));
+        wrapper
+    }
+
+    #[test]
+    fn test_ages() {
+        let wrapper = make_wrapper(3, Some(12));
+        assert!(wrapper.age_since_seen() >= Duration::from_secs(3));
+        assert!(wrapper.age_since_seen() < Duration::from_secs(4));
+        let verified_age = wrapper.age_since_verified().unwrap();
+        assert!(verified_age >= Duration::from_secs(12));
+        assert!(verified_age < Duration::from_secs(13));
+
+        assert_eq!(make_wrapper(3, None).age_since_verified(), None);
+    }
+
+    #[test]
+    fn test_is_stale() {
+        assert!(!make_wrapper(0, Some(6)).is_stale(Duration::from_secs(30)));
+        assert!(make_wrapper(0, Some(60)).is_stale(Duration::from_secs(30)));
+
+        // Never verified is always stale, no matter how recently it was seen
+        assert!(make_wrapper(0, None).is_stale(Duration::from_secs(30)));
+    }
+}
@@ -3,22 +3,51 @@ use std::net::SocketAddr;
 use crate::common::{Id, TransactionId};
 use crate::packets::{Message, MessageType};
 
-use std::time::{Duration, Instant};
+use std::time::Duration;
+use tokio::time::Instant;
 
 use log::debug;
 use tokio::sync::mpsc;
 
+/// How long requests are kept by default before they're pruned
+const DEFAULT_MAX_LIFETIME: Duration = Duration::from_secs(30);
+
+/// How often pruning happens by default
+const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
 pub struct OutboundRequestStorage {
     requests: std::collections::HashMap<TransactionId, RequestInfo>,
+    max_lifetime: Duration,
+    check_interval: Duration,
 }
 
 impl OutboundRequestStorage {
     pub fn new() -> OutboundRequestStorage {
         OutboundRequestStorage {
             requests: std::collections::HashMap::new(),
+            max_lifetime: DEFAULT_MAX_LIFETIME,
+            check_interval: DEFAULT_CHECK_INTERVAL,
         }
     }
 
+    /// Sets how long requests are kept before [prune_expired](OutboundRequestStorage::prune_expired)
+    /// removes them, and how often that should be called.
+    pub fn set_max_lifetime(&mut self, max_lifetime: Duration, check_interval: Duration) {
+        self.max_lifetime = max_lifetime;
+        self.check_interval = check_interval;
+    }
+
+    /// How often [prune_expired](OutboundRequestStorage::prune_expired) should be called
+    pub fn check_interval(&self) -> Duration {
+        self.check_interval
+    }
+
+    /// Removes requests that have been waiting longer than the max lifetime. Whoever was
+    /// waiting on their response channels sees them close.
+    pub fn prune_expired(&mut self) {
+        self.prune_older_than(self.max_lifetime);
+    }
+
     pub fn add_request(&mut self, info: RequestInfo) {
         self.requests
             .insert(info.packet.transaction_id.clone().into(), info);
@@ -85,6 +114,25 @@ impl OutboundRequestStorage {
         None
     }
 
+    /// Takes the request with transaction id `tid` that was sent to `src_addr`, if whoever
+    /// sent it wants the raw bytes of whatever comes back (even if it isn't a matching
+    /// response, or doesn't parse at all).
+    pub fn take_raw_request_info(
+        &mut self,
+        tid: &[u8],
+        src_addr: SocketAddr,
+    ) -> Option<RequestInfo> {
+        let tid = tid.to_vec().into();
+        match self.requests.get(&tid) {
+            Some(request_info)
+                if request_info.addr == src_addr && request_info.raw_response_channel.is_some() =>
+            {
+                self.requests.remove(&tid)
+            }
+            _ => None,
+        }
+    }
+
     pub fn prune_older_than(&mut self, duration: Duration) {
         match Instant::now().checked_sub(duration) {
             None => {
@@ -116,6 +164,7 @@ pub struct RequestInfo {
     packet: Message,
     created_at: Instant,
     pub(crate) response_channel: Option<mpsc::Sender<Message>>,
+    pub(crate) raw_response_channel: Option<mpsc::Sender<Vec<u8>>>,
 }
 
 impl RequestInfo {
@@ -131,8 +180,18 @@ impl RequestInfo {
             packet: packet,
             created_at: Instant::now(),
             response_channel: response_channel,
+            raw_response_channel: None,
         }
     }
+
+    /// Also sends the raw bytes of the response to `raw_response_channel`
+    pub fn with_raw_response_channel(
+        mut self,
+        raw_response_channel: mpsc::Sender<Vec<u8>>,
+    ) -> Self {
+        self.raw_response_channel = Some(raw_response_channel);
+        self
+    }
 }
 
 #[cfg(test)]
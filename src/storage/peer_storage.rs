@@ -1,7 +1,9 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
-use crate::common::Id;
+use crate::common::{AddressFamily, Id};
 
 use lru::LruCache;
 
@@ -22,34 +24,149 @@ impl PeerInfo {
     }
 }
 
+/// Which stored peer an announce counts as a repeat of.
+///
+/// A repeated announce refreshes the stored peer's timestamp instead of adding another
+/// entry for the info_hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePeerPolicy {
+    /// A peer with the same IP address and port
+    SameAddress,
+
+    /// A peer with the same IP address. If the port changed, the stored peer is replaced
+    /// by one with the new port, so each IP address holds at most one entry per info_hash.
+    SameIp,
+}
+
+/// Whether [PeerStorage::announce_peer] stored a new peer or refreshed one it already had
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceOutcome {
+    New,
+    Refreshed,
+}
+
+/// A pinned info_hash can store this many times as many peers as the others
+const PINNED_PEERS_FACTOR: usize = 4;
+
+/// The peers stored for an info_hash
+struct Swarm {
+    peers: LruCache<SocketAddr, PeerInfo>,
+
+    /// How long peers stay fresh, if this info_hash doesn't use the default
+    ttl: Option<Duration>,
+}
+
+impl Swarm {
+    fn new(capacity: usize) -> Swarm {
+        Swarm {
+            peers: LruCache::new(capacity),
+            ttl: None,
+        }
+    }
+
+    /// Moves all the peers and the TTL into a new swarm with room for `capacity` peers
+    fn resized(mut self, capacity: usize) -> Swarm {
+        let mut swarm = Swarm::new(capacity);
+        swarm.ttl = self.ttl;
+        // Oldest first, so the most recently used peers stay that way
+        while let Some((addr, info)) = self.peers.pop_lru() {
+            swarm.peers.put(addr, info);
+        }
+        swarm
+    }
+}
+
 pub struct PeerStorage {
     /// Stored in RefCell to allow interior mutability... LruCache mutates on .get()
-    peers: RefCell<LruCache<Id, LruCache<SocketAddr, PeerInfo>>>,
+    peers: RefCell<LruCache<Id, Swarm>>,
+
+    /// Info hashes that are never evicted to make room for others. Not counted
+    /// against max_torrents.
+    pinned: HashMap<Id, Swarm>,
     max_peers_per_torrent: usize,
+    duplicate_policy: DuplicatePeerPolicy,
 }
 
 impl PeerStorage {
     pub fn new(max_torrents: usize, max_peers_per_torrent: usize) -> PeerStorage {
+        PeerStorage::with_capacity(max_torrents, max_peers_per_torrent, 0)
+    }
+
+    /// Same as [new](PeerStorage::new), but makes room for `pinned_capacity` pinned
+    /// info_hashes up front.
+    ///
+    /// Unpinned info_hashes, and the peers of each info_hash, are kept in LRU caches that
+    /// already make room for `max_torrents` and `max_peers_per_torrent` entries when
+    /// they're created. Pinned info_hashes aren't limited, so they're kept in a map that
+    /// grows as needed.
+    pub fn with_capacity(
+        max_torrents: usize,
+        max_peers_per_torrent: usize,
+        pinned_capacity: usize,
+    ) -> PeerStorage {
         PeerStorage {
             peers: RefCell::new(LruCache::new(max_torrents)),
+            pinned: HashMap::with_capacity(pinned_capacity),
             max_peers_per_torrent: max_peers_per_torrent,
+            duplicate_policy: DuplicatePeerPolicy::SameAddress,
         }
     }
 
-    pub fn announce_peer(&mut self, info_hash: Id, peer_addr: SocketAddr) {
-        let mut peers = self.peers.borrow_mut();
-        match peers.get_mut(&info_hash) {
-            Some(swarm_lru) => {
-                swarm_lru.put(peer_addr, PeerInfo::new(peer_addr));
-            }
+    /// Returns how many pinned info_hashes there's room for without reallocating
+    pub fn pinned_capacity(&self) -> usize {
+        self.pinned.capacity()
+    }
+
+    /// Changes which stored peers later announces count as repeats of. Peers already
+    /// stored are left as they are.
+    pub fn set_duplicate_policy(&mut self, duplicate_policy: DuplicatePeerPolicy) {
+        self.duplicate_policy = duplicate_policy;
+    }
+
+    /// Stores `peer_addr` as a peer for `info_hash`, or refreshes its timestamp if it's a
+    /// repeat of a stored peer according to the [DuplicatePeerPolicy].
+    pub fn announce_peer(&mut self, info_hash: Id, peer_addr: SocketAddr) -> AnnounceOutcome {
+        self.announce_peer_with_ttl(info_hash, peer_addr, None)
+    }
+
+    /// Same as [announce_peer](PeerStorage::announce_peer), but if `ttl` is given, it also
+    /// becomes the info_hash's TTL (see [set_ttl](PeerStorage::set_ttl)).
+    pub fn announce_peer_with_ttl(
+        &mut self,
+        info_hash: Id,
+        peer_addr: SocketAddr,
+        ttl: Option<Duration>,
+    ) -> AnnounceOutcome {
+        let duplicate_policy = self.duplicate_policy;
+        let outcome = if let Some(swarm) = self.pinned.get_mut(&info_hash) {
+            swarm.ttl = ttl.or(swarm.ttl);
+            add_to_swarm(swarm, peer_addr, duplicate_policy)
+        } else {
+            let mut peers = self.peers.borrow_mut();
+            match peers.get_mut(&info_hash) {
+                Some(swarm) => {
+                    swarm.ttl = ttl.or(swarm.ttl);
+                    add_to_swarm(swarm, peer_addr, duplicate_policy)
+                }
 
-            None => {
-                let mut swarm_lru = LruCache::new(self.max_peers_per_torrent);
-                swarm_lru.put(peer_addr, PeerInfo::new(peer_addr));
-                peers.put(info_hash, swarm_lru);
+                None => {
+                    let mut swarm = Swarm::new(self.max_peers_per_torrent);
+                    swarm.ttl = ttl;
+                    swarm.peers.put(peer_addr, PeerInfo::new(peer_addr));
+                    peers.put(info_hash, swarm);
+                    AnnounceOutcome::New
+                }
+            }
+        };
+        match outcome {
+            AnnounceOutcome::New => {
+                debug!(target: "rustydht_lib::PeerStorage", "{} is in swarm with info_hash {}", peer_addr, info_hash)
+            }
+            AnnounceOutcome::Refreshed => {
+                debug!(target: "rustydht_lib::PeerStorage", "{} is still in swarm with info_hash {}", peer_addr, info_hash)
             }
         }
-        debug!(target: "rustydht_lib::PeerStorage", "{} is in swarm with info_hash {}", peer_addr, info_hash);
+        outcome
     }
 
     pub fn get_peers(
@@ -61,32 +178,264 @@ impl PeerStorage {
         infos.iter().map(|info| info.addr).collect()
     }
 
+    /// Like [get_peers](PeerStorage::get_peers), but returns the peers of any of the
+    /// address `families` instead of only IPv4 peers.
+    pub fn get_peers_for_families(
+        &self,
+        info_hash: &Id,
+        newer_than: Option<std::time::Instant>,
+        families: &[AddressFamily],
+    ) -> Vec<SocketAddr> {
+        if let Some(swarm) = self.pinned.get(info_hash) {
+            return fresh_peers(swarm, newer_than, families)
+                .map(|info| info.addr)
+                .collect();
+        }
+        let mut peers = self.peers.borrow_mut();
+        match peers.get(info_hash) {
+            Some(swarm) => fresh_peers(swarm, newer_than, families)
+                .map(|info| info.addr)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the IPv4 peers of `info_hash` that announced after `newer_than`, with when
+    /// they did. Like [get_peers](PeerStorage::get_peers), this only has IPv4 peers, which
+    /// were the only ones it ever returned; see
+    /// [get_peers_for_families](PeerStorage::get_peers_for_families) for the others.
     pub fn get_peers_info(
         &self,
         info_hash: &Id,
         newer_than: Option<std::time::Instant>,
     ) -> Vec<PeerInfo> {
+        if let Some(swarm) = self.pinned.get(info_hash) {
+            return fresh_peers(swarm, newer_than, &[AddressFamily::V4])
+                .cloned()
+                .collect();
+        }
         let mut peers = self.peers.borrow_mut();
-        let mut to_ret = Vec::new();
-        if let Some(swarm_lru) = peers.get(info_hash) {
-            let mut tmp = swarm_lru
+        match peers.get(info_hash) {
+            Some(swarm) => fresh_peers(swarm, newer_than, &[AddressFamily::V4])
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Counts the peers of any of the address `families` that
+    /// [get_peers_for_families](PeerStorage::get_peers_for_families) would return, without
+    /// copying them. Unlike it, this doesn't mark the info_hash as recently used.
+    pub fn count_peers(
+        &self,
+        info_hash: &Id,
+        newer_than: Option<std::time::Instant>,
+        families: &[AddressFamily],
+    ) -> usize {
+        if let Some(swarm) = self.pinned.get(info_hash) {
+            return fresh_peers(swarm, newer_than, families).count();
+        }
+        let peers = self.peers.borrow();
+        match peers.peek(info_hash) {
+            Some(swarm) => fresh_peers(swarm, newer_than, families).count(),
+            None => 0,
+        }
+    }
+
+    /// Sets how long peers of `info_hash` stay fresh, overriding the default freshness
+    /// window (e.g. shorter for a live event, longer for an archive), or goes back to the
+    /// default if `ttl` is None. The TTL is stored with the info_hash's peers, so it's
+    /// forgotten when they are. Returns false if there are no peers stored for `info_hash`.
+    pub fn set_ttl(&mut self, info_hash: &Id, ttl: Option<Duration>) -> bool {
+        if let Some(swarm) = self.pinned.get_mut(info_hash) {
+            swarm.ttl = ttl;
+            return true;
+        }
+        match self.peers.borrow_mut().peek_mut(info_hash) {
+            Some(swarm) => {
+                swarm.ttl = ttl;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the TTL of `info_hash`, if it has one of its own
+    pub fn get_ttl(&self, info_hash: &Id) -> Option<Duration> {
+        match self.pinned.get(info_hash) {
+            Some(swarm) => swarm.ttl,
+            None => self
+                .peers
+                .borrow()
+                .peek(info_hash)
+                .and_then(|swarm| swarm.ttl),
+        }
+    }
+
+    /// Removes the peers that have outlived the TTL of their info_hash, and the info
+    /// hashes that are left without peers (unless pinned). Info hashes without a TTL of
+    /// their own are left alone. Returns the number of peers removed.
+    pub fn prune_expired(&mut self) -> usize {
+        let now = Instant::now();
+        let mut removed = 0;
+        let mut empty = Vec::new();
+        let mut peers = self.peers.borrow_mut();
+        let swarms = self
+            .pinned
+            .iter_mut()
+            .map(|(info_hash, swarm)| (info_hash, swarm, true))
+            .chain(
+                peers
+                    .iter_mut()
+                    .map(|(info_hash, swarm)| (info_hash, swarm, false)),
+            );
+        for (info_hash, swarm, pinned) in swarms {
+            // Nothing can be older than a TTL that reaches back before the clock started
+            let cutoff = match swarm.ttl.and_then(|ttl| now.checked_sub(ttl)) {
+                Some(cutoff) => cutoff,
+                None => continue,
+            };
+            let expired: Vec<SocketAddr> = swarm
+                .peers
                 .iter()
-                .filter(|pi| pi.0.ip().is_ipv4()) // Only return IPv4 for now, you dog!
-                .filter(|pi| newer_than.is_none() || pi.1.last_updated > newer_than.unwrap())
-                .map(|pi| pi.1.clone())
+                .filter(|(_, info)| info.last_updated <= cutoff)
+                .map(|(addr, _)| *addr)
                 .collect();
-            to_ret.append(&mut tmp);
+            for addr in &expired {
+                swarm.peers.pop(addr);
+            }
+            removed += expired.len();
+            if !pinned && swarm.peers.is_empty() {
+                empty.push(*info_hash);
+            }
         }
-
-        to_ret
+        for info_hash in &empty {
+            peers.pop(info_hash);
+        }
+        if removed > 0 {
+            debug!(target: "rustydht_lib::PeerStorage", "Pruned {} peers that outlived their info_hash's TTL", removed);
+        }
+        removed
     }
 
+    /// Returns all the info hashes with stored peers, pinned ones first. Pinned info hashes
+    /// that don't have any peers (yet) aren't included.
     pub fn get_info_hashes(&self) -> Vec<Id> {
         let peers = self.peers.borrow();
-        peers.iter().map(|kv| kv.0.clone()).collect()
+        self.pinned
+            .iter()
+            .filter(|(_, swarm)| !swarm.peers.is_empty())
+            .map(|(info_hash, _)| info_hash)
+            .chain(peers.iter().map(|kv| kv.0))
+            .copied()
+            .collect()
+    }
+
+    /// Pins `info_hash`, so that its peers are kept no matter how many other info hashes
+    /// are announced. It also gets room for more peers than other info hashes. Peers
+    /// already stored for it are kept.
+    pub fn pin(&mut self, info_hash: Id) {
+        if self.pinned.contains_key(&info_hash) {
+            return;
+        }
+        let capacity = self.max_peers_per_torrent * PINNED_PEERS_FACTOR;
+        let swarm = match self.peers.borrow_mut().pop(&info_hash) {
+            Some(old_swarm) => old_swarm.resized(capacity),
+            None => Swarm::new(capacity),
+        };
+        self.pinned.insert(info_hash, swarm);
+    }
+
+    /// Unpins `info_hash`, so that it competes for space with the other info hashes
+    /// again. Returns false if it wasn't pinned.
+    pub fn unpin(&mut self, info_hash: &Id) -> bool {
+        let pinned_swarm = match self.pinned.remove(info_hash) {
+            Some(swarm) => swarm,
+            None => return false,
+        };
+        if !pinned_swarm.peers.is_empty() {
+            self.peers
+                .borrow_mut()
+                .put(*info_hash, pinned_swarm.resized(self.max_peers_per_torrent));
+        }
+        true
+    }
+
+    /// Returns true if `info_hash` is pinned
+    pub fn is_pinned(&self, info_hash: &Id) -> bool {
+        self.pinned.contains_key(info_hash)
+    }
+
+    /// Removes info hashes that have fewer than `min_peers` peers stored, since they're
+    /// likely dead torrents. Pinned info hashes are never removed. Returns the number of
+    /// info hashes removed.
+    pub fn prune_sparse(&mut self, min_peers: usize) -> usize {
+        let mut peers = self.peers.borrow_mut();
+        let sparse: Vec<Id> = peers
+            .iter()
+            .filter(|(_, swarm)| swarm.peers.len() < min_peers)
+            .map(|(info_hash, _)| *info_hash)
+            .collect();
+        for info_hash in &sparse {
+            peers.pop(info_hash);
+        }
+        if !sparse.is_empty() {
+            debug!(target: "rustydht_lib::PeerStorage", "Pruned {} info_hashes with fewer than {} peers", sparse.len(), min_peers);
+        }
+        sparse.len()
+    }
+
+    /// Changes the maximum number of (unpinned) info_hashes stored. If there are more than
+    /// that already, the least recently used ones are evicted.
+    pub fn set_max_torrents(&mut self, max_torrents: usize) {
+        self.peers.borrow_mut().resize(max_torrents);
+    }
+
+    /// Returns the number of info_hashes stored that aren't pinned, which is what
+    /// max_torrents limits
+    pub fn count_unpinned(&self) -> usize {
+        self.peers.borrow().len()
     }
 }
 
+/// Adds a peer to an existing swarm, refreshing it if it's a repeat according to `policy`
+fn add_to_swarm(
+    swarm: &mut Swarm,
+    peer_addr: SocketAddr,
+    policy: DuplicatePeerPolicy,
+) -> AnnounceOutcome {
+    let swarm_lru = &mut swarm.peers;
+    let existing = match policy {
+        DuplicatePeerPolicy::SameAddress => swarm_lru.pop(&peer_addr),
+        DuplicatePeerPolicy::SameIp => {
+            let stored_addr = swarm_lru
+                .iter()
+                .map(|(addr, _)| *addr)
+                .find(|addr| addr.ip() == peer_addr.ip());
+            stored_addr.and_then(|addr| swarm_lru.pop(&addr))
+        }
+    };
+    swarm_lru.put(peer_addr, PeerInfo::new(peer_addr));
+    match existing {
+        Some(_) => AnnounceOutcome::Refreshed,
+        None => AnnounceOutcome::New,
+    }
+}
+
+/// The peers of a swarm that we hand out: IPv4 ones that announced after `newer_than`
+fn fresh_peers<'a>(
+    swarm: &'a Swarm,
+    newer_than: Option<std::time::Instant>,
+    families: &'a [AddressFamily],
+) -> impl Iterator<Item = &'a PeerInfo> {
+    swarm
+        .peers
+        .iter()
+        .filter(move |pi| families.contains(&AddressFamily::from(pi.0)))
+        .filter(move |pi| newer_than.is_none() || pi.1.last_updated > newer_than.unwrap())
+        .map(|pi| pi.1)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -157,6 +506,190 @@ mod tests {
         assert_eq!(peer2, peers[0]);
     }
 
+    #[test]
+    fn test_prune_sparse() {
+        let mut storage = PeerStorage::new(10, 10);
+        let lonely_hash =
+            Id::from_hex("1988091919880919198809191988091919880919").expect("Couldn't make Id");
+        let busy_hash =
+            Id::from_hex("2088091919880919198809191988091919880920").expect("Couldn't make Id");
+        storage.announce_peer(lonely_hash, "10.0.0.6:1234".parse().unwrap());
+        storage.announce_peer(busy_hash, "10.0.0.6:1234".parse().unwrap());
+        storage.announce_peer(busy_hash, "10.0.0.7:1234".parse().unwrap());
+        storage.announce_peer(busy_hash, "10.0.0.8:1234".parse().unwrap());
+
+        assert_eq!(1, storage.prune_sparse(2));
+
+        let hashes = storage.get_info_hashes();
+        assert_eq!(1, hashes.len());
+        assert_eq!(busy_hash, hashes[0]);
+        assert_eq!(3, storage.get_peers(&busy_hash, None).len());
+    }
+
+    #[test]
+    fn test_ttl() {
+        let mut storage = PeerStorage::new(10, 10);
+        let live_hash =
+            Id::from_hex("1988091919880919198809191988091919880919").expect("Couldn't make Id");
+        let default_hash =
+            Id::from_hex("2088091919880919198809191988091919880920").expect("Couldn't make Id");
+        assert!(!storage.set_ttl(&live_hash, Some(Duration::from_millis(50))));
+        storage.announce_peer_with_ttl(
+            live_hash,
+            "10.0.0.6:1234".parse().unwrap(),
+            Some(Duration::from_millis(50)),
+        );
+        storage.announce_peer(default_hash, "10.0.0.6:1234".parse().unwrap());
+
+        // Announcing without a TTL keeps the one that's there
+        storage.announce_peer(live_hash, "10.0.0.7:1234".parse().unwrap());
+        assert_eq!(storage.get_ttl(&live_hash), Some(Duration::from_millis(50)));
+        assert_eq!(storage.get_ttl(&default_hash), None);
+
+        // The TTL survives pinning
+        storage.pin(live_hash);
+        storage.unpin(&live_hash);
+        assert_eq!(storage.get_ttl(&live_hash), Some(Duration::from_millis(50)));
+
+        assert_eq!(storage.prune_expired(), 0);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(storage.prune_expired(), 2);
+        assert_eq!(storage.get_info_hashes(), vec![default_hash]);
+        assert_eq!(storage.get_ttl(&live_hash), None);
+
+        assert!(storage.set_ttl(&default_hash, Some(Duration::from_secs(3600))));
+        assert_eq!(storage.prune_expired(), 0);
+        assert_eq!(storage.get_peers(&default_hash, None).len(), 1);
+    }
+
+    #[test]
+    fn test_get_peers_for_families() {
+        let mut storage = PeerStorage::new(10, 10);
+        let info_hash =
+            Id::from_hex("1988091919880919198809191988091919880919").expect("Couldn't make Id");
+        let v4_peer: SocketAddr = "10.0.0.6:1234".parse().unwrap();
+        let v6_peer: SocketAddr = "[2001:db8::6]:1234".parse().unwrap();
+        storage.announce_peer(info_hash, v4_peer);
+        storage.announce_peer(info_hash, v6_peer);
+
+        assert_eq!(storage.get_peers(&info_hash, None), vec![v4_peer]);
+        assert_eq!(
+            storage.get_peers_for_families(&info_hash, None, &[AddressFamily::V6]),
+            vec![v6_peer]
+        );
+        let mut both = storage.get_peers_for_families(
+            &info_hash,
+            None,
+            &[AddressFamily::V4, AddressFamily::V6],
+        );
+        both.sort();
+        assert_eq!(both, vec![v4_peer, v6_peer]);
+        assert_eq!(
+            storage.count_peers(&info_hash, None, &[AddressFamily::V4]),
+            1
+        );
+        assert_eq!(
+            storage.count_peers(&info_hash, None, &[AddressFamily::V4, AddressFamily::V6]),
+            2
+        );
+    }
+
+    #[test]
+    fn test_duplicate_announces() {
+        let mut storage = PeerStorage::new(1, 10);
+        let info_hash =
+            Id::from_hex("1988091919880919198809191988091919880919").expect("Couldn't make Id");
+        let peer1: SocketAddr = "10.0.0.6:1234".parse().expect("Couldn't make SocketAddr");
+        assert_eq!(
+            AnnounceOutcome::New,
+            storage.announce_peer(info_hash, peer1)
+        );
+        let first_seen = storage.get_peers_info(&info_hash, None)[0].last_updated;
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        // The same address refreshes the stored peer
+        assert_eq!(
+            AnnounceOutcome::Refreshed,
+            storage.announce_peer(info_hash, peer1)
+        );
+        let peers = storage.get_peers_info(&info_hash, None);
+        assert_eq!(1, peers.len());
+        assert!(peers[0].last_updated > first_seen);
+
+        // By default a new port is another peer
+        let peer1_new_port: SocketAddr = "10.0.0.6:4321".parse().unwrap();
+        assert_eq!(
+            AnnounceOutcome::New,
+            storage.announce_peer(info_hash, peer1_new_port)
+        );
+        assert_eq!(2, storage.get_peers(&info_hash, None).len());
+
+        // Deduplicating by IP replaces the peer's old port
+        storage.set_duplicate_policy(DuplicatePeerPolicy::SameIp);
+        let peer1_newer_port: SocketAddr = "10.0.0.6:5555".parse().unwrap();
+        assert_eq!(
+            AnnounceOutcome::Refreshed,
+            storage.announce_peer(info_hash, peer1_newer_port)
+        );
+        let peers = storage.get_peers(&info_hash, None);
+        assert_eq!(2, peers.len());
+        assert!(peers.contains(&peer1_newer_port));
+        assert!(!peers.contains(&peer1_new_port));
+    }
+
+    #[test]
+    fn test_pinned_info_hash() {
+        let mut storage = PeerStorage::new(1, 2);
+        let pinned_hash =
+            Id::from_hex("1988091919880919198809191988091919880919").expect("Couldn't make Id");
+        let other_hash =
+            Id::from_hex("2088091919880919198809191988091919880920").expect("Couldn't make Id");
+        let newest_hash =
+            Id::from_hex("2188091919880919198809191988091919880921").expect("Couldn't make Id");
+        storage.announce_peer(pinned_hash, "10.0.0.6:1234".parse().unwrap());
+        storage.pin(pinned_hash);
+        assert!(storage.is_pinned(&pinned_hash));
+
+        // The pinned info_hash doesn't count against max_torrents, and has room for more peers
+        for port in 1..=5 {
+            storage.announce_peer(pinned_hash, SocketAddr::new([10, 0, 0, 7].into(), port));
+        }
+        storage.announce_peer(other_hash, "10.0.0.8:1234".parse().unwrap());
+        storage.announce_peer(newest_hash, "10.0.0.9:1234".parse().unwrap());
+        assert_eq!(6, storage.get_peers(&pinned_hash, None).len());
+        assert_eq!(0, storage.get_peers(&other_hash, None).len());
+        assert_eq!(1, storage.get_peers(&newest_hash, None).len());
+        assert_eq!(vec![pinned_hash, newest_hash], storage.get_info_hashes());
+        assert_eq!(1, storage.count_unpinned());
+
+        // Nor is it sparse
+        assert_eq!(1, storage.prune_sparse(2));
+        assert_eq!(vec![pinned_hash], storage.get_info_hashes());
+
+        // Once unpinned, it's like any other
+        assert!(storage.unpin(&pinned_hash));
+        assert!(!storage.unpin(&pinned_hash));
+        assert_eq!(2, storage.get_peers(&pinned_hash, None).len());
+        storage.announce_peer(other_hash, "10.0.0.8:1234".parse().unwrap());
+        assert_eq!(vec![other_hash], storage.get_info_hashes());
+    }
+
+    #[test]
+    fn test_pinned_info_hash_without_peers() {
+        let mut storage = PeerStorage::new(2, 2);
+        let pinned_hash =
+            Id::from_hex("1988091919880919198809191988091919880919").expect("Couldn't make Id");
+        let other_hash =
+            Id::from_hex("2088091919880919198809191988091919880920").expect("Couldn't make Id");
+        storage.pin(pinned_hash);
+        storage.announce_peer(other_hash, "10.0.0.8:1234".parse().unwrap());
+        assert!(storage.is_pinned(&pinned_hash));
+        assert_eq!(vec![other_hash], storage.get_info_hashes());
+
+        storage.announce_peer(pinned_hash, "10.0.0.6:1234".parse().unwrap());
+        assert_eq!(vec![pinned_hash, other_hash], storage.get_info_hashes());
+    }
+
     #[test]
     fn test_get_peers_newer_than() {
         let mut storage = PeerStorage::new(1, 3);
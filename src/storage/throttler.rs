@@ -1,4 +1,4 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::time::{Duration, Instant};
 
 use log::debug;
@@ -42,6 +42,9 @@ pub struct Throttler<const NUM_RECORDS: usize> {
     period: Duration,
     naughty_timeout: Duration,
     max_tracking: Duration,
+
+    ipv4_prefix_len: u8,
+    ipv6_prefix_len: u8,
 }
 
 impl<const NUM_RECORDS: usize> Throttler<NUM_RECORDS> {
@@ -57,10 +60,43 @@ impl<const NUM_RECORDS: usize> Throttler<NUM_RECORDS> {
             period: period,
             naughty_timeout: naughty_timeout,
             max_tracking: max_tracking,
+            ipv4_prefix_len: 32,
+            ipv6_prefix_len: 64,
+        }
+    }
+
+    /// Sets how many leading bits of an address identify the source that's throttled.
+    ///
+    /// Addresses that share their first `ipv4_prefix_len` (or `ipv6_prefix_len`) bits are
+    /// counted together. By default, each IPv4 address is its own source, while IPv6
+    /// addresses are grouped by /64 since one host can typically use any address in its /64.
+    pub fn set_prefix_lengths(&mut self, ipv4_prefix_len: u8, ipv6_prefix_len: u8) {
+        self.ipv4_prefix_len = ipv4_prefix_len.min(32);
+        self.ipv6_prefix_len = ipv6_prefix_len.min(128);
+    }
+
+    /// Returns the network of `ip` that it's throttled as
+    fn source_of(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(v4) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.ipv4_prefix_len as u32)
+                    .unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+            }
+            IpAddr::V6(v6) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.ipv6_prefix_len as u32)
+                    .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+            }
         }
     }
 
     /// Returns true if the provided IP is throttled.
+    ///
+    /// The IP is counted together with the others in its network, see
+    /// [set_prefix_lengths](Throttler::set_prefix_lengths).
     pub fn check_throttle(
         &mut self,
         ip: IpAddr,
@@ -71,12 +107,13 @@ impl<const NUM_RECORDS: usize> Throttler<NUM_RECORDS> {
             Some(instant) => instant,
             None => Instant::now(),
         };
+        let source = self.source_of(ip);
 
         let mut found: Option<&mut ThrottlerRecord> = None;
         let mut lamest: Option<&mut ThrottlerRecord> = None;
         for record in &mut self.records {
             // If record exists for this IP, use it
-            if record.ip == ip {
+            if record.ip == source {
                 found = Some(record);
                 break;
             }
@@ -119,7 +156,7 @@ impl<const NUM_RECORDS: usize> Throttler<NUM_RECORDS> {
         } else if let Some(lamest) = lamest {
             lamest.packets = count.unwrap_or(1);
             lamest.expiration = now + self.period;
-            lamest.ip = ip;
+            lamest.ip = source;
             lamest.creation_time = now;
 
             if lamest.packets > self.rate_limit {
@@ -203,6 +240,44 @@ mod tests {
         assert!(!throttler.check_throttle(ip, Some(fake_time), None));
     }
 
+    #[test]
+    // Tests that addresses in the same IPv6 /64 are throttled as one
+    fn test_ipv6_prefix() {
+        let mut throttler = Throttler::<32>::new(
+            5,
+            Duration::from_secs(5),
+            Duration::from_secs(100),
+            Duration::from_secs(1000),
+        );
+        for i in 0..5 {
+            let ip = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, i, i, i, i));
+            assert!(!throttler.check_throttle(ip, None, None));
+        }
+        let ip = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0xffff, 0, 0, 1));
+        assert!(throttler.check_throttle(ip, None, None));
+
+        // The neighboring /64 is somebody else
+        let ip = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 2, 0, 0, 0, 1));
+        assert!(!throttler.check_throttle(ip, None, None));
+
+        // IPv4 addresses are still on their own
+        for i in 0..6 {
+            let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 50, i));
+            assert!(!throttler.check_throttle(ip, None, None));
+        }
+
+        // Unless configured otherwise
+        throttler.set_prefix_lengths(24, 128);
+        for i in 10..15 {
+            let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, i));
+            assert!(!throttler.check_throttle(ip, None, None));
+        }
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 100));
+        assert!(throttler.check_throttle(ip, None, None));
+        let ip = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0xffff, 0, 0, 2));
+        assert!(!throttler.check_throttle(ip, None, None));
+    }
+
     #[test]
     /// Tests that the throttler avoids overflowing packet counts if that happens somehow
     fn test_avoids_overflow() {